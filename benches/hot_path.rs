@@ -0,0 +1,93 @@
+//! The request path from datagram to reply, run with `cargo bench` and
+//! compare against a baseline before a release:
+//!
+//! ```sh
+//! cargo bench -- --save-baseline before
+//! cargo bench -- --baseline before
+//! ```
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use dhc3po::bench::{self, Server};
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Clients a server sees again and again, well within its pool so offers
+/// are reused rather than the pool running dry
+const CLIENTS: u32 = 4096;
+
+fn parse(c: &mut Criterion) {
+    let discover = bench::discover(1, 0xdead_beef);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(discover.len() as u64));
+    group.bench_function("discover", |b| {
+        b.iter(|| bench::parse(black_box(&discover)).unwrap())
+    });
+    group.finish();
+}
+
+fn serialise(c: &mut Criterion) {
+    let mut buffer = [0u8; 1472];
+    c.bench_function("serialise/offer_options", |b| {
+        b.iter(|| bench::serialise_options(black_box(&mut buffer)))
+    });
+}
+
+fn answer(c: &mut Criterion) {
+    let server = Server::new();
+    let discovers: Vec<Vec<u8>> = (0..CLIENTS)
+        .map(|client| bench::discover(client, client))
+        .collect();
+    let mut next = 0;
+    c.bench_function("answer/discover", |b| {
+        b.iter_batched(
+            || {
+                next = (next + 1) % discovers.len();
+                &discovers[next]
+            },
+            |discover| server.answer(discover),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+/// Every thread answering its own clients from the one pool, the time is
+/// for one DISCOVER on each thread
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("answer/contended");
+    for threads in [2, 4, 8] {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_function(format!("{threads}_threads"), |b| {
+            let server = Server::new();
+            let discovers: Vec<Vec<Vec<u8>>> = (0..threads)
+                .map(|thread| {
+                    (thread * CLIENTS..(thread + 1) * CLIENTS)
+                        .map(|client| bench::discover(client, client))
+                        .collect()
+                })
+                .collect();
+            b.iter_custom(|iters| {
+                let started = Instant::now();
+                thread::scope(|scope| {
+                    for discovers in &discovers {
+                        let server = server.clone();
+                        scope.spawn(move || {
+                            for discover in discovers.iter().cycle().take(iters as usize) {
+                                server.answer(discover);
+                            }
+                        });
+                    }
+                });
+                started.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(3));
+    targets = parse, serialise, answer, contention
+}
+criterion_main!(benches);
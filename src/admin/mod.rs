@@ -0,0 +1,621 @@
+//! A small HTTP listener for people looking after the server. It serves a
+//! dashboard at `/` and the JSON it is built from under `/api`:
+//!
+//! * `GET /api/pool` how many addresses are bound, offered and free, roughly
+//!   how many seconds until none are free, our ranges and exclusions, and
+//!   addresses in conflict that clients keep declining
+//! * `GET /api/leases` every offer and lease that has not expired, with
+//!   where its client should be by the lease timers as `client_state`, see
+//!   [crate::state::ClientState]
+//! * `GET /api/events` the replies we sent most recently
+//! * `GET /api/stream` Server-Sent Events of each reply we send, as `reply`
+//!   events shaped like those of `/api/events`, and each lease that is bound,
+//!   renewed or reclaimed, as `lease` events shaped like those of
+//!   `/api/history`
+//! * `GET /api/history/<ip|mac>` who held the address, or which addresses
+//!   the client held, as far back as we remember, see [crate::audit]
+//! * `GET /api/stats` requests by message type, why we dropped those we could
+//!   not parse, how long we took to answer, and leases bound, renewed and
+//!   reclaimed in total and by the hour for the last day, and how far behind
+//!   the lease journal is
+//! * `POST /api/forcerenew/<ip>` send a FORCERENEW to the client bound to
+//!   `<ip>`, or to every bound client without one
+//! * `POST /api/ranges/<start>-<end>` add a range, or grow or shrink the
+//!   range it overlaps to these bounds
+//! * `DELETE /api/ranges/<start>-<end>` stop handing out a range
+//! * `POST /api/exclusions/<start>[-<end>]` stop handing out addresses
+//!   inside our ranges, `DELETE` hands them out again
+//! * `GET /api/trace` the clients we are tracing, `POST /api/trace/<mac>`
+//!   logs the packets of `<mac>` decoded and everything we decide about it
+//!   whatever `RUST_LOG` says, `DELETE` stops, see [crate::logging::trace]
+//!
+//! Clients holding an address that is no longer in the pool keep it until
+//! their lease ends, we NAK them when they try to renew so they move to a
+//! new one. Changes last until we restart, put them in the config too. We
+//! serve one subnet, to serve another run another server.
+//!
+//! There is no authentication, keep `admin-listen` on localhost or a
+//! management network. We refuse a POST when a browser tells us it came from
+//! another site's page.
+
+use log::{info, warn};
+
+use crate::alerts;
+use crate::audit::{AuditEvent, AuditRecord};
+use crate::dhcp::{self, Dhcp};
+use crate::events::{self, Event, Notice};
+use crate::hostname;
+use crate::json;
+use crate::logging;
+use crate::oui;
+use crate::state::LeaseState;
+use crate::stats;
+use crate::types::ClientFqdn;
+use crate::{AddrPool, Error, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The page itself, it polls the API so needs nothing else from us
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Give up on clients that connect and then say nothing
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most header lines we read before giving up on a request
+const MAX_HEADERS: usize = 64;
+
+/// How often we write to an idle event stream, so proxies keep it open and
+/// we notice when the subscriber has gone
+const STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    /// Set by browsers on cross-origin requests and every POST
+    origin: Option<String>,
+}
+
+impl Request {
+    /// A page on another site must not be able to drive the API through the
+    /// browser of someone on our network
+    fn is_cross_site(&self) -> bool {
+        match (&self.origin, &self.host) {
+            (Some(origin), Some(host)) => {
+                origin
+                    .strip_prefix("http://")
+                    .or(origin.strip_prefix("https://"))
+                    != Some(host)
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    /// A change we could not make, and why
+    fn rejected(error: Error) -> Self {
+        Self {
+            status: "400 Bad Request",
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{error}\n"),
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{status}\n"),
+        }
+    }
+}
+
+/// Bind the admin listener and serve requests in the background, `socket`
+/// is our DHCP socket which we send FORCERENEW messages from
+pub fn start(listen: SocketAddr, pool: Arc<Mutex<AddrPool>>, socket: UdpSocket) -> Result<()> {
+    let listener = TcpListener::bind(listen).map_err(Error::CannotBindAdmin)?;
+    let socket = Arc::new(socket);
+    info!("Admin interface on http://{listen}/");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (pool, socket) = (pool.clone(), socket.clone());
+                    thread::spawn(move || handle(stream, &pool, &socket));
+                }
+                Err(error) => warn!("Failed to accept admin connection: {error}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, pool: &Mutex<AddrPool>, socket: &UdpSocket) {
+    _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+
+    let response = match read_request(&stream) {
+        Some(request) if request.is_cross_site() => Response::error("403 Forbidden"),
+        Some(request) if request.method == "GET" && request.path == "/api/stream" => {
+            return stream_events(stream);
+        }
+        Some(request) => route(&request, pool, socket),
+        None => Response::error("400 Bad Request"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    let result = stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(response.body.as_bytes()));
+    if let Err(error) = result {
+        warn!("Failed to answer admin request: {error}");
+    }
+}
+
+/// Send every reply and lease change as a Server-Sent Event until the client
+/// goes away
+fn stream_events(mut stream: TcpStream) {
+    let notices = events::subscribe();
+    _ = stream.set_write_timeout(Some(STREAM_KEEPALIVE));
+    let mut result = stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+    );
+    while result.is_ok() {
+        let message = match notices.recv_timeout(STREAM_KEEPALIVE) {
+            Ok(Notice::Reply(event)) => format!("event: reply\ndata: {}\n\n", event_json(&event)),
+            Ok(Notice::Lease(record)) => {
+                format!("event: lease\ndata: {}\n\n", record_json(&record))
+            }
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_owned(),
+            // We fell too far behind, the client reconnects and catches up
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        result = stream.write_all(message.as_bytes());
+    }
+}
+
+/// The request line and the headers we care about, we have no use for a body
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let target = parts.next()?;
+    let mut request = Request {
+        method,
+        path: target.split('?').next()?.to_owned(),
+        host: None,
+        origin: None,
+    };
+
+    for _ in 0..MAX_HEADERS {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = Some(value.trim().to_owned());
+        match name.trim().to_ascii_lowercase().as_str() {
+            "host" => request.host = value,
+            "origin" => request.origin = value,
+            _ => {}
+        }
+    }
+    Some(request)
+}
+
+fn route(request: &Request, pool: &Mutex<AddrPool>, socket: &UdpSocket) -> Response {
+    let path = request.path.as_str();
+    if let Some(range) = path.strip_prefix("/api/ranges/") {
+        return match request.method.as_str() {
+            "POST" => change_pool(pool, range, AddrPool::set_range),
+            "DELETE" => change_pool(pool, range, AddrPool::remove_range),
+            _ => Response::error("405 Method Not Allowed"),
+        };
+    }
+    if let Some(range) = path.strip_prefix("/api/exclusions/") {
+        return match request.method.as_str() {
+            "POST" => change_pool(pool, range, AddrPool::add_exclusion),
+            "DELETE" => change_pool(pool, range, AddrPool::remove_exclusion),
+            _ => Response::error("405 Method Not Allowed"),
+        };
+    }
+    if let Some(mac_address) = path.strip_prefix("/api/trace/") {
+        let Ok(mac_address) = mac_address.parse() else {
+            return Response::error("400 Bad Request");
+        };
+        match request.method.as_str() {
+            "POST" => _ = logging::add_traced(mac_address),
+            "DELETE" if !logging::remove_traced(&mac_address) => {
+                return Response::error("404 Not Found")
+            }
+            "DELETE" => {}
+            _ => return Response::error("405 Method Not Allowed"),
+        }
+        return Response::json(traced_json());
+    }
+    if request.method == "POST" {
+        return match path.strip_prefix("/api/forcerenew") {
+            Some("" | "/") => force_renew(pool, socket, None),
+            Some(ip) => match ip.trim_start_matches('/').parse() {
+                Ok(ip) => force_renew(pool, socket, Some(ip)),
+                Err(_) => Response::error("400 Bad Request"),
+            },
+            None => Response::error("404 Not Found"),
+        };
+    }
+    if request.method != "GET" {
+        return Response::error("405 Method Not Allowed");
+    }
+
+    match path {
+        "/" => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: DASHBOARD.to_owned(),
+        },
+        path if path.starts_with("/api/history/") => {
+            history_json(&pool.lock().unwrap(), &path["/api/history/".len()..])
+                .map_or_else(|| Response::error("400 Bad Request"), Response::json)
+        }
+        "/api/pool" => Response::json(pool_json(&pool.lock().unwrap())),
+        "/api/leases" => Response::json(leases_json(&pool.lock().unwrap())),
+        "/api/events" => Response::json(events_json()),
+        "/api/stats" => Response::json(stats_json()),
+        "/api/trace" => Response::json(traced_json()),
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+/// Tell the client bound to `ip_addr`, or every bound client, to renew now
+fn force_renew(pool: &Mutex<AddrPool>, socket: &UdpSocket, ip_addr: Option<Ipv4Addr>) -> Response {
+    let pool = pool.lock().unwrap();
+    let client_port = pool.ports().client;
+    let targets: Vec<Ipv4Addr> = match ip_addr {
+        Some(ip_addr) => vec![ip_addr],
+        None => pool
+            .entries()
+            .iter()
+            .filter(|entry| entry.state == LeaseState::Bound)
+            .map(|entry| entry.ip_addr)
+            .collect(),
+    };
+
+    let mut buffer = [0u8; dhcp::MAX_MESSAGE_LEN];
+    let mut sent = Vec::new();
+    for ip_addr in targets {
+        let Some(len) = Dhcp::force_renew(&pool, &ip_addr, &mut buffer) else {
+            continue;
+        };
+        match socket.send_to(&buffer[..len], (ip_addr, client_port)) {
+            Ok(_) => sent.push(format!("\"{ip_addr}\"")),
+            Err(error) => warn!("Failed to send ForceRenew to {ip_addr}: {error}"),
+        }
+    }
+
+    if ip_addr.is_some() && sent.is_empty() {
+        return Response::error("404 Not Found");
+    }
+    Response::json(format!("{{\"sent\": [{}]}}", sent.join(", ")))
+}
+
+/// Apply a change to our ranges or exclusions, `range` is `<start>-<end>` or
+/// a single address
+fn change_pool(
+    pool: &Mutex<AddrPool>,
+    range: &str,
+    change: fn(&mut AddrPool, Ipv4Addr, Ipv4Addr) -> Result<&mut AddrPool>,
+) -> Response {
+    let range = match range.split_once('-') {
+        Some((start, end)) => start.parse().ok().zip(end.parse().ok()),
+        None => range.parse().ok().map(|ip| (ip, ip)),
+    };
+    let Some((start, end)) = range else {
+        return Response::error("400 Bad Request");
+    };
+
+    let mut pool = pool.lock().unwrap();
+    match change(&mut pool, start, end) {
+        Ok(pool) => Response::json(pool_json(pool)),
+        Err(error) => Response::rejected(error),
+    }
+}
+
+fn traced_json() -> String {
+    let traced: Vec<String> = logging::traced()
+        .iter()
+        .map(|mac_address| format!("\"{mac_address}\""))
+        .collect();
+    format!("[{}]", traced.join(", "))
+}
+
+/// Address ranges as JSON pairs
+fn ranges_json(ranges: &[(Ipv4Addr, Ipv4Addr)]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| format!("[\"{start}\", \"{end}\"]"))
+        .collect();
+    format!("[{}]", ranges.join(", "))
+}
+
+fn pool_json(pool: &AddrPool) -> String {
+    let entries = pool.entries();
+    let count = |state| entries.iter().filter(|entry| entry.state == state).count();
+    let (bound, offered) = (count(LeaseState::Bound), count(LeaseState::Offered));
+    let exhausted_in = alerts::exhausted_in().map(|time| time.as_secs().to_string());
+    format!(
+        "{{\"subnet\": \"{}\", \"size\": {}, \"bound\": {bound}, \"offered\": {offered}, \"free\": {}, \"exhausted_in\": {}, \"ranges\": {}, \"exclusions\": {}, \"conflicts\": {}}}",
+        pool.subnet(),
+        pool.size(),
+        pool.size().saturating_sub(bound + offered),
+        exhausted_in.as_deref().unwrap_or("null"),
+        ranges_json(pool.ranges()),
+        ranges_json(pool.exclusions()),
+        conflicts_json(pool)
+    )
+}
+
+/// Addresses clients keep declining
+fn conflicts_json(pool: &AddrPool) -> String {
+    let conflicts: Vec<String> = pool
+        .conflicts()
+        .iter()
+        .map(|(ip_addr, declines)| {
+            format!(
+                "{{\"ip\": \"{ip_addr}\", \"declines\": {}, \"until\": {}}}",
+                declines.count,
+                json::time(declines.until)
+            )
+        })
+        .collect();
+    format!("[{}]", conflicts.join(", "))
+}
+
+fn leases_json(pool: &AddrPool) -> String {
+    let entries: Vec<String> = pool
+        .entries()
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"ip\": \"{}\", \"mac\": \"{}\", \"vendor\": {}, \"hostname\": {}, \"mud_url\": {}, \"uuid\": {}, \"fqdn\": {}, \"dns_updates\": {}, \"state\": \"{:?}\", \"client_state\": \"{}\", \"expires\": {}}}",
+                entry.ip_addr,
+                entry.mac_address,
+                json::optional(oui::vendor(&entry.mac_address)),
+                json::optional(entry.hostname.as_deref()),
+                json::optional(entry.mud_url.as_deref()),
+                json::optional(entry.uuid.map(|uuid| uuid.to_string()).as_deref()),
+                json::optional(
+                    entry
+                        .fqdn
+                        .as_ref()
+                        .and_then(ClientFqdn::name)
+                        .and_then(|name| hostname::sanitize(&name))
+                        .as_deref()
+                ),
+                json::optional(entry.fqdn.as_ref().map(ClientFqdn::server_updates)),
+                entry.state,
+                entry.client_state.name(),
+                json::expires(entry.expires)
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(", "))
+}
+
+/// The audit records of an address or a client
+fn history_json(pool: &AddrPool, address: &str) -> Option<String> {
+    let records = match address.parse() {
+        Ok(ip_addr) => pool.audit_log().for_ip(ip_addr),
+        Err(_) => pool.audit_log().for_mac(address.parse().ok()?),
+    };
+    let records: Vec<String> = records.iter().map(record_json).collect();
+    Some(format!("[{}]", records.join(", ")))
+}
+
+fn record_json(record: &AuditRecord) -> String {
+    format!(
+        "{{\"time\": {}, \"event\": \"{}\", \"ip\": \"{}\", \"mac\": \"{}\", \"expires\": {}}}",
+        json::time(record.time),
+        record.event.name(),
+        record.ip_addr,
+        record.mac_address,
+        json::expires(record.expires)
+    )
+}
+
+fn events_json() -> String {
+    let events: Vec<String> = events::recent().iter().rev().map(event_json).collect();
+    format!("[{}]", events.join(", "))
+}
+
+fn event_json(event: &Event) -> String {
+    let ip_addr = event.ip_addr.map(|ip| ip.to_string());
+    format!(
+        "{{\"time\": {}, \"type\": \"{:?}\", \"mac\": \"{}\", \"vendor\": {}, \"ip\": {}}}",
+        json::time(event.time),
+        event.message_type,
+        event.mac_address,
+        json::optional(oui::vendor(&event.mac_address)),
+        json::optional(ip_addr.as_deref())
+    )
+}
+
+fn stats_json() -> String {
+    let snapshot = stats::snapshot();
+    let received: Vec<String> = snapshot
+        .received
+        .iter()
+        .map(|(message_type, count)| format!("\"{message_type:?}\": {count}"))
+        .collect();
+    let parse_errors: Vec<String> = snapshot
+        .parse_errors
+        .iter()
+        .map(|(reason, count)| format!("\"{reason}\": {count}"))
+        .collect();
+    // Bucket bounds in microseconds, null for the one catching the rest
+    let bounds = stats::LATENCY_BUCKETS.iter().map(|bound| bound.to_string());
+    let latency: Vec<String> = bounds
+        .chain(["null".to_owned()])
+        .zip(&snapshot.latency)
+        .map(|(bound, count)| format!("{{\"le_us\": {bound}, \"count\": {count}}}"))
+        .collect();
+    // Counts by event name, then the same for each hour by its start
+    let counts = |counts: &[u64]| -> String {
+        AuditEvent::ALL
+            .iter()
+            .zip(counts)
+            .map(|(event, count)| format!("\"{}\": {count}", event.name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let hourly: Vec<String> = snapshot
+        .churn
+        .iter()
+        .map(|(hour, hour_counts)| {
+            format!(
+                "{{\"hour\": {}, {}}}",
+                json::time(*hour),
+                counts(hour_counts)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"received\": {{{}}}, \"parse_errors\": {{{}}}, \"latency\": {{\"buckets\": [{}], \"count\": {}, \"sum_us\": {}}}, \"leases\": {{{}, \"hourly\": [{}]}}, \"journal\": {{\"pending\": {}, \"syncs\": {}, \"lag_us\": {}}}}}",
+        received.join(", "),
+        parse_errors.join(", "),
+        latency.join(", "),
+        snapshot.latency.iter().sum::<u64>(),
+        snapshot.latency_sum.as_micros(),
+        counts(&snapshot.leases),
+        hourly.join(", "),
+        snapshot.journal_pending,
+        snapshot.journal_syncs,
+        snapshot.journal_lag.as_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> Mutex<AddrPool> {
+        let range = (
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        Mutex::new(AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], &[range], &[]).unwrap())
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            host: Some("127.0.0.1:8067".to_owned()),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn requests_are_routed_by_method_and_path() {
+        let (pool, socket) = (pool(), UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap());
+        let route = |method, path| route(&request(method, path), &pool, &socket);
+
+        let dashboard = route("GET", "/");
+        assert_eq!(dashboard.status, "200 OK");
+        assert!(dashboard.content_type.starts_with("text/html"));
+        let pool_json = route("GET", "/api/pool");
+        assert_eq!(pool_json.content_type, "application/json");
+        assert!(pool_json.body.contains("\"subnet\": \"192.168.1.0\""));
+        assert!(pool_json.body.contains("\"size\": 11"));
+
+        let excluded = route("POST", "/api/exclusions/192.168.1.15");
+        assert_eq!(excluded.status, "200 OK");
+        assert!(excluded
+            .body
+            .contains("\"exclusions\": [[\"192.168.1.15\", \"192.168.1.15\"]]"));
+        let rejected = route("POST", "/api/ranges/10.0.0.1-10.0.0.5");
+        assert_eq!(rejected.status, "400 Bad Request");
+
+        // Nobody is bound to renew
+        assert_eq!(
+            route("POST", "/api/forcerenew/192.168.1.10").status,
+            "404 Not Found"
+        );
+        assert_eq!(route("POST", "/api/forcerenew").body, "{\"sent\": []}");
+
+        assert_eq!(route("GET", "/api/nothing").status, "404 Not Found");
+        assert_eq!(
+            route("PUT", "/api/ranges/192.168.1.30").status,
+            "405 Method Not Allowed"
+        );
+        assert_eq!(
+            route("DELETE", "/api/pool").status,
+            "405 Method Not Allowed"
+        );
+        assert_eq!(
+            route("POST", "/api/trace/not-a-mac").status,
+            "400 Bad Request"
+        );
+        assert_eq!(
+            route("GET", "/api/history/nowhere").status,
+            "400 Bad Request"
+        );
+    }
+
+    #[test]
+    fn requests_from_other_sites_are_refused() {
+        let mut request = request("POST", "/api/forcerenew");
+        assert!(!request.is_cross_site());
+        request.origin = Some("http://127.0.0.1:8067".to_owned());
+        assert!(!request.is_cross_site());
+        request.origin = Some("https://evil.example".to_owned());
+        assert!(request.is_cross_site());
+        request.host = None;
+        assert!(request.is_cross_site());
+    }
+
+    #[test]
+    fn request_lines_and_headers_are_read() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client
+            .write_all(b"POST /api/trace/02:00:00:00:00:01?x=1 HTTP/1.1\r\nHost: admin\r\nORIGIN: http://admin\r\nX-Other: 1\r\n\r\n")
+            .unwrap();
+
+        let request = read_request(&server).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/trace/02:00:00:00:00:01");
+        assert_eq!(request.host.as_deref(), Some("admin"));
+        assert_eq!(request.origin.as_deref(), Some("http://admin"));
+    }
+}
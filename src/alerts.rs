@@ -0,0 +1,354 @@
+//! Warn people before the pool runs out. We sample how many addresses are in
+//! use, alert when that crosses one of `alert-thresholds`, optionally POSTing
+//! to `alert-webhook`, and estimate when the pool will be exhausted from the
+//! recent trend. We alert the same way when clients keep DECLINEing an
+//! address, see [crate::state::Declines]
+
+use log::{info, warn};
+
+use crate::json;
+use crate::state::Declines;
+use crate::AddrPool;
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Alert when the pool is this full unless `alert-thresholds` says otherwise
+pub const DEFAULT_THRESHOLD: u8 = 90;
+
+/// How often we look at the pool
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How far back the trend for the estimate goes
+const TREND_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Too short a trend gives wild estimates, so wait for this much of one
+const MIN_TREND_SPAN: Duration = Duration::from_secs(60);
+
+/// Give up on a webhook that does not answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The latest estimate, for the admin API
+static EXHAUSTED_IN: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// The alert keys of the config file
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// Percentages of the pool in use we alert at, in any order
+    pub thresholds: Vec<u8>,
+    /// Also tell this URL when we alert
+    pub webhook: Option<Webhook>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            thresholds: vec![DEFAULT_THRESHOLD],
+            webhook: None,
+        }
+    }
+}
+
+/// A plain `http://` URL, we POST a small JSON body to it
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    /// `host:port` to connect to
+    authority: String,
+    path: String,
+}
+
+impl Webhook {
+    /// `http://host[:port][/path]`, TLS is left to a local proxy
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return None;
+        }
+        let authority = match authority.contains(':') {
+            true => authority.to_owned(),
+            false => format!("{authority}:80"),
+        };
+        Some(Self {
+            authority,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Send `body`, returning the status line of the response
+    pub fn post(&self, body: &str) -> std::io::Result<String> {
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        let mut stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+        stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.authority,
+            body.len()
+        )?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        Ok(status.trim().to_owned())
+    }
+}
+
+/// How long until the pool is full at the current rate, [None] when it is
+/// not filling up or we have not watched it for long enough
+pub fn exhausted_in() -> Option<Duration> {
+    *EXHAUSTED_IN.lock().unwrap()
+}
+
+/// Watch the pool in the background
+pub fn start(config: AlertConfig, pool: Arc<Mutex<AddrPool>>) {
+    thread::spawn(move || {
+        let mut samples = VecDeque::new();
+        // The highest threshold we are over, 0 when under them all
+        let mut level = 0;
+        // Addresses in conflict we have alerted on
+        let mut conflicts = BTreeSet::new();
+
+        loop {
+            let (subnet, in_use, size, conflicted) = {
+                let pool = pool.lock().unwrap();
+                (pool.subnet(), pool.in_use(), pool.size(), pool.conflicts())
+            };
+
+            for (ip_addr, declines) in &conflicted {
+                if conflicts.insert(*ip_addr) {
+                    warn!(
+                        "{ip_addr} in {subnet} was declined {} times, something is squatting on it",
+                        declines.count
+                    );
+                    notify(&config, &conflict_json(subnet, *ip_addr, declines));
+                }
+            }
+            conflicts.retain(|ip_addr| conflicted.iter().any(|(conflict, _)| conflict == ip_addr));
+
+            let now = Instant::now();
+            samples.push_back((now, in_use));
+            while samples
+                .front()
+                .is_some_and(|(time, _)| now.duration_since(*time) > TREND_WINDOW)
+            {
+                samples.pop_front();
+            }
+            let estimate = estimate(&samples, size.saturating_sub(in_use));
+            *EXHAUSTED_IN.lock().unwrap() = estimate;
+
+            let percent = (in_use * 100).checked_div(size).unwrap_or(0);
+            let crossed = crossed(&config.thresholds, percent);
+
+            if crossed != level {
+                match crossed > level {
+                    true => warn!("Pool {subnet} is {percent}% full, {in_use} of {size} in use"),
+                    false => info!("Pool {subnet} is back down to {percent}% full"),
+                }
+                let alert = Alert {
+                    subnet,
+                    threshold: crossed,
+                    in_use,
+                    size,
+                    estimate,
+                };
+                notify(&config, &alert.to_json());
+                level = crossed;
+            }
+
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+    });
+}
+
+/// The highest of `thresholds` that `percent` is at or over, 0 for none
+fn crossed(thresholds: &[u8], percent: usize) -> u8 {
+    thresholds
+        .iter()
+        .copied()
+        .filter(|threshold| percent >= *threshold as usize)
+        .max()
+        .unwrap_or(0)
+}
+
+/// POST `body` to the webhook, if we have one
+fn notify(config: &AlertConfig, body: &str) {
+    if let Some(webhook) = &config.webhook {
+        match webhook.post(body) {
+            Ok(status) => info!("Alert webhook answered {status}"),
+            Err(error) => warn!("Failed to call the alert webhook: {error}"),
+        }
+    }
+}
+
+/// What we tell the webhook about an address in conflict
+fn conflict_json(subnet: Ipv4Addr, ip_addr: Ipv4Addr, declines: &Declines) -> String {
+    format!(
+        "{{\"subnet\": {}, \"conflict\": {}, \"declines\": {}, \"until\": {}}}",
+        json::string(&subnet.to_string()),
+        json::string(&ip_addr.to_string()),
+        declines.count,
+        json::time(declines.until)
+    )
+}
+
+/// Fit a line through the samples and see when it reaches the pool size
+fn estimate(samples: &VecDeque<(Instant, usize)>, free: usize) -> Option<Duration> {
+    let (first, _) = samples.front()?;
+    let (last, _) = samples.back()?;
+    if last.duration_since(*first) < MIN_TREND_SPAN {
+        return None;
+    }
+
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|(time, in_use)| (time.duration_since(*first).as_secs_f64(), *in_use as f64))
+        .collect();
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    // Addresses per second
+    let slope = covariance / variance;
+    (slope > 0.0).then(|| Duration::from_secs_f64(free as f64 / slope))
+}
+
+/// What we tell the webhook
+struct Alert {
+    subnet: Ipv4Addr,
+    /// The threshold we are now over, 0 once we are back under them all
+    threshold: u8,
+    in_use: usize,
+    size: usize,
+    estimate: Option<Duration>,
+}
+
+impl Alert {
+    fn to_json(&self) -> String {
+        let estimate = self.estimate.map(|time| time.as_secs().to_string());
+        format!(
+            "{{\"subnet\": {}, \"threshold\": {}, \"in_use\": {}, \"size\": {}, \"exhausted_in\": {}}}",
+            json::string(&self.subnet.to_string()),
+            self.threshold,
+            self.in_use,
+            self.size,
+            estimate.as_deref().unwrap_or("null")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn webhooks_are_plain_http_urls() {
+        let webhook = Webhook::parse("http://alerts.example").unwrap();
+        assert_eq!(
+            (webhook.authority.as_str(), webhook.path.as_str()),
+            ("alerts.example:80", "/")
+        );
+        let webhook = Webhook::parse("http://10.0.0.1:8080/hooks/dhcp").unwrap();
+        assert_eq!(
+            (webhook.authority.as_str(), webhook.path.as_str()),
+            ("10.0.0.1:8080", "/hooks/dhcp")
+        );
+        assert!(Webhook::parse("https://alerts.example").is_none());
+        assert!(Webhook::parse("http:///path").is_none());
+    }
+
+    #[test]
+    fn webhooks_are_posted_the_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alert", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with("{}") {
+                let len = stream.read(&mut buffer).unwrap();
+                request.push_str(std::str::from_utf8(&buffer[..len]).unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        let status = Webhook::parse(&url).unwrap().post("{}").unwrap();
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /alert HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 2\r\n"));
+    }
+
+    #[test]
+    fn the_highest_threshold_crossed_is_the_level() {
+        assert_eq!(crossed(&[90, 75], 74), 0);
+        assert_eq!(crossed(&[90, 75], 75), 75);
+        assert_eq!(crossed(&[90, 75], 100), 90);
+        assert_eq!(crossed(&[], 100), 0);
+    }
+
+    #[test]
+    fn exhaustion_is_estimated_from_the_trend() {
+        let start = Instant::now();
+        let sample = |secs, in_use| (start + Duration::from_secs(secs), in_use);
+
+        // One address a minute, with ten left
+        let samples = VecDeque::from([sample(0, 0), sample(60, 1), sample(120, 2)]);
+        assert_eq!(estimate(&samples, 10), Some(Duration::from_secs(600)));
+
+        // Not watched for long enough
+        let samples = VecDeque::from([sample(0, 0), sample(30, 5)]);
+        assert_eq!(estimate(&samples, 10), None);
+
+        // Emptying
+        let samples = VecDeque::from([sample(0, 5), sample(60, 4)]);
+        assert_eq!(estimate(&samples, 10), None);
+    }
+
+    #[test]
+    fn alerts_are_json() {
+        let alert = Alert {
+            subnet: Ipv4Addr::new(192, 168, 1, 0),
+            threshold: 90,
+            in_use: 91,
+            size: 100,
+            estimate: None,
+        };
+        assert_eq!(
+            alert.to_json(),
+            "{\"subnet\": \"192.168.1.0\", \"threshold\": 90, \"in_use\": 91, \"size\": 100, \"exhausted_in\": null}"
+        );
+        let parsed = json::parse(
+            &Alert {
+                estimate: Some(Duration::from_secs(600)),
+                ..alert
+            }
+            .to_json(),
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.get("exhausted_in").and_then(json::Value::as_i64),
+            Some(600)
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Putting a client in the kernel's ARP cache before we unicast a reply to
+//! the address we are giving it. The client cannot answer ARP for an address
+//! it does not hold yet, so without an entry the reply waits on a lookup that
+//! never succeeds and is dropped
+
+#[cfg(target_os = "linux")]
+use {
+    crate::types::MacAddr,
+    std::io,
+    std::net::Ipv4Addr,
+    std::os::raw::{c_int, c_ulong},
+};
+
+/// What to do with the entries we add, `--inject-arp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpInjection {
+    /// Leave them for the kernel to age out like any other
+    Keep,
+
+    /// Delete them once the reply is sent, the client ARPs for us soon enough
+    Remove,
+}
+
+#[cfg(target_os = "linux")]
+const SIOCDARP: c_ulong = 0x8953;
+#[cfg(target_os = "linux")]
+const SIOCSARP: c_ulong = 0x8955;
+#[cfg(target_os = "linux")]
+const AF_INET: u16 = 2;
+#[cfg(target_os = "linux")]
+const ARPHRD_ETHER: u16 = 1;
+/// The entry is complete, we know the MAC
+#[cfg(target_os = "linux")]
+const ATF_COM: c_int = 0x02;
+/// Interface names are at most 15 bytes and a null
+#[cfg(target_os = "linux")]
+const IFNAMSIZ: usize = 16;
+
+/// `struct sockaddr`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct SockAddr {
+    family: u16,
+    data: [u8; 14],
+}
+
+/// `struct arpreq` from `<net/if_arp.h>`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct ArpReq {
+    protocol_addr: SockAddr,
+    hardware_addr: SockAddr,
+    flags: c_int,
+    netmask: SockAddr,
+    /// Empty lets the kernel pick the interface that routes to the address
+    device: [u8; IFNAMSIZ],
+}
+
+#[cfg(target_os = "linux")]
+impl ArpReq {
+    fn new(ip_addr: Ipv4Addr) -> Self {
+        let mut request = Self::default();
+        request.protocol_addr.family = AF_INET;
+        // A sockaddr_in, the port then the address
+        request.protocol_addr.data[2..6].copy_from_slice(&ip_addr.octets());
+        request
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+/// Send replies for `ip_addr` to `mac_address` without asking, through any
+/// AF_INET socket `fd`. Needs CAP_NET_ADMIN
+#[cfg(target_os = "linux")]
+pub fn add(fd: c_int, ip_addr: Ipv4Addr, mac_address: MacAddr) -> io::Result<()> {
+    let mut request = ArpReq::new(ip_addr);
+    request.hardware_addr.family = ARPHRD_ETHER;
+    request.hardware_addr.data[..MacAddr::LEN].copy_from_slice(&mac_address.bytes());
+    request.flags = ATF_COM;
+    // SAFETY: the kernel reads a whole arpreq, which we own for the call
+    check(unsafe { ioctl(fd, SIOCSARP, &request as *const ArpReq) })
+}
+
+/// Undo [add]
+#[cfg(target_os = "linux")]
+pub fn remove(fd: c_int, ip_addr: Ipv4Addr) -> io::Result<()> {
+    let request = ArpReq::new(ip_addr);
+    // SAFETY: as in add
+    check(unsafe { ioctl(fd, SIOCDARP, &request as *const ArpReq) })
+}
+
+#[cfg(target_os = "linux")]
+fn check(ret: c_int) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,207 @@
+//! Which client held which address and when, for questions like who had
+//! 192.168.1.12 last Tuesday. We keep the most recent records in memory for
+//! the admin API and, with `audit-file` set, append every record to the file
+//! as one line:
+//!
+//! ```text
+//! # <time> <bound|renewed|reclaimed> <ip> <chaddr> <expires>
+//! 1700000000 bound 192.168.1.10 02:00:00:00:00:01 1700003600
+//! ```
+//!
+//! Times are seconds since the epoch, a lease that never expires is written
+//! as expiring at 4294967295. We never rewrite the file, rotate it with
+//! logrotate's `copytruncate` or similar.
+
+use crate::events;
+use crate::stats;
+use crate::types::MacAddr;
+use crate::{Error, Result};
+use log::error;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many records we keep in memory, the file keeps everything
+const AUDIT_HISTORY_LEN: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A client took up an address we offered it
+    Bound,
+
+    /// A client extended a lease it already held
+    Renewed,
+
+    /// A lease that had expired was given to another client
+    Reclaimed,
+
+    /// A client gave its lease back early
+    Released,
+
+    /// A client found someone else using the address we gave it
+    Declined,
+}
+
+impl AuditEvent {
+    /// Every event, in the order [crate::stats] counts them
+    pub const ALL: [Self; 5] = [
+        Self::Bound,
+        Self::Renewed,
+        Self::Reclaimed,
+        Self::Released,
+        Self::Declined,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bound => "bound",
+            Self::Renewed => "renewed",
+            Self::Reclaimed => "reclaimed",
+            Self::Released => "released",
+            Self::Declined => "declined",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    pub event: AuditEvent,
+    pub ip_addr: Ipv4Addr,
+    pub mac_address: MacAddr,
+    /// When the lease ends, for a reclaimed lease when it ended
+    pub expires: SystemTime,
+}
+
+impl AuditRecord {
+    /// One line of the audit file, without the newline
+    fn encode(&self) -> String {
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        format!(
+            "{} {} {} {} {}",
+            secs(self.time),
+            self.event.name(),
+            self.ip_addr,
+            self.mac_address,
+            secs(self.expires)
+        )
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let time = |secs: &str| Some(UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [recorded, event, ip_addr, mac_address, expires] = fields[..] else {
+            return None;
+        };
+        let event = match event {
+            "bound" => AuditEvent::Bound,
+            "renewed" => AuditEvent::Renewed,
+            "reclaimed" => AuditEvent::Reclaimed,
+            "released" => AuditEvent::Released,
+            "declined" => AuditEvent::Declined,
+            _ => return None,
+        };
+        Some(Self {
+            time: time(recorded)?,
+            event,
+            ip_addr: ip_addr.parse().ok()?,
+            mac_address: mac_address.parse().ok()?,
+            expires: time(expires)?,
+        })
+    }
+}
+
+/// The records we remember, and the file we append them to
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    file: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// Append to `path` from now on, after reading the latest records already
+    /// in it. A missing file is created on the first record
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(Error::CannotReadAudit(error)),
+        };
+
+        let mut log = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = AuditRecord::decode(line)
+                .ok_or_else(|| Error::InvalidAuditLine(index + 1, line.to_owned()))?;
+            log.remember(record);
+        }
+        log.file = Some(path.to_owned());
+        Ok(log)
+    }
+
+    pub fn record(
+        &mut self,
+        time: SystemTime,
+        event: AuditEvent,
+        ip_addr: Ipv4Addr,
+        mac_address: MacAddr,
+        expires: SystemTime,
+    ) {
+        let record = AuditRecord {
+            time,
+            event,
+            ip_addr,
+            mac_address,
+            expires,
+        };
+        if let Some(path) = &self.file {
+            let line = record.encode() + "\n";
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()));
+            if let Err(error) = result {
+                error!("{}", Error::CannotWriteAudit(error));
+            }
+        }
+        events::record_lease(&record);
+        stats::record_lease(record.event, record.time);
+        self.remember(record);
+    }
+
+    fn remember(&mut self, record: AuditRecord) {
+        if self.records.len() == AUDIT_HISTORY_LEN {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Every record we remember for this address, oldest first
+    pub fn for_ip(&self, ip_addr: Ipv4Addr) -> Vec<AuditRecord> {
+        self.records
+            .iter()
+            .filter(|record| record.ip_addr == ip_addr)
+            .cloned()
+            .collect()
+    }
+
+    /// Every record we remember for this client, oldest first
+    pub fn for_mac(&self, mac_address: MacAddr) -> Vec<AuditRecord> {
+        self.records
+            .iter()
+            .filter(|record| record.mac_address == mac_address)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,124 @@
+//! Ways into the request path for `benches/hot_path.rs` and the
+//! `dhc3po-load` generator, which only see our public API. Nothing here is
+//! meant for anything else and it may change at any time
+
+use crate::dhcp::Dhcp;
+use crate::state::AddrPool;
+use crate::transport::Transport;
+use crate::types::{DhcpOption, MacAddr, MessageType, ParameterRequest};
+use crate::{handle_request, wire, Result};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// What a typical client asks for
+const PARAMETER_REQUESTS: [ParameterRequest; 6] = [
+    ParameterRequest::SubnetMask,
+    ParameterRequest::Router,
+    ParameterRequest::DomainNameServer,
+    ParameterRequest::DomainName,
+    ParameterRequest::IpAddressLease,
+    ParameterRequest::NtpServers,
+];
+
+/// The client a benchmark or load run pretends to be, one of up to 2^32
+pub fn mac_address(client: u32) -> MacAddr {
+    let [a, b, c, d] = client.to_be_bytes();
+    MacAddr::new([2, 0, a, b, c, d])
+}
+
+/// A DISCOVER from `client` as a typical client's DHCP stack builds it, with
+/// `xid` as its transaction id
+pub fn discover(client: u32, xid: u32) -> Vec<u8> {
+    let mac_address = mac_address(client);
+    let mut packet = vec![0u8; 240];
+    packet[..4].copy_from_slice(&[1, 1, MacAddr::LEN as u8, 0]);
+    packet[wire::XID].copy_from_slice(&xid.to_be_bytes());
+    packet[wire::CHADDR][..MacAddr::LEN].copy_from_slice(&mac_address.bytes());
+    packet[wire::MAGIC_START..wire::OPTIONS_START].copy_from_slice(&wire::MAGIC);
+
+    let options = [
+        DhcpOption::MessageType(MessageType::Discover),
+        DhcpOption::HostName(format!("client-{client}")),
+        DhcpOption::ParameterRequestList(PARAMETER_REQUESTS.to_vec()),
+        DhcpOption::End,
+    ];
+    let mut buffer = [0u8; 312];
+    let mut len = 0;
+    for option in &options {
+        len += option.serialise(&mut buffer[len..]);
+    }
+    packet.extend_from_slice(&buffer[..len]);
+    packet
+}
+
+/// Parse a request the way the server does
+pub fn parse(data: &[u8]) -> Result<()> {
+    Dhcp::parse(data).map(|_| ())
+}
+
+/// The options of a typical OFFER, serialised into `buffer`
+pub fn serialise_options(buffer: &mut [u8]) -> usize {
+    let options = [
+        DhcpOption::MessageType(MessageType::Offer),
+        DhcpOption::DhcpServerIpAddr([10, 0, 0, 1]),
+        DhcpOption::LeaseTime(43200),
+        DhcpOption::SubnetMask([255, 255, 0, 0]),
+        DhcpOption::Router([10, 0, 0, 254]),
+        DhcpOption::DomainNameServer([10, 0, 0, 53]),
+        DhcpOption::DomainName("bench.home.arpa".to_owned()),
+        DhcpOption::NtpServers(vec![[10, 0, 0, 123]]),
+        DhcpOption::End,
+    ];
+    let mut len = 0;
+    for option in &options {
+        len += option.serialise(&mut buffer[len..]);
+    }
+    len
+}
+
+/// A server for 10.0.0.0/16 answering into the void, share it between
+/// threads to measure allocating under contention
+#[derive(Clone)]
+pub struct Server {
+    pool: Arc<Mutex<AddrPool>>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        let range = (Ipv4Addr::new(10, 0, 1, 0), Ipv4Addr::new(10, 0, 255, 254));
+        let mut pool = AddrPool::new([10, 0, 0, 0], [255, 255, 0, 0], &[range], &[]).unwrap();
+        pool.set_server_identifier(Ipv4Addr::new(10, 0, 0, 1));
+        pool.options_mut()
+            .set(DhcpOption::LeaseTime(43200))
+            .set(DhcpOption::Router([10, 0, 0, 254]))
+            .set(DhcpOption::DomainNameServer([10, 0, 0, 53]));
+        Self {
+            pool: Arc::new(Mutex::new(pool)),
+        }
+    }
+
+    /// Parse, allocate for and answer `request` as the server would
+    pub fn answer(&self, request: &[u8]) {
+        handle_request(&Discard, self.pool.clone(), request);
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drops every reply
+struct Discard;
+
+impl Transport for Discard {
+    fn recv_from(&self, _buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send_to(&self, data: &[u8], _destination: SocketAddr) -> io::Result<usize> {
+        Ok(data.len())
+    }
+}
@@ -0,0 +1,136 @@
+//! Operator tooling for a dhc3po lease file
+
+use dhc3po::error::{Error, Result};
+use dhc3po::leases::{self, Lease};
+use dhc3po::oui;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: dhc3po-ctl leases export [--format json|csv] [--lease-file <PATH>] [--oui-file <PATH>]
+       dhc3po-ctl leases import --format dnsmasq|isc|dhc3po <FILE> [--lease-file <PATH>]
+
+Import while dhc3po is stopped, it rewrites the lease file as clients bind.
+
+Options:
+  --format <FORMAT>     Export as json (the default) or csv, import from a
+                        dnsmasq leases file, an ISC dhcpd.leases or our own
+  --lease-file <PATH>   The lease-file from the dhc3po config, defaults to
+                        dhc3po.leases
+  --oui-file <PATH>     Name vendors from this OUI table, see oui-file
+  -h, --help            Print this help";
+
+enum Command {
+    Export,
+    Import(PathBuf),
+}
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error}\n\n{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut command = None;
+    let mut format = None;
+    let mut lease_file = PathBuf::from(leases::DEFAULT_LEASE_FILE);
+
+    match args.next().as_deref() {
+        Some("leases") => {}
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        Some(arg) => return Err(Error::InvalidArgument(arg.to_owned())),
+        None => return Err(Error::MissingArgumentValue("leases".to_owned())),
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "export" => command = Some(Command::Export),
+            "import" => command = Some(Command::Import(PathBuf::new())),
+            "--format" => format = Some(value(&arg, args.next())?),
+            "--lease-file" => lease_file = value(&arg, args.next())?.into(),
+            "--oui-file" => oui::set_path(value(&arg, args.next())?.into()),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => match &mut command {
+                Some(Command::Import(path)) if path.as_os_str().is_empty() => *path = arg.into(),
+                _ => return Err(Error::InvalidArgument(arg)),
+            },
+        }
+    }
+
+    match command {
+        Some(Command::Export) => export(&lease_file, format.as_deref().unwrap_or("json")),
+        Some(Command::Import(path)) if !path.as_os_str().is_empty() => {
+            let format =
+                format.ok_or_else(|| Error::MissingArgumentValue("--format".to_owned()))?;
+            import(&lease_file, &path, &format)
+        }
+        Some(Command::Import(_)) => Err(Error::MissingArgumentValue("import".to_owned())),
+        None => Err(Error::MissingArgumentValue("leases".to_owned())),
+    }
+}
+
+fn export(lease_file: &Path, format: &str) -> Result<()> {
+    let leases = leases::load(lease_file)?;
+    let output = match format {
+        "json" => leases::to_json(&leases),
+        "csv" => leases::to_csv(&leases),
+        _ => {
+            return Err(Error::InvalidArgumentValue(
+                "--format".to_owned(),
+                format.to_owned(),
+            ))
+        }
+    };
+    print!("{output}");
+    Ok(())
+}
+
+/// Merge the leases from `path` into our lease file, imported leases replace
+/// any we already have for the same address
+fn import(lease_file: &Path, path: &Path, format: &str) -> Result<()> {
+    let text = std::fs::read_to_string(path).map_err(Error::CannotReadLeases)?;
+    let imported = match format {
+        "dnsmasq" => leases::parse_dnsmasq(&text)?,
+        "isc" => leases::parse_isc(&text)?,
+        "dhc3po" => leases::parse(&text)?,
+        _ => {
+            return Err(Error::InvalidArgumentValue(
+                "--format".to_owned(),
+                format.to_owned(),
+            ))
+        }
+    };
+
+    let count = imported.len();
+    let mut merged: BTreeMap<_, Lease> = leases::load(lease_file)?
+        .into_iter()
+        .map(|lease| (lease.ip_addr, lease))
+        .collect();
+    merged.extend(imported.into_iter().map(|lease| (lease.ip_addr, lease)));
+
+    let merged: Vec<Lease> = merged.into_values().collect();
+    leases::save(lease_file, &merged)?;
+    println!(
+        "Imported {count} leases into {}, it now holds {}",
+        lease_file.display(),
+        merged.len()
+    );
+    Ok(())
+}
+
+/// Options that take a value must be followed by one
+fn value(arg: &str, value: Option<String>) -> Result<String> {
+    value.ok_or_else(|| Error::MissingArgumentValue(arg.to_owned()))
+}
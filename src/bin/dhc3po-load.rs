@@ -0,0 +1,206 @@
+//! A load generator, floods a server with DISCOVERs from many clients at
+//! once and reports how many were answered and how quickly
+
+use dhc3po::error::{Error, Result};
+use dhc3po::types::{MessageType, OptionsView};
+use dhc3po::{bench, wire};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: dhc3po-load [--clients <N>] [--concurrency <N>] [--server <ADDR>]
+                   [--relay <IP>] [--timeout <MS>]
+
+Sends a DISCOVER from each of N made up clients, with at most --concurrency
+waiting for an answer at a time, then prints how many were offered an
+address and how long that took. Point it at a test server, never at one
+serving real clients, every DISCOVER holds an address for a while.
+
+Options:
+  --clients <N>         How many clients to pretend to be, defaults to 1000
+  --concurrency <N>     DISCOVERs waiting for an answer at once, defaults to 64
+  --server <ADDR>       Where to send, defaults to 255.255.255.255:67
+  --relay <IP>          Pretend to be a relay at <IP>, putting it in giaddr and
+                        listening there on port 67 for the answers, rather than
+                        on port 68 for broadcasts
+  --timeout <MS>        Give up on an answer after this long, defaults to 1000
+  -h, --help            Print this help";
+
+/// How long the receiver waits before checking if we are done
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Options {
+    clients: u32,
+    concurrency: usize,
+    server: SocketAddr,
+    relay: Option<Ipv4Addr>,
+    timeout: Duration,
+}
+
+fn main() -> ExitCode {
+    let options = match parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("Error: {error}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// [None] when asked for help
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Options>> {
+    let mut options = Options {
+        clients: 1000,
+        concurrency: 64,
+        server: SocketAddr::from((Ipv4Addr::BROADCAST, 67)),
+        relay: None,
+        timeout: Duration::from_millis(1000),
+    };
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::MissingArgumentValue(arg.clone()));
+        let invalid = |value: &str| Error::InvalidArgumentValue(arg.clone(), value.to_owned());
+        match arg.as_str() {
+            "--clients" => {
+                let value = value?;
+                options.clients = value.parse().map_err(|_| invalid(&value))?;
+            }
+            "--concurrency" => {
+                let value = value?;
+                options.concurrency = value
+                    .parse()
+                    .ok()
+                    .filter(|concurrency| *concurrency > 0)
+                    .ok_or_else(|| invalid(&value))?;
+            }
+            "--server" => {
+                let value = value?;
+                options.server = value.parse().map_err(|_| invalid(&value))?;
+            }
+            "--relay" => {
+                let value = value?;
+                options.relay = Some(value.parse().map_err(|_| invalid(&value))?);
+            }
+            "--timeout" => {
+                let value = value?;
+                let millis = value.parse().map_err(|_| invalid(&value))?;
+                options.timeout = Duration::from_millis(millis);
+            }
+            "-h" | "--help" => return Ok(None),
+            _ => return Err(Error::InvalidArgument(arg)),
+        }
+    }
+    Ok(Some(options))
+}
+
+fn run(options: &Options) -> Result<()> {
+    let listen = match options.relay {
+        Some(relay) => SocketAddr::from((relay, 67)),
+        None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 68)),
+    };
+    let socket = UdpSocket::bind(listen).map_err(Error::CannotBindToAddress)?;
+    socket
+        .set_broadcast(true)
+        .and_then(|()| socket.set_read_timeout(Some(POLL_INTERVAL)))
+        .map_err(Error::CannotBindToAddress)?;
+
+    // Transaction ids we are waiting on, and when we sent them
+    let waiting: Mutex<HashMap<u32, Instant>> = Mutex::new(HashMap::new());
+    let latencies = Mutex::new(Vec::new());
+    let done = AtomicBool::new(false);
+    let started = Instant::now();
+    let mut lost = 0;
+
+    thread::scope(|scope| {
+        scope.spawn(|| receive(&socket, &waiting, &latencies, &done));
+
+        let mut expire = || {
+            let mut waiting = waiting.lock().unwrap();
+            let before = waiting.len();
+            waiting.retain(|_, sent| sent.elapsed() < options.timeout);
+            lost += before - waiting.len();
+            waiting.len()
+        };
+        for client in 0..options.clients {
+            while expire() >= options.concurrency {
+                thread::sleep(Duration::from_micros(100));
+            }
+            let mut discover = bench::discover(client, client);
+            if let Some(relay) = options.relay {
+                discover[wire::GIADDR].copy_from_slice(&relay.octets());
+            }
+            waiting.lock().unwrap().insert(client, Instant::now());
+            if let Err(error) = socket.send_to(&discover, options.server) {
+                eprintln!("Could not send to {}: {error}", options.server);
+            }
+        }
+        while expire() > 0 {
+            thread::sleep(POLL_INTERVAL);
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+
+    let elapsed = started.elapsed();
+    let mut latencies = latencies.into_inner().unwrap();
+    latencies.sort();
+    println!(
+        "{} DISCOVERs in {:.2}s, {} offers ({:.0}/s), {} unanswered",
+        options.clients,
+        elapsed.as_secs_f64(),
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        lost
+    );
+    if let Some(max) = latencies.last() {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {max:?}",
+            percentile(50),
+            percentile(90),
+            percentile(99)
+        );
+    }
+    Ok(())
+}
+
+/// Match answers to the DISCOVERs waiting for them until `done`
+fn receive(
+    socket: &UdpSocket,
+    waiting: &Mutex<HashMap<u32, Instant>>,
+    latencies: &Mutex<Vec<Duration>>,
+    done: &AtomicBool,
+) {
+    let mut buffer = [0u8; 1500];
+    while !done.load(Ordering::Relaxed) {
+        let Ok(len) = socket.recv(&mut buffer) else {
+            continue;
+        };
+        let reply = &buffer[..len];
+        if reply.len() < wire::OPTIONS_START
+            || OptionsView::new(reply).message_type() != Some(MessageType::Offer)
+        {
+            continue;
+        }
+        let xid = u32::from_be_bytes(reply[wire::XID].try_into().unwrap());
+        if let Some(sent) = waiting.lock().unwrap().remove(&xid) {
+            latencies.lock().unwrap().push(sent.elapsed());
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! `dhc3po --check-config`, load the config as the server would and report
+//! everything that would stop it starting or is likely a mistake, so a bad
+//! config fails when it is written rather than when the next client renews
+
+use crate::cli::Args;
+use crate::config::Config;
+use crate::types::DhcpOption;
+use crate::{load_config, Error, Result, DEFAULT_LEASE_TIME};
+use std::net::Ipv4Addr;
+
+/// What is wrong with a config, errors stop the server starting
+#[derive(Debug, Default)]
+struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let report = check(&load_config(args)?);
+    for error in &report.errors {
+        println!("error: {error}");
+    }
+    for warning in &report.warnings {
+        println!("warning: {warning}");
+    }
+    println!(
+        "{} errors, {} warnings",
+        report.errors.len(),
+        report.warnings.len()
+    );
+
+    match report.errors.len() {
+        0 => Ok(()),
+        errors => Err(Error::InvalidConfig(errors)),
+    }
+}
+
+fn check(config: &Config) -> Report {
+    let mut report = Report::default();
+    let (subnet, mask) = (config.subnet & config.mask, config.mask);
+    let broadcast = subnet | !mask;
+    let in_subnet = |ip: Ipv4Addr| ip & mask == subnet;
+
+    let bits = u32::from(mask);
+    if bits.leading_ones() + bits.trailing_zeros() != u32::BITS {
+        report
+            .errors
+            .push(format!("mask {mask} has holes in it, it is not a prefix"));
+    }
+    if config.subnet != subnet {
+        report.warnings.push(format!(
+            "subnet {} has host bits set, we serve {subnet}/{}",
+            config.subnet,
+            bits.leading_ones()
+        ));
+    }
+
+    // Quarantine ranges are ranges like any other, only for other clients
+    let quarantine_ranges = config
+        .quarantine
+        .iter()
+        .flat_map(|quarantine| quarantine.ranges.iter().copied());
+    let mut ranges: Vec<_> = config
+        .ranges
+        .iter()
+        .copied()
+        .chain(quarantine_ranges)
+        .collect();
+    ranges.sort();
+    for &(start, end) in &ranges {
+        if start > end {
+            report
+                .errors
+                .push(format!("range {start} to {end} runs backwards"));
+        } else if !in_subnet(start) || !in_subnet(end) {
+            report.errors.push(format!(
+                "range {start} to {end} is not inside {subnet} mask {mask}"
+            ));
+        } else if start <= subnet || end >= broadcast {
+            report.errors.push(format!(
+                "range {start} to {end} includes the network or broadcast address"
+            ));
+        }
+    }
+    for pair in ranges.windows(2).filter(|pair| pair[0].1 >= pair[1].0) {
+        report.errors.push(format!(
+            "ranges starting at {} and {} overlap",
+            pair[0].0, pair[1].0
+        ));
+    }
+    let in_ranges = |ip: Ipv4Addr| {
+        ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&ip))
+    };
+    let excluded = |ip: Ipv4Addr| {
+        config
+            .exclusions
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&ip))
+    };
+
+    for &(start, end) in &config.exclusions {
+        if start > end {
+            report
+                .errors
+                .push(format!("exclusion {start} to {end} runs backwards"));
+        } else if !ranges
+            .iter()
+            .any(|range| start <= range.1 && end >= range.0)
+        {
+            report.warnings.push(format!(
+                "exclusion {start} to {end} is outside every range, it has no effect"
+            ));
+        }
+    }
+
+    for (index, &(mac_address, ip_addr)) in config.reservations.iter().enumerate() {
+        let earlier = &config.reservations[..index];
+        if !in_subnet(ip_addr) {
+            report.errors.push(format!(
+                "reservation of {ip_addr} for {mac_address} is outside the subnet"
+            ));
+        } else if excluded(ip_addr) {
+            report.errors.push(format!(
+                "reservation of {ip_addr} for {mac_address} is excluded"
+            ));
+        }
+        let quarantined = config.quarantine.iter().any(|quarantine| {
+            quarantine
+                .ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&ip_addr))
+        });
+        if quarantined {
+            report.errors.push(format!(
+                "reservation of {ip_addr} for {mac_address} is in a quarantine range, \
+                 clients with reservations never get those"
+            ));
+        }
+        if earlier.iter().any(|(other, _)| *other == mac_address) {
+            report
+                .errors
+                .push(format!("{mac_address} has more than one reservation"));
+        }
+        if let Some((other, _)) = earlier.iter().find(|(_, other)| *other == ip_addr) {
+            report.errors.push(format!(
+                "{ip_addr} is reserved for both {other} and {mac_address}"
+            ));
+        }
+    }
+
+    for (index, &(uuid, ip_addr)) in config.uuid_reservations.iter().enumerate() {
+        if !in_subnet(ip_addr) {
+            report.errors.push(format!(
+                "reservation of {ip_addr} for {uuid} is outside the subnet"
+            ));
+        } else if excluded(ip_addr) {
+            report
+                .errors
+                .push(format!("reservation of {ip_addr} for {uuid} is excluded"));
+        }
+        if config.uuid_reservations[..index]
+            .iter()
+            .any(|(other, _)| *other == uuid)
+        {
+            report
+                .errors
+                .push(format!("{uuid} has more than one reservation"));
+        }
+        let taken = config
+            .reservations
+            .iter()
+            .map(|(mac_address, ip)| (mac_address.to_string(), ip))
+            .chain(
+                config.uuid_reservations[..index]
+                    .iter()
+                    .map(|(other, ip)| (other.to_string(), ip)),
+            )
+            .find(|(_, other)| **other == ip_addr);
+        if let Some((other, _)) = taken {
+            report
+                .errors
+                .push(format!("{ip_addr} is reserved for both {other} and {uuid}"));
+        }
+    }
+
+    for (index, (relay_id, ip_addr)) in config.relay_reservations.iter().enumerate() {
+        if !in_subnet(*ip_addr) {
+            report.errors.push(format!(
+                "reservation of {ip_addr} for {relay_id} is outside the subnet"
+            ));
+        } else if excluded(*ip_addr) {
+            report.errors.push(format!(
+                "reservation of {ip_addr} for {relay_id} is excluded"
+            ));
+        }
+        let earlier = &config.relay_reservations[..index];
+        if earlier.iter().any(|(other, _)| other == relay_id) {
+            report
+                .errors
+                .push(format!("{relay_id} has more than one reservation"));
+        }
+        let taken = config
+            .reservations
+            .iter()
+            .map(|(mac_address, ip)| (mac_address.to_string(), ip))
+            .chain(
+                config
+                    .uuid_reservations
+                    .iter()
+                    .map(|(uuid, ip)| (uuid.to_string(), ip)),
+            )
+            .chain(earlier.iter().map(|(other, ip)| (other.to_string(), ip)))
+            .find(|(_, other)| *other == ip_addr);
+        if let Some((other, _)) = taken {
+            report.errors.push(format!(
+                "{ip_addr} is reserved for both {other} and {relay_id}"
+            ));
+        }
+    }
+
+    if let Some(server_id) = config.server_id.filter(|ip| !in_subnet(*ip)) {
+        report.warnings.push(format!(
+            "server-id {server_id} is outside the subnet, clients only reach us \
+             through a relay"
+        ));
+    }
+    if let Some(server_id) = config
+        .server_id
+        .filter(|ip| in_ranges(*ip) && !excluded(*ip))
+    {
+        report.warnings.push(format!(
+            "server-id {server_id} is in a range, we will exclude it"
+        ));
+    }
+
+    if config.lease_store.is_some() && config.lease_file.is_some() {
+        report
+            .warnings
+            .push("lease-file is ignored as lease-store is set".to_owned());
+    }
+
+    if config.lease_journal && (config.lease_file.is_none() || config.lease_store.is_some()) {
+        report
+            .warnings
+            .push("lease-journal only applies to a lease-file".to_owned());
+    }
+
+    let lease_time = config.options.iter().find_map(|option| match option {
+        DhcpOption::LeaseTime(time) => Some(*time),
+        _ => None,
+    });
+    if let Some(adaptive) = config
+        .adaptive_lease
+        .filter(|adaptive| adaptive.min >= lease_time.unwrap_or(DEFAULT_LEASE_TIME))
+    {
+        report.warnings.push(format!(
+            "adaptive-lease-min {} is no shorter than the lease-time in [options], \
+             leases only shrink in sections with longer ones",
+            adaptive.min
+        ));
+    }
+
+    // Every layer of options, named as in the config
+    let layers = [
+        ("[options]".to_owned(), &config.options),
+        ("[subnet-options]".to_owned(), &config.subnet_options),
+    ]
+    .into_iter()
+    .chain(
+        config
+            .class_options
+            .iter()
+            .map(|(class, options)| (format!("[class {class}]"), options)),
+    )
+    .chain(
+        config
+            .user_class_options
+            .iter()
+            .map(|(class, options)| (format!("[user-class {class}]"), options)),
+    )
+    .chain(
+        config
+            .host_options
+            .iter()
+            .map(|(mac_address, options)| (format!("[host {mac_address}]"), options)),
+    )
+    .chain(
+        config
+            .quarantine
+            .iter()
+            .map(|quarantine| ("[quarantine]".to_owned(), &quarantine.options)),
+    );
+    for (section, options) in layers {
+        for option in options {
+            match option {
+                DhcpOption::Router(router) if !in_subnet(Ipv4Addr::from(*router)) => {
+                    report.errors.push(format!(
+                        "router {} in {section} is outside the subnet, clients cannot \
+                         reach it",
+                        Ipv4Addr::from(*router)
+                    ))
+                }
+                DhcpOption::BroadcastAddress(address) if Ipv4Addr::from(*address) != broadcast => {
+                    report.warnings.push(format!(
+                        "broadcast-address {} in {section} is not {broadcast}, the \
+                         broadcast address of the subnet",
+                        Ipv4Addr::from(*address)
+                    ))
+                }
+                DhcpOption::Router(ip_addr) | DhcpOption::DomainNameServer(ip_addr)
+                    if in_ranges(Ipv4Addr::from(*ip_addr))
+                        && !excluded(Ipv4Addr::from(*ip_addr)) =>
+                {
+                    report.warnings.push(format!(
+                        "{} in {section} is in a range, we will exclude it",
+                        Ipv4Addr::from(*ip_addr)
+                    ))
+                }
+                DhcpOption::LeaseTime(0) => report.warnings.push(format!(
+                    "lease-time in {section} is 0, leases expire at once"
+                )),
+                _ => {}
+            }
+        }
+    }
+
+    // Every class a client matches is layered in order, so a class listed
+    // after a more specific one overrides it
+    for (index, (class, _)) in config.class_options.iter().enumerate() {
+        let shadowed = config.class_options[..index]
+            .iter()
+            .filter(|(earlier, _)| earlier.starts_with(class.as_str()) && earlier != class);
+        for (earlier, _) in shadowed {
+            report.warnings.push(format!(
+                "[class {class}] comes after [class {earlier}] so overrides it for \
+                 every client they both match, list the more specific class last"
+            ));
+        }
+    }
+
+    report
+}
@@ -0,0 +1,324 @@
+//! Command line argument parsing, we keep this dependency free
+
+use crate::arp::ArpInjection;
+use crate::simulate::Simulation;
+use crate::{Error, Result};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: dhc3po [OPTIONS]
+       dhc3po replay <CAPTURE> [OPTIONS]
+       dhc3po decode <HEX|FILE>
+       dhc3po simulate [SIMULATE OPTIONS]
+       dhc3po --check-config [OPTIONS]
+
+Commands:
+  replay <CAPTURE>      Feed the requests in a pcap file through a server built
+                        from the config and print how it would answer, nothing
+                        is sent
+  decode <HEX|FILE>     Print the fields and options of a DHCP packet given as
+                        hex, or in a file of hex or raw bytes
+  simulate              Pretend to be many clients each getting an address,
+                        renewing and releasing it, against a test server, ours
+                        or another, and print how many made it and how quickly
+
+Options:
+  --config <PATH>       Load the config from this file, or from an etcd key
+                        as etcd://host[:port][,host[:port]...]/key which we
+                        watch and apply changes to as they are written
+  --check-config        Report problems with the config and exit, non-zero if
+                        the server would not start
+  --dnsmasq-config <PATH>
+                        Load the dhcp- lines of a dnsmasq config instead
+  --user <USER>         Drop to this user after binding the socket
+  --group <GROUP>       Drop to this group after binding the socket
+  --chroot              Chroot into the working directory after binding the socket
+  --service             Run under the Windows service control manager
+  --container           Run as a container's main process, settings not given
+                        here come from DHC3PO_CONFIG, DHC3PO_SERVER_ID,
+                        DHC3PO_EGRESS_INTERFACE, DHC3PO_UNICAST_REPLIES,
+                        DHC3PO_INJECT_ARP and PORT.
+                        Also enabled by setting DHC3PO_CONTAINER
+  --port <PORT>         Listen on this port rather than 67
+  --egress-interface <NAME>
+                        Only serve on this interface, so broadcast replies
+                        leave by it rather than by the default route
+  --unicast-replies     Send replies for clients without an address to the
+                        address we give them, for networks that drop broadcasts
+  --inject-arp <keep|remove>
+                        Unicast replies to clients without an address after
+                        adding them to the ARP cache, then keep or remove the
+                        entry. Linux only, needs CAP_NET_ADMIN
+  --server-id <IP>      Address to identify as, defaults to our interface address
+  --not-authoritative   Ignore requests for unknown addresses rather than NAK them
+  --option <CODE=VALUE> Serve any option by code, e.g. 150=hex:0A000001, the
+                        value can be hex:, ip:, u8:, u16:, u32: or str:
+  -h, --help            Print this help
+
+Simulate options:
+  --clients <N>         How many clients to pretend to be, defaults to 1000
+  --concurrency <N>     Clients waiting for an answer at once, defaults to 64
+  --server <ADDR>       Where to send, defaults to 255.255.255.255:67
+  --relay <IP>          Pretend to be a relay at <IP>, putting it in giaddr and
+                        listening there on port 67 for the answers, rather than
+                        on port 68 for broadcasts
+  --client-port <PORT>  Listen for broadcasts on this port rather than 68
+  --timeout <MS>        Give up on an answer after this long, defaults to 1000
+  --renewals <N>        Renewals before each client releases, defaults to 1";
+
+/// What we were asked to do
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Command {
+    /// Run the server
+    #[default]
+    Serve,
+
+    /// Answer the requests in a pcap file without sending anything
+    Replay(PathBuf),
+
+    /// Print a packet given as hex or a file
+    Decode(String),
+
+    /// Pretend to be many clients against a server
+    Simulate(Simulation),
+
+    /// Report problems with the config and exit
+    CheckConfig,
+}
+
+/// The options passed to us on the command line
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub command: Command,
+
+    /// Where to load our configuration from
+    pub config: Option<PathBuf>,
+
+    /// Where to load a dnsmasq configuration from instead
+    pub dnsmasq_config: Option<PathBuf>,
+
+    /// Name or uid of the user to run as once the socket is bound
+    pub user: Option<String>,
+
+    /// Name or gid of the group to run as once the socket is bound
+    pub group: Option<String>,
+
+    /// Chroot into the current working directory once the socket is bound
+    pub chroot: bool,
+
+    /// We were started by the Windows service control manager
+    pub service: bool,
+
+    /// We are a container's main process, see [crate::cli::Args::parse]
+    pub container: bool,
+
+    /// Listen here rather than on [crate::SERVER_PORT]
+    pub port: Option<u16>,
+
+    /// Serve and reply on this interface only
+    pub egress_interface: Option<String>,
+
+    /// Unicast replies to the address we give clients instead of broadcasting
+    pub unicast_replies: bool,
+
+    /// Unicast them after putting the client in the ARP cache
+    pub inject_arp: Option<ArpInjection>,
+
+    /// Overrides the server identifier detected from our interface
+    pub server_id: Option<Ipv4Addr>,
+
+    /// Stay silent rather than NAK requests for addresses we do not know
+    pub not_authoritative: bool,
+
+    /// Extra options given by numeric code and encoded value
+    pub options: Vec<(u8, String)>,
+}
+
+impl Args {
+    /// Parse the arguments of the current process, in container mode falling
+    /// back to the environment for anything not on the command line
+    pub fn parse() -> Result<Self> {
+        let mut args = Self::parse_from(std::env::args().skip(1))?;
+        args.container |= std::env::var_os("DHC3PO_CONTAINER").is_some();
+        if args.container {
+            args.merge_env(|name| std::env::var(name).ok())?;
+        }
+        Ok(args)
+    }
+
+    /// Fill in what the command line did not set from the variables `var`
+    /// returns, containers are mostly configured through the environment
+    fn merge_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let invalid = |name: &str, value: String| Error::InvalidArgumentValue(name.into(), value);
+        if self.config.is_none() && self.dnsmasq_config.is_none() {
+            self.config = var("DHC3PO_CONFIG").map(PathBuf::from);
+        }
+        if let Some(value) = var("DHC3PO_SERVER_ID").filter(|_| self.server_id.is_none()) {
+            let server_id = value.parse();
+            self.server_id = Some(server_id.map_err(|_| invalid("DHC3PO_SERVER_ID", value))?);
+        }
+        if let Some(value) = var("PORT").filter(|_| self.port.is_none()) {
+            self.port = Some(value.parse().map_err(|_| invalid("PORT", value))?);
+        }
+        if self.egress_interface.is_none() {
+            self.egress_interface = var("DHC3PO_EGRESS_INTERFACE");
+        }
+        if let Some(value) = var("DHC3PO_UNICAST_REPLIES") {
+            self.unicast_replies |= match value.as_str() {
+                "1" | "true" => true,
+                "0" | "false" => false,
+                _ => return Err(invalid("DHC3PO_UNICAST_REPLIES", value)),
+            };
+        }
+        if let Some(value) = var("DHC3PO_INJECT_ARP").filter(|_| self.inject_arp.is_none()) {
+            let inject_arp = Self::arp_injection(&value);
+            self.inject_arp = Some(inject_arp.ok_or_else(|| invalid("DHC3PO_INJECT_ARP", value))?);
+        }
+        Ok(())
+    }
+
+    fn parse_from(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "replay" if parsed.command == Command::Serve => {
+                    parsed.command = Command::Replay(Self::value(&arg, args.next())?.into())
+                }
+                "decode" if parsed.command == Command::Serve => {
+                    parsed.command = Command::Decode(Self::value(&arg, args.next())?)
+                }
+                "simulate" if parsed.command == Command::Serve => {
+                    parsed.command = Command::Simulate(Simulation::default())
+                }
+                "--clients" | "--concurrency" | "--server" | "--relay" | "--client-port"
+                | "--timeout" | "--renewals"
+                    if matches!(parsed.command, Command::Simulate(_)) =>
+                {
+                    let value = Self::value(&arg, args.next())?;
+                    let Command::Simulate(simulation) = &mut parsed.command else {
+                        unreachable!("matched above")
+                    };
+                    simulation
+                        .set(&arg, &value)
+                        .ok_or(Error::InvalidArgumentValue(arg, value))?;
+                }
+                "--check-config" if parsed.command == Command::Serve => {
+                    parsed.command = Command::CheckConfig
+                }
+                "--config" => parsed.config = Some(Self::value(&arg, args.next())?.into()),
+                "--dnsmasq-config" => {
+                    parsed.dnsmasq_config = Some(Self::value(&arg, args.next())?.into())
+                }
+                "--user" => parsed.user = Some(Self::value(&arg, args.next())?),
+                "--group" => parsed.group = Some(Self::value(&arg, args.next())?),
+                "--chroot" => parsed.chroot = true,
+                "--service" => parsed.service = true,
+                "--container" => parsed.container = true,
+                "--port" => {
+                    let value = Self::value(&arg, args.next())?;
+                    parsed.port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| Error::InvalidArgumentValue(arg, value))?,
+                    );
+                }
+                "--egress-interface" => {
+                    parsed.egress_interface = Some(Self::value(&arg, args.next())?)
+                }
+                "--unicast-replies" => parsed.unicast_replies = true,
+                "--inject-arp" => {
+                    let value = Self::value(&arg, args.next())?;
+                    parsed.inject_arp = Some(
+                        Self::arp_injection(&value)
+                            .ok_or(Error::InvalidArgumentValue(arg, value))?,
+                    );
+                }
+                "--server-id" => {
+                    let value = Self::value(&arg, args.next())?;
+                    parsed.server_id = Some(
+                        value
+                            .parse()
+                            .map_err(|_| Error::InvalidArgumentValue(arg, value))?,
+                    );
+                }
+                "--not-authoritative" => parsed.not_authoritative = true,
+                "--option" => {
+                    let value = Self::value(&arg, args.next())?;
+                    let option = value
+                        .split_once('=')
+                        .and_then(|(code, value)| Some((code.parse().ok()?, value.to_owned())));
+                    match option {
+                        Some(option) => parsed.options.push(option),
+                        None => return Err(Error::InvalidArgumentValue(arg, value)),
+                    }
+                }
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => return Err(Error::InvalidArgument(arg)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn arp_injection(value: &str) -> Option<ArpInjection> {
+        match value {
+            "keep" => Some(ArpInjection::Keep),
+            "remove" => Some(ArpInjection::Remove),
+            _ => None,
+        }
+    }
+
+    /// Options that take a value must be followed by one
+    fn value(arg: &str, value: Option<String>) -> Result<String> {
+        value.ok_or_else(|| Error::MissingArgumentValue(arg.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse_from(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn privileges_are_dropped_to_the_given_user_and_group() {
+        let args = parse(&["--user", "dhcp", "--group", "nogroup", "--chroot"]).unwrap();
+        assert_eq!(args.user.as_deref(), Some("dhcp"));
+        assert_eq!(args.group.as_deref(), Some("nogroup"));
+        assert!(args.chroot);
+
+        let args = parse(&[]).unwrap();
+        assert_eq!((args.user, args.group, args.chroot), (None, None, false));
+
+        assert!(matches!(
+            parse(&["--user"]),
+            Err(Error::MissingArgumentValue(arg)) if arg == "--user"
+        ));
+    }
+    #[test]
+    fn service_mode_is_opt_in() {
+        assert!(parse(&["--service"]).unwrap().service);
+        assert!(!parse(&[]).unwrap().service);
+    }
+    #[test]
+    fn options_are_given_by_code_and_value() {
+        let args = parse(&["--option", "150=ip:10.0.0.1", "--option", "224=str:a=b"]).unwrap();
+        assert_eq!(
+            args.options,
+            [(150, "ip:10.0.0.1".to_owned()), (224, "str:a=b".to_owned())]
+        );
+        for invalid in ["150", "256=u8:1", "=u8:1"] {
+            assert!(matches!(
+                parse(&["--option", invalid]),
+                Err(Error::InvalidArgumentValue(_, value)) if value == invalid
+            ));
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Read the DHCP parts of a dnsmasq config so home router users can bring
+//! theirs along. We understand:
+//!
+//! ```text
+//! dhcp-range=192.168.0.50,192.168.0.150,255.255.255.0,12h
+//! dhcp-range=192.168.0.200,192.168.0.220
+//! dhcp-host=11:22:33:44:55:66,192.168.0.60
+//! dhcp-option=option:router,192.168.0.1
+//! dhcp-option=6,1.1.1.1,8.8.8.8
+//! dhcp-authoritative
+//! domain=home
+//! ```
+//!
+//! Ranges must share the subnet of the first, which also sets the lease time.
+//! Tags are ignored so tagged options apply to everyone, and options that
+//! only matter to dnsmasq's DNS server are skipped with a warning.
+
+use log::warn;
+
+use super::Config;
+use crate::types::DhcpOption;
+use crate::{Error, Result, INFINITE_LEASE_TIME};
+use std::net::Ipv4Addr;
+
+/// dnsmasq's lease time when the range does not give one
+const DEFAULT_LEASE_TIME: u32 = 3600;
+
+/// dnsmasq learns the mask from the interface, we cannot so guess a /24
+const DEFAULT_MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+/// dnsmasq names for the options people actually set
+const OPTION_NAMES: [(&str, u8); 10] = [
+    ("netmask", 1),
+    ("router", 3),
+    ("dns-server", 6),
+    ("hostname", 12),
+    ("domain-name", 15),
+    ("broadcast", 28),
+    ("ntp-server", 42),
+    ("netbios-ns", 44),
+    ("tftp-server", 66),
+    ("bootfile-name", 67),
+];
+
+pub fn parse(text: &str) -> Result<Config> {
+    let mut config = Config {
+        // dnsmasq stays silent for addresses it does not know by default
+        authoritative: false,
+        options: Vec::new(),
+        ..Config::default()
+    };
+    let mut ranges = Vec::new();
+    let mut domain = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let invalid = || Error::InvalidConfigLine(index + 1, line.to_owned());
+
+        let content = line.split('#').next().unwrap_or_default().trim();
+        if content.is_empty() {
+            continue;
+        }
+
+        let (key, value) = content.split_once('=').unwrap_or((content, ""));
+        let (key, value) = (key.trim(), value.trim());
+        let fields: Vec<&str> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !is_tag(field))
+            .collect();
+
+        match key {
+            "dhcp-range" => ranges.push(parse_range(&fields).ok_or_else(invalid)?),
+            "dhcp-host" => {
+                let mac = fields.first().and_then(|mac| mac.parse().ok());
+                let ip = fields.iter().find_map(|field| field.parse().ok());
+                match (mac, ip) {
+                    (Some(mac), Some(ip)) => config.reservations.push((mac, ip)),
+                    _ => return Err(invalid()),
+                }
+            }
+            "dhcp-option" => {
+                if let Some(option) = parse_option(&fields).ok_or_else(invalid)? {
+                    config.options.push(option);
+                }
+            }
+            "dhcp-authoritative" => config.authoritative = true,
+            "domain" => domain = fields.first().map(|domain| domain.to_string()),
+            _ => warn!("Ignoring dnsmasq option {key:?}, we only read the DHCP options"),
+        }
+    }
+
+    // We serve one subnet, so the first range decides which
+    let &(start, _, mask, lease_time) = ranges
+        .first()
+        .ok_or(Error::MissingConfigKey("dhcp-range"))?;
+    config.subnet = start & mask;
+    config.mask = mask;
+    config.ranges = ranges
+        .iter()
+        .filter(|(other, end, ..)| {
+            let same_subnet = *other & mask == config.subnet;
+            if !same_subnet {
+                warn!(
+                    "Ignoring dhcp-range {other},{end}, it is not in {}",
+                    config.subnet
+                );
+            }
+            same_subnet
+        })
+        .map(|&(start, end, ..)| (start, end))
+        .collect();
+
+    let has_option = |code| config.options.iter().any(|option| option.opcode() == code);
+    let domain = domain.filter(|_| !has_option(DhcpOption::DOMAIN_NAME));
+    if !has_option(DhcpOption::LEASE_TIME) {
+        config.options.push(DhcpOption::LeaseTime(lease_time));
+    }
+    if let Some(domain) = domain {
+        config.options.push(DhcpOption::DomainName(domain));
+    }
+
+    Ok(config)
+}
+
+/// `tag:`, `set:` and the older `net:` select which clients a line applies to
+fn is_tag(field: &str) -> bool {
+    ["tag:", "set:", "net:"]
+        .iter()
+        .any(|prefix| field.starts_with(prefix))
+}
+
+/// `<start>,<end>[,<mask>][,<lease time>]`
+fn parse_range(fields: &[&str]) -> Option<(Ipv4Addr, Ipv4Addr, Ipv4Addr, u32)> {
+    let [start, end, rest @ ..] = fields else {
+        return None;
+    };
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+
+    let (mask, rest) = match rest.split_first() {
+        Some((mask, rest)) if mask.parse::<Ipv4Addr>().is_ok() => (mask.parse().ok()?, rest),
+        _ => {
+            warn!("dhcp-range has no netmask, assuming {DEFAULT_MASK}");
+            (DEFAULT_MASK, rest)
+        }
+    };
+
+    let lease_time = match rest {
+        [] => DEFAULT_LEASE_TIME,
+        [lease_time] => parse_lease_time(lease_time)?,
+        _ => return None,
+    };
+
+    Some((start, end, mask, lease_time))
+}
+
+/// Seconds, or a number with an `m`, `h`, `d` or `w` suffix, or `infinite`
+fn parse_lease_time(value: &str) -> Option<u32> {
+    if value == "infinite" {
+        return Some(INFINITE_LEASE_TIME);
+    }
+    let multiplier = match value.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return value.parse().ok(),
+    };
+    value[..value.len() - 1]
+        .parse::<u32>()
+        .ok()?
+        .checked_mul(multiplier)
+}
+
+/// `<code or option:name>,<values>`, [None] inside the result means the line
+/// asks for the option not to be sent which is already our default
+fn parse_option(fields: &[&str]) -> Option<Option<DhcpOption>> {
+    let [code, values @ ..] = fields else {
+        return None;
+    };
+    let code = match code.strip_prefix("option:") {
+        Some(name) => OPTION_NAMES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, code)| *code)?,
+        None => code.parse().ok()?,
+    };
+    if values.is_empty() {
+        return Some(None);
+    }
+
+    // Addresses are the common case, anything else we send as text
+    let value = match values.iter().all(|value| value.parse::<Ipv4Addr>().is_ok()) {
+        true => format!("ip:{}", values.join(",")),
+        false => format!("str:{}", values.join(",").trim_matches('"')),
+    };
+    DhcpOption::from_code_value(code, &value).ok().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_hosts_and_options_are_read() {
+        let config = parse(
+            "\
+dhcp-range=192.168.0.50,192.168.0.150,255.255.255.0,12h
+dhcp-range=set:guest,192.168.0.200,192.168.0.220
+dhcp-range=10.0.0.10,10.0.0.20,255.255.255.0
+dhcp-host=11:22:33:44:55:66,laptop,192.168.0.60
+dhcp-option=option:router,192.168.0.1
+dhcp-option=tag:guest,6,1.1.1.1,8.8.8.8
+dhcp-option=66,\"tftp.home\"
+dhcp-authoritative
+domain=home
+cache-size=1000
+",
+        )
+        .unwrap();
+        assert_eq!(config.subnet, Ipv4Addr::new(192, 168, 0, 0));
+        assert_eq!(config.mask, Ipv4Addr::new(255, 255, 255, 0));
+        // The range in another subnet is dropped
+        let range = |start, end| {
+            (
+                Ipv4Addr::new(192, 168, 0, start),
+                Ipv4Addr::new(192, 168, 0, end),
+            )
+        };
+        assert_eq!(config.ranges, [range(50, 150), range(200, 220)]);
+        assert_eq!(
+            config.reservations,
+            [(
+                "11:22:33:44:55:66".parse().unwrap(),
+                Ipv4Addr::new(192, 168, 0, 60)
+            )]
+        );
+        assert!(config.authoritative);
+        assert_eq!(
+            config.options,
+            [
+                DhcpOption::Unknown(3, vec![192, 168, 0, 1]),
+                DhcpOption::Unknown(6, vec![1, 1, 1, 1, 8, 8, 8, 8]),
+                DhcpOption::Unknown(66, b"tftp.home".to_vec()),
+                DhcpOption::LeaseTime(12 * 60 * 60),
+                DhcpOption::DomainName("home".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lease_times_take_dnsmasq_units() {
+        assert_eq!(parse_lease_time("90"), Some(90));
+        assert_eq!(parse_lease_time("45m"), Some(45 * 60));
+        assert_eq!(parse_lease_time("2d"), Some(2 * 24 * 60 * 60));
+        assert_eq!(parse_lease_time("1w"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_lease_time("infinite"), Some(INFINITE_LEASE_TIME));
+        assert_eq!(parse_lease_time("9999999w"), None);
+        assert_eq!(parse_lease_time("soon"), None);
+    }
+
+    #[test]
+    fn bad_lines_are_reported() {
+        assert!(matches!(
+            parse("dhcp-range=192.168.0.50,192.168.0.150\ndhcp-host=192.168.0.60"),
+            Err(Error::InvalidConfigLine(2, _))
+        ));
+        assert!(matches!(
+            parse("dhcp-range=192.168.0.50"),
+            Err(Error::InvalidConfigLine(1, _))
+        ));
+        assert!(matches!(
+            parse("domain=home"),
+            Err(Error::MissingConfigKey("dhcp-range"))
+        ));
+    }
+}
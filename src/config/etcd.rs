@@ -0,0 +1,54 @@
+//! The config kept in an etcd key rather than a file, so every server of a
+//! cluster shares one and picks up changes as they are written. The key holds
+//! the text of a config file, `--config etcd://host[:port][,host[:port]...]/key`
+
+use log::{info, warn};
+
+use super::Config;
+use crate::etcd::Etcd;
+use crate::{Error, Result};
+use std::io;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// The revision of the config we loaded, watching carries on from there
+static LOADED_REVISION: AtomicI64 = AtomicI64::new(0);
+
+/// The cluster and key of an `etcd://` URL, the key is required
+fn parse_url(url: &str) -> Result<(Etcd, &str)> {
+    Etcd::parse(url)
+        .filter(|(_, key)| !key.is_empty())
+        .ok_or_else(|| Error::InvalidArgumentValue("--config".to_owned(), url.to_owned()))
+}
+
+pub fn load(url: &str) -> Result<Config> {
+    let (mut etcd, key) = parse_url(url)?;
+    let kv = etcd
+        .get(key)
+        .map_err(Error::CannotReadConfig)?
+        .ok_or_else(|| {
+            Error::CannotReadConfig(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("etcd has no key {key}"),
+            ))
+        })?;
+    LOADED_REVISION.store(kv.mod_revision, Ordering::Relaxed);
+    Config::parse(&String::from_utf8_lossy(&kv.value))
+}
+
+/// Call `apply` with the config each time it is written after we loaded it,
+/// configs that do not parse are logged and skipped
+pub fn watch(url: &str, apply: impl Fn(Config) + Send + 'static) -> Result<()> {
+    let (etcd, key) = parse_url(url)?;
+    etcd.watch(
+        key.to_owned(),
+        LOADED_REVISION.load(Ordering::Relaxed),
+        move |text| match Config::parse(&String::from_utf8_lossy(text)) {
+            Ok(config) => {
+                info!("The config in etcd changed, applying it");
+                apply(config)
+            }
+            Err(error) => warn!("Ignoring the new config in etcd, {error}"),
+        },
+    );
+    Ok(())
+}
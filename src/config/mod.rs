@@ -0,0 +1,1019 @@
+//! Loading our configuration from a file so we do not need to recompile to
+//! change it. The format is a minimal INI style:
+//!
+//! ```text
+//! # Comments start with a hash
+//! subnet = 192.168.1.0
+//! mask = 255.255.255.0
+//! range = 192.168.1.10 192.168.1.40
+//! # Repeat range for more addresses, exclude leaves holes in them
+//! range = 192.168.1.50 192.168.1.99
+//! exclude = 192.168.1.64
+//! authoritative = true
+//! on-exhausted = ignore
+//! # sequential, random or hash of the client
+//! allocation = sequential
+//! # Clients behind one option 82 circuit-id hold at most this many leases
+//! max-leases-per-circuit = 2
+//! # Share the segment with another server, waiting 500ms before offering to
+//! # the clients whose MAC hashes odd and answering 80% of DISCOVERs
+//! offer-delay = 500
+//! offer-delay-parity = odd
+//! offer-percent = 80
+//! # Clients that have been trying for 4 seconds by secs skip the above, and
+//! # are the only ones to get the last 5 free addresses
+//! waiting-secs = 4
+//! waiting-reserve = 5
+//! # When a client gives a name another holds, suffix it with -2, -3 and so
+//! # on, the end of its MAC, keep no name for it, or allow both
+//! hostname-conflict = suffix
+//! # Past 80% of the pool in use shrink leases, down to 1800 seconds when full
+//! adaptive-lease-above = 80
+//! adaptive-lease-min = 1800
+//! parsing = strict
+//! # Replies start with these options, then those the client asked for in
+//! # the order it asked
+//! option-order = 53 54 51
+//! server-id = 192.168.1.86
+//! server-hostname = dhc3po
+//! boot-file-in-header = true
+//! buffer-size = 1500
+//! # Somewhere other than 67 and 68, for tests and runs without root
+//! server-port = 6767
+//! client-port = 6868
+//! lease-file = dhc3po.leases
+//! # Journal leases and sync them in the background rather than rewrite the
+//! # lease file on every bind, see crate::store::JournalStore
+//! lease-journal = true
+//! lease-store = redis://127.0.0.1:6379
+//! # Or etcd, listing every member of the cluster
+//! lease-store = etcd://10.0.0.1:2379,10.0.0.2:2379,10.0.0.3:2379/dhc3po
+//! audit-file = dhc3po.audit
+//! log = syslog
+//! # Log these clients' packets decoded and everything we decide about them,
+//! # see crate::logging
+//! log-packets = 02:00:00:00:00:01 02:00:00:00:00:02
+//! otlp-endpoint = http://127.0.0.1:4318/v1/traces
+//! oui-file = /usr/share/ieee-data/oui.txt
+//! admin-listen = 127.0.0.1:8067
+//! alert-thresholds = 80 90
+//! alert-webhook = http://127.0.0.1:9000/dhcp
+//! mud-webhook = http://127.0.0.1:9000/mud
+//!
+//! [options]
+//! router = 192.168.1.254
+//! domain-name-server = 1.1.1.1
+//! # Seconds east of UTC, this is UTC-5
+//! time-offset = -18000
+//! time-server = 192.168.1.254
+//! ntp-servers = 192.168.1.254 192.168.1.253
+//! netbios-name-servers = 192.168.1.20
+//! # b, p, m or h
+//! netbios-node-type = h
+//! netbios-scope = corp
+//! domain-name = home
+//! lease-time = 32400
+//! tftp-server-name = 192.168.10.1
+//! boot-file-name = stage0.bin
+//! root-path = 192.168.10.1:/export/root
+//! extensions-path = extensions.bin
+//! captive-portal = https://portal.example.com/api
+//! ipv6-only-preferred = 1800
+//! # Or leave empty so Windows stops looking for a proxy
+//! proxy-autodiscovery = http://wpad.home/wpad.dat
+//! # Who updates DNS for clients that send option 81, server for A and PTR
+//! # whatever they ask, none for neither, or client to do as they ask
+//! client-fqdn = client
+//! # Any option can be given by code, see DhcpOption::from_code_value
+//! 150 = ip:10.0.0.1
+//!
+//! # Override [options] for this subnet
+//! [subnet-options]
+//! domain-name = lab.home
+//! domain-search = lab.home home
+//! interface-mtu = 9000
+//! # Worked out from subnet and mask when not given
+//! broadcast-address = 192.168.1.255
+//!
+//! # Override the above for clients whose vendor class starts with PXEClient
+//! [class PXEClient]
+//! # siaddr, which [options] and [user-class] sections can set too
+//! next-server = 192.168.10.1
+//! boot-file-name = ipxe.efi
+//! # A boot menu in option 43, entries are a boot server type then the text
+//! pxe-discovery-control = 3
+//! pxe-boot-server = 0x8000 192.168.10.1
+//! pxe-menu = 0x8000 Install Ubuntu
+//! pxe-menu = 0 Boot from local disk
+//! pxe-prompt = 10 Press F8 for the boot menu
+//!
+//! # Override the above for clients with this user class (option 77), iPXE
+//! # sends iPXE so it can be given a script rather than itself again
+//! [user-class iPXE]
+//! next-server = 192.168.10.2
+//! boot-file-name = http://192.168.10.1/boot.ipxe
+//!
+//! # Override everything for one client
+//! [host 02:00:00:00:00:01]
+//! domain-name-server = 192.168.1.53
+//!
+//! # Always give these clients the same address, optionally for longer or
+//! # shorter than the lease-time they would otherwise get
+//! [hosts]
+//! 02:00:00:00:00:01 = 192.168.1.5
+//! 02:00:00:00:00:02 = 192.168.1.6 604800
+//! # Seconds or infinite, for printers and the like that should never move
+//! 02:00:00:00:00:03 = 192.168.1.7 infinite
+//! # Or by SMBIOS UUID (option 97), whichever NIC the machine boots from
+//! 4c4c4544-0042-3510-8052-b4c04f385331 = 192.168.1.8
+//! # Or by the option 82 circuit-id or remote-id of the relay, whatever
+//! # hardware is plugged in there, as text or 0x and hex digits
+//! circuit-id:eth0/1/3 = 192.168.1.9
+//! remote-id:0x020000000001 = 192.168.1.10
+//!
+//! # Optional, share leases with a hot standby, see crate::failover
+//! [failover]
+//! role = primary
+//! peer = 192.168.1.87:647
+//! listen = 0.0.0.0:647
+//! mclt = 3600
+//!
+//! # Optional, clients without a reservation, host options or an allow line
+//! # get addresses from these ranges with these options until registered
+//! [quarantine]
+//! range = 192.168.1.200 192.168.1.249
+//! allow = 02:00:00:00:00:04 02:00:00:00:00:05
+//! router = 192.168.1.1
+//! domain-name-server = 192.168.1.1
+//! captive-portal = https://register.home/api
+//! lease-time = 300
+//! ```
+
+mod dnsmasq;
+pub mod etcd;
+
+use crate::alerts::{AlertConfig, Webhook};
+use crate::dhcp::Parsing;
+use crate::failover::{self, FailoverConfig, Role};
+use crate::hostname::HostnameConflict;
+use crate::logging::LogTarget;
+use crate::state::{
+    AdaptiveLease, Allocation, BootStage, ExhaustedPolicy, LoadSharing, Parity, Waiting,
+    DEFAULT_OPTION_ORDER,
+};
+use crate::store;
+use crate::types::{ClientFqdn, DhcpOption, MacAddr, PxeOptions, RelayId, Uuid};
+use crate::{Error, Result, DEFAULT_UDP_BUFFER_SIZE, INFINITE_LEASE_TIME};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Every client must be able to send us a 576 byte datagram (RFC 2131)
+const MIN_BUFFER_SIZE: usize = 576;
+
+/// Everything needed to set up an [crate::AddrPool]
+#[derive(Debug)]
+pub struct Config {
+    pub subnet: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    /// Disjoint ranges of addresses we hand out
+    pub ranges: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// Addresses inside `ranges` we never hand out
+    pub exclusions: Vec<(Ipv4Addr, Ipv4Addr)>,
+    pub authoritative: bool,
+    pub on_exhausted: ExhaustedPolicy,
+    pub allocation: Allocation,
+    /// How many leases clients relayed from one circuit can hold
+    pub max_leases_per_circuit: Option<usize>,
+    /// How we share the segment with servers we have no failover with
+    pub load_sharing: LoadSharing,
+    /// Who counts as waiting from secs, and the addresses kept for them
+    pub waiting: Option<Waiting>,
+    /// How leases shrink as the pool fills
+    pub adaptive_lease: Option<AdaptiveLease>,
+    /// What a client gets when it claims a name another holds
+    pub hostname_conflict: HostnameConflict,
+    /// Whether to drop or repair requests with bad options
+    pub parsing: Parsing,
+    /// The codes of the options our replies start with
+    pub option_order: Vec<u8>,
+    /// When [None] we use the address of the interface facing the subnet
+    pub server_id: Option<Ipv4Addr>,
+    /// Goes in sname of our replies
+    pub server_hostname: Option<String>,
+    /// Copy the client's boot-file-name into file of our replies
+    pub boot_file_in_header: bool,
+    /// Requests larger than this are discarded
+    pub buffer_size: usize,
+    /// Where we listen, unless `--port` says otherwise
+    pub server_port: Option<u16>,
+    /// Where we send replies to clients
+    pub client_port: Option<u16>,
+    /// Where we keep leases across restarts, in memory only when [None]
+    pub lease_file: Option<PathBuf>,
+    /// Write `lease_file` through a journal, see [crate::store::JournalStore]
+    pub lease_journal: bool,
+    /// A store shared with other servers instead, see [crate::store]
+    pub lease_store: Option<String>,
+    /// Where we append who held which address when, see [crate::audit]
+    pub audit_file: Option<PathBuf>,
+    /// Where log lines go once the config is loaded
+    pub log: LogTarget,
+    /// Clients we log everything about, see [crate::logging::trace]
+    pub log_packets: Vec<MacAddr>,
+    /// Where we send request traces, see [crate::telemetry]
+    pub otlp_endpoint: Option<Webhook>,
+    /// A full OUI table, see [crate::oui]
+    pub oui_file: Option<PathBuf>,
+    /// Where to serve the dashboard and API, see [crate::admin]
+    pub admin_listen: Option<SocketAddr>,
+    /// Global options, every later layer overrides these
+    pub options: Vec<DhcpOption>,
+    /// Options for our subnet
+    pub subnet_options: Vec<DhcpOption>,
+    /// Options by vendor class identifier prefix
+    pub class_options: Vec<(String, Vec<DhcpOption>)>,
+    /// Options by user class
+    pub user_class_options: Vec<(String, Vec<DhcpOption>)>,
+    /// Options for one client by chaddr
+    pub host_options: Vec<(MacAddr, Vec<DhcpOption>)>,
+    /// `next-server` from `[options]` and the class sections
+    pub next_servers: Vec<(BootStage, Ipv4Addr)>,
+    /// Addresses reserved for a client by chaddr
+    pub reservations: Vec<(MacAddr, Ipv4Addr)>,
+    /// Addresses reserved for a machine by the UUID in option 97
+    pub uuid_reservations: Vec<(Uuid, Ipv4Addr)>,
+    /// Addresses reserved for whoever is behind a relay circuit or line
+    pub relay_reservations: Vec<(RelayId, Ipv4Addr)>,
+    /// Present when we have a `[failover]` section
+    pub failover: Option<FailoverConfig>,
+    /// When to warn that the pool is filling up
+    pub alerts: AlertConfig,
+    /// Where to POST the MUD URLs of new devices, see [crate::mud]
+    pub mud_webhook: Option<Webhook>,
+    /// Present when we have a `[quarantine]` section
+    pub quarantine: Option<QuarantineConfig>,
+}
+
+/// Where clients we do not know go, see [crate::AddrPool::set_quarantine]
+#[derive(Debug, Default)]
+pub struct QuarantineConfig {
+    /// Disjoint from `ranges`, only clients we do not know get these
+    pub ranges: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// Clients we know without a reservation or host options
+    pub allow: Vec<MacAddr>,
+    /// Layered over the class options of clients we do not know
+    pub options: Vec<DhcpOption>,
+}
+
+impl Default for Config {
+    /// What we serve when no configuration file is given
+    fn default() -> Self {
+        Self {
+            subnet: [192, 168, 1, 0].into(),
+            mask: [255, 255, 255, 0].into(),
+            ranges: vec![([192, 168, 1, 10].into(), [192, 168, 1, 40].into())],
+            exclusions: Vec::new(),
+            authoritative: true,
+            on_exhausted: ExhaustedPolicy::Ignore,
+            allocation: Allocation::Sequential,
+            max_leases_per_circuit: None,
+            load_sharing: LoadSharing::default(),
+            waiting: None,
+            adaptive_lease: None,
+            hostname_conflict: HostnameConflict::Suffix,
+            parsing: Parsing::Strict,
+            option_order: DEFAULT_OPTION_ORDER.to_vec(),
+            server_id: None,
+            server_hostname: None,
+            boot_file_in_header: false,
+            buffer_size: DEFAULT_UDP_BUFFER_SIZE,
+            server_port: None,
+            client_port: None,
+            lease_file: None,
+            lease_journal: false,
+            lease_store: None,
+            audit_file: None,
+            log: LogTarget::default(),
+            otlp_endpoint: None,
+            log_packets: Vec::new(),
+            oui_file: None,
+            admin_listen: None,
+            options: vec![
+                DhcpOption::Router([192, 168, 1, 254]),
+                DhcpOption::BootFileName("stage0.bin".into()),
+                DhcpOption::TftpServerName("192.168.10.1".into()),
+                DhcpOption::DomainName("home".into()),
+                DhcpOption::DomainNameServer([1, 1, 1, 1]),
+                DhcpOption::LeaseTime(32400),
+            ],
+            subnet_options: Vec::new(),
+            class_options: Vec::new(),
+            user_class_options: Vec::new(),
+            host_options: Vec::new(),
+            next_servers: Vec::new(),
+            reservations: Vec::new(),
+            uuid_reservations: Vec::new(),
+            relay_reservations: Vec::new(),
+            failover: None,
+            alerts: AlertConfig::default(),
+            mud_webhook: None,
+            quarantine: None,
+        }
+    }
+}
+
+/// The `[section]` of the file we are currently in
+enum Section {
+    Global,
+    Options,
+    SubnetOptions,
+    /// Index into the class options
+    Class(usize),
+    /// Index into the user class options
+    UserClass(usize),
+    /// Index into the host options
+    Host(usize),
+    Hosts,
+    Failover,
+    Quarantine,
+}
+
+/// The keys of a `[failover]` section as we find them
+#[derive(Default)]
+struct FailoverKeys {
+    role: Option<Role>,
+    listen: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+    mclt: Option<u32>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(Error::CannotReadConfig)?;
+        Self::parse(&text)
+    }
+
+    /// Load the config from etcd rather than a file, see [etcd]
+    pub fn load_etcd(url: &str) -> Result<Self> {
+        etcd::load(url)
+    }
+
+    /// Load the DHCP parts of a dnsmasq config instead of our own format
+    pub fn load_dnsmasq(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(Error::CannotReadConfig)?;
+        dnsmasq::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut subnet = None;
+        let mut mask = None;
+        let mut ranges = Vec::new();
+        let mut exclusions = Vec::new();
+        let mut authoritative = true;
+        let mut on_exhausted = ExhaustedPolicy::default();
+        let mut allocation = Allocation::default();
+        let mut max_leases_per_circuit = None;
+        let mut load_sharing = LoadSharing::default();
+        let mut waiting_secs = None;
+        let mut waiting_reserve = 0;
+        let mut adaptive_lease_above = None;
+        let mut adaptive_lease_min = None;
+        let mut hostname_conflict = HostnameConflict::default();
+        let mut parsing = Parsing::default();
+        let mut option_order = DEFAULT_OPTION_ORDER.to_vec();
+        let mut server_id = None;
+        let mut server_hostname = None;
+        let mut boot_file_in_header = false;
+        let mut buffer_size = DEFAULT_UDP_BUFFER_SIZE;
+        let mut server_port = None;
+        let mut client_port = None;
+        let mut lease_file = None;
+        let mut lease_journal = false;
+        let mut lease_store = None;
+        let mut audit_file = None;
+        let mut log = LogTarget::default();
+        let mut otlp_endpoint = None;
+        let mut log_packets = Vec::new();
+        let mut oui_file = None;
+        let mut admin_listen = None;
+        let mut options = Vec::new();
+        let mut subnet_options = Vec::new();
+        let mut class_options: Vec<(String, Vec<DhcpOption>)> = Vec::new();
+        let mut user_class_options: Vec<(String, Vec<DhcpOption>)> = Vec::new();
+        let mut host_options: Vec<(MacAddr, Vec<DhcpOption>)> = Vec::new();
+        let mut next_servers = Vec::new();
+        let mut reservations = Vec::new();
+        let mut uuid_reservations = Vec::new();
+        let mut relay_reservations = Vec::new();
+        let mut failover = None;
+        let mut alerts = AlertConfig::default();
+        let mut mud_webhook = None;
+        let mut quarantine = None;
+
+        let mut section = Section::Global;
+        for (index, line) in text.lines().enumerate() {
+            let invalid = || Error::InvalidConfigLine(index + 1, line.to_owned());
+
+            let content = line.split('#').next().unwrap_or_default().trim();
+            if content.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = content.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                section = match name.trim().split_once(' ') {
+                    Some(("class", class)) => {
+                        let class = class.trim().trim_matches('"');
+                        Section::Class(index_of(&mut class_options, class.to_owned()))
+                    }
+                    Some(("user-class", class)) => {
+                        let class = class.trim().trim_matches('"');
+                        Section::UserClass(index_of(&mut user_class_options, class.to_owned()))
+                    }
+                    Some(("host", mac)) => {
+                        let mac = parse(mac.trim()).ok_or_else(invalid)?;
+                        Section::Host(index_of(&mut host_options, mac))
+                    }
+                    Some(_) => return Err(invalid()),
+                    None => match name.trim() {
+                        "options" => Section::Options,
+                        "subnet-options" => Section::SubnetOptions,
+                        "hosts" => Section::Hosts,
+                        "failover" => {
+                            failover.get_or_insert_with(FailoverKeys::default);
+                            Section::Failover
+                        }
+                        "quarantine" => {
+                            quarantine.get_or_insert_with(QuarantineConfig::default);
+                            Section::Quarantine
+                        }
+                        _ => return Err(invalid()),
+                    },
+                };
+                continue;
+            }
+
+            let (key, value) = content.split_once('=').ok_or_else(invalid)?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match section {
+                Section::Global => match key {
+                    "subnet" => subnet = Some(parse(value).ok_or_else(invalid)?),
+                    "mask" => mask = Some(parse(value).ok_or_else(invalid)?),
+                    "range" => ranges.push(parse_range(value).ok_or_else(invalid)?),
+                    "exclude" => exclusions.push(
+                        parse(value)
+                            .map(|ip| (ip, ip))
+                            .or_else(|| parse_range(value))
+                            .ok_or_else(invalid)?,
+                    ),
+                    "authoritative" => authoritative = parse(value).ok_or_else(invalid)?,
+                    "on-exhausted" => {
+                        on_exhausted = match value {
+                            "ignore" => ExhaustedPolicy::Ignore,
+                            "nak" => ExhaustedPolicy::Nak,
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    "allocation" => {
+                        allocation = match value {
+                            "sequential" => Allocation::Sequential,
+                            "random" => Allocation::Random,
+                            "hash" => Allocation::Hash,
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    "max-leases-per-circuit" => {
+                        max_leases_per_circuit =
+                            Some(parse(value).filter(|max| *max > 0).ok_or_else(invalid)?)
+                    }
+                    "offer-delay" => {
+                        load_sharing.delay =
+                            Duration::from_millis(parse(value).ok_or_else(invalid)?)
+                    }
+                    "offer-delay-parity" => {
+                        load_sharing.parity = Some(match value {
+                            "even" => Parity::Even,
+                            "odd" => Parity::Odd,
+                            _ => return Err(invalid()),
+                        })
+                    }
+                    "waiting-secs" => waiting_secs = Some(parse(value).ok_or_else(invalid)?),
+                    "waiting-reserve" => waiting_reserve = parse(value).ok_or_else(invalid)?,
+                    "adaptive-lease-above" => {
+                        adaptive_lease_above = Some(
+                            parse(value)
+                                .filter(|above| *above < 100)
+                                .ok_or_else(invalid)?,
+                        )
+                    }
+                    "adaptive-lease-min" => {
+                        adaptive_lease_min =
+                            Some(parse(value).filter(|min| *min > 0).ok_or_else(invalid)?)
+                    }
+                    "hostname-conflict" => {
+                        hostname_conflict = match value {
+                            "suffix" => HostnameConflict::Suffix,
+                            "mac" => HostnameConflict::Mac,
+                            "refuse" => HostnameConflict::Refuse,
+                            "allow" => HostnameConflict::Allow,
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    "offer-percent" => {
+                        load_sharing.percent = parse(value)
+                            .filter(|percent| (1..=100).contains(percent))
+                            .ok_or_else(invalid)?
+                    }
+                    "parsing" => {
+                        parsing = match value {
+                            "strict" => Parsing::Strict,
+                            "lenient" => Parsing::Lenient,
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    "option-order" => {
+                        option_order = value
+                            .split_whitespace()
+                            .map(parse)
+                            .collect::<Option<Vec<u8>>>()
+                            .filter(|codes| !codes.contains(&DhcpOption::END))
+                            .ok_or_else(invalid)?
+                    }
+                    "server-id" => server_id = Some(parse(value).ok_or_else(invalid)?),
+                    "server-hostname" => {
+                        // sname is 64 bytes including the null terminator
+                        if value.len() >= 64 {
+                            return Err(invalid());
+                        }
+                        server_hostname = Some(value.to_owned());
+                    }
+                    "boot-file-in-header" => {
+                        boot_file_in_header = parse(value).ok_or_else(invalid)?
+                    }
+                    "lease-file" => lease_file = Some(PathBuf::from(value)),
+                    "lease-journal" => lease_journal = parse(value).ok_or_else(invalid)?,
+                    "lease-store" => {
+                        store::open(value).ok_or_else(invalid)?;
+                        lease_store = Some(value.to_owned());
+                    }
+                    "audit-file" => audit_file = Some(PathBuf::from(value)),
+                    "log" => log = LogTarget::parse(value).ok_or_else(invalid)?,
+                    "log-packets" => log_packets.extend(
+                        value
+                            .split_whitespace()
+                            .map(parse)
+                            .collect::<Option<Vec<MacAddr>>>()
+                            .ok_or_else(invalid)?,
+                    ),
+                    "otlp-endpoint" => {
+                        otlp_endpoint = Some(Webhook::parse(value).ok_or_else(invalid)?)
+                    }
+                    "oui-file" => oui_file = Some(PathBuf::from(value)),
+                    "admin-listen" => admin_listen = Some(parse(value).ok_or_else(invalid)?),
+                    "alert-thresholds" => {
+                        alerts.thresholds = value
+                            .split(|c: char| c.is_whitespace() || c == ',')
+                            .filter(|threshold| !threshold.is_empty())
+                            .map(|threshold| parse(threshold.trim_end_matches('%')))
+                            .collect::<Option<Vec<u8>>>()
+                            .filter(|thresholds| thresholds.iter().all(|t| (1..=100).contains(t)))
+                            .ok_or_else(invalid)?
+                    }
+                    "mud-webhook" => mud_webhook = Some(Webhook::parse(value).ok_or_else(invalid)?),
+                    "alert-webhook" => {
+                        alerts.webhook = Some(Webhook::parse(value).ok_or_else(invalid)?)
+                    }
+                    "buffer-size" => {
+                        buffer_size = parse(value)
+                            .filter(|size| *size >= MIN_BUFFER_SIZE)
+                            .ok_or_else(invalid)?
+                    }
+                    "server-port" => {
+                        server_port =
+                            Some(parse(value).filter(|port| *port > 0).ok_or_else(invalid)?)
+                    }
+                    "client-port" => {
+                        client_port =
+                            Some(parse(value).filter(|port| *port > 0).ok_or_else(invalid)?)
+                    }
+                    _ => return Err(invalid()),
+                },
+                // siaddr is in the header rather than an option but layers like one
+                Section::Options if key == "next-server" => {
+                    next_servers.push((BootStage::Any, parse(value).ok_or_else(invalid)?))
+                }
+                Section::Class(index) if key == "next-server" => {
+                    let stage = BootStage::VendorClass(class_options[index].0.clone());
+                    next_servers.push((stage, parse(value).ok_or_else(invalid)?))
+                }
+                Section::UserClass(index) if key == "next-server" => {
+                    let stage = BootStage::UserClass(user_class_options[index].0.clone());
+                    next_servers.push((stage, parse(value).ok_or_else(invalid)?))
+                }
+                Section::Options => add_option(&mut options, key, value).ok_or_else(invalid)?,
+                Section::SubnetOptions => {
+                    add_option(&mut subnet_options, key, value).ok_or_else(invalid)?
+                }
+                Section::Class(index) => {
+                    add_option(&mut class_options[index].1, key, value).ok_or_else(invalid)?
+                }
+                Section::UserClass(index) => {
+                    add_option(&mut user_class_options[index].1, key, value).ok_or_else(invalid)?
+                }
+                Section::Host(index) => {
+                    add_option(&mut host_options[index].1, key, value).ok_or_else(invalid)?
+                }
+                Section::Hosts => {
+                    // A machine by UUID has no host options to keep a lease time
+                    if let Some(uuid) = parse(key) {
+                        uuid_reservations.push((uuid, parse(value).ok_or_else(invalid)?));
+                        continue;
+                    }
+                    // Nor has whoever is behind a relay circuit
+                    if key.starts_with("circuit-id:") || key.starts_with("remote-id:") {
+                        let relay_id = parse(key).ok_or_else(invalid)?;
+                        relay_reservations.push((relay_id, parse(value).ok_or_else(invalid)?));
+                        continue;
+                    }
+                    let mac_address = parse(key).ok_or_else(invalid)?;
+                    // An optional lease time follows the address
+                    let mut fields = value.split_whitespace();
+                    let ip_addr = fields.next().and_then(parse).ok_or_else(invalid)?;
+                    if let Some(lease_time) = fields.next() {
+                        let lease_time = parse_lease_time(lease_time).ok_or_else(invalid)?;
+                        let index = index_of(&mut host_options, mac_address);
+                        host_options[index]
+                            .1
+                            .push(DhcpOption::LeaseTime(lease_time));
+                    }
+                    if fields.next().is_some() {
+                        return Err(invalid());
+                    }
+                    reservations.push((mac_address, ip_addr));
+                }
+                Section::Failover => {
+                    let keys = failover.get_or_insert_with(FailoverKeys::default);
+                    match key {
+                        "role" => {
+                            keys.role = Some(match value {
+                                "primary" => Role::Primary,
+                                "secondary" => Role::Secondary,
+                                _ => return Err(invalid()),
+                            })
+                        }
+                        "listen" => {
+                            keys.listen = Some(parse_socket_addr(value).ok_or_else(invalid)?)
+                        }
+                        "peer" => keys.peer = Some(parse_socket_addr(value).ok_or_else(invalid)?),
+                        "mclt" => keys.mclt = Some(parse(value).ok_or_else(invalid)?),
+                        _ => return Err(invalid()),
+                    }
+                }
+                Section::Quarantine => {
+                    let quarantine = quarantine.get_or_insert_with(QuarantineConfig::default);
+                    match key {
+                        "range" => quarantine
+                            .ranges
+                            .push(parse_range(value).ok_or_else(invalid)?),
+                        "allow" => quarantine.allow.extend(
+                            value
+                                .split_whitespace()
+                                .map(parse)
+                                .collect::<Option<Vec<MacAddr>>>()
+                                .ok_or_else(invalid)?,
+                        ),
+                        _ => add_option(&mut quarantine.options, key, value).ok_or_else(invalid)?,
+                    }
+                }
+            }
+        }
+
+        let failover = match failover {
+            Some(keys) => Some(FailoverConfig {
+                role: keys.role.ok_or(Error::MissingConfigKey("role"))?,
+                listen: keys.listen.unwrap_or_else(|| {
+                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, failover::DEFAULT_PORT))
+                }),
+                peer: keys.peer.ok_or(Error::MissingConfigKey("peer"))?,
+                mclt: keys.mclt.unwrap_or(failover::DEFAULT_MCLT),
+            }),
+            None => None,
+        };
+
+        if ranges.is_empty()
+            || quarantine
+                .as_ref()
+                .is_some_and(|quarantine: &QuarantineConfig| quarantine.ranges.is_empty())
+        {
+            return Err(Error::MissingConfigKey("range"));
+        }
+
+        Ok(Self {
+            subnet: subnet.ok_or(Error::MissingConfigKey("subnet"))?,
+            mask: mask.ok_or(Error::MissingConfigKey("mask"))?,
+            ranges,
+            exclusions,
+            authoritative,
+            on_exhausted,
+            allocation,
+            max_leases_per_circuit,
+            load_sharing,
+            waiting: waiting_secs.map(|secs| Waiting {
+                secs,
+                reserve: waiting_reserve,
+            }),
+            adaptive_lease: match (adaptive_lease_above, adaptive_lease_min) {
+                (Some(above), Some(min)) => Some(AdaptiveLease { above, min }),
+                (None, None) => None,
+                (Some(_), None) => return Err(Error::MissingConfigKey("adaptive-lease-min")),
+                (None, Some(_)) => return Err(Error::MissingConfigKey("adaptive-lease-above")),
+            },
+            hostname_conflict,
+            parsing,
+            option_order,
+            server_id,
+            server_hostname,
+            boot_file_in_header,
+            buffer_size,
+            server_port,
+            client_port,
+            lease_file,
+            lease_journal,
+            lease_store,
+            audit_file,
+            log,
+            otlp_endpoint,
+            log_packets,
+            oui_file,
+            admin_listen,
+            options,
+            subnet_options,
+            class_options,
+            user_class_options,
+            host_options,
+            next_servers,
+            reservations,
+            uuid_reservations,
+            relay_reservations,
+            failover,
+            alerts,
+            mud_webhook,
+            quarantine,
+        })
+    }
+}
+
+/// Where the options for `name` go, a section can be given more than once
+fn index_of<T: PartialEq>(layers: &mut Vec<(T, Vec<DhcpOption>)>, name: T) -> usize {
+    match layers.iter().position(|(existing, _)| *existing == name) {
+        Some(index) => index,
+        None => {
+            layers.push((name, Vec::new()));
+            layers.len() - 1
+        }
+    }
+}
+
+/// `--config` as an `etcd://` URL, when it is one rather than a path
+pub fn etcd_url(path: &Path) -> Option<&str> {
+    path.to_str().filter(|path| path.starts_with("etcd://"))
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+/// An address with an optional port, defaulting to the failover port
+fn parse_socket_addr(value: &str) -> Option<SocketAddr> {
+    parse(value).or_else(|| Some((parse::<Ipv4Addr>(value)?, failover::DEFAULT_PORT).into()))
+}
+
+/// Two addresses, separated by whitespace or a `-`
+fn parse_range(value: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let mut addrs = value
+        .split(|c: char| c.is_whitespace() || c == '-')
+        .filter(|addr| !addr.is_empty());
+    let range = (parse(addrs.next()?)?, parse(addrs.next()?)?);
+    addrs.next().is_none().then_some(range)
+}
+
+/// One or more addresses, separated by whitespace or `,`
+fn parse_addrs(value: &str) -> Option<Vec<[u8; 4]>> {
+    let addrs = value
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| parse::<Ipv4Addr>(addr).map(|addr| addr.octets()))
+        .collect::<Option<Vec<_>>>()?;
+    (!addrs.is_empty()).then_some(addrs)
+}
+
+/// A DNS name whose labels and length fit in a DNS message
+fn parse_domain(value: &str) -> Option<String> {
+    let domain = value.trim_end_matches('.');
+    let valid = domain.len() <= DhcpOption::MAX_DOMAIN_NAME_LEN
+        && domain
+            .split('.')
+            .all(|label| (1..=DhcpOption::MAX_DOMAIN_LABEL_LEN).contains(&label.len()));
+    valid.then(|| domain.to_owned())
+}
+
+/// Seconds, or `infinite` for a lease that never expires
+fn parse_lease_time(value: &str) -> Option<u32> {
+    match value {
+        "infinite" => Some(INFINITE_LEASE_TIME),
+        seconds => parse(seconds),
+    }
+}
+
+/// Add an option to a section, the `pxe-` keys build up one option 43
+fn add_option(options: &mut Vec<DhcpOption>, name: &str, value: &str) -> Option<()> {
+    let Some(name) = name.strip_prefix("pxe-") else {
+        options.push(parse_option(name, value)?);
+        return Some(());
+    };
+    let existing = options
+        .iter()
+        .position(|option| matches!(option, DhcpOption::PxeOptions(_)));
+    let index = existing.unwrap_or_else(|| {
+        options.push(DhcpOption::PxeOptions(PxeOptions::default()));
+        options.len() - 1
+    });
+    let DhcpOption::PxeOptions(pxe) = &mut options[index] else {
+        unreachable!()
+    };
+
+    // Most values start with a boot server type, in decimal or 0x hex
+    let (first, rest) = value.split_once(char::is_whitespace).unwrap_or((value, ""));
+    let server_type = || match first.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => parse(first),
+    };
+    let text = rest.trim().trim_matches('"').to_owned();
+    match name {
+        "discovery-control" => {
+            pxe.set_discovery_control(parse(value)?);
+        }
+        "boot-server" => {
+            pxe.add_boot_server(server_type()?, parse_addrs(rest)?)?;
+        }
+        "menu" => {
+            pxe.add_menu_entry(server_type()?, text)?;
+        }
+        "prompt" => {
+            pxe.set_prompt(parse(first)?, text)?;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// An option by name, or by numeric code with an encoded value
+fn parse_option(name: &str, value: &str) -> Option<DhcpOption> {
+    if let Ok(code) = name.parse::<u8>() {
+        return DhcpOption::from_code_value(code, value).ok();
+    }
+
+    let addr = || parse::<Ipv4Addr>(value).map(|addr| addr.octets());
+    Some(match name {
+        "router" => DhcpOption::Router(addr()?),
+        "domain-name-server" => DhcpOption::DomainNameServer(addr()?),
+        "broadcast-address" => DhcpOption::BroadcastAddress(addr()?),
+        "time-offset" => DhcpOption::TimeOffset(parse(value)?),
+        "time-server" => DhcpOption::TimeServer(parse_addrs(value)?),
+        "interface-mtu" => DhcpOption::InterfaceMtu(
+            parse(value).filter(|mtu| *mtu >= DhcpOption::MIN_INTERFACE_MTU)?,
+        ),
+        "ntp-servers" => DhcpOption::NtpServers(parse_addrs(value)?),
+        "netbios-name-servers" => DhcpOption::NetBiosNameServer(parse_addrs(value)?),
+        "netbios-node-type" => DhcpOption::NetBiosNodeType(match value {
+            "b" | "B" => DhcpOption::NETBIOS_B_NODE,
+            "p" | "P" => DhcpOption::NETBIOS_P_NODE,
+            "m" | "M" => DhcpOption::NETBIOS_M_NODE,
+            "h" | "H" => DhcpOption::NETBIOS_H_NODE,
+            _ => return None,
+        }),
+        "netbios-scope" => DhcpOption::NetBiosScope(value.to_owned()),
+        "host-name" => DhcpOption::HostName(value.to_owned()),
+        "client-fqdn" => DhcpOption::ClientFqdn(ClientFqdn::policy(match value {
+            "server" => ClientFqdn::S,
+            "client" => 0,
+            "none" => ClientFqdn::N,
+            _ => return None,
+        })),
+        "domain-name" => DhcpOption::DomainName(parse_domain(value)?),
+        "domain-search" => {
+            let domains = value
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|domain| !domain.is_empty())
+                .map(parse_domain)
+                .collect::<Option<Vec<_>>>()
+                .filter(|domains| !domains.is_empty())?;
+            // Not every client joins an option split over several instances
+            if DhcpOption::encode_domain_search(&domains).len() > u8::MAX as usize {
+                return None;
+            }
+            DhcpOption::DomainSearch(domains)
+        }
+        "tftp-server-name" => DhcpOption::TftpServerName(value.to_owned()),
+        "boot-file-name" => DhcpOption::BootFileName(value.to_owned()),
+        "root-path" => DhcpOption::RootPath(value.to_owned()),
+        "extensions-path" => DhcpOption::ExtensionsPath(value.to_owned()),
+        "boot-file-size" => DhcpOption::BootFileSize(parse(value)?),
+        "lease-time" => DhcpOption::LeaseTime(parse_lease_time(value)?),
+        "ipv6-only-preferred" => DhcpOption::Ipv6OnlyPreferred(
+            parse(value).filter(|wait| *wait >= DhcpOption::MIN_V6ONLY_WAIT)?,
+        ),
+        // A URI, which always has a scheme
+        "captive-portal" if value.contains(':') => DhcpOption::CaptivePortal(value.to_owned()),
+        // Empty stops clients looking up wpad.<domain>, else a URL
+        "proxy-autodiscovery" if value.is_empty() || value.contains(':') => {
+            DhcpOption::ProxyAutodiscovery(value.to_owned())
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest config we accept
+    const MINIMAL: &str = "\
+subnet = 192.168.1.0
+mask = 255.255.255.0
+range = 192.168.1.10 192.168.1.40
+";
+
+    #[test]
+    fn the_example_config_is_valid() {
+        let config = Config::parse(include_str!("../../dhc3po.conf")).unwrap();
+        assert_eq!(config.subnet, Ipv4Addr::new(192, 168, 1, 0));
+        assert_eq!(
+            config.ranges,
+            [(
+                Ipv4Addr::new(192, 168, 1, 10),
+                Ipv4Addr::new(192, 168, 1, 40)
+            )]
+        );
+        assert!(config
+            .options
+            .contains(&DhcpOption::Router([192, 168, 1, 254])));
+        assert!(config
+            .options
+            .contains(&DhcpOption::DomainName("home".to_owned())));
+        assert!(config.options.contains(&DhcpOption::LeaseTime(32400)));
+    }
+
+    #[test]
+    fn options_are_read_by_name_or_code() {
+        let text = format!(
+            "{MINIMAL}[options]\n\
+             domain-name-server = 1.1.1.1 # after a comment\n\
+             lease-time = infinite\n\
+             150 = ip:10.0.0.1\n"
+        );
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(
+            config.options,
+            [
+                DhcpOption::DomainNameServer([1, 1, 1, 1]),
+                DhcpOption::LeaseTime(INFINITE_LEASE_TIME),
+                DhcpOption::Unknown(150, vec![10, 0, 0, 1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line_or_the_missing_key() {
+        let text = format!("{MINIMAL}\n[options]\nrouter = nowhere\n");
+        assert!(matches!(
+            Config::parse(&text),
+            Err(Error::InvalidConfigLine(6, line)) if line == "router = nowhere"
+        ));
+        assert!(matches!(
+            Config::parse("unknown-key = 1"),
+            Err(Error::InvalidConfigLine(1, _))
+        ));
+        assert!(matches!(
+            Config::parse("subnet = 192.168.1.0\nrange = 192.168.1.10 192.168.1.40"),
+            Err(Error::MissingConfigKey("mask"))
+        ));
+        assert!(matches!(
+            Config::parse("subnet = 192.168.1.0\nmask = 255.255.255.0"),
+            Err(Error::MissingConfigKey("range"))
+        ));
+    }
+    #[test]
+    fn buffers_hold_at_least_what_every_client_may_send() {
+        let buffer_size = |text: &str| Config::parse(&format!("{MINIMAL}{text}")).ok();
+        assert_eq!(
+            buffer_size("").unwrap().buffer_size,
+            DEFAULT_UDP_BUFFER_SIZE
+        );
+        assert_eq!(buffer_size("buffer-size = 9000").unwrap().buffer_size, 9000);
+        assert_eq!(buffer_size("buffer-size = 576").unwrap().buffer_size, 576);
+        assert!(buffer_size("buffer-size = 575").is_none());
+    }
+}
@@ -0,0 +1,84 @@
+//! Running in a container. With `--container` or `DHC3PO_CONTAINER` set our
+//! settings can also come from the environment, see [crate::cli], and we
+//! stop cleanly on SIGTERM. As PID 1 we get no default signal handlers, so
+//! without our own `docker stop` would wait out its timeout and SIGKILL us
+//!
+//! Clients only reach us with host or macvlan networking, or through a relay
+//! for bridged networks. Where broadcasts leave by the wrong interface
+//! `--egress-interface` pins our socket to the right one, and where the
+//! network drops them `--unicast-replies` sends replies to the offered
+//! address instead
+
+use crate::SHUTDOWN;
+use std::os::raw::c_int;
+use std::sync::atomic::Ordering;
+#[cfg(target_os = "linux")]
+use {
+    crate::{Error, Result},
+    std::net::UdpSocket,
+    std::os::fd::AsRawFd,
+    std::os::raw::c_void,
+};
+
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+
+#[cfg(target_os = "linux")]
+const SOL_SOCKET: c_int = 1;
+#[cfg(target_os = "linux")]
+const SO_BINDTODEVICE: c_int = 25;
+/// Interface names are at most 15 bytes and a null
+#[cfg(target_os = "linux")]
+const IFNAMSIZ: usize = 16;
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    #[cfg(target_os = "linux")]
+    fn setsockopt(
+        socket: c_int,
+        level: c_int,
+        name: c_int,
+        value: *const c_void,
+        len: u32,
+    ) -> c_int;
+}
+
+extern "C" fn stop(_signum: c_int) {
+    // Storing to an atomic is all that is safe in a signal handler, the
+    // server loop notices within a poll interval
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+/// Set [SHUTDOWN] on SIGTERM and SIGINT rather than dying, or as PID 1
+/// ignoring them
+pub fn stop_on_signals() {
+    // SAFETY: the handler only stores to an atomic
+    unsafe {
+        signal(SIGTERM, stop);
+        signal(SIGINT, stop);
+    }
+}
+
+/// Only receive on and send out of `interface`, so broadcast replies leave
+/// by it rather than whichever interface has the default route
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(socket: &UdpSocket, interface: &str) -> Result<()> {
+    let invalid = |error| Error::CannotBindToInterface(interface.to_owned(), error);
+    if interface.is_empty() || interface.len() >= IFNAMSIZ {
+        return Err(invalid(std::io::ErrorKind::InvalidInput.into()));
+    }
+    // SAFETY: the name is valid for its length, which the kernel copies
+    let ret = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_BINDTODEVICE,
+            interface.as_ptr().cast(),
+            interface.len() as u32,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(invalid(std::io::Error::last_os_error())),
+    }
+}
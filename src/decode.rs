@@ -0,0 +1,245 @@
+//! `dhc3po decode <HEX|FILE>`, print every field and option of a DHCP packet
+//! and anything about it we would object to when serving
+
+use crate::dhcp::{Dhcp, Parsing};
+use crate::types::{ClientFqdn, DhcpOption, MacAddr, OptionsView, ParameterRequest};
+use crate::{wire, Error, Result};
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::path::Path;
+
+/// Option 52 values saying options continue in file, sname or both
+const OVERLOAD_FILE: u8 = 1;
+const OVERLOAD_SNAME: u8 = 2;
+
+/// Decode a packet given as hex, or the path of a file holding hex or the
+/// raw bytes, and print it
+pub fn run(input: &str) -> Result<()> {
+    let data = match Path::new(input).is_file() {
+        true => {
+            let bytes = std::fs::read(input).map_err(Error::CannotReadCapture)?;
+            std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(parse_hex)
+                .unwrap_or(bytes)
+        }
+        false => parse_hex(input)
+            .ok_or_else(|| Error::InvalidArgumentValue("decode".into(), input.into()))?,
+    };
+    print!("{}", decode(&data)?);
+    Ok(())
+}
+
+/// Bytes as hex digits, optionally separated by whitespace or `:`, lines
+/// starting with `#` are comments
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(str::bytes)
+        .filter(|byte| !byte.is_ascii_whitespace() && *byte != b':')
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// A description of every field, option and problem in `data`
+pub fn decode(data: &[u8]) -> Result<String> {
+    let header = data
+        .get(..wire::MAGIC_START)
+        .ok_or(Error::PayloadTooShort(data.len()))?;
+    let mut text = String::new();
+    let mut warnings = Vec::new();
+
+    let addr = |at: Range<usize>| Ipv4Addr::from(<[u8; 4]>::try_from(&header[at]).unwrap());
+    let (op, htype, hlen) = (header[wire::OP], header[wire::HTYPE], header[wire::HLEN]);
+    let flags = u16::from_be_bytes(header[wire::FLAGS].try_into().unwrap());
+    let chaddr = &header[wire::CHADDR][..(hlen as usize).min(16)];
+    let chaddr = match <[u8; MacAddr::LEN]>::try_from(chaddr) {
+        Ok(mac) => MacAddr::new(mac).to_string(),
+        Err(_) => hex(chaddr),
+    };
+    let field = |text: &mut String, name: &str, value: String| {
+        writeln!(text, "{name:<8}{value}").unwrap();
+    };
+
+    let op_name = match op {
+        1 => "BOOTREQUEST",
+        2 => "BOOTREPLY",
+        _ => "unknown",
+    };
+    let htype_name = match htype {
+        1 => "Ethernet",
+        6 => "IEEE 802",
+        32 => "InfiniBand",
+        _ => "unknown",
+    };
+    field(&mut text, "op", format!("{op} ({op_name})"));
+    field(&mut text, "htype", format!("{htype} ({htype_name})"));
+    field(&mut text, "hlen", hlen.to_string());
+    field(&mut text, "hops", header[wire::HOPS].to_string());
+    let xid = u32::from_be_bytes(header[wire::XID].try_into().unwrap());
+    field(&mut text, "xid", format!("0x{xid:08x}"));
+    field(
+        &mut text,
+        "secs",
+        u16::from_be_bytes(header[wire::SECS].try_into().unwrap()).to_string(),
+    );
+    let broadcast = match flags & 0x8000 != 0 {
+        true => " (broadcast)",
+        false => "",
+    };
+    field(&mut text, "flags", format!("0x{flags:04x}{broadcast}"));
+    field(&mut text, "ciaddr", addr(wire::CIADDR).to_string());
+    field(&mut text, "yiaddr", addr(wire::YIADDR).to_string());
+    field(&mut text, "siaddr", addr(wire::SIADDR).to_string());
+    field(&mut text, "giaddr", addr(wire::GIADDR).to_string());
+    field(&mut text, "chaddr", chaddr);
+    field(
+        &mut text,
+        "sname",
+        format!(
+            "{:?}",
+            String::from_utf8_lossy(wire::sname(header).unwrap())
+        ),
+    );
+    field(
+        &mut text,
+        "file",
+        format!("{:?}", String::from_utf8_lossy(wire::file(header).unwrap())),
+    );
+
+    if data.get(wire::MAGIC_START..wire::OPTIONS_START) != Some(&wire::MAGIC[..]) {
+        warnings.push("no DHCP magic cookie, this is plain BOOTP or not DHCP at all".to_owned());
+    } else {
+        let mut options = split_options(&data[wire::OPTIONS_START..], &mut warnings);
+        // Options carry on into file then sname when option 52 says so
+        let overload = options
+            .iter()
+            .find(|(code, _)| *code == DhcpOption::OPTION_OVERLOAD)
+            .and_then(|(_, value)| value.first().copied())
+            .unwrap_or_default();
+        if overload & OVERLOAD_FILE != 0 {
+            options.extend(split_options(&header[wire::FILE_START..], &mut warnings));
+        }
+        if overload & OVERLOAD_SNAME != 0 {
+            options.extend(split_options(
+                &header[wire::SNAME_START..wire::FILE_START],
+                &mut warnings,
+            ));
+        }
+
+        writeln!(text, "options").unwrap();
+        for (code, value) in options {
+            let option = DhcpOption::parse(code, &value).unwrap_or_else(|error| {
+                warnings.push(format!("option {code} is invalid, {error}"));
+                DhcpOption::Unknown(code, value.into_owned())
+            });
+            writeln!(text, "  {code:>3} {}", describe(&option)).unwrap();
+        }
+    }
+
+    // Only requests go through our parser, replies are ours to build
+    if op == 1 {
+        if let Err(error) = Dhcp::parse(data) {
+            let lenient = match Dhcp::parse_with(data, Parsing::Lenient) {
+                Ok(_) => ", unless parsing is lenient",
+                Err(_) => "",
+            };
+            warnings.push(format!("we would drop this request{lenient}, {error}"));
+        }
+    }
+    if !warnings.is_empty() {
+        writeln!(text, "warnings").unwrap();
+        for warning in warnings {
+            writeln!(text, "  {warning}").unwrap();
+        }
+    }
+
+    Ok(text)
+}
+
+/// Like the parser but keeps what it can of truncated options and
+/// notes the truncation in `warnings`
+fn split_options<'a>(area: &'a [u8], warnings: &mut Vec<String>) -> Vec<(u8, Cow<'a, [u8]>)> {
+    let options = OptionsView::over(area);
+    if let Some(code) = options.truncated() {
+        warnings.push(format!("option {code} runs past the end of the packet"));
+    }
+    options.joined()
+}
+
+/// An option's name and value, in a form people can read
+fn describe(option: &DhcpOption) -> String {
+    let ip_addr = |addr: &[u8; 4]| Ipv4Addr::from(*addr).to_string();
+    match option {
+        DhcpOption::SubnetMask(addr) => format!("SubnetMask {}", ip_addr(addr)),
+        DhcpOption::Router(addr) => format!("Router {}", ip_addr(addr)),
+        DhcpOption::DomainNameServer(addr) => format!("DomainNameServer {}", ip_addr(addr)),
+        DhcpOption::BroadcastAddress(addr) => format!("BroadcastAddress {}", ip_addr(addr)),
+        DhcpOption::RequestedIpAddr(addr) => format!("RequestedIpAddr {}", ip_addr(addr)),
+        DhcpOption::DhcpServerIpAddr(addr) => format!("DhcpServerIpAddr {}", ip_addr(addr)),
+        DhcpOption::SubnetSelection(addr) => format!("SubnetSelection {}", ip_addr(addr)),
+        DhcpOption::VendorClassIndentifier(class) => {
+            format!(
+                "VendorClassIndentifier {:?}",
+                String::from_utf8_lossy(class)
+            )
+        }
+        DhcpOption::ClientIdentifier(id) => format!("ClientIdentifier {}", hex(id.as_bytes())),
+        DhcpOption::ClientUid(uid) => format!("ClientUid {}", hex(uid)),
+        DhcpOption::UserClass(class) => {
+            let classes: Vec<String> = class
+                .classes()
+                .iter()
+                .map(|class| format!("{:?}", String::from_utf8_lossy(class)))
+                .collect();
+            format!("UserClass {}", classes.join(", "))
+        }
+        DhcpOption::ClientFqdn(fqdn) => {
+            let flags: String = [
+                ('S', ClientFqdn::S),
+                ('O', ClientFqdn::O),
+                ('E', ClientFqdn::E),
+                ('N', ClientFqdn::N),
+            ]
+            .into_iter()
+            .filter(|(_, flag)| fqdn.has(*flag))
+            .map(|(name, _)| name)
+            .collect();
+            match fqdn.name() {
+                Some(name) => format!("ClientFqdn {name:?} flags {flags:?}"),
+                None => format!("ClientFqdn {} flags {flags:?}", hex(&fqdn.to_bytes()[3..])),
+            }
+        }
+        DhcpOption::RelayAgentInformation(info) => {
+            let sub_options: Vec<String> = info
+                .iter()
+                .map(|(code, value)| format!("{code}: {}", hex(value)))
+                .collect();
+            format!("RelayAgentInformation {}", sub_options.join(", "))
+        }
+        DhcpOption::Unknown(code, value) => format!("{} {}", option_name(*code), hex(value)),
+        option => format!("{option:?}"),
+    }
+}
+
+/// Name an option we do not decode, from the parameters clients request
+fn option_name(code: u8) -> String {
+    match ParameterRequest::from(code) {
+        ParameterRequest::Other(_) => "Unknown".to_owned(),
+        param => format!("{param:?}"),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    bytes.join(" ")
+}
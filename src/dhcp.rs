@@ -1,18 +1,69 @@
 //! In this file we manage the DHCP specific data types and parsing
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 
+use crate::state::{ClientClass, ClientState, ExhaustedPolicy, LeaseState};
 use crate::types::{
-    ClientIdentifier, DhcpOption, DhcpOptionList, MacAddr, MessageType, ParameterRequest,
+    ClientFqdn, ClientIdentifier, ClientKey, DhcpOption, DhcpOptionList, MacAddr, MessageType,
+    OptionsView, ParameterRequest, RelayAgentInfo, Uuid,
 };
-use crate::UDP_BUFFER_SIZE;
+use crate::{events, mud, oui, telemetry, wire};
 use crate::{AddrPool, Error, Result};
+use std::borrow::Cow;
+use std::net::Ipv4Addr;
+use std::ops::Range;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The largest DHCP message we will send, a 1500 byte ethernet frame minus
+/// the IP and UDP headers
+pub const MAX_MESSAGE_LEN: usize = 1472;
+
+/// Where a reply has to go, see RFC 2131 section 4.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// The relay agent at giaddr, on the server port
+    Relay(Ipv4Addr),
+
+    /// The client at an address it already holds
+    Client(Ipv4Addr),
+
+    /// The client at the address we are giving it, which it cannot answer
+    /// ARP for yet, so we say which MAC to send to
+    Unbound(Ipv4Addr, MacAddr),
+
+    /// Everyone on our link, for clients without a usable address
+    Broadcast,
+}
+
+/// A reply [Dhcp::handle] built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    /// How much of the buffer it fills
+    pub len: usize,
+    pub destination: Destination,
+    /// Hold it back this long before sending, see
+    /// [crate::state::LoadSharing]
+    pub delay: Option<Duration>,
+}
+
+/// How forgiving [Dhcp::parse_with] is of clients that break the spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parsing {
+    /// Drop any packet with an option we cannot decode
+    #[default]
+    Strict,
+
+    /// Cut overlong options down to size, skip the ones we still cannot
+    /// decode and keep whatever options came before a truncation, logging
+    /// each repair
+    Lenient,
+}
 
 /// A [Dhcp] represents a DHCP packet
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
-pub struct Dhcp<'dhcp> {
+pub struct Dhcp {
     /// op - Operate Code of the message
     op_code: u8,
 
@@ -35,11 +86,12 @@ pub struct Dhcp<'dhcp> {
     /// flags
     flags: [u8; 2],
 
-    /// ciaddr - Address of requestee
+    /// ciaddr - The address the client already holds, when renewing,
+    /// rebinding, releasing or informing
     client_addr: [u8; 4],
 
-    /// yiaddr - The DHCP server address
-    server_addr: [u8; 4],
+    /// yiaddr - The address the server gives the client
+    your_addr: [u8; 4],
 
     /// siaddr - The next server to ask about future steps
     next_server_addr: [u8; 4],
@@ -47,36 +99,55 @@ pub struct Dhcp<'dhcp> {
     /// giaddr - Relay agent IP address, used in booting via relay agent.
     relay_addr: [u8; 4],
 
-    /// chaddr - Client hardware address (We will only handle MAC)
-    client_hw_addr: [u8; 6],
+    /// chaddr - Client hardware address, the first `hlen` bytes are used
+    client_hw_addr: [u8; 16],
 
-    // sname - Optional server host name, null terminated string.
-    server_hostname: [u8; 64],
+    // sname - Optional server host name, null terminated string. We never
+    // read a request's, so only replies we fill it in for carry one
+    server_hostname: String,
 
     /// file - Boot file name, null terminated string; "generic" name or null
     /// in DHCPDISCOVER, fully qualified directory-path name in DHCPOFFER.
-    file: [u8; 128],
+    /// Like sname only set in replies
+    file: String,
 
     /// options - The variable length data after the magic
-    options: DhcpOptionList<'dhcp>,
+    options: DhcpOptionList,
 
     /// Required option that makes sense to store top level
     message_type: MessageType,
 }
 
-impl<'dhcp> Dhcp<'dhcp> {
-    /// The "magic" of a DHCP Payload
-    const MAGIC: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+impl Dhcp {
     const MINIMUM_PAYLOAD_LENGTH: usize = 240;
-    const OPTIONS_START: usize = 240;
-    const OPTION_LEN_OFFSET: usize = 1;
     const REQUEST_OP_CODE: u8 = 1;
     const REPLY_OP_CODE: u8 = 2;
     const HW_TYPE_ETHERNET: u8 = 1;
-    const HW_ADDRESS_LEN: u8 = 6;
+    /// Token ring and other IEEE 802 networks, their addresses are MACs too
+    const HW_TYPE_IEEE_802: u8 = 6;
+    /// chaddr is 16 bytes, Infiniband's 20 byte addresses do not fit so it
+    /// sends a zero hlen and identifies itself with option 61 (RFC 4390)
+    const MAX_HW_ADDRESS_LEN: u8 = 16;
+    /// Clients that do not send option 57 must accept a 576 byte datagram
+    const DEFAULT_MAX_MESSAGE_SIZE: u16 = 576;
+    const IP_UDP_HEADER_LEN: usize = 28;
+    const OVERLOAD_LEN: usize = 3;
+    /// Relay agents discard requests relayed more often than this, we do the
+    /// same for any that get through (RFC 1542 4.1.1)
+    const MAX_HOPS: u8 = 16;
+    /// The top bit of flags asks for replies to be broadcast
+    const BROADCAST_FLAG: [u8; 2] = [0x80, 0x00];
+    /// Option 60 of PXE clients and of the servers answering them
+    const PXE_CLIENT_CLASS: &[u8] = b"PXEClient";
 
     /// Convert &[u8] from a UDP Packet into a more rust friendly Dhcp struct
     pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_with(data, Parsing::Strict)
+    }
+
+    /// [Dhcp::parse], forgiving bad options when `parsing` is lenient. The
+    /// header still has to be valid
+    pub fn parse_with(data: &[u8], parsing: Parsing) -> Result<Self> {
         let data_len = data.len();
 
         if data_len < Self::MINIMUM_PAYLOAD_LENGTH {
@@ -88,457 +159,1804 @@ impl<'dhcp> Dhcp<'dhcp> {
             return Err(Error::NotADhcpRequest(dhcp_op_code));
         }
 
-        if data[236..240] != Self::MAGIC {
-            return Err(Error::DhcpMagicMissing);
+        let header = wire::Header::read(data)?;
+        if header.hops > Self::MAX_HOPS {
+            return Err(Error::TooManyHops(header.hops));
         }
 
         let mut message_type = MessageType::Unset;
-        let mut option_ptr = Self::OPTIONS_START;
         let mut options = DhcpOptionList::builder();
-        loop {
-            // The options pointer is out of bounds so we are done
-            if option_ptr >= data_len {
-                break;
+        for (code, value) in Self::split_options(&data[wire::OPTIONS_START..], parsing)? {
+            let option = match parsing {
+                Parsing::Strict => DhcpOption::parse(code, &value)?,
+                Parsing::Lenient => match Self::repair_option(code, value, data) {
+                    Some(option) => option,
+                    None => continue,
+                },
+            };
+            match option {
+                DhcpOption::MessageType(msg_type) => message_type = msg_type,
+                option => _ = options.add(option),
             }
+        }
 
-            // We will store the option length so we can increment
-            let mut option_len = 0;
+        if message_type == MessageType::Unset {
+            return Err(Error::NoMessageDhcpTypeProvided);
+        }
 
-            let option_opcode: u8 = data[option_ptr];
+        let (hw_addr_ty, hw_addr_len) = (header.htype, header.hlen);
+        let is_mac = matches!(hw_addr_ty, Self::HW_TYPE_ETHERNET | Self::HW_TYPE_IEEE_802);
+        if hw_addr_len > Self::MAX_HW_ADDRESS_LEN || (is_mac && hw_addr_len != MacAddr::LEN as u8) {
+            return Err(Error::InvalidHwAddrLen(hw_addr_ty, hw_addr_len));
+        }
+        if hw_addr_len == 0 && options.get(DhcpOption::CLIENT_ID).is_none() {
+            return Err(Error::MissingClientIdentifier(hw_addr_ty));
+        }
 
-            // Add the option to our array of options if we found one and
-            // increment the counter
-            match option_opcode {
-                DhcpOption::PAD => _ = options.add(DhcpOption::Pad),
-                DhcpOption::MESSAGE_TYPE => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+        Ok(Self {
+            op_code: header.op,
+            hw_addr_ty,
+            hw_addr_len,
+            hops: header.hops,
+            transaction_id: header.xid,
+            secs: header.secs,
+            flags: header.flags,
+            client_addr: header.ciaddr,
+            your_addr: header.yiaddr,
+            next_server_addr: header.siaddr,
+            relay_addr: header.giaddr,
+            client_hw_addr: header.chaddr,
+            server_hostname: String::new(),
+            file: String::new(),
+            options,
+            message_type,
+        })
+    }
 
-                    if option_len != DhcpOption::MESSAGE_TYPE_LEN {
-                        return Err(Error::MessageTypeBadLen(option_len));
-                    }
+    /// The key we bind the client's lease to, their client identifier if they
+    /// sent one, falling back to chaddr
+    fn client_key(&self) -> ClientKey {
+        let client_id = match self.options.get(DhcpOption::CLIENT_ID) {
+            Some(DhcpOption::ClientIdentifier(client_id)) => Some(client_id.clone()),
+            // Without option 61 a client is identified by htype and chaddr
+            // (RFC 2132), which for anything but a MAC is all we have
+            _ if self.hw_addr_ty != Self::HW_TYPE_ETHERNET => {
+                let mut id = vec![self.hw_addr_ty];
+                id.extend_from_slice(&self.client_hw_addr[..self.hw_addr_len as usize]);
+                ClientIdentifier::try_from(&id[..]).ok()
+            }
+            _ => None,
+        };
+        ClientKey::new(client_id, self.client_mac())
+    }
 
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+    /// The subnet to choose a scope from, option 118 beats the relay's
+    /// link-selection sub-option which beats giaddr (RFC 3011, RFC 3527).
+    /// [None] means the client is on our own link
+    fn link_selection(&self) -> Option<Ipv4Addr> {
+        if let Some(DhcpOption::SubnetSelection(subnet)) =
+            self.options.get(DhcpOption::SUBNET_SELECTION)
+        {
+            return Some((*subnet).into());
+        }
+        let relay_addr =
+            Some(Ipv4Addr::from(self.relay_addr)).filter(|addr| !addr.is_unspecified());
+        // Only a relay may say which link the client is on
+        let link = self
+            .relay_agent_info()
+            .and_then(RelayAgentInfo::link_selection);
+        relay_addr.and(link).or(relay_addr)
+    }
 
-                    if let Some(msg_type) = data.get(option_ptr) {
-                        message_type = (*msg_type).try_into()?;
-                    };
+    /// chaddr as a MAC, zero padded or cut short when it is something else
+    pub fn client_mac(&self) -> MacAddr {
+        let mut mac = [0u8; MacAddr::LEN];
+        let len = (self.hw_addr_len as usize).min(MacAddr::LEN);
+        mac[..len].copy_from_slice(&self.client_hw_addr[..len]);
+        mac.into()
+    }
+
+    /// Split the options area into (code, value) pairs. An option longer than
+    /// 255 bytes is split across several instances which we join back
+    /// together in order, as described in RFC 3396. Only those are copied,
+    /// every other value borrows from `data`
+    fn split_options(data: &[u8], parsing: Parsing) -> Result<Vec<(u8, Cow<'_, [u8]>)>> {
+        let options = OptionsView::over(data);
+        if let Some(code) = options.truncated() {
+            match parsing {
+                Parsing::Strict => return Err(Error::DhcpOptionLenOutOfBounds),
+                Parsing::Lenient => {
+                    warn!("Option {code} runs past the end of the packet, ignoring the rest")
                 }
-                DhcpOption::REQUESTED_IP_ADDR => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+            }
+        }
+        Ok(options.joined())
+    }
 
-                    if option_len != DhcpOption::IP_ADDR_LEN {
-                        return Err(Error::InvalidIpAddrLen(option_len));
-                    }
+    /// Decode an option for [Parsing::Lenient], cutting overlong values down
+    /// to what we accept and giving up on the option if that is not enough
+    fn repair_option(code: u8, mut value: Cow<[u8]>, data: &[u8]) -> Option<DhcpOption> {
+        let max_len = match code {
+            DhcpOption::MESSAGE_TYPE => DhcpOption::MESSAGE_TYPE_LEN,
+            DhcpOption::PARAMETER_REQUEST_LIST => DhcpOption::MAX_PARAMETER_REQUEST_LIST_LEN,
+            _ => u8::MAX,
+        } as usize;
+        if value.len() > max_len {
+            warn!(
+                "Cutting option {code} from {} to {max_len} bytes",
+                value.len()
+            );
+            value.to_mut().truncate(max_len);
+        }
 
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+        // Some clients send their MAC as option 61 without the hardware type
+        // in front, key them the same as clients that get it right
+        let (hw_addr_ty, hw_addr_len) = (data[1], data[2] as usize);
+        if code == DhcpOption::CLIENT_ID
+            && hw_addr_len == MacAddr::LEN
+            && value[..] == data[28..28 + MacAddr::LEN]
+        {
+            warn!("Adding hardware type {hw_addr_ty} to a client identifier without one");
+            value.to_mut().insert(0, hw_addr_ty);
+        }
 
-                    let ip_addr_bytes =
-                        data.get(option_ptr..option_ptr + DhcpOption::IP_ADDR_LEN as usize);
+        DhcpOption::parse(code, &value)
+            .inspect_err(|error| warn!("Skipping option {code}, {error}"))
+            .ok()
+    }
 
-                    if let Some(ip_addr_bytes) = ip_addr_bytes {
-                        // We can unwap safetly here because we check above
-                        let ip_addr = <[u8; 4]>::try_from(ip_addr_bytes).unwrap();
-                        options.add(DhcpOption::RequestedIpAddr(ip_addr));
-                    }
-                }
-                DhcpOption::DHCP_SERVER_IP_ADDR => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+    /// Construct a new Dhcp response given a request
+    fn build_response(&self) -> Self {
+        let mut options = DhcpOptionList::builder();
+        // A client that asked for a subnet gets the option back (RFC 3011)
+        if let Some(selection) = self.options.get(DhcpOption::SUBNET_SELECTION) {
+            options.add(selection.clone());
+        }
+        // Relays strip option 82 from our replies and drop those without it,
+        // set_options puts it last. Only a relay adds it, so we only echo it
+        // to one (RFC 3046 2.2)
+        if let Some(info) = self
+            .options
+            .get(DhcpOption::RELAY_AGENT_INFO)
+            .filter(|_| self.relay_addr != [0, 0, 0, 0])
+        {
+            options.add(info.clone());
+        }
 
-                    if option_len != DhcpOption::IP_ADDR_LEN {
-                        return Err(Error::InvalidIpAddrLen(option_len));
-                    }
+        Self {
+            op_code: Self::REPLY_OP_CODE,
+            hw_addr_ty: self.hw_addr_ty,
+            hw_addr_len: self.hw_addr_len,
+            // Servers always send zero hops and secs (RFC 2131 table 3)
+            hops: 0,
+            transaction_id: self.transaction_id,
+            secs: [0, 0],
+            // The relay needs both to get the reply back to the client
+            flags: self.flags,
+            // Only an ACK echoes ciaddr, see Dhcp::ack
+            client_addr: [0, 0, 0, 0],
+            your_addr: [0, 0, 0, 0],
+            next_server_addr: [0, 0, 0, 0],
+            relay_addr: self.relay_addr,
+            client_hw_addr: self.client_hw_addr,
+            // Filled by insert_boot_fields when configured
+            server_hostname: String::new(),
+            file: String::new(),
+            options,
+            message_type: MessageType::Unset,
+        }
+    }
 
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+    /// Options 60 and 77, what we match `[class]` and `[user-class]`
+    /// sections against
+    fn class(&self) -> ClientClass<'_> {
+        let vendor = match self.options.get(DhcpOption::VENDOR_CLASS_ID) {
+            Some(DhcpOption::VendorClassIndentifier(class)) => Some(&class[..]),
+            _ => None,
+        };
+        let user = match self.options.get(DhcpOption::USER_CLASS) {
+            Some(DhcpOption::UserClass(class)) => Some(class),
+            _ => None,
+        };
+        ClientClass { vendor, user }
+    }
 
-                    let ip_addr_bytes =
-                        data.get(option_ptr..option_ptr + DhcpOption::IP_ADDR_LEN as usize);
+    /// The machine's SMBIOS UUID from option 97, sent by PXE clients
+    fn client_uuid(&self) -> Option<Uuid> {
+        match self.options.get(DhcpOption::CLIENT_UID) {
+            Some(DhcpOption::ClientUid(uid)) => Uuid::from_client_uid(uid),
+            _ => None,
+        }
+    }
 
-                    if let Some(ip_addr_bytes) = ip_addr_bytes {
-                        // We can unwap safetly here because we check above
-                        let ip_addr = <[u8; 4]>::try_from(ip_addr_bytes).unwrap();
-                        options.add(DhcpOption::DhcpServerIpAddr(ip_addr));
-                    }
+    /// Option 82, added by the relay agent that forwarded the request
+    fn relay_agent_info(&self) -> Option<&RelayAgentInfo> {
+        match self.options.get(DhcpOption::RELAY_AGENT_INFO) {
+            Some(DhcpOption::RelayAgentInformation(info)) => Some(info),
+            _ => None,
+        }
+    }
+
+    /// Move the reservations of the machine's UUID or the relay circuit the
+    /// client is on to this client, only a relay may say which circuit
+    fn bind_reservations(&self, pool: &mut AddrPool) {
+        let client_mac = self.client_mac();
+        if let Some(uuid) = self.client_uuid() {
+            pool.bind_uuid(&uuid, &client_mac);
+        }
+        if let Some(info) = self
+            .relay_agent_info()
+            .filter(|_| self.relay_addr != [0; 4])
+        {
+            pool.bind_relay_agent_info(info, &client_mac);
+        }
+    }
+
+    fn insert_requested_options(&self, pool: &MutexGuard<AddrPool>, res: &mut Self) {
+        let options = pool.options_for(&self.client_mac(), self.class());
+        if let Some(DhcpOption::ParameterRequestList(params)) =
+            self.options.get(DhcpOption::PARAMETER_REQUEST_LIST)
+        {
+            for param in params {
+                match self.requested_option(pool, &options, *param) {
+                    Some(option) => _ = res.options.set(option),
+                    None => warn!("Did not include option: {param:?}"),
                 }
-                DhcpOption::MAX_MESSAGE_SIZE => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+            }
+        }
 
-                    if option_len != DhcpOption::MAX_MESSAGE_SIZE_LEN {
-                        return Err(Error::MaxMessageSizeBadLen(option_len));
+        // PXE firmware only reads a boot menu from replies that say PXEClient
+        if let Some(DhcpOption::PxeOptions(_)) = res.options.get(DhcpOption::VENDOR_SPECIFIC_INFO) {
+            res.options.set(DhcpOption::VendorClassIndentifier(
+                Self::PXE_CLIENT_CLASS.to_vec(),
+            ));
+        }
+    }
+
+    /// What we answer `param` with from the client's `options`. Most are
+    /// kept under the code asked for, the rest are listed here
+    fn requested_option(
+        &self,
+        pool: &MutexGuard<AddrPool>,
+        options: &DhcpOptionList,
+        param: ParameterRequest,
+    ) -> Option<DhcpOption> {
+        match param {
+            // Failover and adaptive leases shorten the configured time
+            ParameterRequest::IpAddressLease => {
+                let lease_time = pool.lease_time(&self.client_mac(), self.class());
+                Some(DhcpOption::LeaseTime(lease_time))
+            }
+            // Windows before Vista asks for the routes of option 121 under
+            // Microsoft's code, in the same format
+            ParameterRequest::ClasslessStaticRouteMicrosoft => match options.get(param.code()) {
+                Some(option) => Some(option.clone()),
+                None => match options.get(DhcpOption::CLASSLESS_STATIC_ROUTE)? {
+                    DhcpOption::Unknown(_, routes) => {
+                        Some(DhcpOption::Unknown(param.code(), routes.clone()))
                     }
+                    _ => None,
+                },
+            },
+            param => options.get(param.code()).cloned(),
+        }
+    }
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+    /// Whether option `code` is in the client's parameter request list
+    fn requested(&self, code: u8) -> bool {
+        match self.options.get(DhcpOption::PARAMETER_REQUEST_LIST) {
+            Some(DhcpOption::ParameterRequestList(params)) => {
+                params.iter().any(|param| param.code() == code)
+            }
+            _ => false,
+        }
+    }
+
+    /// Our answer to option 81 under the policy of the client's scope and
+    /// classes, [None] if it did not send one or we have no policy
+    fn client_fqdn(&self, pool: &MutexGuard<AddrPool>) -> Option<ClientFqdn> {
+        let Some(DhcpOption::ClientFqdn(fqdn)) = self.options.get(DhcpOption::CLIENT_FQDN) else {
+            return None;
+        };
+        match pool
+            .options_for(&self.client_mac(), self.class())
+            .get(DhcpOption::CLIENT_FQDN)
+        {
+            Some(DhcpOption::ClientFqdn(policy)) => Some(fqdn.answer(policy)),
+            _ => None,
+        }
+    }
 
-                    let max_msg_size = data
-                        .get(option_ptr..option_ptr + DhcpOption::MAX_MESSAGE_SIZE_LEN as usize);
+    /// Clients that send option 81 wait for ours to know who updates DNS
+    fn insert_client_fqdn(&self, pool: &MutexGuard<AddrPool>, res: &mut Self) {
+        if let Some(answer) = self.client_fqdn(pool) {
+            res.options.set(DhcpOption::ClientFqdn(answer));
+        }
+    }
 
-                    if let Some(max_msg_size) = max_msg_size {
-                        let max_msg_size = u16::from_be_bytes(max_msg_size.try_into().unwrap());
-                        options.add(DhcpOption::MaxMessageSize(max_msg_size));
-                    }
-                }
-                DhcpOption::PARAMETER_REQUEST_LIST => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+    fn insert_server_addr(&self, pool: &MutexGuard<AddrPool>, res: &mut Self) {
+        if let Some(DhcpOption::DhcpServerIpAddr(addr)) =
+            pool.options().get(DhcpOption::DHCP_SERVER_IP_ADDR)
+        {
+            res.next_server_addr = *addr;
+            res.options.set(DhcpOption::DhcpServerIpAddr(*addr));
+        }
+    }
 
-                    if option_len < DhcpOption::MIN_PARAMETER_REQUEST_LEN {
-                        return Err(Error::InvalidParameterRequestLen(option_len));
-                    }
-                    if option_len > DhcpOption::MAX_PARAMETER_REQUEST_LIST_LEN {
-                        return Err(Error::InvalidParameterRequestLen(option_len));
-                    }
+    /// siaddr, sname and file, for firmware that looks there rather than at
+    /// options
+    fn insert_boot_fields(&self, pool: &MutexGuard<AddrPool>, res: &mut Self) {
+        if let Some(next_server) = pool.next_server(self.class()) {
+            res.next_server_addr = next_server.octets();
+        }
+        if let Some(hostname) = pool.server_hostname() {
+            if hostname.len() < wire::FILE_START - wire::SNAME_START {
+                res.server_hostname = hostname.to_owned();
+            }
+        }
+        if !pool.boot_file_in_header() {
+            return;
+        }
+        let options = pool.options_for(&self.client_mac(), self.class());
+        if let Some(DhcpOption::BootFileName(file)) = options.get(DhcpOption::BOOT_FILE_NAME) {
+            match file.len() < wire::MAGIC_START - wire::FILE_START {
+                true => res.file = file.clone(),
+                false => warn!(
+                    "Boot file {file} is too long for the file field, sending it as an option only"
+                ),
+            }
+        }
+    }
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+    /// What every OFFER and ACK carries whether the client asked for it or
+    /// not, picky clients drop replies without a subnet mask, lease time or
+    /// server identifier
+    fn insert_mandatory_options(&self, pool: &MutexGuard<AddrPool>, res: &mut Self) {
+        let (client_mac, class) = (self.client_mac(), self.class());
+        let options = pool.options_for(&client_mac, class);
+        if let Some(mask) = options.get(DhcpOption::SUBNET_MASK) {
+            res.options.set(mask.clone());
+        }
+        // Ask the pool as failover may shorten the configured time
+        let lease_time = pool.lease_time(&client_mac, class);
+        res.options.set(DhcpOption::LeaseTime(lease_time));
+        self.insert_server_addr(pool, res);
+    }
 
-                    let list = data.get(option_ptr..option_ptr + option_len as usize);
+    /// Handler for a DHCP Discover, `waiting` when the client has been trying
+    /// long enough to have the addresses we hold back
+    fn offer(&self, pool: Arc<Mutex<AddrPool>>, waiting: bool) -> Option<Self> {
+        let mut res = self.build_response();
+        let requested_ip = match self.options.get(DhcpOption::REQUESTED_IP_ADDR) {
+            Some(DhcpOption::RequestedIpAddr(ip)) => Some((*ip).into()),
+            _ => None,
+        };
+        let mut pool = pool.lock().unwrap();
 
-                    if let Some(list) = list {
-                        let mut req_params = [None; DhcpOptionList::MAX_LEN as usize];
+        let client_mac = self.client_mac();
+        self.bind_reservations(&mut pool);
+        // Clients that can live without IPv4 get no address (RFC 8925 3.3)
+        let options = pool.options_for(&client_mac, self.class());
+        if let Some(v6_only) = options.get(DhcpOption::IPV6_ONLY_PREFERRED) {
+            if self.requested(DhcpOption::IPV6_ONLY_PREFERRED) {
+                info!("{client_mac} prefers IPv6 only, offering no address");
+                res.options.set(v6_only.clone());
+                self.insert_server_addr(&pool, &mut res);
+                res.options
+                    .add(DhcpOption::MessageType(MessageType::Offer))
+                    .add(DhcpOption::End);
+                return Some(res);
+            }
+        }
 
-                        for (index, param) in list.iter().enumerate() {
-                            let req_param = (*param).into();
-                            req_params[index] = Some(req_param);
-                        }
-                        options.add(DhcpOption::ParameterRequestList(req_params));
-                    }
+        if !waiting && pool.is_held_back(&self.client_key(), &client_mac) {
+            info!(
+                "Not offering {client_mac} an address yet, the last few are for clients \
+                 that have been waiting"
+            );
+            return None;
+        }
+        let circuit_id = self.relay_agent_info().and_then(RelayAgentInfo::circuit_id);
+        res.your_addr = match pool.offer(&self.client_key(), &client_mac, requested_ip, circuit_id)
+        {
+            Ok(addr) => addr.octets(),
+            Err(error @ Error::CircuitLeaseLimit(_)) => {
+                info!("Not offering {client_mac} an address, {error}");
+                return None;
+            }
+            Err(_) => match pool.on_exhausted() {
+                ExhaustedPolicy::Ignore => return None,
+                ExhaustedPolicy::Nak => {
+                    // Not in RFC 2131, most clients will ignore it
+                    info!("Sending {client_mac} a NAK for its DISCOVER, we have no free addresses");
+                    self.nack(&mut res, pool, "no free addresses");
+                    return Some(res);
                 }
-                DhcpOption::VENDOR_CLASS_ID => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+            },
+        };
 
-                    if option_len > DhcpOption::MAX_VENDOR_CLASS_ID_LEN {
-                        return Err(Error::InvalidVendorClassIdentifierLen(option_len));
-                    }
+        self.insert_requested_options(&pool, &mut res);
+        self.insert_client_fqdn(&pool, &mut res);
+        self.insert_mandatory_options(&pool, &mut res);
+        self.insert_boot_fields(&pool, &mut res);
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+        drop(pool);
 
-                    let option_raw = data.get(option_ptr..option_ptr + option_len as usize);
+        // Specific Offer Options
+        res.options
+            .add(DhcpOption::MessageType(MessageType::Offer))
+            .add(DhcpOption::End);
+        Some(res)
+    }
 
-                    if let Some(option_raw) = option_raw {
-                        let mut option = [0u8; DhcpOption::MAX_VENDOR_CLASS_ID_LEN as usize];
-                        option[..option_len as usize]
-                            .copy_from_slice(&option_raw[..option_len as usize]);
+    #[inline(always)]
+    fn ack(&self, res: &mut Self, pool: MutexGuard<AddrPool>) {
+        res.client_addr = self.client_addr;
+        self.insert_requested_options(&pool, res);
+        self.insert_client_fqdn(&pool, res);
+        self.insert_mandatory_options(&pool, res);
+        self.insert_boot_fields(&pool, res);
 
-                        options.add(DhcpOption::VendorClassIndentifier(option));
-                    }
-                }
-                DhcpOption::CLIENT_SYSTEM_ARCH => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+        drop(pool);
 
-                    if option_len != DhcpOption::CLIENT_SYSTEM_ARCH_LEN {
-                        return Err(Error::InvalidClientSystemArchLen(option_len));
-                    }
+        res.options
+            .add(DhcpOption::MessageType(MessageType::Ack))
+            .add(DhcpOption::End);
+    }
+
+    /// `reason` goes in option 56 so client logs and captures say why
+    #[inline(always)]
+    fn nack(&self, res: &mut Self, pool: MutexGuard<AddrPool>, reason: &str) {
+        // A NAK must still tell the client who sent it, but no other options
+        if let Some(server_id) = pool.server_identifier() {
+            res.options
+                .set(DhcpOption::DhcpServerIpAddr(server_id.octets()));
+        }
+        res.options.set(DhcpOption::Message(reason.to_owned()));
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+        // The relay must broadcast it, the client's address is no good
+        if self.relay_addr != [0, 0, 0, 0] {
+            res.flags = Self::BROADCAST_FLAG;
+        }
 
-                    let option_raw = data.get(option_ptr..option_ptr + option_len as usize);
+        drop(pool);
 
-                    if let Some(option_raw) = option_raw {
-                        let mut option = [0u8; DhcpOption::CLIENT_SYSTEM_ARCH_LEN as usize];
-                        option.copy_from_slice(&option_raw[..option_len as usize]);
-                        options.add(DhcpOption::ClientSystemArch(option));
-                    }
+        res.options
+            .add(DhcpOption::MessageType(MessageType::Nack))
+            .add(DhcpOption::End);
+    }
+
+    /// Keep options 12 and 97 with the lease so people can tell devices
+    /// apart, and our answer to 81 so DNS updaters know which names are theirs
+    fn remember_hostname(&self, pool: &mut MutexGuard<AddrPool>, ip_addr: &Ipv4Addr) {
+        if let Some(DhcpOption::HostName(hostname)) = self.options.get(DhcpOption::HOST_NAME) {
+            pool.set_hostname(ip_addr, hostname);
+        }
+        if let Some(answer) = self.client_fqdn(pool) {
+            pool.set_fqdn(ip_addr, answer);
+        }
+        if let Some(uuid) = self.client_uuid() {
+            pool.set_uuid(ip_addr, uuid);
+        }
+        if let Some(DhcpOption::MudUrl(url)) = self.options.get(DhcpOption::MUD_URL) {
+            if pool.set_mud_url(ip_addr, url) {
+                if let Some(webhook) = pool.mud_webhook() {
+                    mud::announce(webhook, *ip_addr, self.client_mac(), url);
                 }
-                DhcpOption::CLIENT_NET_DEV_INTERFACE => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+            }
+        }
+    }
 
-                    if option_len != DhcpOption::CLIENT_NET_DEV_INTERFACE_LEN {
-                        return Err(Error::InvalidClientNetworkDeviceInterfaceLen(option_len));
-                    }
+    /// Handler for a DHCP Request, [None] means we should stay silent
+    fn verify(&self, pool: Arc<Mutex<AddrPool>>) -> Option<Dhcp> {
+        let mut res = self.build_response();
+        let requested_ip = self.options.get(DhcpOption::REQUESTED_IP_ADDR);
+        let client_mac = self.client_mac();
+        let client_key = self.client_key();
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+        let mut pool = pool.lock().unwrap();
+        self.bind_reservations(&mut pool);
+        let lease_time = pool.lease_time(&client_mac, self.class());
+        let mut reason = "no address requested";
+
+        // SELECTING, but the client chose an offer from another server
+        if self.for_another_server(&pool) {
+            info!("Client selected another server");
+            return None;
+        }
+        let state = pool.client_state(&client_key);
+        debug!("{} is {}", oui::describe(&client_mac), state.name());
+
+        // SELECTING, the client names us so it must be taking up our OFFER
+        let selecting = self.options.get(DhcpOption::DHCP_SERVER_IP_ADDR).is_some();
+        if selecting && state == ClientState::Init && pool.authoritative() {
+            warn!(
+                "{} sent a REQUEST without an OFFER from us",
+                oui::describe(&client_mac)
+            );
+            self.nack(&mut res, pool, "no offer was made to this client");
+            return Some(res);
+        }
 
-                    let option_raw = data.get(option_ptr..option_ptr + option_len as usize);
+        // INIT-REBOOT from a client that has moved here from another network
+        let asked_for = match requested_ip {
+            Some(&DhcpOption::RequestedIpAddr(ip)) => Some(Ipv4Addr::from(ip)),
+            _ => Some(self.client_addr.into()).filter(|ip: &Ipv4Addr| !ip.is_unspecified()),
+        };
+        if let Some(ip) = asked_for.filter(|ip| !pool.in_subnet(ip)) {
+            if !pool.authoritative() {
+                info!("Not authoritative for {ip} on another network, ignoring");
+                return None;
+            }
+            info!(
+                "{} asked for {ip} from another network",
+                oui::describe(&client_mac)
+            );
+            self.nack(&mut res, pool, "requested address is on another network");
+            return Some(res);
+        }
 
-                    if let Some(option_raw) = option_raw {
-                        let mut option = [0u8; DhcpOption::CLIENT_NET_DEV_INTERFACE_LEN as usize];
-                        option.copy_from_slice(&option_raw[..option_len as usize]);
-                        options.add(DhcpOption::ClientNetworkDeviceInterface(option));
-                    }
-                }
-                DhcpOption::CLIENT_ID => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+        // RENEWING | REBINDING
+        let client_ip_set = self.client_addr != [0, 0, 0, 0];
+        if client_ip_set && requested_ip.is_none() {
+            if pool
+                .renew(
+                    &client_key,
+                    &client_mac,
+                    &self.client_addr.into(),
+                    lease_time,
+                )
+                .is_some()
+            {
+                res.your_addr = self.client_addr;
+                self.remember_hostname(&mut pool, &self.client_addr.into());
+                self.ack(&mut res, pool);
+                return Some(res);
+            }
+            warn!(
+                "Client renewing unknown lease: {:?} while {}",
+                self.client_addr,
+                state.name()
+            );
+            reason = pool.refusal_reason(&client_key, &client_mac, &self.client_addr.into());
+
+            if !pool.authoritative() && !pool.is_leased(&self.client_addr.into()) {
+                info!("Not authoritative for {:?}, ignoring", self.client_addr);
+                return None;
+            }
+        }
 
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+        // SELECTING || INIT-REBOOT, only addresses we offered can be bound
+        if let Some(&DhcpOption::RequestedIpAddr(ip)) = requested_ip {
+            if pool
+                .commit(&client_key, &client_mac, &ip.into(), lease_time)
+                .is_some()
+            {
+                res.your_addr = ip;
+                self.remember_hostname(&mut pool, &ip.into());
+                self.ack(&mut res, pool);
+                return Some(res);
+            }
+            warn!("Client requested IP not valid: {:?}", requested_ip);
+            reason = pool.refusal_reason(&client_key, &client_mac, &ip.into());
 
-                    let option_raw = &data[option_ptr..option_ptr + option_len as usize];
+            // We have no record of the address, it may belong to another server
+            if !pool.authoritative() && !pool.is_leased(&Ipv4Addr::from(ip)) {
+                info!("Not authoritative for {:?}, ignoring", ip);
+                return None;
+            }
+        }
 
-                    match ClientIdentifier::try_from(option_raw) {
-                        Ok(client_id) => options.add(DhcpOption::ClientIdentifier(client_id)),
-                        Err(err) => return Err(err),
-                    };
-                }
-                DhcpOption::CLIENT_UID => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+        // Fallthrough into nack
+        self.nack(&mut res, pool, reason);
+        error!(
+            "Sending Nack XID: {:X?}, MAC: {}, {reason}",
+            self.transaction_id,
+            oui::describe(&client_mac)
+        );
+        Some(res)
+    }
 
-                    if option_len < DhcpOption::MIN_CLIENT_UID_LEN {
-                        return Err(Error::InvalidClientUidLen(option_len));
-                    }
-                    if option_len > DhcpOption::MAX_CLIENT_UID_LEN {
-                        return Err(Error::InvalidClientUidLen(option_len));
-                    }
+    /// Handler for a DHCP Inform, a client with an address configured some
+    /// other way asking for the rest of its options (RFC 2131 4.3.5). We
+    /// ACK with options but no address or lease time
+    fn inform(&self, pool: Arc<Mutex<AddrPool>>) -> Dhcp {
+        let mut res = self.build_response();
+        res.client_addr = self.client_addr;
+        let pool = pool.lock().unwrap();
+        self.insert_requested_options(&pool, &mut res);
+        self.insert_server_addr(&pool, &mut res);
+        self.insert_boot_fields(&pool, &mut res);
+        drop(pool);
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+        res.options
+            .remove(DhcpOption::LEASE_TIME)
+            .add(DhcpOption::MessageType(MessageType::Ack))
+            .add(DhcpOption::End);
+        res
+    }
 
-                    let option_raw = data.get(option_ptr..option_ptr + option_len as usize);
+    /// Handler for a DHCP Release, the client is done with ciaddr. Never
+    /// answered
+    fn release(&self, pool: Arc<Mutex<AddrPool>>) {
+        let mut pool = pool.lock().unwrap();
+        if self.for_another_server(&pool) {
+            return;
+        }
+        let ip_addr = Ipv4Addr::from(self.client_addr);
+        match pool.release(&self.client_key(), &ip_addr) {
+            Some(()) => info!("{} released {ip_addr}", oui::describe(&self.client_mac())),
+            None => info!("Ignoring RELEASE of {ip_addr}, it is not leased to the client"),
+        }
+    }
 
-                    if let Some(option_raw) = option_raw {
-                        let mut option = [0u8; DhcpOption::MAX_CLIENT_UID_LEN as usize];
-                        option.copy_from_slice(&option_raw[..option_len as usize]);
-                        options.add(DhcpOption::ClientUid(option));
-                    };
-                }
-                DhcpOption::END => _ = options.add(DhcpOption::End),
-                // Catch options we have not defined
-                option => {
-                    option_len = *data
-                        .get(option_ptr + Self::OPTION_LEN_OFFSET)
-                        .ok_or(Error::DhcpOptionLenOutOfBounds)?;
+    /// Handler for a DHCP Decline, the client found someone else already
+    /// using the address we gave it, in option 50. Never answered
+    fn decline(&self, pool: Arc<Mutex<AddrPool>>) {
+        let mut pool = pool.lock().unwrap();
+        if self.for_another_server(&pool) {
+            return;
+        }
+        let Some(&DhcpOption::RequestedIpAddr(ip_addr)) =
+            self.options.get(DhcpOption::REQUESTED_IP_ADDR)
+        else {
+            warn!("Ignoring DECLINE without the address being declined");
+            return;
+        };
+        let ip_addr = Ipv4Addr::from(ip_addr);
+        match pool.decline(&self.client_key(), &ip_addr) {
+            Some(declines) if declines.is_conflict() => error!(
+                "{ip_addr} was declined {} times, something is squatting on it, keeping it back for a week",
+                declines.count
+            ),
+            Some(_) => warn!(
+                "{} declined {ip_addr}, something else is using it",
+                oui::describe(&self.client_mac())
+            ),
+            None => info!("Ignoring DECLINE of {ip_addr}, we did not give it to the client"),
+        }
+    }
 
-                    // Increment pointer to start of data
-                    option_ptr += Self::OPTION_LEN_OFFSET + 1;
+    /// Whether the client names another server in option 54, so the
+    /// message is not ours to act on
+    fn for_another_server(&self, pool: &AddrPool) -> bool {
+        match self.options.get(DhcpOption::DHCP_SERVER_IP_ADDR) {
+            Some(DhcpOption::DhcpServerIpAddr(server_id)) => {
+                pool.server_identifier() != Some((*server_id).into())
+            }
+            _ => false,
+        }
+    }
 
-                    warn!("Unknown DhcpOption Recieved: {option}");
+    /// The codes our reply leads with, `first` then those the client asked
+    /// for in the order it asked
+    fn option_order(&self, first: &[u8]) -> Vec<u8> {
+        let mut order = first.to_vec();
+        if let Some(DhcpOption::ParameterRequestList(params)) =
+            self.options.get(DhcpOption::PARAMETER_REQUEST_LIST)
+        {
+            for code in params.iter().map(ParameterRequest::code) {
+                if !order.contains(&code) {
+                    order.push(code);
                 }
-            };
+            }
+        }
+        order
+    }
 
-            // Stop if we got an [DhcpOption::End]
-            if option_opcode == DhcpOption::END {
-                break;
+    /// The largest response the client will accept, from option 57 if they
+    /// sent it, never more than fits in an ethernet frame
+    fn max_response_len(&self) -> usize {
+        let size = match self.options.get(DhcpOption::MAX_MESSAGE_SIZE) {
+            Some(&DhcpOption::MaxMessageSize(size)) => size.max(Self::DEFAULT_MAX_MESSAGE_SIZE),
+            _ => Self::DEFAULT_MAX_MESSAGE_SIZE,
+        };
+        // Option 57 counts the IP and UDP headers, our buffer does not
+        (size as usize - Self::IP_UDP_HEADER_LEN).min(MAX_MESSAGE_LEN)
+    }
+
+    /// Write the reply to `buffer`, its options led by the codes in `order`
+    fn serialiase(&self, buffer: &mut [u8], max_len: usize, order: &[u8]) -> usize {
+        let header = wire::Header {
+            op: self.op_code,
+            htype: self.hw_addr_ty,
+            hlen: self.hw_addr_len,
+            hops: self.hops,
+            xid: self.transaction_id,
+            secs: self.secs,
+            flags: self.flags,
+            ciaddr: self.client_addr,
+            yiaddr: self.your_addr,
+            siaddr: self.next_server_addr,
+            giaddr: self.relay_addr,
+            chaddr: self.client_hw_addr,
+        };
+        // The buffer is reused between replies, this clears what the last one
+        // left. sname and file were checked to fit when we set them
+        let (sname, file) = (self.server_hostname.as_bytes(), self.file.as_bytes());
+        header
+            .write(buffer, sname, file)
+            .expect("replies are built in a buffer with room for the header");
+
+        self.set_options(buffer, max_len.min(buffer.len()), order)
+    }
+
+    fn set_options(&self, buffer: &mut [u8], max_len: usize, order: &[u8]) -> usize {
+        // Serialise up front so we know if everything will fit, back to back
+        // in one buffer rather than one allocation per option
+        let mut scratch = [0u8; MAX_MESSAGE_LEN];
+        let mut bytes = Vec::with_capacity(MAX_MESSAGE_LEN);
+        let mut encoded: Vec<(u8, Range<usize>)> = Vec::new();
+        for opt in self.options.iter() {
+            if matches!(opt, DhcpOption::End) {
+                continue;
             }
-            // Increment counter
-            option_ptr += option_len as usize;
+            let len = opt.serialise(&mut scratch);
+            encoded.push((opt.opcode(), bytes.len()..bytes.len() + len));
+            bytes.extend_from_slice(&scratch[..len]);
+        }
+        // Whatever is not in `order` keeps the order it was set in
+        encoded.sort_by_key(|(code, _)| {
+            order
+                .iter()
+                .position(|first| first == code)
+                .unwrap_or(order.len())
+        });
+        // The echo of option 82 goes last whatever the order, and is the last
+        // thing to lose its room
+        let mut relay_info = encoded
+            .iter()
+            .position(|(code, _)| *code == DhcpOption::RELAY_AGENT_INFO)
+            .map(|index| bytes[encoded.remove(index).1].to_vec())
+            .unwrap_or_default();
+
+        // Everything fits after the magic with room for End, the common case
+        if wire::OPTIONS_START + bytes.len() < max_len {
+            let mut option_ptr = wire::OPTIONS_START;
+            for option in encoded
+                .iter()
+                .map(|(_, range)| &bytes[range.clone()])
+                .chain([&relay_info[..]])
+            {
+                buffer[option_ptr..option_ptr + option.len()].copy_from_slice(option);
+                option_ptr += option.len();
+            }
+            buffer[option_ptr] = DhcpOption::END;
+            // Final Len of the UDP packet
+            return option_ptr + 1;
         }
 
-        if message_type == MessageType::Unset {
-            return Err(Error::NoMessageDhcpTypeProvided);
+        // Otherwise overflow into file then sname (RFC 2131 4.1), leaving
+        // room in the options for the overload option, option 82 and each
+        // area's End. Fields we filled in have no room
+        let options_end = match max_len
+            .checked_sub(Self::OVERLOAD_LEN + relay_info.len() + 1)
+            .filter(|end| *end >= wire::OPTIONS_START)
+        {
+            Some(end) => end,
+            None => {
+                warn!("Dropped option 82, it does not fit in {max_len} bytes");
+                relay_info.clear();
+                max_len - Self::OVERLOAD_LEN - 1
+            }
+        };
+        let free = |field: &str, start: usize, end: usize| match field.is_empty() {
+            true => OptionArea::new(start, end),
+            false => OptionArea::new(start, start),
+        };
+        let mut areas = [
+            OptionArea::new(wire::OPTIONS_START, options_end),
+            free(
+                &self.file,
+                wire::FILE_START,
+                wire::OPTIONS_START - wire::MAGIC.len() - 1,
+            ),
+            free(
+                &self.server_hostname,
+                wire::SNAME_START,
+                wire::FILE_START - 1,
+            ),
+        ];
+
+        // Clients look for these before they know to check for overload
+        let (pinned, rest): (Vec<_>, Vec<_>) = encoded.into_iter().partition(|(code, _)| {
+            matches!(
+                *code,
+                DhcpOption::MESSAGE_TYPE | DhcpOption::DHCP_SERVER_IP_ADDR
+            )
+        });
+        for (code, range) in pinned.iter().chain(&rest) {
+            let bytes = &bytes[range.clone()];
+            if !areas.iter_mut().any(|area| area.push(buffer, bytes)) {
+                warn!("Dropped option {code}, it does not fit in {max_len} bytes");
+            }
         }
 
-        Ok(Self {
-            op_code: data[0],
-            hw_addr_ty: data[1],
-            hw_addr_len: data[2],
-            hops: data[3],
-            transaction_id: data[4..8].try_into().unwrap(),
-            secs: data[8..10].try_into().unwrap(),
-            flags: data[10..12].try_into().unwrap(),
-            client_addr: data[12..16].try_into().unwrap(),
-            server_addr: data[16..20].try_into().unwrap(),
-            next_server_addr: data[20..24].try_into().unwrap(),
-            relay_addr: data[24..28].try_into().unwrap(),
-            client_hw_addr: data[28..34].try_into().unwrap(),
-            server_hostname: data[44..108].try_into().unwrap(),
-            file: data[108..236].try_into().unwrap(),
-            options,
-            message_type,
+        let [options, file, sname] = &mut areas;
+        let overload = match (file.is_used(), sname.is_used()) {
+            (false, false) => None,
+            (true, false) => Some(1),
+            (false, true) => Some(2),
+            (true, true) => Some(3),
+        };
+        if let Some(overload) = overload {
+            options.end += Self::OVERLOAD_LEN;
+            options.push(buffer, &[DhcpOption::OPTION_OVERLOAD, 1, overload]);
+            for area in [file, sname] {
+                if area.is_used() {
+                    buffer[area.ptr] = DhcpOption::END;
+                }
+            }
+        }
+        options.end += relay_info.len();
+        options.push(buffer, &relay_info);
+        buffer[options.ptr] = DhcpOption::END;
+        // Final Len of the UDP packet
+        options.ptr + 1
+    }
+
+    /// Seconds since the client started, from the header
+    pub fn secs(&self) -> u16 {
+        u16::from_be_bytes(self.secs)
+    }
+
+    /// What the client is asking for, option 53
+    pub fn message_type(&self) -> MessageType {
+        self.message_type
+    }
+
+    /// State machine to decide what to do with packet, returns the reply we
+    /// built in `buffer` or [None] if we should not respond
+    pub fn handle(&self, pool: Arc<Mutex<AddrPool>>, buffer: &mut [u8]) -> Option<Reply> {
+        info!(
+            "Recieved {:?} from {}",
+            self.message_type,
+            oui::describe(&self.client_mac())
+        );
+        let span = telemetry::span("classify");
+        if !pool.lock().unwrap().serving() {
+            info!("Leaving it to our failover peer");
+            return None;
+        }
+        if let Some(link) = self.link_selection() {
+            if !pool.lock().unwrap().in_subnet(&link) {
+                info!("We have no scope for {link}, ignoring");
+                return None;
+            }
+        }
+        let max_len = self.max_response_len();
+        let (unicast, order) = {
+            let pool = pool.lock().unwrap();
+            let unicast = pool.unicast_replies() || pool.inject_arp().is_some();
+            (unicast, self.option_order(pool.option_order()))
+        };
+        let load_sharing = pool.lock().unwrap().load_sharing();
+        let waiting = pool.lock().unwrap().is_waiting(self.secs());
+        drop(span);
+
+        let span = telemetry::span("allocate");
+        let mut delay = None;
+        let res = match self.message_type {
+            MessageType::Discover => {
+                // Clients that have waited long enough take what they can get
+                if !waiting && !load_sharing.answers() {
+                    info!("Leaving this DISCOVER to the other servers");
+                    return None;
+                }
+                let offer = self.offer(pool, waiting)?;
+                delay = load_sharing
+                    .delay_for(&self.client_mac())
+                    .filter(|_| !waiting);
+                match delay {
+                    Some(delay) => info!(
+                        "Holding back IP Offer: {:?} for {delay:?}, another server may answer first",
+                        offer.your_addr
+                    ),
+                    None => info!("Sending IP Offer: {:?}", offer.your_addr),
+                }
+                offer
+            }
+            MessageType::Request => self.verify(pool)?,
+            MessageType::Inform => self.inform(pool),
+            MessageType::Release => {
+                self.release(pool);
+                return None;
+            }
+            MessageType::Decline => {
+                self.decline(pool);
+                return None;
+            }
+            // Only servers send these, a client or relay that echoes them
+            // back to us gets nothing
+            MessageType::Offer | MessageType::Ack | MessageType::Nack | MessageType::ForceRenew => {
+                info!("Ignoring {:?}, only servers send those", self.message_type);
+                return None;
+            }
+            MessageType::Unknown(_) | MessageType::Unset => {
+                warn!(
+                    "Ignoring message type {}, we do not know it",
+                    self.message_type.code()
+                );
+                return None;
+            }
+        };
+        drop(span);
+        res.record_event();
+        let _span = telemetry::span("serialize");
+        Some(Reply {
+            len: res.serialiase(buffer, max_len, &order),
+            destination: self.destination(&res, unicast),
+            delay,
         })
     }
 
-    /// Construct a new Dhcp response given a request
-    fn build_response(&self) -> Self {
-        Self {
+    /// Where our reply to this request goes, RFC 2131 section 4.1. With
+    /// `unicast` we send to the address we are giving a client that has none
+    /// yet, which only arrives where something routes it without ARP or we
+    /// put it in the ARP cache
+    fn destination(&self, reply: &Dhcp, unicast: bool) -> Destination {
+        let is_nak = matches!(
+            reply.options.get(DhcpOption::MESSAGE_TYPE),
+            Some(DhcpOption::MessageType(MessageType::Nack))
+        );
+        if self.relay_addr != [0, 0, 0, 0] {
+            Destination::Relay(self.relay_addr.into())
+        } else if is_nak || self.client_addr == [0, 0, 0, 0] {
+            // We cannot unicast to a client without an address unless its MAC
+            // is put in the ARP cache, so we broadcast unless told that we
+            // can or the network gets it there anyway
+            match unicast && !is_nak && reply.your_addr != [0, 0, 0, 0] {
+                true => Destination::Unbound(reply.your_addr.into(), self.client_mac()),
+                false => Destination::Broadcast,
+            }
+        } else {
+            Destination::Client(self.client_addr.into())
+        }
+    }
+
+    /// A FORCERENEW (RFC 3203) telling the client bound to `ip_addr` to renew
+    /// its lease now, [None] when nobody is bound to it. Send it to the client
+    /// directly
+    pub fn force_renew(pool: &AddrPool, ip_addr: &Ipv4Addr, buffer: &mut [u8]) -> Option<usize> {
+        let entry = pool
+            .entries()
+            .into_iter()
+            .find(|entry| entry.ip_addr == *ip_addr && entry.state == LeaseState::Bound)?;
+
+        let mut client_hw_addr = [0u8; Self::MAX_HW_ADDRESS_LEN as usize];
+        client_hw_addr[..MacAddr::LEN].copy_from_slice(&entry.mac_address.bytes());
+
+        // The client matches on nothing but the message type and server id,
+        // so any transaction id will do
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+
+        let mut options = DhcpOptionList::builder();
+        if let Some(server_id) = pool.server_identifier() {
+            options.add(DhcpOption::DhcpServerIpAddr(server_id.octets()));
+        }
+        options
+            .add(DhcpOption::MessageType(MessageType::ForceRenew))
+            .add(DhcpOption::End);
+
+        let message = Self {
             op_code: Self::REPLY_OP_CODE,
             hw_addr_ty: Self::HW_TYPE_ETHERNET,
-            hw_addr_len: Self::HW_ADDRESS_LEN,
-            // NOT IMPLEMENTED
+            hw_addr_len: MacAddr::LEN as u8,
             hops: 0,
-            transaction_id: self.transaction_id,
-            // NOT IMPLEMENTED
+            transaction_id: nanos.to_be_bytes(),
             secs: [0, 0],
             flags: [0, 0],
             client_addr: [0, 0, 0, 0],
-            // NOT IMPLEMENTED
-            server_addr: [0, 0, 0, 0],
+            your_addr: [0, 0, 0, 0],
             next_server_addr: [0, 0, 0, 0],
             relay_addr: [0, 0, 0, 0],
-            client_hw_addr: self.client_hw_addr,
-            // NOT IMPLEMENTED
-            server_hostname: [0u8; 64],
-            // NOT IMPLMENTED
-            file: [0u8; 128],
-            options: DhcpOptionList::builder(),
-            message_type: MessageType::Unset,
+            client_hw_addr,
+            server_hostname: String::new(),
+            file: String::new(),
+            options,
+            message_type: MessageType::ForceRenew,
+        };
+        info!(
+            "Sending ForceRenew to {}",
+            oui::describe(&entry.mac_address)
+        );
+        message.record_event();
+        let max_len = Self::DEFAULT_MAX_MESSAGE_SIZE as usize - Self::IP_UDP_HEADER_LEN;
+        Some(message.serialiase(buffer, max_len, pool.option_order()))
+    }
+
+    /// Note a reply we are about to send in [events]
+    fn record_event(&self) {
+        if let Some(&DhcpOption::MessageType(message_type)) =
+            self.options.get(DhcpOption::MESSAGE_TYPE)
+        {
+            let ip_addr = Ipv4Addr::from(self.your_addr);
+            events::record(
+                message_type,
+                self.client_mac(),
+                (!ip_addr.is_unspecified()).then_some(ip_addr),
+            );
         }
     }
+}
 
-    fn insert_requested_options(&self, pool: &MutexGuard<AddrPool<'dhcp>>, res: &mut Self) {
-        let insert_matching_options = |req_option: &ParameterRequest| {
-            if let Some(opt) = pool.options().consume()[*req_option as usize] {
-                _ = &res.options.add(opt);
-            } else {
-                warn!("Did not include option: {req_option:?}")
-            }
-        };
+/// A region of the packet we can write options into, the end is exclusive
+/// and leaves room for an End option
+struct OptionArea {
+    start: usize,
+    end: usize,
+    ptr: usize,
+}
 
-        if let Some(DhcpOption::ParameterRequestList(option_req_list)) =
-            self.options.get(DhcpOption::PARAMETER_REQUEST_LIST)
-        {
-            option_req_list
-                .iter()
-                .flatten()
-                .for_each(insert_matching_options);
+impl OptionArea {
+    fn new(start: usize, end: usize) -> Self {
+        Self {
+            start,
+            end,
+            ptr: start,
         }
     }
 
-    fn insert_server_addr(&self, pool: &MutexGuard<AddrPool<'dhcp>>, res: &mut Self) {
-        if let Some(DhcpOption::DhcpServerIpAddr(addr)) =
-            pool.options().get(DhcpOption::DHCP_SERVER_IP_ADDR)
-        {
-            res.server_addr = addr;
-            res.options.add(DhcpOption::DhcpServerIpAddr(addr));
+    /// Write the option if it fits, returns false if it does not
+    fn push(&mut self, buffer: &mut [u8], bytes: &[u8]) -> bool {
+        if self.ptr + bytes.len() > self.end {
+            return false;
         }
+        buffer[self.ptr..self.ptr + bytes.len()].copy_from_slice(bytes);
+        self.ptr += bytes.len();
+        true
     }
 
-    fn insert_lease(&self, pool: &MutexGuard<AddrPool<'dhcp>>, res: &mut Self) {
-        if let Some(DhcpOption::LeaseTime(lease)) = pool.options().get(DhcpOption::LEASE_TIME) {
-            res.options.add(DhcpOption::LeaseTime(lease));
+    fn is_used(&self) -> bool {
+        self.ptr != self.start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DEFAULT_OPTION_ORDER;
+    use crate::types::{ParameterRequest, PxeOptions};
+    use proptest::prelude::*;
+
+    /// Wrap serialised options in a minimal DISCOVER
+    fn packet(options: &[DhcpOption]) -> Vec<u8> {
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        buffer[0] = Dhcp::REQUEST_OP_CODE;
+        buffer[1] = Dhcp::HW_TYPE_ETHERNET;
+        buffer[2] = MacAddr::LEN as u8;
+        buffer[236..240].copy_from_slice(&wire::MAGIC);
+
+        let mut ptr = wire::OPTIONS_START;
+        ptr += DhcpOption::MessageType(MessageType::Discover).serialise(&mut buffer[ptr..]);
+        for option in options {
+            ptr += option.serialise(&mut buffer[ptr..]);
         }
+        ptr += DhcpOption::End.serialise(&mut buffer[ptr..]);
+
+        buffer[..ptr].to_vec()
     }
 
-    /// Handler for a DHCP Discover
-    fn offer(&self, pool: Arc<Mutex<AddrPool<'dhcp>>>) -> Self {
-        let mut res = self.build_response();
-        let mut pool = pool.lock().unwrap();
+    /// Serialise, parse and serialise again, the bytes must not change
+    fn round_trip(option: DhcpOption) -> Dhcp {
+        let data = packet(std::slice::from_ref(&option));
+        let parsed = Dhcp::parse(&data).unwrap();
+        let reparsed = parsed.options.get(option.opcode()).unwrap();
+        assert_eq!(packet(std::slice::from_ref(reparsed)), data, "{option:?}");
+        parsed
+    }
 
-        res.client_addr = pool.request(&MacAddr::new(self.client_hw_addr)).octets();
+    /// For options we parse into a typed variant the value must match too
+    fn typed_round_trip(option: DhcpOption) {
+        let parsed = round_trip(option.clone());
+        assert_eq!(parsed.options.get(option.opcode()), Some(&option));
+    }
 
-        self.insert_requested_options(&pool, &mut res);
-        self.insert_lease(&pool, &mut res);
-        self.insert_server_addr(&pool, &mut res);
+    #[test]
+    fn typed_options_round_trip() {
+        let params = vec![
+            ParameterRequest::SubnetMask,
+            ParameterRequest::Router,
+            ParameterRequest::BootfileName,
+        ];
+
+        // Longer than the 32 bytes we used to limit vendor classes to
+        let vendor_class = b"android-dhcp-14:Pixel 8 Pro:husky:google".to_vec();
+
+        let uid: Vec<u8> = (0..17).collect();
+
+        // A circuit-id naming the switch port and a MAC as the remote-id
+        let relay_info = [&[1, 8][..], b"eth0/1/3", &[2, 6, 2, 0, 0, 0, 0, 1]].concat();
+        let relay_info = RelayAgentInfo::try_from(&relay_info[..]).unwrap();
+
+        let client_id = ClientIdentifier::try_from(&[1, 2, 0, 0, 0, 0, 1][..]).unwrap();
+
+        // RFC 4361, type 255 then an IAID and a DUID-LLT
+        let mut duid = vec![ClientIdentifier::DUID, 0, 0, 0, 1, 0, 1, 0, 1];
+        duid.extend_from_slice(&[0x2a, 0x3b, 0x4c, 0x5d, 2, 0, 0, 0, 0, 1]);
+        let duid = ClientIdentifier::try_from(&duid[..]).unwrap();
+
+        typed_round_trip(DhcpOption::RequestedIpAddr([192, 168, 1, 10]));
+        typed_round_trip(DhcpOption::DhcpServerIpAddr([192, 168, 1, 1]));
+        typed_round_trip(DhcpOption::MaxMessageSize(1500));
+        typed_round_trip(DhcpOption::ParameterRequestList(params));
+        typed_round_trip(DhcpOption::VendorClassIndentifier(vendor_class));
+        typed_round_trip(DhcpOption::ClientIdentifier(client_id));
+        typed_round_trip(DhcpOption::ClientIdentifier(duid));
+        typed_round_trip(DhcpOption::ClientSystemArch([0, 7]));
+        typed_round_trip(DhcpOption::ClientNetworkDeviceInterface([1, 3, 16]));
+        typed_round_trip(DhcpOption::ClientUid(uid));
+        typed_round_trip(DhcpOption::SubnetSelection([10, 20, 0, 0]));
+        typed_round_trip(DhcpOption::RelayAgentInformation(relay_info));
+        // iPXE sends its class bare, RFC 3004 clients length prefix each one
+        typed_round_trip(DhcpOption::UserClass(b"iPXE"[..].into()));
+        typed_round_trip(DhcpOption::UserClass(b"\x04iPXE\x03lab"[..].into()));
+        typed_round_trip(DhcpOption::BroadcastAddress([192, 168, 1, 255]));
+        typed_round_trip(DhcpOption::Message("offer expired".into()));
+        typed_round_trip(DhcpOption::MudUrl(
+            "https://mud.example.com/lightbulb.json".into(),
+        ));
+        typed_round_trip(DhcpOption::Unknown(150, vec![10, 0, 0, 1]));
+    }
 
-        drop(pool);
+    #[test]
+    fn server_options_round_trip() {
+        round_trip(DhcpOption::SubnetMask([255, 255, 255, 0]));
+        round_trip(DhcpOption::Router([192, 168, 1, 254]));
+        round_trip(DhcpOption::DomainNameServer([1, 1, 1, 1]));
+        round_trip(DhcpOption::HostName("r2d2".into()));
+        round_trip(DhcpOption::DomainName("home".into()));
+        round_trip(DhcpOption::BootFileSize(512));
+        round_trip(DhcpOption::InterfaceMtu(9000));
+        round_trip(DhcpOption::LeaseTime(43200));
+        round_trip(DhcpOption::TftpServerName("192.168.10.1".into()));
+        round_trip(DhcpOption::BootFileName("stage0.bin".into()));
+        round_trip(DhcpOption::RootPath("192.168.10.1:/export/root".into()));
+        round_trip(DhcpOption::ExtensionsPath("extensions.bin".into()));
+        round_trip(DhcpOption::Ipv6OnlyPreferred(1800));
+
+        let mut pxe = PxeOptions::default();
+        pxe.set_discovery_control(3)
+            .add_boot_server(0x8000, vec![[192, 168, 10, 1]])
+            .unwrap()
+            .add_menu_entry(0x8000, "Install Ubuntu".into())
+            .unwrap()
+            .add_menu_entry(0, "Boot from disk".into())
+            .unwrap()
+            .set_prompt(10, "Press F8 for the boot menu".into())
+            .unwrap();
+        let parsed = round_trip(DhcpOption::PxeOptions(pxe));
+        let Some(DhcpOption::Unknown(_, value)) =
+            parsed.options.get(DhcpOption::VENDOR_SPECIFIC_INFO)
+        else {
+            panic!("option 43 is missing");
+        };
+        // Every sub-option present and an end sub-option last
+        let (mut codes, mut rest) = (Vec::new(), &value[..]);
+        while let [code, len, after @ ..] = rest {
+            codes.push(*code);
+            rest = &after[*len as usize..];
+        }
+        assert_eq!(codes, [6, 8, 9, 10]);
+        assert_eq!(rest, [PxeOptions::END]);
+        round_trip(DhcpOption::CaptivePortal(
+            "https://portal.example.com/api".into(),
+        ));
+        round_trip(DhcpOption::ProxyAutodiscovery(
+            "http://wpad.home/wpad.dat".into(),
+        ));
+        round_trip(DhcpOption::ProxyAutodiscovery(String::new()));
+        round_trip(DhcpOption::TimeServer(vec![[192, 168, 1, 254]]));
+        round_trip(DhcpOption::NtpServers(vec![[10, 0, 0, 1], [10, 0, 0, 2]]));
+        round_trip(DhcpOption::NetBiosNameServer(vec![[192, 168, 1, 20]]));
+        round_trip(DhcpOption::NetBiosNodeType(DhcpOption::NETBIOS_H_NODE));
+        round_trip(DhcpOption::NetBiosScope("corp".into()));
+    }
 
-        // Specific Offer Options
-        res.options
-            .add(DhcpOption::MessageType(MessageType::Offer))
-            .add(DhcpOption::End);
-        res
+    #[test]
+    fn options_by_code_take_each_encoding() {
+        let value = |value| DhcpOption::from_code_value(150, value).ok();
+        let unknown = |bytes: &[u8]| Some(DhcpOption::Unknown(150, bytes.to_vec()));
+        assert_eq!(value("hex:0A00ff01"), unknown(&[10, 0, 255, 1]));
+        assert_eq!(
+            value("ip:10.0.0.1, 10.0.0.2"),
+            unknown(&[10, 0, 0, 1, 10, 0, 0, 2])
+        );
+        assert_eq!(value("u8:7"), unknown(&[7]));
+        assert_eq!(value("u16:1500"), unknown(&[5, 220]));
+        assert_eq!(value("u32:86400"), unknown(&[0, 1, 81, 128]));
+        assert_eq!(value("str:tftp"), unknown(b"tftp"));
+
+        for invalid in ["hex:0A0", "hex:zz", "ip:10.0.0", "u8:256", "str", "bin:01"] {
+            assert_eq!(value(invalid), None, "{invalid}");
+        }
+        // Pad and End have no length to carry a value
+        assert!(DhcpOption::from_code_value(DhcpOption::PAD, "u8:1").is_err());
+        assert!(DhcpOption::from_code_value(DhcpOption::END, "u8:1").is_err());
     }
 
-    #[inline(always)]
-    fn ack(&self, res: &mut Self, pool: MutexGuard<AddrPool<'dhcp>>) {
-        self.insert_requested_options(&pool, res);
-        self.insert_server_addr(&pool, res);
+    #[test]
+    fn option_lists_keep_wire_order_and_repeats() {
+        let mut list = DhcpOptionList::builder();
+        list.add(DhcpOption::NtpServers(vec![[10, 0, 0, 1]]))
+            .add(DhcpOption::LeaseTime(600))
+            .add(DhcpOption::NtpServers(vec![[10, 0, 0, 2]]));
+        let servers: Vec<_> = list.get_all(DhcpOption::NTP_SERVERS).collect();
+        assert_eq!(
+            servers,
+            [
+                &DhcpOption::NtpServers(vec![[10, 0, 0, 1]]),
+                &DhcpOption::NtpServers(vec![[10, 0, 0, 2]])
+            ]
+        );
 
-        drop(pool);
+        // Setting replaces them all in the place of the first
+        list.set(DhcpOption::NtpServers(vec![[10, 0, 0, 3]]));
+        let options: Vec<_> = list.iter().cloned().collect();
+        assert_eq!(
+            options,
+            [
+                DhcpOption::NtpServers(vec![[10, 0, 0, 3]]),
+                DhcpOption::LeaseTime(600)
+            ]
+        );
+        list.remove(DhcpOption::NTP_SERVERS);
+        assert_eq!(list.get(DhcpOption::NTP_SERVERS), None);
+    }
 
-        res.options
-            .add(DhcpOption::MessageType(MessageType::Ack))
-            .add(DhcpOption::End);
+    #[test]
+    fn client_identifiers_of_any_legal_length_are_kept() {
+        assert!(matches!(
+            ClientIdentifier::try_from(&[1][..]),
+            Err(Error::InvalidClientIdLen(1))
+        ));
+        for len in [2, 7, 255] {
+            let bytes: Vec<u8> = (0..len).map(|byte| byte as u8).collect();
+            let client_id = ClientIdentifier::try_from(&bytes[..]).unwrap();
+            assert_eq!(client_id.as_bytes(), bytes);
+            assert_eq!(client_id.id_type(), 0);
+
+            let request = Dhcp::parse(&packet(&[DhcpOption::ClientIdentifier(client_id.clone())]));
+            assert_eq!(
+                request.unwrap().client_key(),
+                ClientKey::ClientId(client_id)
+            );
+        }
     }
 
-    #[inline(always)]
-    fn nack(&self, res: &mut Self) {
-        res.options
-            .add(DhcpOption::MessageType(MessageType::Nack))
-            .add(DhcpOption::End);
+    #[test]
+    fn any_hardware_type_is_accepted() {
+        let with_hw_addr = |htype: u8, chaddr: &[u8], options: &[DhcpOption]| {
+            let mut data = packet(options);
+            data[1] = htype;
+            data[2] = chaddr.len() as u8;
+            data[28..28 + chaddr.len()].copy_from_slice(chaddr);
+            Dhcp::parse(&data)
+        };
+
+        // Without option 61 the hardware type and chaddr identify the client
+        let chaddr: Vec<u8> = (1..=16).collect();
+        let request = with_hw_addr(20, &chaddr, &[]).unwrap();
+        let client_id = ClientIdentifier::try_from(&[&[20][..], &chaddr].concat()[..]).unwrap();
+        assert_eq!(request.client_key(), ClientKey::ClientId(client_id));
+
+        // InfiniBand leaves chaddr empty and must send option 61
+        let client_id = ClientIdentifier::try_from(&[0xff, 0, 0, 0, 1, 0, 4][..]).unwrap();
+        let option = DhcpOption::ClientIdentifier(client_id.clone());
+        let request = with_hw_addr(32, &[], &[option]).unwrap();
+        assert_eq!(request.client_key(), ClientKey::ClientId(client_id));
+        assert!(matches!(
+            with_hw_addr(32, &[], &[]),
+            Err(Error::MissingClientIdentifier(32))
+        ));
+
+        // A MAC is always six bytes
+        assert!(with_hw_addr(Dhcp::HW_TYPE_IEEE_802, &[2, 0, 0, 0, 0, 1], &[]).is_ok());
+        assert!(matches!(
+            with_hw_addr(Dhcp::HW_TYPE_ETHERNET, &chaddr, &[]),
+            Err(Error::InvalidHwAddrLen(1, 16))
+        ));
     }
 
-    fn verify(&self, pool: Arc<Mutex<AddrPool<'dhcp>>>) -> Dhcp {
-        let mut res = self.build_response();
-        let requested_ip = self.options.get(DhcpOption::REQUESTED_IP_ADDR);
-        let client_mac: MacAddr = self.client_hw_addr.into();
+    #[test]
+    fn domain_search_reuses_suffixes() {
+        let domains = ["home.arpa".into(), "lab.home.arpa".into()];
+        let mut expected = b"\x04home\x04arpa\x00\x03lab".to_vec();
+        // A pointer back to home.arpa at offset 0
+        expected.extend_from_slice(&[0xc0, 0x00]);
+        assert_eq!(DhcpOption::encode_domain_search(&domains), expected);
+    }
 
-        let pool = pool.lock().unwrap();
+    #[test]
+    fn time_offset_is_twos_complement() {
+        // UTC-5
+        let parsed = round_trip(DhcpOption::TimeOffset(-18000));
+        assert_eq!(
+            parsed.options.get(DhcpOption::TIME_OFFSET),
+            Some(&DhcpOption::Unknown(2, vec![0xff, 0xff, 0xb9, 0xb0]))
+        );
+    }
 
-        // RENEWING | REBINDING
-        let client_ip_set = self.client_addr != [0, 0, 0, 0];
-        if client_ip_set && requested_ip.is_none() {
-            res.client_addr = self.client_addr;
-            self.ack(&mut res, pool);
-            return res;
-        }
+    #[test]
+    fn long_options_are_split_and_joined() {
+        let value: Vec<u8> = (0..260).map(|i| i as u8).collect();
+        let data = packet(&[DhcpOption::Unknown(150, value.clone())]);
 
-        // SELECTING || INIT-REBOOT
-        if let Some(DhcpOption::RequestedIpAddr(ip)) = requested_ip {
-            if pool.verify_request(&client_mac, &ip.into()).is_some() {
-                res.client_addr = ip;
-                self.ack(&mut res, pool);
-                return res;
-            }
-            warn!("Client requested IP not valid: {:?}", requested_ip);
+        // Split into a full instance and the remainder
+        assert_eq!(data[243..245], [150, 255]);
+        assert_eq!(data[500..502], [150, 5]);
+
+        let parsed = Dhcp::parse(&data).unwrap();
+        assert_eq!(parsed.options.get_all(150).count(), 1);
+        assert_eq!(
+            parsed.options.get(150),
+            Some(&DhcpOption::Unknown(150, value))
+        );
+    }
+
+    #[test]
+    fn replies_fit_the_max_message_size_by_overloading_file_and_sname() {
+        let max_len = |size| {
+            let options: &[DhcpOption] = match size {
+                Some(size) => &[DhcpOption::MaxMessageSize(size)],
+                None => &[],
+            };
+            Dhcp::parse(&packet(options)).unwrap().max_response_len()
+        };
+        let header_len = Dhcp::IP_UDP_HEADER_LEN;
+        assert_eq!(max_len(None), 576 - header_len);
+        // Every client can take 576 bytes whatever it says
+        assert_eq!(max_len(Some(300)), 576 - header_len);
+        assert_eq!(max_len(Some(1500)), 1500 - header_len);
+
+        let request = Dhcp::parse(&packet(&[])).unwrap();
+        let mut reply = request.build_response();
+        reply
+            .options
+            .add(DhcpOption::MessageType(MessageType::Offer));
+        for code in 224..227 {
+            reply
+                .options
+                .add(DhcpOption::Unknown(code, vec![code; 100]));
         }
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let len = reply.serialiase(&mut buffer, max_len(None), &DEFAULT_OPTION_ORDER);
+        assert!(len <= max_len(None));
+        let area = wire::Options::over(&buffer[wire::OPTIONS_START..len]);
+        assert_eq!(area.get(DhcpOption::OPTION_OVERLOAD), Some(&[1][..]));
+        let file = wire::file(&buffer[..len]).unwrap();
+        let sent: Vec<u8> = wire::Options::of(&buffer[..len])
+            .chain(wire::Options::over(file))
+            .map(|(code, _)| code)
+            .collect();
+        for code in 224..227 {
+            assert!(sent.contains(&code), "{code} was dropped");
+        }
+    }
 
-        // Fallthrough into nack
-        self.nack(&mut res);
-        error!(
-            "Sending Nack XID: {:X?}, MAC: {:X?}",
-            self.transaction_id, self.client_hw_addr
+    #[test]
+    fn relay_agent_info_is_echoed_last_whatever_else_is_dropped() {
+        let relay_info = [&[1, 8][..], b"eth0/1/3", &[2, 6, 2, 0, 0, 0, 0, 1]].concat();
+        let info = RelayAgentInfo::try_from(&relay_info[..]).unwrap();
+        let mut data = packet(&[DhcpOption::RelayAgentInformation(info)]);
+        data[wire::GIADDR].copy_from_slice(&[10, 0, 0, 1]);
+        let request = Dhcp::parse(&data).unwrap();
+        let max_len = Dhcp::DEFAULT_MAX_MESSAGE_SIZE as usize - Dhcp::IP_UDP_HEADER_LEN;
+        let last = |buffer: &[u8], len: usize| {
+            let options: Vec<_> = wire::Options::of(&buffer[..len]).collect();
+            assert_eq!(buffer[len - 1], DhcpOption::END);
+            options.last().map(|(code, value)| (*code, value.to_vec()))
+        };
+
+        // Even when asked to put it first
+        let mut reply = request.build_response();
+        reply
+            .options
+            .add(DhcpOption::MessageType(MessageType::Offer));
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let len = reply.serialiase(&mut buffer, max_len, &[82, 53]);
+        assert_eq!(buffer[wire::OPTIONS_START], DhcpOption::MESSAGE_TYPE);
+        assert_eq!(
+            last(&buffer, len),
+            Some((DhcpOption::RELAY_AGENT_INFO, relay_info.clone()))
         );
-        res
+
+        // More than fits in options, file and sname together
+        for code in 224..230 {
+            reply
+                .options
+                .add(DhcpOption::Unknown(code, vec![code; 120]));
+        }
+        let len = reply.serialiase(&mut buffer, max_len, &DEFAULT_OPTION_ORDER);
+        assert!(len <= max_len);
+        assert_eq!(
+            last(&buffer, len),
+            Some((DhcpOption::RELAY_AGENT_INFO, relay_info))
+        );
+        let sent: Vec<u8> = wire::Options::of(&buffer[..len])
+            .map(|(code, _)| code)
+            .collect();
+        assert!(sent.contains(&DhcpOption::MESSAGE_TYPE));
+        assert!(!sent.contains(&229));
     }
 
-    fn serialiase(&self, buffer: &mut [u8; UDP_BUFFER_SIZE]) -> usize {
-        buffer[0] = self.op_code;
-        buffer[1] = self.hw_addr_ty;
-        buffer[2] = self.hw_addr_len;
-        buffer[3] = self.hops;
-        buffer[4..8].copy_from_slice(&self.transaction_id);
-        buffer[10..12].copy_from_slice(&self.flags);
-        buffer[16..20].copy_from_slice(&self.client_addr);
-        buffer[20..24].copy_from_slice(&self.server_addr);
-        buffer[28..34].copy_from_slice(&self.client_hw_addr);
-        buffer[236..240].copy_from_slice(&Dhcp::MAGIC);
+    #[test]
+    fn relay_agent_info_too_large_to_echo_is_dropped() {
+        // Four sub-options, over 1 KB split into 255 byte pieces (RFC 3396)
+        let relay_info: Vec<u8> = (1..=4)
+            .flat_map(|code| [&[code, 250][..], &[code; 250]].concat())
+            .collect();
+        let info = RelayAgentInfo::try_from(&relay_info[..]).unwrap();
+        let mut data = packet(&[DhcpOption::RelayAgentInformation(info)]);
+        let max_len = Dhcp::DEFAULT_MAX_MESSAGE_SIZE as usize - Dhcp::IP_UDP_HEADER_LEN;
+        let mut buffer = [0u8; MAX_MESSAGE_LEN];
+        let sent = |request: &Dhcp, buffer: &mut [u8]| {
+            let mut reply = request.build_response();
+            reply
+                .options
+                .add(DhcpOption::MessageType(MessageType::Offer));
+            let len = reply.serialiase(buffer, max_len, &DEFAULT_OPTION_ORDER);
+            assert!(len <= max_len);
+            wire::Options::of(&buffer[..len])
+                .map(|(code, _)| code)
+                .collect::<Vec<u8>>()
+        };
+
+        // Only a relay gets option 82 back
+        let request = Dhcp::parse(&data).unwrap();
+        assert_eq!(sent(&request, &mut buffer), [DhcpOption::MESSAGE_TYPE]);
 
-        self.set_options(buffer)
+        data[wire::GIADDR].copy_from_slice(&[10, 0, 0, 1]);
+        let request = Dhcp::parse(&data).unwrap();
+        assert_eq!(sent(&request, &mut buffer), [DhcpOption::MESSAGE_TYPE]);
     }
 
-    fn set_options(&self, buffer: &mut [u8; UDP_BUFFER_SIZE]) -> usize {
-        // Start at 240 (After the magic bytes)
-        let mut option_ptr = 240;
-        // For every option we want
-        for opt in self.options.consume() {
-            if opt.is_none() {
-                continue;
-            }
-            // Take the length so we can dynamically push on our option
-            let len = opt.unwrap().serialise(&mut buffer[option_ptr..]);
-            // Increment the UDP data len
-            option_ptr += len;
+    #[test]
+    fn reused_buffers_carry_nothing_over() {
+        let request = Dhcp::parse(&packet(&[])).unwrap();
+        let mut fresh = [0u8; MAX_MESSAGE_LEN];
+        let fresh_len =
+            request
+                .build_response()
+                .serialiase(&mut fresh, MAX_MESSAGE_LEN, &DEFAULT_OPTION_ORDER);
+
+        // The last reply a thread sent was a PXE one, on garbage
+        let mut reused = [0xa5; MAX_MESSAGE_LEN];
+        let mut pxe = request.build_response();
+        pxe.server_hostname = "boot".to_owned();
+        pxe.file = "ipxe.efi".to_owned();
+        pxe.serialiase(&mut reused, MAX_MESSAGE_LEN, &DEFAULT_OPTION_ORDER);
+
+        let len = request.build_response().serialiase(
+            &mut reused,
+            MAX_MESSAGE_LEN,
+            &DEFAULT_OPTION_ORDER,
+        );
+        assert_eq!(reused[..len], fresh[..fresh_len]);
+    }
+
+    #[test]
+    fn wire_format_round_trips_without_std() {
+        let header = wire::Header {
+            op: Dhcp::REQUEST_OP_CODE,
+            htype: Dhcp::HW_TYPE_ETHERNET,
+            hlen: MacAddr::LEN as u8,
+            xid: [0xde, 0xad, 0xbe, 0xef],
+            secs: [0, 3],
+            giaddr: [10, 0, 0, 1],
+            chaddr: [2, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        let mut buffer = [0xa5; 600];
+        let mut len = header.write(&mut buffer, b"", b"ipxe.efi").unwrap();
+        let long = [7u8; 300];
+        for (code, value) in [(53, &[1][..]), (150, &long[..]), (12, b"pc")] {
+            len += wire::put_option(&mut buffer[len..], code, value).unwrap();
         }
-        // Final Len of the UDP packet
-        option_ptr
+        buffer[len] = wire::END;
+        let packet = &buffer[..len + 1];
+
+        assert_eq!(wire::Header::read(packet), Ok(header));
+        assert_eq!(wire::sname(packet), Some(&b""[..]));
+        assert_eq!(wire::file(packet), Some(&b"ipxe.efi"[..]));
+        assert_eq!(wire::Options::of(packet).get(12), Some(&b"pc"[..]));
+        assert_eq!(
+            wire::Options::of(packet).get(150).map(<[u8]>::len),
+            Some(255)
+        );
+        let mut joined = [0u8; 300];
+        assert_eq!(
+            wire::Options::of(packet).join(150, &mut joined),
+            Some(&long[..])
+        );
+        assert_eq!(wire::Options::of(packet).join(150, &mut [0u8; 299]), None);
+
+        // The same bytes the std parser reads
+        let parsed = Dhcp::parse(packet).unwrap();
+        assert_eq!(parsed.relay_addr, [10, 0, 0, 1]);
+        assert_eq!(
+            parsed.options.get(150),
+            Some(&DhcpOption::Unknown(150, long.to_vec()))
+        );
+
+        assert_eq!(
+            wire::put_option(&mut [0u8; 10], 150, &long),
+            Err(wire::Error::NoRoom)
+        );
+        assert_eq!(
+            wire::Header::read(&packet[..239]),
+            Err(wire::Error::TooShort(239))
+        );
     }
 
-    /// State machine to decide what to do with packet
-    pub fn handle(
-        &self,
-        pool: Arc<Mutex<AddrPool<'dhcp>>>,
-        buffer: &mut [u8; UDP_BUFFER_SIZE],
-    ) -> usize {
-        info!("Recieved {:?}", self.message_type);
-        match self.message_type {
-            MessageType::Discover => {
-                let offer = self.offer(pool);
-                info!("Sending IP Offer: {:?}", offer.client_addr);
-                offer.serialiase(buffer)
-            }
-            MessageType::Request => self.verify(pool).serialiase(buffer),
-            _ => {
-                todo!("{:?}", self.message_type)
+    #[test]
+    fn every_header_field_is_serialised_in_place() {
+        let mut options = DhcpOptionList::builder();
+        options
+            .add(DhcpOption::MessageType(MessageType::Ack))
+            .add(DhcpOption::End);
+        let reply = Dhcp {
+            op_code: Dhcp::REPLY_OP_CODE,
+            hw_addr_ty: Dhcp::HW_TYPE_ETHERNET,
+            hw_addr_len: MacAddr::LEN as u8,
+            hops: 2,
+            transaction_id: [0xde, 0xad, 0xbe, 0xef],
+            secs: [0x01, 0x02],
+            flags: Dhcp::BROADCAST_FLAG,
+            client_addr: [10, 0, 1, 1],
+            your_addr: [10, 0, 1, 2],
+            next_server_addr: [10, 0, 0, 3],
+            relay_addr: [10, 0, 1, 254],
+            client_hw_addr: [2, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            server_hostname: "tftp".to_owned(),
+            file: "pxelinux.0".to_owned(),
+            options,
+            message_type: MessageType::Ack,
+        };
+
+        // As laid out in RFC 2131 2, written out by hand
+        let mut expected = vec![0u8; 240];
+        expected[..4].copy_from_slice(&[2, 1, 6, 2]);
+        expected[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        expected[8..10].copy_from_slice(&[0x01, 0x02]);
+        expected[10..12].copy_from_slice(&[0x80, 0x00]);
+        expected[12..16].copy_from_slice(&[10, 0, 1, 1]);
+        expected[16..20].copy_from_slice(&[10, 0, 1, 2]);
+        expected[20..24].copy_from_slice(&[10, 0, 0, 3]);
+        expected[24..28].copy_from_slice(&[10, 0, 1, 254]);
+        expected[28..34].copy_from_slice(&[2, 0, 0, 0, 0, 1]);
+        expected[44..48].copy_from_slice(b"tftp");
+        expected[108..118].copy_from_slice(b"pxelinux.0");
+        expected[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+        expected.extend_from_slice(&[53, 1, 5, 255]);
+
+        // Whatever the buffer held before is cleared
+        let mut buffer = [0xa5; MAX_MESSAGE_LEN];
+        let len = reply.serialiase(&mut buffer, MAX_MESSAGE_LEN, &DEFAULT_OPTION_ORDER);
+        assert_eq!(buffer[..len], expected[..]);
+    }
+
+    #[test]
+    fn options_view_borrows_unless_joining() {
+        let value: Vec<u8> = (0..260).map(|i| i as u8).collect();
+        let data = packet(&[
+            DhcpOption::RequestedIpAddr([192, 168, 1, 10]),
+            DhcpOption::Unknown(150, value.clone()),
+        ]);
+
+        let options = OptionsView::new(&data);
+        assert_eq!(options.message_type(), Some(MessageType::Discover));
+        assert_eq!(
+            options.requested_ip_addr(),
+            Some(Ipv4Addr::new(192, 168, 1, 10))
+        );
+        assert_eq!(options.server_identifier(), None);
+        assert!(matches!(
+            options.get(DhcpOption::REQUESTED_IP_ADDR),
+            Some(Cow::Borrowed(_))
+        ));
+        assert!(matches!(options.get(150), Some(Cow::Owned(joined)) if joined == value));
+        assert_eq!(options.iter().filter(|(code, _)| *code == 150).count(), 2);
+        assert_eq!(options.truncated(), None);
+
+        // Cut off in the middle of the second instance of 150
+        let cut = OptionsView::new(&data[..510]);
+        assert_eq!(cut.truncated(), Some(150));
+        assert_eq!(cut.message_type(), Some(MessageType::Discover));
+        assert_eq!(cut.get(150).map(|value| value.len()), Some(255));
+    }
+
+    #[test]
+    fn client_fqdn_answers_follow_the_policy() {
+        // Wants to do its own A record, named host.home. on the wire
+        let asked = ClientFqdn::try_from(&b"\x04\x00\x00\x04host\x04home\x00"[..]).unwrap();
+        assert_eq!(asked.name().as_deref(), Some("host.home."));
+
+        let honoured = asked.answer(&ClientFqdn::policy(0));
+        assert_eq!(honoured.to_bytes()[..3], [ClientFqdn::E, 255, 255]);
+        assert_eq!(honoured.server_updates(), "ptr");
+        assert_eq!(honoured.name(), asked.name());
+
+        let overridden = asked.answer(&ClientFqdn::policy(ClientFqdn::S));
+        assert_eq!(
+            overridden.to_bytes()[0],
+            ClientFqdn::S | ClientFqdn::O | ClientFqdn::E
+        );
+        assert_eq!(overridden.server_updates(), "a-and-ptr");
+
+        let nobody = asked.answer(&ClientFqdn::policy(ClientFqdn::N));
+        assert_eq!(
+            nobody.to_bytes()[0],
+            ClientFqdn::N | ClientFqdn::O | ClientFqdn::E
+        );
+        assert_eq!(nobody.server_updates(), "none");
+    }
+
+    #[test]
+    fn message_type_round_trip() {
+        let data = packet(&[]);
+        assert_eq!(
+            Dhcp::parse(&data).unwrap().message_type,
+            MessageType::Discover
+        );
+    }
+
+    /// Options we parse into a typed variant, anything else comes back as
+    /// [DhcpOption::Unknown]
+    const TYPED_OPCODES: [u8; 19] = [
+        DhcpOption::PAD,
+        DhcpOption::HOST_NAME,
+        DhcpOption::REQUESTED_IP_ADDR,
+        DhcpOption::MESSAGE_TYPE,
+        DhcpOption::DHCP_SERVER_IP_ADDR,
+        DhcpOption::PARAMETER_REQUEST_LIST,
+        DhcpOption::MESSAGE,
+        DhcpOption::MAX_MESSAGE_SIZE,
+        DhcpOption::VENDOR_CLASS_ID,
+        DhcpOption::CLIENT_ID,
+        DhcpOption::CLIENT_SYSTEM_ARCH,
+        DhcpOption::CLIENT_NET_DEV_INTERFACE,
+        DhcpOption::CLIENT_UID,
+        DhcpOption::SUBNET_SELECTION,
+        DhcpOption::MUD_URL,
+        DhcpOption::BROADCAST_ADDRESS,
+        DhcpOption::RELAY_AGENT_INFO,
+        DhcpOption::USER_CLASS,
+        DhcpOption::CLIENT_FQDN,
+    ];
+
+    /// Long enough that some values are split across several instances
+    fn value(min: usize) -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(any::<u8>(), min..600)
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        proptest::string::string_regex(".{0,300}").unwrap()
+    }
+
+    fn parameter_request() -> impl Strategy<Value = ParameterRequest> {
+        (1..=u8::MAX).prop_map(ParameterRequest::from)
+    }
+
+    /// Any option a client could send, with a valid value
+    fn client_option() -> impl Strategy<Value = DhcpOption> {
+        prop_oneof![
+            text().prop_map(DhcpOption::HostName),
+            text().prop_map(DhcpOption::Message),
+            text().prop_map(DhcpOption::MudUrl),
+            any::<[u8; 4]>().prop_map(DhcpOption::RequestedIpAddr),
+            any::<[u8; 4]>().prop_map(DhcpOption::DhcpServerIpAddr),
+            any::<[u8; 4]>().prop_map(DhcpOption::SubnetSelection),
+            any::<[u8; 4]>().prop_map(DhcpOption::BroadcastAddress),
+            any::<u16>().prop_map(DhcpOption::MaxMessageSize),
+            proptest::collection::vec(
+                parameter_request(),
+                DhcpOption::MIN_PARAMETER_REQUEST_LEN as usize
+                    ..=DhcpOption::MAX_PARAMETER_REQUEST_LIST_LEN as usize
+            )
+            .prop_map(DhcpOption::ParameterRequestList),
+            value(0).prop_map(DhcpOption::VendorClassIndentifier),
+            value(ClientIdentifier::MIN_LEN).prop_map(|id| {
+                DhcpOption::ClientIdentifier(ClientIdentifier::try_from(&id[..]).unwrap())
+            }),
+            any::<[u8; 2]>().prop_map(DhcpOption::ClientSystemArch),
+            any::<[u8; 3]>().prop_map(DhcpOption::ClientNetworkDeviceInterface),
+            value(DhcpOption::MIN_CLIENT_UID_LEN as usize).prop_map(DhcpOption::ClientUid),
+            value(0).prop_map(|class| DhcpOption::UserClass(class[..].into())),
+            value(3).prop_map(|fqdn| {
+                DhcpOption::ClientFqdn(ClientFqdn::try_from(&fqdn[..]).unwrap())
+            }),
+            proptest::collection::vec(
+                (any::<u8>(), proptest::collection::vec(any::<u8>(), 0..=255)),
+                1..4
+            )
+            .prop_map(|sub_options| {
+                let value: Vec<u8> = sub_options
+                    .iter()
+                    .flat_map(|(code, value)| [&[*code, value.len() as u8][..], value].concat())
+                    .collect();
+                DhcpOption::RelayAgentInformation(RelayAgentInfo::try_from(&value[..]).unwrap())
+            }),
+            (any::<u8>(), value(0))
+                .prop_filter("untyped opcode", |(code, _)| {
+                    !TYPED_OPCODES.contains(code) && *code != DhcpOption::END
+                })
+                .prop_map(|(code, value)| DhcpOption::Unknown(code, value)),
+        ]
+    }
+
+    /// Options only we send, most come back as [DhcpOption::Unknown]
+    fn server_option() -> impl Strategy<Value = DhcpOption> {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(DhcpOption::SubnetMask),
+            any::<[u8; 4]>().prop_map(DhcpOption::Router),
+            any::<[u8; 4]>().prop_map(DhcpOption::DomainNameServer),
+            any::<i32>().prop_map(DhcpOption::TimeOffset),
+            any::<u16>().prop_map(DhcpOption::InterfaceMtu),
+            proptest::collection::vec(any::<[u8; 4]>(), 1..80).prop_map(DhcpOption::TimeServer),
+            proptest::collection::vec(any::<[u8; 4]>(), 1..80).prop_map(DhcpOption::NtpServers),
+            proptest::collection::vec(any::<[u8; 4]>(), 1..80)
+                .prop_map(DhcpOption::NetBiosNameServer),
+            any::<u8>().prop_map(DhcpOption::NetBiosNodeType),
+            text().prop_map(DhcpOption::NetBiosScope),
+            any::<u16>().prop_map(DhcpOption::BootFileSize),
+            any::<u32>().prop_map(DhcpOption::LeaseTime),
+            text().prop_map(DhcpOption::DomainName),
+            text().prop_map(DhcpOption::TftpServerName),
+            text().prop_map(DhcpOption::BootFileName),
+            text().prop_map(DhcpOption::RootPath),
+            text().prop_map(DhcpOption::ExtensionsPath),
+            any::<u32>().prop_map(DhcpOption::Ipv6OnlyPreferred),
+            text().prop_map(DhcpOption::CaptivePortal),
+            text().prop_map(DhcpOption::ProxyAutodiscovery),
+            proptest::collection::vec("[a-z]{1,10}(\\.[a-z]{1,10}){0,3}", 1..10)
+                .prop_map(DhcpOption::DomainSearch),
+        ]
+    }
+
+    fn any_option() -> impl Strategy<Value = DhcpOption> {
+        prop_oneof![
+            client_option(),
+            server_option(),
+            Just(DhcpOption::Pad),
+            Just(DhcpOption::End),
+            (1..=9u8).prop_map(|code| DhcpOption::MessageType(code.into())),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn client_options_round_trip(option in client_option()) {
+            typed_round_trip(option);
+        }
+
+        #[test]
+        fn server_options_keep_their_bytes(option in server_option()) {
+            round_trip(option);
+        }
+
+        #[test]
+        fn serialise_writes_only_what_it_reports(option in any_option()) {
+            // Two fills, so a byte that happens to match one is still caught
+            for fill in [0x00, 0xff] {
+                let mut buffer = [fill; MAX_MESSAGE_LEN];
+                let len = option.serialise(&mut buffer);
+                prop_assert!(buffer[len..].iter().all(|byte| *byte == fill), "{option:?}");
+
+                // The instances we wrote must add up to exactly len
+                if matches!(option, DhcpOption::Pad | DhcpOption::End) {
+                    prop_assert_eq!(len, 1);
+                    continue;
+                }
+                let mut ptr = 0;
+                while ptr < len {
+                    prop_assert_eq!(buffer[ptr], option.opcode());
+                    ptr += buffer[ptr + 1] as usize + 2;
+                }
+                prop_assert_eq!(ptr, len);
             }
         }
     }
@@ -1,13 +1,168 @@
 //! Our custom error handler that we use to wrap errors and give them a more
 //! readable error message
 
+/// WSAEMSGSIZE, Windows fails the recv rather than silently truncating. Unix
+/// truncates without an error, see [crate::serve] for how we notice there
+#[cfg(windows)]
 pub const RECV_DATA_LARGER_THAN_BUFFER: i32 = 10040;
 
+/// The ways a recv on our socket can fail, mapped from the platform specific
+/// error codes so the caller does not need to care which OS we are on
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The read timeout expired without a packet arriving
+    Timeout,
+
+    /// We were interrupted by a signal before a packet arrived
+    Interrupted,
+
+    /// The packet was larger than our buffer
+    Truncated,
+
+    /// A previous send was rejected with an ICMP port unreachable
+    ConnectionReset,
+
+    /// Anything else, these are unexpected
+    Other,
+}
+
+impl From<&std::io::Error> for RecvError {
+    fn from(error: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        #[cfg(windows)]
+        if error.raw_os_error() == Some(RECV_DATA_LARGER_THAN_BUFFER) {
+            return Self::Truncated;
+        }
+
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::Timeout,
+            ErrorKind::Interrupted => Self::Interrupted,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused => Self::ConnectionReset,
+            _ => Self::Other,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// Failed to bind to the requested [super::BIND_ADDRESS]:[super::SERVER_PORT]
     CannotBindToAddress(std::io::Error),
 
+    /// We do not recognise this command line argument
+    InvalidArgument(String),
+
+    /// This command line argument needs a value after it
+    MissingArgumentValue(String),
+
+    /// The value given for this command line argument is not valid
+    InvalidArgumentValue(String, String),
+
+    /// The config file could not be read
+    CannotReadConfig(std::io::Error),
+
+    /// This line of the config file is not valid
+    InvalidConfigLine(usize, String),
+
+    /// The config file is missing a required key
+    MissingConfigKey(&'static str),
+
+    /// `--check-config` found this many errors
+    InvalidConfig(usize),
+
+    /// The user to drop privileges to does not exist
+    UnknownUser(String),
+
+    /// The group to drop privileges to does not exist
+    UnknownGroup(String),
+
+    /// A chroot, setuid or setgid call failed
+    DropPrivileges(std::io::Error),
+
+    /// We managed to setuid back to root after dropping privileges
+    PrivilegesRegained,
+
+    /// Not six hex octets separated by `:` or `-`
+    InvalidMacAddr(String),
+
+    /// Not 32 hex digits, optionally grouped by `-`
+    InvalidUuid(String),
+
+    /// Not `circuit-id:` or `remote-id:` and an id that fits a sub-option
+    InvalidRelayId(String),
+
+    /// A range runs backwards or leaves the subnet
+    InvalidRange(std::net::Ipv4Addr, std::net::Ipv4Addr),
+
+    /// The ranges starting at these addresses overlap
+    OverlappingRanges(std::net::Ipv4Addr, std::net::Ipv4Addr),
+
+    /// We have no range or exclusion from this address to that
+    UnknownRange(std::net::Ipv4Addr, std::net::Ipv4Addr),
+
+    /// A new config serves another subnet, which takes a restart
+    SubnetChanged(std::net::Ipv4Addr, std::net::Ipv4Addr),
+
+    /// The address is outside the subnet, excluded or already reserved, or
+    /// the client already has a reservation
+    InvalidReservation(crate::types::MacAddr, std::net::Ipv4Addr),
+
+    /// As [Error::InvalidReservation] for a machine by SMBIOS UUID
+    InvalidUuidReservation(crate::types::Uuid, std::net::Ipv4Addr),
+
+    /// As [Error::InvalidReservation] for clients behind a relay circuit or
+    /// subscriber line
+    InvalidRelayReservation(crate::types::RelayId, std::net::Ipv4Addr),
+
+    /// The lease file exists but could not be read
+    CannotReadLeases(std::io::Error),
+
+    /// The lease file could not be written
+    CannotWriteLeases(std::io::Error),
+
+    /// This line of a lease file is not valid
+    InvalidLeaseLine(usize, String),
+
+    /// The audit file exists but could not be read
+    CannotReadAudit(std::io::Error),
+
+    /// A record could not be appended to the audit file
+    CannotWriteAudit(std::io::Error),
+
+    /// This line of the audit file is not valid
+    InvalidAuditLine(usize, String),
+
+    /// Our socket could not be tied to this interface
+    CannotBindToInterface(String, std::io::Error),
+
+    /// The lease store failed us or could not be reached
+    LeaseStore(std::io::Error),
+
+    /// We could not connect to the syslog daemon or journal
+    CannotOpenLog(std::io::Error),
+
+    /// The capture to replay or packet to decode could not be read
+    CannotReadCapture(std::io::Error),
+
+    /// The capture to replay is not a pcap file we understand
+    InvalidCapture(&'static str),
+
+    /// We only replay captures of Ethernet, Linux cooked or raw IP frames
+    UnsupportedLinkType(u32),
+
+    /// We could not listen for admin connections
+    CannotBindAdmin(std::io::Error),
+
+    /// We could not listen for our failover peer
+    Failover(std::io::Error),
+
+    /// Any other I/O failure, prefer a variant that says what we were doing
+    Io(std::io::Error),
+
+    /// A call to the Windows service control manager failed
+    #[cfg(windows)]
+    WindowsService(std::io::Error),
+
     /// Too short to be a DHCP packet
     PayloadTooShort(usize),
 
@@ -17,11 +172,22 @@ pub enum Error {
     /// Missing the DHCP magic bytes at 236..240
     DhcpMagicMissing,
 
+    /// What we were writing does not fit the buffer
+    NoRoomInBuffer,
+
+    /// hlen is longer than chaddr, or not 6 for a MAC hardware type
+    InvalidHwAddrLen(u8, u8),
+
+    /// The client has no chaddr and did not send option 61, so we cannot
+    /// tell it apart from others of this hardware type
+    MissingClientIdentifier(u8),
+
     /// A Dhcp message must contain a message type
     NoMessageDhcpTypeProvided,
 
-    /// The Dhcp Option is not handled
-    InvalidDhcpOptionMessageType(u8),
+    /// Relayed more times than any real network needs, most likely looping
+    /// between relay agents
+    TooManyHops(u8),
 
     /// DHCP Option Length index is out of bounds
     DhcpOptionLenOutOfBounds,
@@ -35,11 +201,10 @@ pub enum Error {
     /// The minimum allowed is 1 byte
     InvalidParameterRequestLen(u8),
 
-    /// We set a limit in [crate::dhcp::MAX_PARAMETER_REQUEST_LIST_LEN]
+    /// We set a limit in [crate::types::DhcpOption::MAX_PARAMETER_REQUEST_LIST_LEN]
     UnsupportedRequestedParameters(u8),
 
-    /// Limits set in [crate::dhcp::DhcpOption::MIN_CLIENT_UID_LEN] and
-    /// [crate::dhcp::DhcpOption::MAX_CLIENT_UID_LEN]
+    /// Shorter than [crate::types::DhcpOption::MIN_CLIENT_UID_LEN]
     InvalidClientUidLen(u8),
 
     /// Expected to be 3 bytes
@@ -48,11 +213,18 @@ pub enum Error {
     /// Expected to be 2 bytes
     InvalidClientSystemArchLen(u8),
 
-    /// We only support ethernet 0x1
-    UnsupportedClientIdHwType(u8),
+    /// Shorter than [crate::types::ClientIdentifier::MIN_LEN]
+    InvalidClientIdLen(usize),
 
-    /// Expected to be 32 bytes
-    InvalidVendorClassIdentifierLen(u8),
+    /// Option 82 is empty or a sub-option runs past its end
+    InvalidRelayAgentInfo,
+
+    /// Option 81 is too short for its flags and rcodes
+    InvalidClientFqdnLen(usize),
+
+    /// A configured option value could not be encoded, see
+    /// [crate::types::DhcpOption::from_code_value]
+    InvalidOptionValue(u8, String),
 
     /// IP Addresses can only be 4 bytes
     InvalidIpAddrLen(u8),
@@ -60,9 +232,237 @@ pub enum Error {
     /// The Server has no IP addresses left to assign
     AllIPAddressesExhausted,
 
+    /// Clients on the relay circuit already hold this many leases
+    CircuitLeaseLimit(usize),
+
     /// The DHCP request did not contain a requested IP Address
     RequestedIpAddrOptionMissing,
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CannotBindToAddress(error) => write!(
+                f,
+                "cannot bind to {}:{}: {error}",
+                super::BIND_ADDRESS,
+                super::SERVER_PORT
+            ),
+            Self::InvalidArgument(arg) => write!(f, "unrecognised argument {arg}"),
+            Self::MissingArgumentValue(arg) => write!(f, "{arg} needs a value"),
+            Self::InvalidArgumentValue(arg, value) => {
+                write!(f, "{value:?} is not a valid value for {arg}")
+            }
+            Self::CannotReadConfig(error) => write!(f, "cannot read the config file: {error}"),
+            Self::InvalidConfigLine(line, text) => {
+                write!(f, "line {line} of the config file is not valid: {text}")
+            }
+            Self::MissingConfigKey(key) => write!(f, "the config file needs a {key}"),
+            Self::InvalidConfig(errors) => write!(f, "the config has {errors} errors"),
+            Self::UnknownUser(user) => write!(f, "there is no user {user}"),
+            Self::UnknownGroup(group) => write!(f, "there is no group {group}"),
+            Self::DropPrivileges(error) => write!(f, "cannot drop privileges: {error}"),
+            Self::PrivilegesRegained => {
+                write!(f, "still able to become root after dropping privileges")
+            }
+            Self::InvalidMacAddr(mac) => write!(f, "{mac:?} is not a MAC address"),
+            Self::InvalidUuid(uuid) => write!(f, "{uuid:?} is not a UUID"),
+            Self::InvalidRelayId(id) => write!(f, "{id:?} is not a circuit-id or remote-id"),
+            Self::InvalidRange(start, end) => {
+                write!(
+                    f,
+                    "the range {start} to {end} runs backwards or leaves the subnet"
+                )
+            }
+            Self::OverlappingRanges(first, second) => {
+                write!(f, "the ranges starting at {first} and {second} overlap")
+            }
+            Self::UnknownRange(start, end) => write!(f, "there is no range {start} to {end}"),
+            Self::SubnetChanged(from, to) => {
+                write!(f, "moving from subnet {from} to {to} needs a restart")
+            }
+            Self::InvalidReservation(mac, ip_addr) => write!(
+                f,
+                "cannot reserve {ip_addr} for {mac}, it is outside the subnet, excluded or \
+                 taken, or {mac} already has a reservation"
+            ),
+            Self::InvalidUuidReservation(uuid, ip_addr) => write!(
+                f,
+                "cannot reserve {ip_addr} for {uuid}, it is outside the subnet, excluded or \
+                 taken, or {uuid} already has a reservation"
+            ),
+            Self::InvalidRelayReservation(relay_id, ip_addr) => write!(
+                f,
+                "cannot reserve {ip_addr} for {relay_id}, it is outside the subnet, excluded or \
+                 taken, or {relay_id} already has a reservation"
+            ),
+            Self::CannotReadLeases(error) => write!(f, "cannot read the lease file: {error}"),
+            Self::CannotWriteLeases(error) => write!(f, "cannot write the lease file: {error}"),
+            Self::InvalidLeaseLine(line, text) => {
+                write!(f, "line {line} of the lease file is not valid: {text}")
+            }
+            Self::CannotReadAudit(error) => write!(f, "cannot read the audit file: {error}"),
+            Self::CannotWriteAudit(error) => write!(f, "cannot write the audit file: {error}"),
+            Self::InvalidAuditLine(line, text) => {
+                write!(f, "line {line} of the audit file is not valid: {text}")
+            }
+            Self::CannotBindToInterface(interface, error) => {
+                write!(f, "cannot serve on interface {interface}: {error}")
+            }
+            Self::LeaseStore(error) => write!(f, "lease store: {error}"),
+            Self::CannotOpenLog(error) => write!(f, "cannot connect to the log target: {error}"),
+            Self::CannotReadCapture(error) => write!(f, "cannot read the capture: {error}"),
+            Self::InvalidCapture(reason) => write!(f, "the capture is not valid, {reason}"),
+            Self::UnsupportedLinkType(link_type) => write!(
+                f,
+                "captures with link type {link_type} are not supported, capture on an \
+                 Ethernet interface or with -i any"
+            ),
+            Self::CannotBindAdmin(error) => write!(f, "cannot listen for admin: {error}"),
+            Self::Failover(error) => write!(f, "cannot listen for our failover peer: {error}"),
+            Self::Io(error) => error.fmt(f),
+            #[cfg(windows)]
+            Self::WindowsService(error) => write!(f, "service control manager: {error}"),
+            Self::PayloadTooShort(len) => {
+                write!(f, "{len} bytes is too short for a DHCP packet")
+            }
+            Self::NotADhcpRequest(op) => write!(f, "op {op} is not a BOOTREQUEST"),
+            Self::DhcpMagicMissing => write!(f, "no DHCP magic cookie"),
+            Self::NoRoomInBuffer => write!(f, "no room left in the buffer"),
+            Self::InvalidHwAddrLen(htype, hlen) => {
+                write!(f, "hlen {hlen} is not valid for hardware type {htype}")
+            }
+            Self::MissingClientIdentifier(htype) => write!(
+                f,
+                "no chaddr or client identifier to tell this hardware type {htype} client apart"
+            ),
+            Self::NoMessageDhcpTypeProvided => write!(f, "no DHCP message type"),
+            Self::TooManyHops(hops) => write!(f, "relayed {hops} times, more than we accept"),
+            Self::DhcpOptionLenOutOfBounds => write!(f, "an option runs past the end"),
+            Self::MessageTypeBadLen(len) => {
+                write!(f, "the message type is {len} bytes, not 1")
+            }
+            Self::MaxMessageSizeBadLen(len) => {
+                write!(f, "the max message size is {len} bytes, not 2")
+            }
+            Self::InvalidParameterRequestLen(len) => {
+                write!(
+                    f,
+                    "the parameter request list is {len} bytes, at least 1 is needed"
+                )
+            }
+            Self::UnsupportedRequestedParameters(len) => write!(
+                f,
+                "{len} requested parameters is more than our limit of {}",
+                crate::types::DhcpOption::MAX_PARAMETER_REQUEST_LIST_LEN
+            ),
+            Self::InvalidClientUidLen(len) => write!(
+                f,
+                "the client UID is {len} bytes, at least {} are needed",
+                crate::types::DhcpOption::MIN_CLIENT_UID_LEN
+            ),
+            Self::InvalidClientNetworkDeviceInterfaceLen(len) => write!(
+                f,
+                "the client network device interface is {len} bytes, not 3"
+            ),
+            Self::InvalidClientSystemArchLen(len) => {
+                write!(f, "the client system architecture is {len} bytes, not 2")
+            }
+            Self::InvalidClientIdLen(len) => write!(
+                f,
+                "the client identifier is {len} bytes, at least {} are needed",
+                crate::types::ClientIdentifier::MIN_LEN
+            ),
+            Self::InvalidRelayAgentInfo => {
+                write!(f, "the relay agent information is empty or cut short")
+            }
+            Self::InvalidClientFqdnLen(len) => {
+                write!(f, "the client FQDN is {len} bytes, at least 3 are needed")
+            }
+            Self::InvalidOptionValue(code, value) => {
+                write!(f, "{value:?} is not a valid value for option {code}")
+            }
+            Self::InvalidIpAddrLen(len) => write!(f, "an address is {len} bytes, not 4"),
+            Self::AllIPAddressesExhausted => write!(f, "every address is in use"),
+            Self::CircuitLeaseLimit(max) => {
+                write!(f, "the circuit already holds its limit of {max} leases")
+            }
+            Self::RequestedIpAddrOptionMissing => write!(f, "no requested address"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::CannotBindToAddress(error)
+            | Self::CannotReadConfig(error)
+            | Self::DropPrivileges(error)
+            | Self::CannotReadLeases(error)
+            | Self::CannotWriteLeases(error)
+            | Self::CannotReadAudit(error)
+            | Self::CannotWriteAudit(error)
+            | Self::CannotBindToInterface(_, error)
+            | Self::LeaseStore(error)
+            | Self::CannotOpenLog(error)
+            | Self::CannotReadCapture(error)
+            | Self::CannotBindAdmin(error)
+            | Self::Failover(error)
+            | Self::Io(error) => Some(error),
+            #[cfg(windows)]
+            Self::WindowsService(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<crate::wire::Error> for Error {
+    fn from(error: crate::wire::Error) -> Self {
+        use crate::wire::Error as WireError;
+
+        match error {
+            WireError::TooShort(len) => Self::PayloadTooShort(len),
+            WireError::MagicMissing => Self::DhcpMagicMissing,
+            WireError::NoRoom => Self::NoRoomInBuffer,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
 /// Our custom Error type, we wrap all library errors inside our [Error]
 pub type Result<T> = std::result::Result<T, self::Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error as IoError, ErrorKind};
+
+    #[test]
+    fn recv_errors_map_the_same_on_every_platform() {
+        let recv_error = |kind: ErrorKind| RecvError::from(&IoError::from(kind));
+        assert_eq!(recv_error(ErrorKind::WouldBlock), RecvError::Timeout);
+        assert_eq!(recv_error(ErrorKind::TimedOut), RecvError::Timeout);
+        assert_eq!(recv_error(ErrorKind::Interrupted), RecvError::Interrupted);
+        assert_eq!(
+            recv_error(ErrorKind::ConnectionReset),
+            RecvError::ConnectionReset
+        );
+        assert_eq!(
+            recv_error(ErrorKind::ConnectionRefused),
+            RecvError::ConnectionReset
+        );
+        assert_eq!(recv_error(ErrorKind::PermissionDenied), RecvError::Other);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_reports_datagrams_larger_than_the_buffer() {
+        let error = IoError::from_raw_os_error(RECV_DATA_LARGER_THAN_BUFFER);
+        assert_eq!(RecvError::from(&error), RecvError::Truncated);
+    }
+}
@@ -0,0 +1,518 @@
+//! Just enough of an etcd v3 client for our config and leases, through the
+//! JSON gateway etcd serves on its client port. Keys and values are base64 in
+//! the JSON, 64 bit numbers are strings. We talk plain HTTP, TLS is left to a
+//! local proxy as for our webhooks.
+//!
+//! A URL lists every member of the cluster, `etcd://host[:port][,host[:port]...][/key]`,
+//! and we move on to the next member when one does not answer so we keep
+//! working through the loss of a node.
+
+use log::{info, warn};
+
+use crate::json::{self, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 2379;
+
+/// Give up on a member that does not answer, we may hold the pool meanwhile
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A watch is quiet until something changes, reconnect this often anyway in
+/// case the connection died without us hearing about it
+const WATCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long we wait before watching again after losing every member
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A key as we read it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// The revision of the cluster when the key was last written
+    pub mod_revision: i64,
+}
+
+/// The outcome of [Etcd::put_if]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Put {
+    Done,
+
+    /// The key was not as expected, this is what it holds now
+    Conflict(Option<KeyValue>),
+}
+
+/// The members of a cluster, see the module docs
+#[derive(Debug, Clone)]
+pub struct Etcd {
+    /// `host:port` of each member
+    members: Vec<String>,
+    /// The member that last answered, we try it first
+    current: usize,
+}
+
+impl Etcd {
+    /// Parse an `etcd://` URL into the cluster and the path after it, which
+    /// is empty when there is none
+    pub fn parse(url: &str) -> Option<(Self, &str)> {
+        let rest = url.strip_prefix("etcd://")?;
+        let (hosts, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], rest[index + 1..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let members = hosts
+            .split(',')
+            .map(|host| match host {
+                "" => None,
+                host if host.contains(':') => Some(host.to_owned()),
+                host => Some(format!("{host}:{DEFAULT_PORT}")),
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((
+            Self {
+                members,
+                current: 0,
+            },
+            path,
+        ))
+    }
+
+    /// The key, or [None] when it does not exist
+    pub fn get(&mut self, key: &str) -> io::Result<Option<KeyValue>> {
+        let body = format!("{{\"key\": {}}}", json::string(&base64(key.as_bytes())));
+        let reply = self.call("/v3/kv/range", &body)?;
+        Ok(kvs(&reply)?.pop())
+    }
+
+    /// Every key starting with `prefix`
+    pub fn get_prefix(&mut self, prefix: &str) -> io::Result<Vec<KeyValue>> {
+        let body = format!(
+            "{{\"key\": {}, \"range_end\": {}}}",
+            json::string(&base64(prefix.as_bytes())),
+            json::string(&base64(&prefix_end(prefix.as_bytes())))
+        );
+        kvs(&self.call("/v3/kv/range", &body)?)
+    }
+
+    pub fn put(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.call("/v3/kv/put", &put_request(key, value))
+            .map(|_| ())
+    }
+
+    /// Write the key only if it was last written at `mod_revision`, 0 means
+    /// only if it does not exist
+    pub fn put_if(&mut self, key: &str, value: &[u8], mod_revision: i64) -> io::Result<Put> {
+        let reply = self.call("/v3/kv/txn", &put_if_request(key, value, mod_revision))?;
+        put_if_outcome(&reply)
+    }
+
+    /// Call `changed` with the value of `key` whenever it is written after
+    /// `revision`, in the background
+    pub fn watch(
+        mut self,
+        key: String,
+        mut revision: i64,
+        changed: impl Fn(&[u8]) + Send + 'static,
+    ) {
+        thread::spawn(move || loop {
+            if let Err(error) = self.watch_once(&key, &mut revision, &changed) {
+                warn!("Lost our watch on etcd key {key}: {error}");
+                thread::sleep(RETRY_INTERVAL);
+            }
+        });
+    }
+
+    /// Catch up with anything we missed since `revision` then follow the key
+    /// until the connection drops
+    fn watch_once(
+        &mut self,
+        key: &str,
+        revision: &mut i64,
+        changed: &impl Fn(&[u8]),
+    ) -> io::Result<()> {
+        if let Some(kv) = self.get(key)?.filter(|kv| kv.mod_revision > *revision) {
+            *revision = kv.mod_revision;
+            changed(&kv.value);
+        }
+
+        let body = format!(
+            "{{\"create_request\": {{\"key\": {}, \"start_revision\": \"{}\"}}}}",
+            json::string(&base64(key.as_bytes())),
+            *revision + 1
+        );
+        let (mut stream, member) = self.send("/v3/watch", &body)?;
+        stream.get_ref().set_read_timeout(Some(WATCH_TIMEOUT))?;
+        if !expect_ok(&mut stream)? {
+            return Err(invalid("a watch that is not streamed"));
+        }
+        info!("Watching etcd key {key} on {member}");
+
+        // One JSON message per line, spread over chunks as etcd sees fit
+        let mut pending = Vec::new();
+        while let Some(chunk) = read_chunk(&mut stream)? {
+            pending.extend_from_slice(&chunk);
+            while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let message = parse(&line)?;
+                let result = message.get("result").ok_or_else(|| {
+                    invalid(&format!("watch failed: {}", String::from_utf8_lossy(&line)))
+                })?;
+                for event in result.get("events").map(Value::items).unwrap_or_default() {
+                    // Deletes leave us with what we had
+                    if event.get("type").and_then(Value::as_str) == Some("DELETE") {
+                        continue;
+                    }
+                    if let Some(kv) = event.get("kv").map(key_value).transpose()? {
+                        *revision = kv.mod_revision;
+                        changed(&kv.value);
+                    }
+                }
+            }
+        }
+        Err(io::ErrorKind::UnexpectedEof.into())
+    }
+
+    /// POST `body` to `path` on the first member that answers
+    fn call(&mut self, path: &str, body: &str) -> io::Result<Value> {
+        let (mut stream, _) = self.send(path, body)?;
+        let chunked = expect_ok(&mut stream)?;
+        let body = read_body(&mut stream, chunked)?;
+        parse(&body)
+    }
+
+    fn send(&mut self, path: &str, body: &str) -> io::Result<(BufReader<TcpStream>, String)> {
+        let mut last_error = io::Error::from(io::ErrorKind::NotFound);
+        for attempt in 0..self.members.len() {
+            let index = (self.current + attempt) % self.members.len();
+            let member = &self.members[index];
+            match connect(member).and_then(|mut stream| {
+                write!(
+                    stream,
+                    "POST {path} HTTP/1.1\r\nHost: {member}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )?;
+                Ok(stream)
+            }) {
+                Ok(stream) => {
+                    if index != self.current {
+                        info!("Using etcd member {member}");
+                        self.current = index;
+                    }
+                    return Ok((BufReader::new(stream), member.clone()));
+                }
+                Err(error) => {
+                    warn!("etcd member {member} did not answer: {error}");
+                    last_error = error;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn connect(member: &str) -> io::Result<TcpStream> {
+    let addr = member
+        .to_socket_addrs()?
+        .next()
+        .ok_or(io::ErrorKind::NotFound)?;
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+fn put_request(key: &str, value: &[u8]) -> String {
+    format!(
+        "{{\"key\": {}, \"value\": {}}}",
+        json::string(&base64(key.as_bytes())),
+        json::string(&base64(value))
+    )
+}
+
+/// A transaction putting the key if it was last written at `mod_revision`,
+/// reading it back if not
+fn put_if_request(key: &str, value: &[u8], mod_revision: i64) -> String {
+    let key64 = json::string(&base64(key.as_bytes()));
+    let compare = match mod_revision {
+        0 => "\"target\": \"CREATE\", \"create_revision\": \"0\"".to_owned(),
+        revision => format!("\"target\": \"MOD\", \"mod_revision\": \"{revision}\""),
+    };
+    format!(
+        "{{\"compare\": [{{\"key\": {key64}, \"result\": \"EQUAL\", {compare}}}], \
+         \"success\": [{{\"request_put\": {}}}], \
+         \"failure\": [{{\"request_range\": {{\"key\": {key64}}}}}]}}",
+        put_request(key, value)
+    )
+}
+
+/// What became of a [put_if_request]
+fn put_if_outcome(reply: &Value) -> io::Result<Put> {
+    // Protobuf leaves false out
+    if reply.get("succeeded").and_then(Value::as_bool) == Some(true) {
+        return Ok(Put::Done);
+    }
+    let range = reply
+        .get("responses")
+        .and_then(|responses| responses.items().first())
+        .and_then(|response| response.get("response_range"))
+        .ok_or_else(|| invalid("a transaction without its range"))?;
+    Ok(Put::Conflict(kvs(range)?.pop()))
+}
+
+/// The first key after every key starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // All of them, etcd's convention for no end
+    vec![0]
+}
+
+/// The `kvs` of a range response
+fn kvs(range: &Value) -> io::Result<Vec<KeyValue>> {
+    range
+        .get("kvs")
+        .map(Value::items)
+        .unwrap_or_default()
+        .iter()
+        .map(key_value)
+        .collect()
+}
+
+fn key_value(kv: &Value) -> io::Result<KeyValue> {
+    let field = |name| {
+        kv.get(name)
+            .and_then(Value::as_str)
+            .and_then(unbase64)
+            .ok_or_else(|| invalid("a key without its name or value"))
+    };
+    Ok(KeyValue {
+        key: String::from_utf8_lossy(&field("key")?).into_owned(),
+        // Protobuf leaves an empty value out
+        value: field("value").unwrap_or_default(),
+        mod_revision: kv
+            .get("mod_revision")
+            .and_then(Value::as_i64)
+            .unwrap_or_default(),
+    })
+}
+
+fn parse(body: &[u8]) -> io::Result<Value> {
+    std::str::from_utf8(body)
+        .ok()
+        .and_then(json::parse)
+        .ok_or_else(|| invalid(&format!("not JSON: {}", String::from_utf8_lossy(body))))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("etcd sent {what}"))
+}
+
+/// Read the status line and headers, failing unless the status is 200.
+/// True when the body is chunked
+fn expect_ok(stream: &mut impl BufRead) -> io::Result<bool> {
+    let mut status = String::new();
+    stream.read_line(&mut status)?;
+    let status = status.trim().to_owned();
+    let mut chunked = false;
+    let mut line = String::new();
+    while stream.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            chunked |= name.eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked");
+        }
+        line.clear();
+    }
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(chunked),
+        _ => {
+            let body = read_body(stream, chunked).unwrap_or_default();
+            Err(io::Error::other(format!(
+                "etcd answered {status}: {}",
+                String::from_utf8_lossy(&body).trim()
+            )))
+        }
+    }
+}
+
+/// The rest of the response, we always ask etcd to close the connection
+fn read_body(stream: &mut impl BufRead, chunked: bool) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    match chunked {
+        true => {
+            while let Some(chunk) = read_chunk(stream)? {
+                body.extend_from_slice(&chunk);
+            }
+        }
+        false => _ = stream.read_to_end(&mut body)?,
+    }
+    Ok(body)
+}
+
+/// One chunk of a chunked body, [None] at the end
+fn read_chunk(stream: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut size = String::new();
+    if stream.read_line(&mut size)? == 0 {
+        return Ok(None);
+    }
+    let size = size.split(';').next().unwrap_or_default().trim();
+    let size = usize::from_str_radix(size, 16).map_err(|_| invalid("a bad chunk size"))?;
+    if size == 0 {
+        return Ok(None);
+    }
+    let mut chunk = vec![0u8; size + 2];
+    stream.read_exact(&mut chunk)?;
+    chunk.truncate(size);
+    Ok(Some(chunk))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Keys and values as the JSON gateway wants them
+pub fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (index, byte)| {
+            bits | (*byte as u32) << (16 - index * 8)
+        });
+        for index in 0..4 {
+            match index <= group.len() {
+                true => encoded.push(BASE64[(bits >> (18 - index * 6)) as usize & 0x3f] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .trim_end_matches('=')
+        .bytes()
+        .map(|byte| {
+            BASE64
+                .iter()
+                .position(|digit| *digit == byte)
+                .map(|d| d as u32)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let mut decoded = Vec::with_capacity(digits.len() * 3 / 4);
+    for group in digits.chunks(4) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (index, digit)| {
+            bits | digit << (18 - index * 6)
+        });
+        for index in 0..group.len().saturating_sub(1) {
+            decoded.push((bits >> (16 - index * 8)) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_list_every_member() {
+        let (etcd, path) = Etcd::parse("etcd://10.0.0.1,10.0.0.2:2380/dhc3po/config/").unwrap();
+        assert_eq!(etcd.members, ["10.0.0.1:2379", "10.0.0.2:2380"]);
+        assert_eq!(path, "dhc3po/config");
+        assert_eq!(Etcd::parse("etcd://etcd").unwrap().1, "");
+        assert!(Etcd::parse("etcd://10.0.0.1,,10.0.0.2").is_none());
+        assert!(Etcd::parse("http://etcd").is_none());
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\x00\xfe", "/wD+"),
+        ] {
+            assert_eq!(base64(data), encoded);
+            assert_eq!(unbase64(encoded).as_deref(), Some(data));
+        }
+        let every_byte: Vec<u8> = (0..=u8::MAX).collect();
+        assert_eq!(unbase64(&base64(&every_byte)), Some(every_byte));
+        assert_eq!(unbase64("Zm9v!"), None);
+    }
+
+    #[test]
+    fn prefixes_end_after_their_last_key() {
+        assert_eq!(prefix_end(b"dhc3po/"), b"dhc3po0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+        assert_eq!(prefix_end(b"\xff"), [0]);
+    }
+
+    #[test]
+    fn chunked_bodies_are_joined() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let mut stream = &response[..];
+        let chunked = expect_ok(&mut stream).unwrap();
+        assert!(chunked);
+        assert_eq!(read_body(&mut stream, chunked).unwrap(), b"hello, world");
+
+        let mut stream = &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}"[..];
+        let chunked = expect_ok(&mut stream).unwrap();
+        assert!(!chunked);
+        assert_eq!(read_body(&mut stream, chunked).unwrap(), b"{}");
+
+        let mut stream = &b"zz\r\nhello\r\n"[..];
+        assert!(read_chunk(&mut stream).is_err());
+        let mut stream = &b"HTTP/1.1 404 Not Found\r\n\r\nno such path"[..];
+        let error = expect_ok(&mut stream).unwrap_err();
+        assert!(error.to_string().contains("404 Not Found: no such path"));
+    }
+
+    #[test]
+    fn transactions_compare_the_mod_revision() {
+        let compare = |mod_revision| {
+            let request = json::parse(&put_if_request("k", b"v", mod_revision)).unwrap();
+            request.get("compare").unwrap().items()[0].clone()
+        };
+        let create = compare(0);
+        assert_eq!(create.get("target").and_then(Value::as_str), Some("CREATE"));
+        assert_eq!(
+            create.get("create_revision").and_then(Value::as_i64),
+            Some(0)
+        );
+
+        let update = compare(42);
+        assert_eq!(update.get("key").and_then(Value::as_str), Some("aw=="));
+        assert_eq!(update.get("result").and_then(Value::as_str), Some("EQUAL"));
+        assert_eq!(update.get("target").and_then(Value::as_str), Some("MOD"));
+        assert_eq!(update.get("mod_revision").and_then(Value::as_i64), Some(42));
+    }
+
+    #[test]
+    fn failed_transactions_say_what_the_key_holds() {
+        let outcome = |reply: &str| put_if_outcome(&json::parse(reply).unwrap());
+        assert_eq!(outcome(r#"{"succeeded": true}"#).unwrap(), Put::Done);
+        assert_eq!(
+            outcome(r#"{"responses": [{"response_range": {}}]}"#).unwrap(),
+            Put::Conflict(None)
+        );
+        let held = r#"{"responses": [{"response_range": {"kvs": [
+            {"key": "aw==", "value": "dg==", "mod_revision": "7"}]}}]}"#;
+        assert_eq!(
+            outcome(held).unwrap(),
+            Put::Conflict(Some(KeyValue {
+                key: "k".to_owned(),
+                value: b"v".to_vec(),
+                mod_revision: 7
+            }))
+        );
+        assert!(outcome("{}").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! A short history of what we told clients, for people watching the server,
+//! and a feed of replies and lease changes as they happen for those who
+//! [subscribe]
+
+use crate::audit::AuditRecord;
+use crate::types::{MacAddr, MessageType};
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// How many events we keep, older ones are dropped
+const RECENT_EVENTS_LEN: usize = 100;
+
+/// How far a subscriber can fall behind before we drop it, so a stalled
+/// subscriber cannot eat our memory or hold up our replies
+const SUBSCRIBER_BACKLOG: usize = 1000;
+
+static RECENT: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+static SUBSCRIBERS: Mutex<Vec<SyncSender<Notice>>> = Mutex::new(Vec::new());
+
+/// What subscribers are told about
+#[derive(Debug, Clone)]
+pub enum Notice {
+    /// We sent a reply, as kept in [recent]
+    Reply(Event),
+
+    /// A client bound, renewed or lost a lease, as kept in the audit log
+    Lease(AuditRecord),
+}
+
+/// A reply we sent
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub time: SystemTime,
+    /// The type of our reply, e.g. [MessageType::Ack]
+    pub message_type: MessageType,
+    pub mac_address: MacAddr,
+    /// yiaddr of the reply, [None] for a NAK
+    pub ip_addr: Option<Ipv4Addr>,
+}
+
+pub fn record(message_type: MessageType, mac_address: MacAddr, ip_addr: Option<Ipv4Addr>) {
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == RECENT_EVENTS_LEN {
+        recent.pop_front();
+    }
+    let event = Event {
+        time: SystemTime::now(),
+        message_type,
+        mac_address,
+        ip_addr,
+    };
+    recent.push_back(event.clone());
+    drop(recent);
+    publish(Notice::Reply(event));
+}
+
+/// A lease changed, see [crate::audit]
+pub fn record_lease(record: &AuditRecord) {
+    publish(Notice::Lease(record.clone()));
+}
+
+/// Hear of every reply and lease change from now on, until the receiver is
+/// dropped or falls [SUBSCRIBER_BACKLOG] behind
+pub fn subscribe() -> Receiver<Notice> {
+    let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+fn publish(notice: Notice) {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|subscriber| subscriber.try_send(notice.clone()).is_ok());
+}
+
+/// The events we remember, most recent last
+pub fn recent() -> Vec<Event> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,321 @@
+//! Hot standby failover between two dhc3po instances serving the same scope
+//!
+//! The primary answers clients and tells the secondary about every lease it
+//! binds over TCP. The secondary stays silent until it has not heard from the
+//! primary for [PEER_TIMEOUT], then takes over. Leases flow in both
+//! directions so the primary learns what the secondary handed out while it
+//! was down, and on every (re)connect we send our whole lease table.
+//!
+//! While we cannot reach our peer we never hand out a lease longer than the
+//! maximum client lead time (MCLT). Any lease the peer missed then expires
+//! soon after it comes back, rather than the same address being handed out
+//! twice for the full lease time.
+//!
+//! The protocol is one message per line:
+//!
+//! ```text
+//! HELLO
+//! LEASE <a line of the lease file, see crate::leases>
+//! ```
+
+use log::{info, warn};
+
+use crate::leases::Lease;
+use crate::{AddrPool, Error, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The port ISC dhcpd uses for failover
+pub const DEFAULT_PORT: u16 = 647;
+
+/// The MCLT ISC dhcpd recommends
+pub const DEFAULT_MCLT: u32 = 3600;
+
+/// How often we tell our peer we are still alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How long without hearing from our peer before we consider it down
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long we wait before trying to reach a peer that is down again
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which of the pair serves clients while both are up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Primary,
+    Secondary,
+}
+
+/// The `[failover]` section of the config file
+#[derive(Debug, Clone)]
+pub struct FailoverConfig {
+    pub role: Role,
+    /// Where our peer connects to us
+    pub listen: SocketAddr,
+    /// Where we connect to our peer
+    pub peer: SocketAddr,
+    /// Maximum client lead time in seconds
+    pub mclt: u32,
+}
+
+/// One line of the protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    Hello,
+    Lease(Lease),
+}
+
+impl Message {
+    /// The line we send, with its newline
+    fn encode(&self) -> String {
+        match self {
+            Self::Hello => "HELLO\n".to_owned(),
+            Self::Lease(lease) => format!("LEASE {}\n", lease.encode()),
+        }
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        match line {
+            "HELLO" => Some(Self::Hello),
+            line => line
+                .strip_prefix("LEASE ")
+                .and_then(Lease::decode)
+                .map(Self::Lease),
+        }
+    }
+}
+
+/// What the DHCP side needs to know about failover, held by the [AddrPool]
+#[derive(Debug)]
+pub struct Link {
+    role: Role,
+    mclt: u32,
+    last_heard: Mutex<Option<Instant>>,
+    updates: Sender<Lease>,
+}
+
+impl Link {
+    /// We have heard from our peer recently
+    pub fn peer_up(&self) -> bool {
+        self.last_heard
+            .lock()
+            .unwrap()
+            .is_some_and(|last_heard| last_heard.elapsed() < PEER_TIMEOUT)
+    }
+
+    /// Whether we should answer clients right now
+    pub fn serving(&self) -> bool {
+        self.role == Role::Primary || !self.peer_up()
+    }
+
+    /// Cap `lease_time` to the MCLT while our peer cannot hear about it
+    pub fn lease_time(&self, lease_time: u32) -> u32 {
+        match self.peer_up() {
+            true => lease_time,
+            false => lease_time.min(self.mclt),
+        }
+    }
+
+    /// Queue a lease we just bound to be sent to our peer
+    pub fn replicate(&self, update: Lease) {
+        // Only fails if the sending thread died, it logs why
+        _ = self.updates.send(update);
+    }
+
+    fn heard(&self) {
+        let mut last_heard = self.last_heard.lock().unwrap();
+        if !last_heard.is_some_and(|last_heard| last_heard.elapsed() < PEER_TIMEOUT) {
+            info!("Failover peer is up");
+        }
+        *last_heard = Some(Instant::now());
+    }
+}
+
+/// Bind our failover listener and start talking to our peer in the background
+pub fn start(config: FailoverConfig, pool: Arc<Mutex<AddrPool>>) -> Result<()> {
+    let listener = TcpListener::bind(config.listen).map_err(Error::Failover)?;
+    info!(
+        "Failover {:?} listening on {}, peer {}",
+        config.role, config.listen, config.peer
+    );
+
+    let (updates, queue) = mpsc::channel();
+    let link = Arc::new(Link {
+        role: config.role,
+        mclt: config.mclt,
+        last_heard: Mutex::new(None),
+        updates,
+    });
+    pool.lock().unwrap().set_failover(link.clone());
+
+    let (accept_pool, accept_link) = (pool.clone(), link.clone());
+    thread::spawn(move || accept(listener, accept_pool, accept_link));
+    thread::spawn(move || send(config.peer, queue, pool));
+    Ok(())
+}
+
+/// Apply the updates our peer sends us
+fn accept(listener: TcpListener, pool: Arc<Mutex<AddrPool>>, link: Arc<Link>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!("Failed to accept failover connection: {error}");
+                continue;
+            }
+        };
+        let (pool, link) = (pool.clone(), link.clone());
+        thread::spawn(move || receive(stream, pool, link));
+    }
+}
+
+fn receive(stream: TcpStream, pool: Arc<Mutex<AddrPool>>, link: Arc<Link>) {
+    // A peer that stops talking mid connection is as good as down
+    _ = stream.set_read_timeout(Some(PEER_TIMEOUT));
+    let peer = stream
+        .peer_addr()
+        .map_or_else(|_| "our peer".to_owned(), |peer| peer.to_string());
+    for message in BufReader::new(stream).lines() {
+        let Ok(message) = message else {
+            break;
+        };
+        link.heard();
+        match Message::decode(&message) {
+            Some(Message::Hello) => {}
+            Some(Message::Lease(lease)) => pool.lock().unwrap().apply_lease(lease),
+            None => warn!("Ignoring unknown failover message {message:?}"),
+        }
+    }
+    warn!("Lost failover connection from {peer}");
+}
+
+/// Keep a connection to our peer open, sending our lease table on connect,
+/// then every lease we bind and a heartbeat while idle
+fn send(peer: SocketAddr, queue: Receiver<Lease>, pool: Arc<Mutex<AddrPool>>) {
+    loop {
+        let mut stream = match TcpStream::connect_timeout(&peer, PEER_TIMEOUT) {
+            Ok(stream) => stream,
+            Err(_) => {
+                thread::sleep(RECONNECT_INTERVAL);
+                continue;
+            }
+        };
+        info!("Connected to failover peer {peer}");
+
+        // The whole table replaces anything queued while we were apart
+        while queue.try_recv().is_ok() {}
+        let leases = pool.lock().unwrap().leases();
+        let mut result = leases
+            .iter()
+            .try_for_each(|lease| send_lease(&mut stream, lease));
+
+        while result.is_ok() {
+            result = match queue.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(lease) => send_lease(&mut stream, &lease),
+                Err(RecvTimeoutError::Timeout) => {
+                    stream.write_all(Message::Hello.encode().as_bytes())
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+        }
+        if let Err(error) = result {
+            warn!("Lost failover connection to {peer}: {error}");
+        }
+    }
+}
+
+fn send_lease(stream: &mut TcpStream, lease: &Lease) -> std::io::Result<()> {
+    stream.write_all(Message::Lease(lease.clone()).encode().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ClientKey, MacAddr};
+    use std::net::Ipv4Addr;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn lease() -> Lease {
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 1]);
+        Lease {
+            ip_addr: Ipv4Addr::new(192, 168, 1, 10),
+            expires: UNIX_EPOCH + Duration::from_secs(4_000_000_000),
+            mac_address,
+            key: ClientKey::Mac(mac_address),
+        }
+    }
+
+    fn link(role: Role, last_heard: Option<Instant>) -> Link {
+        Link {
+            role,
+            mclt: 600,
+            last_heard: Mutex::new(last_heard),
+            updates: mpsc::channel().0,
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for message in [Message::Hello, Message::Lease(lease())] {
+            let line = message.encode();
+            assert!(line.ends_with('\n'));
+            assert_eq!(Message::decode(line.trim_end()), Some(message));
+        }
+        assert_eq!(
+            Message::Lease(lease()).encode(),
+            "LEASE 192.168.1.10 4000000000 020000000001 mac\n"
+        );
+        assert_eq!(Message::decode("LEASE 192.168.1.10"), None);
+        assert_eq!(Message::decode("GOODBYE"), None);
+    }
+
+    #[test]
+    fn leases_are_capped_to_the_mclt_while_the_peer_is_down() {
+        let down = link(Role::Primary, None);
+        assert_eq!(down.lease_time(3600), 600);
+        assert_eq!(down.lease_time(300), 300);
+        assert!(down.serving());
+
+        let up = link(Role::Primary, Some(Instant::now()));
+        assert_eq!(up.lease_time(3600), 3600);
+
+        // The secondary only serves once the primary goes quiet
+        assert!(!link(Role::Secondary, Some(Instant::now())).serving());
+        let quiet = Instant::now().checked_sub(PEER_TIMEOUT);
+        assert!(link(Role::Secondary, quiet).serving());
+    }
+
+    #[test]
+    fn leases_from_our_peer_are_applied_to_the_pool() {
+        let range = (
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        let pool = AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], &[range], &[]).unwrap();
+        let pool = Arc::new(Mutex::new(pool));
+        let link = Arc::new(link(Role::Secondary, None));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let receiver = {
+            let (pool, link) = (pool.clone(), link.clone());
+            thread::spawn(move || receive(stream, pool, link))
+        };
+        peer.write_all(Message::Hello.encode().as_bytes()).unwrap();
+        peer.write_all(b"NONSENSE\n").unwrap();
+        send_lease(&mut peer, &lease()).unwrap();
+        drop(peer);
+        receiver.join().unwrap();
+
+        assert!(link.peer_up());
+        let leases = pool.lock().unwrap().leases();
+        assert_eq!(leases, [lease()]);
+        assert!(leases[0].expires > SystemTime::now());
+    }
+}
@@ -0,0 +1,125 @@
+//! Regression tests against a corpus of packets in `tests/golden`. They are
+//! synthetic, built by hand to carry the fields and options each client is
+//! known to send, not captured from the clients themselves. Each directory is
+//! one client, its `<n>-<message>.hex` requests are sent in order to a fresh
+//! server and we compare how we parsed each one, field by field as
+//! `dhc3po decode` prints it, with `<n>-<message>.parsed` and our reply with
+//! `<n>-<message>.reply.hex`, byte for byte.
+//!
+//! Hex files are whitespace separated bytes, lines starting with `#` are
+//! comments. After a change that is meant to alter our behaviour, check the
+//! differences and bless them with
+//!
+//! ```sh
+//! DHC3PO_BLESS=1 cargo test golden
+//! ```
+
+use crate::cli::Args;
+use crate::config::Config;
+use crate::decode::{decode, parse_hex};
+use crate::dhcp::Dhcp;
+use crate::transport::MemoryTransport;
+use crate::{serve, setup_pool, DEFAULT_UDP_BUFFER_SIZE};
+use std::fmt::Write;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// The server every client talks to, PXE clients also get a boot file and
+/// iPXE a next server of its own, and we update DNS for Windows clients
+const CONFIG: &str = "\
+subnet = 192.168.1.0
+mask = 255.255.255.0
+range = 192.168.1.10 192.168.1.20
+server-id = 192.168.1.1
+
+[options]
+router = 192.168.1.254
+domain-name-server = 192.168.1.53
+domain-name = home.arpa
+lease-time = 3600
+
+[class PXEClient]
+next-server = 192.168.1.2
+tftp-server-name = 192.168.1.2
+boot-file-name = pxelinux.0
+
+[user-class iPXE]
+next-server = 192.168.1.3
+
+[class MSFT]
+client-fqdn = server
+";
+
+fn corpus() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn to_hex(comment: &str, data: &[u8]) -> String {
+    let mut text = format!("# {comment}\n");
+    for line in data.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("{byte:02x}")).collect();
+        writeln!(text, "{}", bytes.join(" ")).unwrap();
+    }
+    text
+}
+
+/// Compare with the file at `path`, or overwrite it when blessing
+fn check(path: &Path, actual: &str, failures: &mut Vec<String>) {
+    if std::env::var_os("DHC3PO_BLESS").is_some() {
+        fs::write(path, actual).unwrap();
+        return;
+    }
+    match fs::read_to_string(path) {
+        Ok(expected) if expected == actual => {}
+        Ok(expected) => failures.push(format!(
+            "{} differs\n--- expected\n{expected}\n--- actual\n{actual}",
+            path.display()
+        )),
+        Err(error) => failures.push(format!("{}: {error}", path.display())),
+    }
+}
+
+#[test]
+fn golden_packets() {
+    let mut clients: Vec<PathBuf> = fs::read_dir(corpus())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    clients.sort();
+    assert!(!clients.is_empty(), "no golden packets found");
+
+    let mut failures = Vec::new();
+    for client in clients {
+        let mut requests: Vec<PathBuf> = fs::read_dir(&client)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".hex"))
+            .filter(|path| !path.to_string_lossy().ends_with(".reply.hex"))
+            .collect();
+        requests.sort();
+
+        let pool = setup_pool(&Args::default(), Config::parse(CONFIG).unwrap());
+        let transport = MemoryTransport::default();
+        for request in requests {
+            let data = parse_hex(&fs::read_to_string(&request).unwrap()).unwrap();
+            let parsed = match Dhcp::parse(&data) {
+                Ok(dhcp) => format!("{:?} from {}\n", dhcp.message_type(), dhcp.client_mac()),
+                Err(error) => format!("dropped, {error}\n"),
+            } + &decode(&data).unwrap();
+            check(&request.with_extension("parsed"), &parsed, &mut failures);
+
+            let client_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, crate::CLIENT_PORT));
+            transport.push(&data, client_addr);
+            serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+            let reply = match transport.take_sent().as_slice() {
+                [] => "# no reply\n".to_owned(),
+                [(reply, destination)] => to_hex(&format!("sent to {destination}"), reply),
+                sent => panic!("{} got {} replies", request.display(), sent.len()),
+            };
+            check(&request.with_extension("reply.hex"), &reply, &mut failures);
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
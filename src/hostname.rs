@@ -0,0 +1,96 @@
+//! Cleaning up the names clients give themselves in option 12 before we keep
+//! them, so what the dashboard shows and DNS updaters read from
+//! `/api/leases` is a name DNS would accept and no two clients share
+
+use crate::types::MacAddr;
+
+/// The longest a label can be (RFC 1035 2.3.4)
+const MAX_LABEL_LEN: usize = 63;
+/// The longest a whole name can be, dots included
+const MAX_NAME_LEN: usize = 253;
+
+/// What to do when a client claims a name another client holds,
+/// `hostname-conflict`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostnameConflict {
+    /// Give the newcomer the first of `-2`, `-3` and so on that is free
+    #[default]
+    Suffix,
+
+    /// Give the newcomer the last three bytes of its MAC, which unlike a
+    /// counter does not depend on who asked first
+    Mac,
+
+    /// Keep no name for the newcomer
+    Refuse,
+
+    /// Let both have it
+    Allow,
+}
+
+impl HostnameConflict {
+    /// A name for a client asking for `name`, [None] if it gets none.
+    /// `taken` says whether another client holds a name, ignoring case
+    pub fn resolve(
+        self,
+        name: String,
+        mac_address: &MacAddr,
+        taken: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        if self == Self::Allow || !taken(&name) {
+            return Some(name);
+        }
+        match self {
+            Self::Suffix => (2..)
+                .map(|n| with_suffix(&name, &n.to_string()))
+                .find(|candidate| !taken(candidate)),
+            Self::Mac => {
+                let bytes = mac_address.bytes();
+                let suffix: String = bytes[3..].iter().map(|b| format!("{b:02x}")).collect();
+                Some(with_suffix(&name, &suffix)).filter(|candidate| !taken(candidate))
+            }
+            Self::Refuse | Self::Allow => None,
+        }
+    }
+}
+
+/// `name` with characters DNS does not allow in a host name replaced by `-`,
+/// labels trimmed to fit and empty ones dropped. [None] when nothing is left
+pub fn sanitize(name: &str) -> Option<String> {
+    let labels: Vec<String> = name
+        .split('.')
+        .map(|label| {
+            let mut clean = String::new();
+            for c in label.chars() {
+                match c.is_ascii_alphanumeric() {
+                    true => clean.push(c),
+                    // One hyphen for a run of anything else
+                    false if !clean.ends_with('-') => clean.push('-'),
+                    false => {}
+                }
+            }
+            clean.truncate(MAX_LABEL_LEN);
+            clean.trim_matches('-').to_owned()
+        })
+        .filter(|label| !label.is_empty())
+        .collect();
+
+    let mut name = labels.join(".");
+    name.truncate(MAX_NAME_LEN);
+    let name = name.trim_end_matches(['-', '.']);
+    Some(name.to_owned()).filter(|name| !name.is_empty())
+}
+
+/// `name` with `-suffix` on its first label, shortened to keep it a label
+fn with_suffix(name: &str, suffix: &str) -> String {
+    let (first, rest) = match name.split_once('.') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (name, None),
+    };
+    let keep = first.len().min(MAX_LABEL_LEN - suffix.len() - 1);
+    let first = format!("{}-{suffix}", first[..keep].trim_end_matches('-'));
+    match rest {
+        Some(rest) => format!("{first}.{rest}"),
+        None => first,
+    }
+}
@@ -0,0 +1,355 @@
+//! Just enough JSON for our exports and APIs and to read the replies of the
+//! services we talk to, we keep this dependency free
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How deep arrays and objects may nest before we give up on a document,
+/// nothing we read comes close and each level is a stack frame
+const MAX_DEPTH: usize = 64;
+
+/// A quoted and escaped JSON string
+pub fn string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for char in value.chars() {
+        match char {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            char if char.is_control() => quoted.push_str(&format!("\\u{:04x}", char as u32)),
+            char => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A string, or `null`
+pub fn optional(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_owned(), string)
+}
+
+/// Seconds since the epoch
+pub fn time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// When a lease expires in seconds since the epoch, `null` if it never does
+pub fn expires(expires: SystemTime) -> String {
+    match expires == crate::leases::never() {
+        true => "null".to_owned(),
+        false => time(expires).to_string(),
+    }
+}
+
+/// A parsed JSON value, for reading the replies of services we talk to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// As written, so large integers survive
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member `key` of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// A number, or a string holding one as 64 bit integers are often sent
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(value) | Self::String(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The items of an array, none for anything else
+    pub fn items(&self) -> &[Value] {
+        match self {
+            Self::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+/// Parse a complete JSON document
+pub fn parse(text: &str) -> Option<Value> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        at: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.at == parser.text.len() {
+        true => Some(value),
+        false => None,
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+    /// How many arrays and objects we are inside
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.at) {
+            self.at += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.at).copied()
+    }
+
+    fn expect(&mut self, literal: &str) -> Option<()> {
+        let end = self.at + literal.len();
+        match self.text.get(self.at..end) == Some(literal.as_bytes()) {
+            true => {
+                self.at = end;
+                Some(())
+            }
+            false => None,
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        match self.peek()? {
+            b'n' => self.expect("null").map(|()| Value::Null),
+            b't' => self.expect("true").map(|()| Value::Bool(true)),
+            b'f' => self.expect("false").map(|()| Value::Bool(false)),
+            b'"' => self.string().map(Value::String),
+            open @ (b'[' | b'{') => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    return None;
+                }
+                self.at += 1;
+                let value = match open {
+                    b'[' => self.array(),
+                    _ => self.object(),
+                };
+                self.depth -= 1;
+                value
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.at;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') =
+                    self.text.get(self.at)
+                {
+                    self.at += 1;
+                }
+                let number = std::str::from_utf8(&self.text[start..self.at]).ok()?;
+                number
+                    .parse::<f64>()
+                    .ok()
+                    .map(|_| Value::Number(number.to_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The rest of an array after its `[`
+    fn array(&mut self) -> Option<Value> {
+        let mut items = Vec::new();
+        if self.peek()? == b']' {
+            self.at += 1;
+            return Some(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek()? {
+                b',' => self.at += 1,
+                b']' => break,
+                _ => return None,
+            }
+        }
+        self.at += 1;
+        Some(Value::Array(items))
+    }
+
+    /// The rest of an object after its `{`
+    fn object(&mut self) -> Option<Value> {
+        let mut members = Vec::new();
+        if self.peek()? == b'}' {
+            self.at += 1;
+            return Some(Value::Object(members));
+        }
+        loop {
+            if self.peek()? != b'"' {
+                return None;
+            }
+            let name = self.string()?;
+            if self.peek()? != b':' {
+                return None;
+            }
+            self.at += 1;
+            members.push((name, self.value()?));
+            match self.peek()? {
+                b',' => self.at += 1,
+                b'}' => break,
+                _ => return None,
+            }
+        }
+        self.at += 1;
+        Some(Value::Object(members))
+    }
+
+    /// A string starting at the opening quote
+    fn string(&mut self) -> Option<String> {
+        self.at += 1;
+        let mut value = Vec::new();
+        loop {
+            let byte = *self.text.get(self.at)?;
+            self.at += 1;
+            match byte {
+                b'"' => return String::from_utf8(value).ok(),
+                b'\\' => {
+                    let escaped = *self.text.get(self.at)?;
+                    self.at += 1;
+                    let char = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let high = self.hex4()?;
+                            match high {
+                                // A surrogate pair, which must be high then low
+                                0xd800..=0xdbff => {
+                                    self.expect("\\u")?;
+                                    let low = self.hex4()?;
+                                    if !(0xdc00..=0xdfff).contains(&low) {
+                                        return None;
+                                    }
+                                    let code = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+                                    char::from_u32(code)?
+                                }
+                                _ => char::from_u32(high)?,
+                            }
+                        }
+                        _ => return None,
+                    };
+                    value.extend_from_slice(char.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte => value.push(byte),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.text.get(self.at..self.at + 4)?;
+        self.at += 4;
+        // from_str_radix would also take a sign
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(string("plain"), r#""plain""#);
+        assert_eq!(string("say \"hi\"\\\n\r\t"), r#""say \"hi\"\\\n\r\t""#);
+        assert_eq!(string("bell\u{7}"), r#""bell\u0007""#);
+        assert_eq!(string("café"), "\"café\"");
+        assert_eq!(optional(None), "null");
+        assert_eq!(optional(Some("</script>")), r#""</script>""#);
+    }
+
+    #[test]
+    fn documents_are_parsed() {
+        let value =
+            parse(r#" {"kvs": [{"key": "a", "version": "3"}], "more": false, "count": -1} "#)
+                .unwrap();
+        let kv = &value.get("kvs").unwrap().items()[0];
+        assert_eq!(kv.get("key").and_then(Value::as_str), Some("a"));
+        assert_eq!(kv.get("version").and_then(Value::as_i64), Some(3));
+        assert_eq!(value.get("more").and_then(Value::as_bool), Some(false));
+        assert_eq!(value.get("count").and_then(Value::as_i64), Some(-1));
+        assert_eq!(value.get("missing"), None);
+
+        assert_eq!(parse("[]"), Some(Value::Array(Vec::new())));
+        assert_eq!(parse("null"), Some(Value::Null));
+        for bad in ["", "[1,]", "{\"a\" 1}", "[1] 2", "tru", "\"open", "{1: 2}"] {
+            assert_eq!(parse(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn escapes_and_surrogate_pairs_are_decoded() {
+        fn text(json: &str) -> Option<String> {
+            parse(json).and_then(|value| value.as_str().map(str::to_owned))
+        }
+        assert_eq!(
+            text(r#""\"\\\/\b\f\n\r\t""#).as_deref(),
+            Some("\"\\/\u{8}\u{c}\n\r\t")
+        );
+        assert_eq!(text(r#""caf\u00e9""#).as_deref(), Some("café"));
+        assert_eq!(text(r#""\ud83d\ude00""#).as_deref(), Some("\u{1f600}"));
+        assert_eq!(
+            text(&string("round \u{1f600} trip\u{7}")).as_deref(),
+            Some("round \u{1f600} trip\u{7}")
+        );
+
+        // A high surrogate must be followed by a low one, and a low one
+        // cannot stand alone
+        assert_eq!(text(r#""\ud800\u0041""#), None);
+        assert_eq!(text(r#""\ud800\ud800""#), None);
+        assert_eq!(text(r#""\ud800""#), None);
+        assert_eq!(text(r#""\udc00""#), None);
+        assert_eq!(text(r#""\u+041""#), None);
+        assert_eq!(text(r#""\x""#), None);
+    }
+
+    #[test]
+    fn nesting_is_capped() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_some());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_none());
+        // Deep enough to overflow the stack without the cap
+        assert!(parse(&"[{\"a\":".repeat(100_000)).is_none());
+    }
+}
@@ -0,0 +1,461 @@
+//! The lease file, so leases survive a restart, and converting it to and from
+//! the formats other tools understand. Each line of the file is one bound
+//! lease:
+//!
+//! ```text
+//! # <ip> <expires, seconds since the epoch> <chaddr> mac
+//! # <ip> <expires, seconds since the epoch> <chaddr> id:<client identifier>
+//! 192.168.1.10 1700000000 020000000001 mac
+//! 192.168.1.11 1700000000 020000000002 id:01020000000002
+//! ```
+//!
+//! Infinite leases are written as expiring at 4294967295.
+
+use crate::types::{ClientIdentifier, ClientKey, MacAddr};
+use crate::{json, oui};
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where `dhc3po-ctl` looks when not told otherwise
+pub const DEFAULT_LEASE_FILE: &str = "dhc3po.leases";
+
+/// When infinite leases expire as far as a [SystemTime] can say. dnsmasq and
+/// ISC dhcpd can both write leases that never expire, we read them as this
+const NEVER_EXPIRES: u64 = u32::MAX as u64;
+
+/// The expiry of an infinite lease, see [NEVER_EXPIRES]
+pub fn never() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(NEVER_EXPIRES)
+}
+
+/// A lease bound to a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub ip_addr: Ipv4Addr,
+    pub expires: SystemTime,
+    pub mac_address: MacAddr,
+    pub key: ClientKey,
+}
+
+impl Lease {
+    /// One line of the lease file, without the newline
+    pub fn encode(&self) -> String {
+        let key = match &self.key {
+            ClientKey::Mac(_) => "mac".to_owned(),
+            ClientKey::ClientId(client_id) => format!("id:{}", hex(client_id.as_bytes(), "")),
+        };
+        format!(
+            "{} {} {} {key}",
+            self.ip_addr,
+            self.expires_secs(),
+            hex(&self.mac_address.bytes(), "")
+        )
+    }
+
+    pub fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let ip_addr = fields.next()?.parse().ok()?;
+        let expires = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+        let mac_address = MacAddr::new(unhex(fields.next()?, "")?.try_into().ok()?);
+        let key = match fields.next()? {
+            "mac" => ClientKey::Mac(mac_address),
+            key => ClientKey::ClientId(parse_client_id(&unhex(key.strip_prefix("id:")?, "")?)?),
+        };
+        fields.next().is_none().then_some(Self {
+            ip_addr,
+            expires,
+            mac_address,
+            key,
+        })
+    }
+
+    fn expires_secs(&self) -> u64 {
+        self.expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    fn client_id(&self) -> Option<String> {
+        match &self.key {
+            ClientKey::ClientId(client_id) => Some(hex(client_id.as_bytes(), ":")),
+            ClientKey::Mac(_) => None,
+        }
+    }
+}
+
+/// Read the lease file, a missing file means no leases yet
+pub fn load(path: &Path) -> Result<Vec<Lease>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => parse(&text),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(Error::CannotReadLeases(error)),
+    }
+}
+
+/// Replace the lease file, we write a new file and rename it over the old one
+/// so a crash never leaves half a file behind
+pub fn save(path: &Path, leases: &[Lease]) -> Result<()> {
+    let mut text = String::from("# dhc3po leases: <ip> <expires> <chaddr> <mac|id:client-id>\n");
+    for lease in leases {
+        text.push_str(&lease.encode());
+        text.push('\n');
+    }
+
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, text)
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(Error::CannotWriteLeases)
+}
+
+/// Parse the contents of our own lease file
+pub fn parse(text: &str) -> Result<Vec<Lease>> {
+    lines(text)
+        .map(|(number, line)| {
+            Lease::decode(line).ok_or_else(|| Error::InvalidLeaseLine(number, line.to_owned()))
+        })
+        .collect()
+}
+
+/// Parse a dnsmasq leases file, one lease per line:
+/// `<expires> <mac> <ip> <hostname|*> <client-id|*>`
+pub fn parse_dnsmasq(text: &str) -> Result<Vec<Lease>> {
+    lines(text)
+        // DUID lines belong to DHCPv6 leases which follow it
+        .take_while(|(_, line)| !line.starts_with("duid"))
+        .map(|(number, line)| {
+            let invalid = || Error::InvalidLeaseLine(number, line.to_owned());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [expires, mac, ip, _hostname, client_id] = fields[..] else {
+                return Err(invalid());
+            };
+
+            let expires = match expires.parse().map_err(|_| invalid())? {
+                0 => NEVER_EXPIRES,
+                expires => expires,
+            };
+            let mac_address = mac.parse().map_err(|_| invalid())?;
+            let client_id = unhex(client_id, ":").and_then(|id| parse_client_id(&id));
+            Ok(Lease {
+                ip_addr: ip.parse().map_err(|_| invalid())?,
+                expires: UNIX_EPOCH + Duration::from_secs(expires),
+                mac_address,
+                key: ClientKey::new(client_id, mac_address),
+            })
+        })
+        .collect()
+}
+
+/// Parse an ISC dhcpd.leases file. The file is a journal, later entries for
+/// an address replace earlier ones, and we only keep active bindings
+pub fn parse_isc(text: &str) -> Result<Vec<Lease>> {
+    let mut leases = BTreeMap::new();
+    let mut current: Option<(usize, IscLease)> = None;
+
+    for (number, line) in lines(text) {
+        let invalid = || Error::InvalidLeaseLine(number, line.to_owned());
+        let statement = line.trim_end_matches(';').trim();
+
+        if let Some(ip) = statement
+            .strip_prefix("lease ")
+            .and_then(|rest| rest.strip_suffix('{'))
+        {
+            let ip_addr = ip.trim().parse().map_err(|_| invalid())?;
+            current = Some((number, IscLease::new(ip_addr)));
+            continue;
+        }
+
+        let Some((_, lease)) = &mut current else {
+            // Other blocks such as host, server-duid and failover state
+            continue;
+        };
+
+        if statement == "}" {
+            let (start, lease) = current.take().unwrap();
+            leases.insert(lease.ip_addr, lease.finish(start));
+            continue;
+        }
+
+        let words: Vec<&str> = statement.split_whitespace().collect();
+        match words[..] {
+            ["ends", "never"] => lease.expires = Some(NEVER_EXPIRES),
+            ["ends", _weekday, date, time] => {
+                lease.expires = Some(parse_isc_date(date, time).ok_or_else(invalid)?)
+            }
+            ["binding", "state", state] => lease.active = state == "active",
+            ["hardware", "ethernet", mac] => {
+                lease.mac_address = Some(mac.parse().map_err(|_| invalid())?)
+            }
+            ["uid", ..] => {
+                let uid = statement["uid".len()..].trim();
+                lease.client_id = parse_isc_uid(uid).and_then(|uid| parse_client_id(&uid));
+            }
+            _ => {}
+        }
+    }
+
+    leases.into_values().filter_map(Result::transpose).collect()
+}
+
+/// A lease block of dhcpd.leases as we read it
+struct IscLease {
+    ip_addr: Ipv4Addr,
+    expires: Option<u64>,
+    mac_address: Option<MacAddr>,
+    client_id: Option<ClientIdentifier>,
+    active: bool,
+}
+
+impl IscLease {
+    fn new(ip_addr: Ipv4Addr) -> Self {
+        Self {
+            ip_addr,
+            expires: None,
+            mac_address: None,
+            client_id: None,
+            // Old versions do not write a binding state
+            active: true,
+        }
+    }
+
+    /// [None] for blocks that are not an active lease
+    fn finish(self, line: usize) -> Result<Option<Lease>> {
+        if !self.active {
+            return Ok(None);
+        }
+        let invalid = || Error::InvalidLeaseLine(line, format!("lease {}", self.ip_addr));
+        let mac_address = self.mac_address.ok_or_else(invalid)?;
+        Ok(Some(Lease {
+            ip_addr: self.ip_addr,
+            expires: UNIX_EPOCH + Duration::from_secs(self.expires.ok_or_else(invalid)?),
+            mac_address,
+            key: ClientKey::new(self.client_id, mac_address),
+        }))
+    }
+}
+
+/// `2023/01/05 22:00:00` in UTC
+fn parse_isc_date(date: &str, time: &str) -> Option<u64> {
+    let date: Vec<i64> = date
+        .split('/')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let time: Vec<u64> = time
+        .split(':')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let ([year, month, day], [hours, minutes, seconds]) = (&date[..], &time[..]) else {
+        return None;
+    };
+    let (year, month, day) = (*year, *month, *day);
+
+    // Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146097 + day_of_era - 719468).ok()?;
+
+    Some(days * 86400 + hours * 3600 + minutes * 60 + *seconds)
+}
+
+/// dhcpd writes uids as colon separated hex or as a quoted string with octal
+/// escapes for anything unprintable
+fn parse_isc_uid(uid: &str) -> Option<Vec<u8>> {
+    let Some(quoted) = uid.strip_prefix('"').and_then(|uid| uid.strip_suffix('"')) else {
+        return unhex(uid, ":");
+    };
+
+    let mut bytes = Vec::new();
+    let mut chars = quoted.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            bytes.push(u8::try_from(char).ok()?);
+            continue;
+        }
+        match chars.next()? {
+            digit @ '0'..='7' => {
+                let octal: String = [digit, chars.next()?, chars.next()?].iter().collect();
+                bytes.push(u8::from_str_radix(&octal, 8).ok()?);
+            }
+            escaped => bytes.push(u8::try_from(escaped).ok()?),
+        }
+    }
+    Some(bytes)
+}
+
+/// Write leases as a JSON array
+pub fn to_json(leases: &[Lease]) -> String {
+    let entries: Vec<String> = leases
+        .iter()
+        .map(|lease| {
+            format!(
+                "  {{\"ip\": \"{}\", \"mac\": \"{}\", \"vendor\": {}, \"client_id\": {}, \"expires\": {}}}",
+                lease.ip_addr,
+                lease.mac_address,
+                json::optional(oui::vendor(&lease.mac_address)),
+                json::optional(lease.client_id().as_deref()),
+                json::expires(lease.expires)
+            )
+        })
+        .collect();
+    match entries.is_empty() {
+        true => "[]\n".to_owned(),
+        false => format!("[\n{}\n]\n", entries.join(",\n")),
+    }
+}
+
+/// Write leases as CSV with a header row
+pub fn to_csv(leases: &[Lease]) -> String {
+    let mut csv = String::from("ip,mac,vendor,client_id,expires\n");
+    let expires = |lease: &Lease| match lease.expires == never() {
+        true => "never".to_owned(),
+        false => lease.expires_secs().to_string(),
+    };
+    for lease in leases {
+        // Vendor names often contain commas
+        let vendor = oui::vendor(&lease.mac_address).unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},\"{}\",{},{}\n",
+            lease.ip_addr,
+            lease.mac_address,
+            vendor.replace('"', "\"\""),
+            lease.client_id().unwrap_or_default(),
+            expires(lease)
+        ));
+    }
+    csv
+}
+
+/// Non empty lines that are not comments, numbered from 1
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn parse_client_id(bytes: &[u8]) -> Option<ClientIdentifier> {
+    ClientIdentifier::try_from(bytes).ok()
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn unhex(text: &str, separator: &str) -> Option<Vec<u8>> {
+    let text = match separator {
+        "" => text.to_owned(),
+        separator => text.replace(separator, ""),
+    };
+    if text.is_empty() || text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(last_octet: u8, expires: u64, client_id: Option<&[u8]>) -> Lease {
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, last_octet]);
+        let client_id = client_id.and_then(parse_client_id);
+        Lease {
+            ip_addr: Ipv4Addr::new(192, 168, 1, last_octet),
+            expires: UNIX_EPOCH + Duration::from_secs(expires),
+            mac_address,
+            key: ClientKey::new(client_id, mac_address),
+        }
+    }
+
+    #[test]
+    fn exported_leases_import_unchanged() {
+        let leases = [
+            lease(10, 1_700_000_000, None),
+            lease(11, NEVER_EXPIRES, Some(&[1, 2, 0, 0, 0, 0, 11])),
+        ];
+        let path = std::env::temp_dir().join(format!("dhc3po-leases-{}", std::process::id()));
+        save(&path, &leases).unwrap();
+        assert_eq!(load(&path).unwrap(), leases);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            leases[1].encode(),
+            "192.168.1.11 4294967295 02000000000b id:0102000000000b"
+        );
+        assert!(matches!(
+            parse("# header\n192.168.1.10 soon 020000000001 mac"),
+            Err(Error::InvalidLeaseLine(2, _))
+        ));
+        // A missing file is no leases yet
+        assert!(load(Path::new("/nonexistent/dhc3po.leases"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn dnsmasq_leases_import() {
+        let text = "\
+1700000000 02:00:00:00:00:0a 192.168.1.10 laptop 01:02:00:00:00:00:0a
+0 02:00:00:00:00:0b 192.168.1.11 * *
+duid 00:01:00:01:2a:3b:4c:5d:02:00:00:00:00:01
+";
+        let leases = parse_dnsmasq(text).unwrap();
+        assert_eq!(
+            leases,
+            [
+                lease(10, 1_700_000_000, Some(&[1, 2, 0, 0, 0, 0, 10])),
+                lease(11, NEVER_EXPIRES, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn isc_leases_import_the_last_active_binding() {
+        let text = r#"
+lease 192.168.1.10 {
+  ends 4 2023/01/05 22:00:00;
+  binding state active;
+  hardware ethernet 02:00:00:00:00:0a;
+  uid "\001\002\000\000\000\000\012";
+}
+lease 192.168.1.11 {
+  ends never;
+  binding state active;
+  hardware ethernet 02:00:00:00:00:0b;
+}
+lease 192.168.1.11 {
+  ends never;
+  binding state free;
+  hardware ethernet 02:00:00:00:00:0b;
+}
+"#;
+        let leases = parse_isc(text).unwrap();
+        assert_eq!(
+            leases,
+            [lease(10, 1_672_956_000, Some(&[1, 2, 0, 0, 0, 0, 10]))]
+        );
+    }
+
+    #[test]
+    fn exports_quote_what_needs_it() {
+        let leases = [lease(10, NEVER_EXPIRES, None)];
+        let csv = to_csv(&leases);
+        assert!(csv.starts_with("ip,mac,vendor,client_id,expires\n"));
+        assert!(csv.ends_with(",never\n"));
+        assert!(to_json(&leases).contains("\"ip\": \"192.168.1.10\""));
+        assert_eq!(to_json(&[]), "[]\n");
+    }
+}
@@ -0,0 +1,1481 @@
+//! # DHC3PO
+//! The DHCP server for star wars fans! The server itself lives in [run], the
+//! binaries are thin wrappers around this library
+//!
+//! Everything but [wire] needs the default `std` feature, without it this is
+//! a `no_std` crate holding only the wire format
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+use std::cell::RefCell;
+#[cfg(feature = "std")]
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+mod admin;
+#[cfg(feature = "std")]
+mod alerts;
+#[cfg(feature = "std")]
+mod arp;
+#[cfg(feature = "std")]
+mod audit;
+#[doc(hidden)]
+#[cfg(feature = "std")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod check;
+#[cfg(feature = "std")]
+pub mod cli;
+#[cfg(feature = "std")]
+mod config;
+#[cfg(all(feature = "std", unix))]
+mod container;
+#[cfg(feature = "std")]
+pub mod decode;
+#[cfg(feature = "std")]
+mod dhcp;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+mod etcd;
+#[cfg(feature = "std")]
+mod events;
+#[cfg(feature = "std")]
+mod failover;
+#[cfg(all(feature = "std", test))]
+mod golden;
+#[cfg(feature = "std")]
+mod hostname;
+#[cfg(feature = "std")]
+mod json;
+#[cfg(feature = "std")]
+pub mod leases;
+#[cfg(feature = "std")]
+pub mod logging;
+#[cfg(feature = "std")]
+mod mud;
+#[cfg(feature = "std")]
+pub mod oui;
+#[cfg(feature = "std")]
+mod pcap;
+#[cfg(all(feature = "std", unix))]
+mod privileges;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod simulate;
+#[cfg(feature = "std")]
+mod state;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod store;
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod systemd;
+#[cfg(feature = "std")]
+mod telemetry;
+#[cfg(feature = "std")]
+mod transport;
+#[cfg(feature = "std")]
+pub mod types;
+#[cfg(all(feature = "std", windows))]
+pub mod windows_service;
+pub mod wire;
+
+#[cfg(feature = "std")]
+use arp::ArpInjection;
+#[cfg(feature = "std")]
+use cli::Args;
+#[cfg(feature = "std")]
+use config::Config;
+#[cfg(feature = "std")]
+use dhcp::{Destination, Dhcp, Reply};
+#[cfg(feature = "std")]
+use error::{Error, RecvError, Result};
+#[cfg(feature = "std")]
+use log::{error, info, warn};
+#[cfg(feature = "std")]
+use state::{AddrPool, Ports};
+#[cfg(feature = "std")]
+use transport::Transport;
+#[cfg(feature = "std")]
+use types::{DhcpOption, DhcpOptionList};
+
+/// Port we listen for incomming DHCP requests, 67 is standard
+#[cfg(feature = "std")]
+const SERVER_PORT: u16 = 67;
+/// Port we listen for incomming DHCP requests, 67 is standard
+#[cfg(feature = "std")]
+const CLIENT_PORT: u16 = 68;
+/// Address we listen on 0.0.0.0 means all interfaces
+#[cfg(feature = "std")]
+const BIND_ADDRESS: &str = "0.0.0.0";
+/// Where we send replies for clients we cannot unicast to
+#[cfg(feature = "std")]
+const BROADCAST_ADDRESS: Ipv4Addr = Ipv4Addr::BROADCAST;
+/// Requests larger than this are discarded unless `buffer-size` is set, PXE
+/// clients commonly send more than the 576 bytes every client may send
+#[cfg(feature = "std")]
+const DEFAULT_UDP_BUFFER_SIZE: usize = 1500;
+/// If a [DhcpOption::LeaseTime] is not specified use this
+#[cfg(feature = "std")]
+const DEFAULT_LEASE_TIME: u32 = 43200;
+/// A [DhcpOption::LeaseTime] of all ones never expires (RFC 2131 3.3)
+#[cfg(feature = "std")]
+const INFINITE_LEASE_TIME: u32 = u32::MAX;
+/// How long we block in recv before checking if we have been asked to stop
+#[cfg(feature = "std")]
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often we forget addresses and declines that no longer matter, see
+/// [AddrPool::retire_expired]
+#[cfg(feature = "std")]
+const RETIRE_INTERVAL: Duration = Duration::from_secs(60);
+/// A read timeout of zero is an error, so we wait at least this long for a
+/// held back OFFER that is already due
+#[cfg(feature = "std")]
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Set to stop the server loop, e.g. by the Windows service control manager
+#[cfg(feature = "std")]
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "std")]
+thread_local! {
+    /// Where this thread builds its replies, reused rather than zeroed for
+    /// every request as serialising clears what it needs to
+    static RESPONSE_BUFFER: RefCell<[u8; dhcp::MAX_MESSAGE_LEN]> =
+        const { RefCell::new([0; dhcp::MAX_MESSAGE_LEN]) };
+
+    /// OFFERs held back by `offer-delay`, [serve] sends them once they are due
+    static DELAYED: RefCell<Vec<Delayed>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A reply held back until `due`, with what we need to send it then
+#[cfg(feature = "std")]
+struct Delayed {
+    due: Instant,
+    data: Vec<u8>,
+    destination: Destination,
+    inject_arp: Option<ArpInjection>,
+    ports: Ports,
+}
+
+/// Our main logic, bind to our [BIND_ADDRESS] on the server port and handle
+/// requests until [SHUTDOWN] is set
+#[cfg(feature = "std")]
+pub fn run(args: &Args) {
+    info!("Dhcp Server Starting...");
+    #[cfg(unix)]
+    if args.container {
+        container::stop_on_signals();
+    }
+    let config = load_config(args).unwrap();
+    logging::set_target(&config.log).unwrap();
+    let buffer_size = config.buffer_size;
+    let failover = config.failover.clone();
+    let admin_listen = config.admin_listen;
+    let alerts = config.alerts.clone();
+    if let Some(endpoint) = config.otlp_endpoint.clone() {
+        telemetry::start(endpoint);
+    }
+    let addr_range = setup_pool(args, config);
+    if let Some(url) = args.config.as_deref().and_then(config::etcd_url) {
+        let (args, pool) = (args.clone(), addr_range.clone());
+        config::etcd::watch(url, move |config| reload(&args, &pool, config)).unwrap();
+    }
+    if let Some(failover) = failover {
+        failover::start(failover, addr_range.clone()).unwrap();
+    }
+    alerts::start(alerts, addr_range.clone());
+    let port = addr_range.lock().unwrap().ports().server;
+    let socket = bind_socket(args, port);
+    if let Some(listen) = admin_listen {
+        let socket = socket.try_clone().unwrap();
+        admin::start(listen, addr_range.clone(), socket).unwrap();
+    }
+
+    // We no longer need root now we hold the socket
+    #[cfg(unix)]
+    privileges::drop(args.user.as_deref(), args.group.as_deref(), args.chroot).unwrap();
+
+    // Wake up often enough to notice a shutdown when we are idle
+    let mut read_timeout = SHUTDOWN_POLL_INTERVAL;
+
+    #[cfg(target_os = "linux")]
+    let mut watchdog = systemd::Watchdog::from_env();
+    #[cfg(target_os = "linux")]
+    {
+        // Also wake up often enough to keep the watchdog fed
+        if let Some(watchdog) = &watchdog {
+            read_timeout = read_timeout.min(watchdog.interval());
+        }
+        systemd::notify("READY=1");
+    }
+
+    socket.set_read_timeout(Some(read_timeout)).unwrap();
+
+    let buffer = &mut vec![0u8; buffer_size + 1];
+    let mut retired = Instant::now();
+    let mut timeout = read_timeout;
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        #[cfg(target_os = "linux")]
+        if let Some(watchdog) = &mut watchdog {
+            watchdog.kick();
+        }
+
+        // Wake up in time to send the next held back OFFER
+        let wait = next_delayed().map_or(read_timeout, |due| {
+            due.saturating_duration_since(Instant::now())
+                .clamp(MIN_READ_TIMEOUT, read_timeout)
+        });
+        if wait != timeout {
+            socket.set_read_timeout(Some(wait)).unwrap();
+            timeout = wait;
+        }
+
+        serve(&socket, &addr_range, buffer);
+
+        if retired.elapsed() >= RETIRE_INTERVAL {
+            addr_range.lock().unwrap().retire_expired();
+            retired = Instant::now();
+        }
+    }
+
+    addr_range.lock().unwrap().close_lease_store();
+    info!("Dhcp Server Stopped");
+}
+
+/// Receive one request and answer it, or give up when the read times out,
+/// then send any held back OFFERs that are due. `buffer` is one byte larger
+/// than the largest request we accept, unix silently truncates a datagram
+/// that does not fit so filling it is how we tell one was too large
+#[cfg(feature = "std")]
+fn serve(transport: &impl Transport, pool: &Arc<Mutex<AddrPool>>, buffer: &mut [u8]) {
+    let max_len = buffer.len() - 1;
+    match transport.recv_from(buffer) {
+        Ok((data_len, _)) if data_len > max_len => handle_truncated(max_len),
+        Ok((data_len, _)) => {
+            thread::scope(|_| handle_request(transport, pool.clone(), &buffer[..data_len]));
+        }
+        Err(ref error) => handle_error(error, max_len),
+    };
+    send_delayed(transport);
+}
+
+/// Send the held back OFFERs that are due
+#[cfg(feature = "std")]
+fn send_delayed(transport: &impl Transport) {
+    let now = Instant::now();
+    let due: Vec<Delayed> = DELAYED.with_borrow_mut(|delayed| {
+        let (due, waiting) = std::mem::take(delayed)
+            .into_iter()
+            .partition(|reply| reply.due <= now);
+        *delayed = waiting;
+        due
+    });
+    for reply in due {
+        send(
+            transport,
+            &reply.data,
+            reply.destination,
+            reply.inject_arp,
+            reply.ports,
+        );
+    }
+}
+
+/// When the next held back OFFER is due
+#[cfg(feature = "std")]
+fn next_delayed() -> Option<Instant> {
+    DELAYED.with_borrow(|delayed| delayed.iter().map(|reply| reply.due).min())
+}
+
+#[cfg(feature = "std")]
+fn bind_socket(args: &Args, port: u16) -> UdpSocket {
+    // Prefer a socket handed to us by systemd socket activation
+    #[cfg(target_os = "linux")]
+    let socket = systemd::listen_socket();
+    #[cfg(not(target_os = "linux"))]
+    let socket = None;
+
+    let socket = socket.unwrap_or_else(|| {
+        info!("Binding to {BIND_ADDRESS}:{port}...");
+        // Get a socket from the OS
+        UdpSocket::bind((BIND_ADDRESS, port))
+            .map_err(Error::CannotBindToAddress)
+            .unwrap()
+    });
+    socket.set_broadcast(true).unwrap();
+
+    if let Some(interface) = &args.egress_interface {
+        #[cfg(target_os = "linux")]
+        {
+            container::bind_to_device(&socket, interface).unwrap();
+            info!("Serving on interface {interface} only");
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("--egress-interface {interface} only has an effect on Linux");
+    }
+    socket
+}
+
+#[cfg(feature = "std")]
+fn load_config(args: &Args) -> Result<Config> {
+    match (&args.config, &args.dnsmasq_config) {
+        (Some(path), _) => {
+            info!("Loading config from {}", path.display());
+            match config::etcd_url(path) {
+                Some(url) => Config::load_etcd(url),
+                None => Config::load(path),
+            }
+        }
+        (None, Some(path)) => {
+            info!("Loading dnsmasq config from {}", path.display());
+            Config::load_dnsmasq(path)
+        }
+        (None, None) => Ok(Config::default()),
+    }
+}
+
+#[cfg(feature = "std")]
+fn setup_pool(args: &Args, mut config: Config) -> Arc<Mutex<AddrPool>> {
+    let (lease_store, lease_file) = (config.lease_store.take(), config.lease_file.take());
+    let lease_journal = config.lease_journal;
+    let audit_file = config.audit_file.take();
+    let mut addr_pool = configure_pool(args, config).unwrap();
+
+    if let Some(path) = audit_file {
+        addr_pool.set_audit_log(audit::AuditLog::open(&path).unwrap());
+    }
+    let store = match (lease_store, lease_file) {
+        (Some(url), _) => Some((store::open(&url).unwrap(), store::redact(&url))),
+        (None, Some(path)) => {
+            let location = path.display().to_string();
+            match lease_journal {
+                true => Some((Box::new(store::JournalStore::new(path)) as _, location)),
+                false => Some((Box::new(store::FileStore::new(path)) as _, location)),
+            }
+        }
+        (None, None) => None,
+    };
+    if let Some((mut store, location)) = store {
+        let leases = store.load().unwrap();
+        info!("Restoring {} leases from {location}", leases.len());
+        let report = addr_pool.restore(leases);
+        info!("Leases from {location}: {report}");
+        addr_pool.set_lease_store(store);
+    }
+
+    Arc::new(Mutex::new(addr_pool))
+}
+
+/// A pool serving the ranges and options of `config`, everything we can
+/// change while running without losing our leases
+#[cfg(feature = "std")]
+fn configure_pool(args: &Args, config: Config) -> Result<AddrPool> {
+    let servers = configured_servers(&config);
+    // Get an IP Range to Allocate to and share between threads
+    let mut addr_pool = AddrPool::new(
+        config.subnet,
+        config.mask,
+        &config.ranges,
+        &config.exclusions,
+    )?;
+
+    // Add our DHCP Options, the subnet's override the global ones
+    let layer = |options: Vec<DhcpOption>| {
+        let mut list = DhcpOptionList::builder();
+        options.into_iter().for_each(|option| {
+            list.add(option);
+        });
+        list
+    };
+    addr_pool
+        .options_mut()
+        .merge(&layer(config.options))
+        .merge(&layer(config.subnet_options));
+    for (class, options) in config.class_options {
+        addr_pool.add_class_options(class, layer(options));
+    }
+    for (class, options) in config.user_class_options {
+        addr_pool.add_user_class_options(class, layer(options));
+    }
+    for (mac_address, options) in config.host_options {
+        addr_pool.add_host_options(mac_address, layer(options));
+    }
+    for (stage, ip_addr) in config.next_servers {
+        addr_pool.add_next_server(stage, ip_addr);
+    }
+    if let Some(path) = config.oui_file {
+        oui::set_path(path);
+    }
+    logging::set_traced(config.log_packets);
+    for (mac_address, ip_addr) in config.reservations {
+        addr_pool.add_reservation(mac_address, ip_addr)?;
+    }
+    for (uuid, ip_addr) in config.uuid_reservations {
+        addr_pool.add_uuid_reservation(uuid, ip_addr)?;
+    }
+    for (relay_id, ip_addr) in config.relay_reservations {
+        addr_pool.add_relay_reservation(relay_id, ip_addr)?;
+    }
+    if let Some(quarantine) = config.quarantine {
+        addr_pool.set_quarantine(
+            &quarantine.ranges,
+            &quarantine.allow,
+            layer(quarantine.options),
+        )?;
+    }
+    addr_pool
+        .set_unicast_replies(args.unicast_replies)
+        .set_inject_arp(args.inject_arp)
+        .set_ports(Ports {
+            server: args.port.or(config.server_port).unwrap_or(SERVER_PORT),
+            client: config.client_port.unwrap_or(CLIENT_PORT),
+        });
+    for (code, value) in &args.options {
+        addr_pool.options_mut().add_raw(*code, value)?;
+    }
+
+    addr_pool
+        .set_authoritative(config.authoritative && !args.not_authoritative)
+        .set_on_exhausted(config.on_exhausted)
+        .set_allocation(config.allocation)
+        .set_load_sharing(config.load_sharing)
+        .set_hostname_conflict(config.hostname_conflict)
+        .set_parsing(config.parsing)
+        .set_option_order(config.option_order)
+        .set_boot_file_in_header(config.boot_file_in_header);
+    if let Some(max) = config.max_leases_per_circuit {
+        addr_pool.set_max_leases_per_circuit(max);
+    }
+    if let Some(waiting) = config.waiting {
+        addr_pool.set_waiting(waiting);
+    }
+    if let Some(adaptive_lease) = config.adaptive_lease {
+        addr_pool.set_adaptive_lease(adaptive_lease);
+    }
+    if let Some(webhook) = config.mud_webhook {
+        addr_pool.set_mud_webhook(webhook);
+    }
+    if let Some(hostname) = config.server_hostname {
+        addr_pool.set_server_hostname(hostname);
+    }
+
+    let server_id = args
+        .server_id
+        .or(config.server_id)
+        .or_else(|| interface_addr(addr_pool.subnet()));
+    match server_id {
+        Some(server_id) => {
+            info!("Using server identifier {server_id}");
+            addr_pool.set_server_identifier(server_id);
+        }
+        None => warn!("Could not determine a server identifier, clients may reject us"),
+    }
+
+    // A client given one of these would clash with a machine already on it
+    let in_use = server_id.map(|ip_addr| (ip_addr, "us")).into_iter();
+    for (ip_addr, user) in in_use.chain(servers) {
+        if addr_pool.exclude(ip_addr) {
+            warn!("Excluding {ip_addr} from the pool, it is in use by {user}");
+        }
+    }
+
+    Ok(addr_pool)
+}
+
+/// Serve a config that changed while we run, keys that are not part of the
+/// pool such as `admin-listen` or `lease-store` take a restart
+#[cfg(feature = "std")]
+fn reload(args: &Args, pool: &Mutex<AddrPool>, config: Config) {
+    let result = configure_pool(args, config)
+        .and_then(|new| pool.lock().unwrap().reconfigure(new).map(|_| ()));
+    match result {
+        Ok(()) => info!("Now serving the new config"),
+        Err(error) => warn!("Still serving the old config, {error}"),
+    }
+}
+
+/// The routers and DNS servers in every layer of options
+#[cfg(feature = "std")]
+fn configured_servers(config: &Config) -> Vec<(Ipv4Addr, &'static str)> {
+    let layers = [&config.options, &config.subnet_options]
+        .into_iter()
+        .chain(config.class_options.iter().map(|(_, options)| options))
+        .chain(config.user_class_options.iter().map(|(_, options)| options))
+        .chain(config.host_options.iter().map(|(_, options)| options))
+        .chain(
+            config
+                .quarantine
+                .iter()
+                .map(|quarantine| &quarantine.options),
+        );
+    layers
+        .flatten()
+        .filter_map(|option| match option {
+            DhcpOption::Router(ip_addr) => Some((Ipv4Addr::from(*ip_addr), "the router")),
+            DhcpOption::DomainNameServer(ip_addr) => {
+                Some((Ipv4Addr::from(*ip_addr), "a DNS server"))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Find the address of the interface we would use to reach `subnet`, this
+/// is what clients expect to see as our server identifier
+#[cfg(feature = "std")]
+fn interface_addr(subnet: Ipv4Addr) -> Option<Ipv4Addr> {
+    // Connecting a UDP socket sends nothing but makes the OS pick a route
+    let socket = UdpSocket::bind((BIND_ADDRESS, 0)).ok()?;
+    socket.connect((subnet, SERVER_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        std::net::IpAddr::V4(addr) if !addr.is_unspecified() => Some(addr),
+        _ => None,
+    }
+}
+
+/// If the recv call fails, handle and log the errors
+#[cfg(feature = "std")]
+fn handle_error(error: &std::io::Error, buffer_size: usize) {
+    match RecvError::from(error) {
+        // Our read timeout expired, nothing to worry about
+        RecvError::Timeout | RecvError::Interrupted => {}
+        RecvError::Truncated => handle_truncated(buffer_size),
+        // An ICMP port unreachable from a previous send, Windows reports these
+        // on the next recv
+        RecvError::ConnectionReset => warn!("Client was unreachable: {error}"),
+        RecvError::Other => error!("Failed to receive: {error}"),
+    };
+}
+
+/// A request did not fit in our `buffer_size` byte buffer
+#[cfg(feature = "std")]
+fn handle_truncated(buffer_size: usize) {
+    warn!("Discarded a packet larger than {buffer_size} bytes, see buffer-size");
+}
+
+/// The entry point to our [Dhcp] logic
+#[cfg(feature = "std")]
+fn handle_request(transport: &impl Transport, pool: Arc<Mutex<AddrPool>>, data: &[u8]) {
+    RESPONSE_BUFFER.with_borrow_mut(|response_buffer| {
+        answer(transport, pool, data, response_buffer);
+    });
+}
+
+/// [handle_request], building any reply in `response_buffer`
+#[cfg(feature = "std")]
+fn answer(
+    transport: &impl Transport,
+    pool: Arc<Mutex<AddrPool>>,
+    data: &[u8],
+    response_buffer: &mut [u8],
+) {
+    let _trace = telemetry::trace("dhcp.request");
+    let _tracing = logging::trace(data);
+    let started = Instant::now();
+    // Send the packet to the DHCP module to parse and craft a response
+    let (parsing, inject_arp, ports) = {
+        let pool = pool.lock().unwrap();
+        (pool.parsing(), pool.inject_arp(), pool.ports())
+    };
+    logging::packet(format_args!("Received"), data);
+    let span = telemetry::span("parse");
+    let parsed = Dhcp::parse_with(data, parsing);
+    drop(span);
+    let request = match parsed {
+        Ok(request) => request,
+        Err(error) => {
+            warn!("Dropping request we could not parse: {error}");
+            stats::record_parse_error(&error);
+            telemetry::set_error(error);
+            return;
+        }
+    };
+    stats::record_received(request.message_type());
+    telemetry::set_attribute("dhcp.message_type", format!("{:?}", request.message_type()));
+    telemetry::set_attribute("dhcp.client_mac", request.client_mac());
+    telemetry::set_attribute("dhcp.secs", request.secs());
+    let response = request.handle(pool, response_buffer);
+    stats::record_latency(started.elapsed());
+    let Some(Reply {
+        len,
+        destination,
+        delay,
+    }) = response
+    else {
+        return;
+    };
+    let data = &response_buffer[..len];
+    match delay {
+        // Hold it back without holding up the requests behind it
+        Some(delay) => DELAYED.with_borrow_mut(|delayed| {
+            delayed.push(Delayed {
+                due: Instant::now() + delay,
+                data: data.to_vec(),
+                destination,
+                inject_arp,
+                ports,
+            })
+        }),
+        None => send(transport, data, destination, inject_arp, ports),
+    }
+}
+
+/// Send the crafted response to the client, or the relay that forwarded it
+#[cfg(feature = "std")]
+fn send(
+    transport: &impl Transport,
+    data: &[u8],
+    destination: Destination,
+    inject_arp: Option<ArpInjection>,
+    ports: Ports,
+) {
+    let mut injected = None;
+    let destination = match destination {
+        Destination::Relay(relay) => SocketAddr::from((relay, ports.server)),
+        Destination::Client(client) => SocketAddr::from((client, ports.client)),
+        Destination::Unbound(client, mac_address) => {
+            if inject_arp.is_some() {
+                match transport.add_arp_entry(client, mac_address) {
+                    Ok(()) => injected = Some(client),
+                    Err(error) => warn!("Could not put {client} in the ARP cache: {error}"),
+                }
+            }
+            SocketAddr::from((client, ports.client))
+        }
+        Destination::Broadcast => SocketAddr::from((BROADCAST_ADDRESS, ports.client)),
+    };
+    logging::packet(format_args!("Sending to {destination}"), data);
+    let _span = telemetry::span("send");
+    if let Err(error) = transport.send_to(data, destination) {
+        error!("Failed to send our reply to {destination}: {error}");
+    }
+    if let (Some(client), Some(ArpInjection::Remove)) = (injected, inject_arp) {
+        if let Err(error) = transport.remove_arp_entry(client) {
+            warn!("Could not take {client} out of the ARP cache: {error}");
+        }
+    }
+}
+
+#[cfg(all(feature = "std", test))]
+mod tests {
+    use super::*;
+    use state::ExhaustedPolicy;
+    use transport::MemoryTransport;
+    use types::OptionsView;
+    use types::{MacAddr, MessageType, ParameterRequest};
+
+    const SERVER_ID: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const CLIENT: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), CLIENT_PORT);
+
+    fn pool() -> Arc<Mutex<AddrPool>> {
+        let range = (
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        let mut pool = AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], &[range], &[]).unwrap();
+        pool.set_server_identifier(SERVER_ID);
+        Arc::new(Mutex::new(pool))
+    }
+
+    /// A request from `mac_address` as a client's DHCP stack would build it
+    fn request(message_type: MessageType, mac_address: MacAddr, options: &[DhcpOption]) -> Vec<u8> {
+        let mut packet = vec![0u8; 240];
+        packet[..4].copy_from_slice(&[1, 1, MacAddr::LEN as u8, 0]);
+        packet[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        packet[28..34].copy_from_slice(&mac_address.bytes());
+        packet[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+
+        let mut buffer = [0u8; 312];
+        let mut len = DhcpOption::MessageType(message_type).serialise(&mut buffer);
+        for option in options {
+            len += option.serialise(&mut buffer[len..]);
+        }
+        len += DhcpOption::End.serialise(&mut buffer[len..]);
+        packet.extend_from_slice(&buffer[..len]);
+        packet
+    }
+
+    /// Send `data` through the server and return the one reply it sent
+    fn exchange(
+        transport: &MemoryTransport,
+        pool: &Arc<Mutex<AddrPool>>,
+        data: &[u8],
+    ) -> (Vec<u8>, SocketAddr) {
+        transport.push(data, CLIENT);
+        serve(transport, pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        let mut sent = transport.take_sent();
+        assert_eq!(sent.len(), 1, "expected a single reply");
+        sent.remove(0)
+    }
+
+    fn yiaddr(reply: &[u8]) -> Ipv4Addr {
+        Ipv4Addr::new(reply[16], reply[17], reply[18], reply[19])
+    }
+
+    #[test]
+    fn discover_offer_request_ack() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 1]);
+        let broadcast = SocketAddr::from((BROADCAST_ADDRESS, CLIENT_PORT));
+
+        let discover = request(MessageType::Discover, mac_address, &[]);
+        let (offer, destination) = exchange(&transport, &pool, &discover);
+        assert_eq!(destination, broadcast);
+        assert_eq!(offer[0], 2, "a reply");
+        assert_eq!(offer[4..8], discover[4..8], "xid is echoed");
+        let options = OptionsView::new(&offer);
+        assert_eq!(options.message_type(), Some(MessageType::Offer));
+        assert_eq!(options.server_identifier(), Some(SERVER_ID));
+        let offered = yiaddr(&offer);
+        assert!(pool.lock().unwrap().in_subnet(&offered));
+
+        let request = request(
+            MessageType::Request,
+            mac_address,
+            &[
+                DhcpOption::RequestedIpAddr(offered.octets()),
+                DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+            ],
+        );
+        let (ack, destination) = exchange(&transport, &pool, &request);
+        assert_eq!(destination, broadcast);
+        assert_eq!(
+            OptionsView::new(&ack).message_type(),
+            Some(MessageType::Ack)
+        );
+        assert_eq!(yiaddr(&ack), offered);
+
+        let leases = pool.lock().unwrap().leases();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(
+            (leases[0].ip_addr, leases[0].mac_address),
+            (offered, mac_address)
+        );
+    }
+
+    #[test]
+    fn request_for_an_address_we_did_not_offer_is_nacked() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let request = request(
+            MessageType::Request,
+            MacAddr::new([2, 0, 0, 0, 0, 2]),
+            &[DhcpOption::RequestedIpAddr([192, 168, 1, 15])],
+        );
+
+        let (nak, _) = exchange(&transport, &pool, &request);
+        let options = OptionsView::new(&nak);
+        assert_eq!(options.message_type(), Some(MessageType::Nack));
+        assert_eq!(
+            options.get(DhcpOption::MESSAGE).as_deref(),
+            Some(&b"address not offered to this client"[..])
+        );
+        assert!(pool.lock().unwrap().leases().is_empty());
+    }
+
+    #[test]
+    fn clients_get_back_the_address_of_a_lease_that_ran_out() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let clock = state::ManualClock::new(std::time::UNIX_EPOCH);
+        pool.lock().unwrap().set_clock(clock.clone());
+        pool.lock().unwrap().set_authoritative(true);
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 13]);
+        let discover = request(MessageType::Discover, mac_address, &[]);
+        let select = |offered: Ipv4Addr| {
+            request(
+                MessageType::Request,
+                mac_address,
+                &[
+                    DhcpOption::RequestedIpAddr(offered.octets()),
+                    DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+                ],
+            )
+        };
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        let bound = yiaddr(&offer);
+        exchange(&transport, &pool, &select(bound));
+
+        // Expired but not yet reclaimed, the slot is still theirs
+        clock.set(std::time::UNIX_EPOCH + Duration::from_secs(DEFAULT_LEASE_TIME.into()));
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        assert_eq!(yiaddr(&offer), bound);
+        let (ack, _) = exchange(&transport, &pool, &select(bound));
+        assert_eq!(
+            OptionsView::new(&ack).message_type(),
+            Some(MessageType::Ack)
+        );
+        assert_eq!(yiaddr(&ack), bound);
+    }
+
+    #[test]
+    fn discovers_for_a_full_pool_follow_the_exhausted_policy() {
+        let transport = MemoryTransport::default();
+        let single = (
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 10),
+        );
+        let pool = AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], &[single], &[]).unwrap();
+        let pool = Arc::new(Mutex::new(pool));
+        let discover = |host| {
+            request(
+                MessageType::Discover,
+                MacAddr::new([2, 0, 0, 0, 0, host]),
+                &[],
+            )
+        };
+        exchange(&transport, &pool, &discover(1));
+
+        transport.push(&discover(2), CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+
+        pool.lock().unwrap().set_on_exhausted(ExhaustedPolicy::Nak);
+        let (nak, _) = exchange(&transport, &pool, &discover(2));
+        let options = OptionsView::new(&nak);
+        assert_eq!(options.message_type(), Some(MessageType::Nack));
+        assert_eq!(options.message().as_deref(), Some("no free addresses"));
+    }
+
+    #[test]
+    fn inform_is_acked_and_other_messages_are_not_answered() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        pool.lock()
+            .unwrap()
+            .options_mut()
+            .set(DhcpOption::LeaseTime(600));
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 4]);
+        let requested = [DhcpOption::ParameterRequestList(vec![
+            ParameterRequest::SubnetMask,
+            ParameterRequest::IpAddressLease,
+        ])];
+
+        let mut inform = request(MessageType::Inform, mac_address, &requested);
+        inform[12..16].copy_from_slice(&[192, 168, 1, 50]);
+        let (ack, destination) = exchange(&transport, &pool, &inform);
+        assert_eq!(
+            destination,
+            SocketAddr::from(([192, 168, 1, 50], CLIENT_PORT))
+        );
+        let options = OptionsView::new(&ack);
+        assert_eq!(options.message_type(), Some(MessageType::Ack));
+        assert!(options.get(DhcpOption::SUBNET_MASK).is_some());
+        assert!(options.get(DhcpOption::LEASE_TIME).is_none());
+        assert_eq!(yiaddr(&ack), Ipv4Addr::UNSPECIFIED);
+
+        // A RELEASE of a lease we do not know, replies only servers send and
+        // a type from the future are all ignored without a reply
+        let mut release = request(MessageType::Release, mac_address, &[]);
+        release[12..16].copy_from_slice(&[192, 168, 1, 10]);
+        for message_type in [MessageType::Offer, MessageType::Unknown(42)] {
+            transport.push(&request(message_type, mac_address, &[]), CLIENT);
+        }
+        transport.push(&release, CLIENT);
+        for _ in 0..3 {
+            serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        }
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn options_we_have_no_name_for_are_served_by_code() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        pool.lock()
+            .unwrap()
+            .options_mut()
+            .add_raw(150, "ip:10.0.0.1")
+            .unwrap();
+        let requested = [DhcpOption::ParameterRequestList(vec![
+            ParameterRequest::Other(150),
+            ParameterRequest::Other(224),
+        ])];
+        let discover = request(
+            MessageType::Discover,
+            MacAddr::new([2, 0, 0, 0, 0, 5]),
+            &requested,
+        );
+
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        let options = OptionsView::new(&offer);
+        assert_eq!(options.get(150).as_deref(), Some(&[10, 0, 0, 1][..]));
+        assert!(options.get(224).is_none());
+    }
+
+    #[test]
+    fn every_configured_option_is_returned_when_requested() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let routes = vec![24, 10, 0, 1, 192, 168, 1, 254];
+        let configured = [
+            DhcpOption::TimeOffset(3600),
+            DhcpOption::Router([192, 168, 1, 254]),
+            DhcpOption::TimeServer(vec![[192, 168, 1, 2]]),
+            DhcpOption::DomainNameServer([192, 168, 1, 53]),
+            DhcpOption::DomainName("home.arpa".to_owned()),
+            DhcpOption::RootPath("/srv/root".to_owned()),
+            DhcpOption::ExtensionsPath("/srv/extensions".to_owned()),
+            DhcpOption::InterfaceMtu(1400),
+            DhcpOption::BootFileSize(64),
+            DhcpOption::NtpServers(vec![[192, 168, 1, 123]]),
+            DhcpOption::NetBiosNameServer(vec![[192, 168, 1, 3]]),
+            DhcpOption::NetBiosNodeType(DhcpOption::NETBIOS_H_NODE),
+            DhcpOption::NetBiosScope("scope".to_owned()),
+            DhcpOption::LeaseTime(600),
+            DhcpOption::TftpServerName("tftp.home.arpa".to_owned()),
+            DhcpOption::BootFileName("pxelinux.0".to_owned()),
+            DhcpOption::CaptivePortal("https://portal.home.arpa".to_owned()),
+            DhcpOption::DomainSearch(vec!["home.arpa".to_owned()]),
+            DhcpOption::ProxyAutodiscovery("http://wpad.home.arpa/wpad.dat".to_owned()),
+            DhcpOption::Unknown(DhcpOption::CLASSLESS_STATIC_ROUTE, routes.clone()),
+            DhcpOption::Unknown(150, vec![192, 168, 1, 69]),
+        ];
+        for option in &configured {
+            pool.lock().unwrap().options_mut().set(option.clone());
+        }
+        // Including those the pool sets itself
+        let mut codes: Vec<u8> = configured.iter().map(DhcpOption::opcode).collect();
+        codes.extend([
+            DhcpOption::SUBNET_MASK,
+            DhcpOption::BROADCAST_ADDRESS,
+            DhcpOption::DHCP_SERVER_IP_ADDR,
+        ]);
+        let mut params: Vec<ParameterRequest> = codes.iter().map(|code| (*code).into()).collect();
+        params.push(ParameterRequest::ClasslessStaticRouteMicrosoft);
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 6]);
+        let requested = [DhcpOption::ParameterRequestList(params)];
+
+        let discover = request(MessageType::Discover, mac_address, &requested);
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        let request = request(
+            MessageType::Request,
+            mac_address,
+            &[
+                requested[0].clone(),
+                DhcpOption::RequestedIpAddr(yiaddr(&offer).octets()),
+                DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+            ],
+        );
+        let (ack, _) = exchange(&transport, &pool, &request);
+
+        for reply in [offer, ack] {
+            let options = OptionsView::new(&reply);
+            for code in &codes {
+                assert!(options.get(*code).is_some(), "option {code} is missing");
+            }
+            assert_eq!(options.get(249).as_deref(), Some(&routes[..]));
+        }
+    }
+
+    #[test]
+    fn mandatory_options_are_sent_to_clients_that_do_not_ask() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        pool.lock()
+            .unwrap()
+            .options_mut()
+            .set(DhcpOption::Router([192, 168, 1, 254]));
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 7]);
+        let picky = [DhcpOption::ParameterRequestList(vec![
+            ParameterRequest::Router,
+        ])];
+
+        let (offer, _) = exchange(
+            &transport,
+            &pool,
+            &request(MessageType::Discover, mac_address, &picky),
+        );
+        let (ack, _) = exchange(
+            &transport,
+            &pool,
+            &request(
+                MessageType::Request,
+                mac_address,
+                &[DhcpOption::RequestedIpAddr(yiaddr(&offer).octets())],
+            ),
+        );
+        for reply in [offer, ack] {
+            let options = OptionsView::new(&reply);
+            assert_eq!(
+                options.get(DhcpOption::SUBNET_MASK).as_deref(),
+                Some(&[255, 255, 255, 0][..])
+            );
+            assert_eq!(
+                options.get(DhcpOption::LEASE_TIME).as_deref(),
+                Some(&DEFAULT_LEASE_TIME.to_be_bytes()[..])
+            );
+            assert_eq!(options.server_identifier(), Some(SERVER_ID));
+        }
+    }
+
+    #[test]
+    fn relayed_replies_keep_giaddr_and_flags_and_loops_are_dropped() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let relay = Ipv4Addr::new(192, 168, 1, 254);
+        let mut discover = request(MessageType::Discover, MacAddr::new([2, 0, 0, 0, 0, 8]), &[]);
+        discover[wire::HOPS] = 1;
+        discover[wire::FLAGS].copy_from_slice(&[0x80, 0]);
+        discover[wire::GIADDR].copy_from_slice(&relay.octets());
+
+        let (offer, destination) = exchange(&transport, &pool, &discover);
+        assert_eq!(destination, SocketAddr::from((relay, SERVER_PORT)));
+        let header = wire::Header::read(&offer).unwrap();
+        assert_eq!(header.giaddr, relay.octets());
+        assert_eq!(header.flags, [0x80, 0]);
+        assert_eq!(header.hops, 0);
+
+        discover[wire::HOPS] = 17;
+        transport.push(&discover, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn option_118_chooses_the_scope_and_is_echoed() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 11]);
+
+        let discover = request(
+            MessageType::Discover,
+            mac_address,
+            &[DhcpOption::SubnetSelection([192, 168, 1, 0])],
+        );
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        assert!(pool.lock().unwrap().in_subnet(&yiaddr(&offer)));
+        assert_eq!(
+            OptionsView::new(&offer)
+                .get(DhcpOption::SUBNET_SELECTION)
+                .as_deref(),
+            Some(&[192, 168, 1, 0][..])
+        );
+
+        // A link we do not serve is left to another server
+        let discover = request(
+            MessageType::Discover,
+            mac_address,
+            &[DhcpOption::SubnetSelection([10, 20, 0, 0])],
+        );
+        transport.push(&discover, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn relays_can_select_a_link_other_than_giaddr() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        // A relay reachable only from another VRF, serving our subnet
+        let relay = Ipv4Addr::new(10, 0, 0, 1);
+        let link_selection = [5, 4, 192, 168, 1, 0];
+        let relay_info = types::RelayAgentInfo::try_from(&link_selection[..]).unwrap();
+        let mut discover = request(
+            MessageType::Discover,
+            MacAddr::new([2, 0, 0, 0, 0, 10]),
+            &[DhcpOption::RelayAgentInformation(relay_info)],
+        );
+        discover[wire::GIADDR].copy_from_slice(&relay.octets());
+
+        let (offer, destination) = exchange(&transport, &pool, &discover);
+        assert_eq!(destination, SocketAddr::from((relay, SERVER_PORT)));
+        assert!(pool.lock().unwrap().in_subnet(&yiaddr(&offer)));
+
+        // Without it giaddr is the link, which is not ours
+        let mut discover = request(
+            MessageType::Discover,
+            MacAddr::new([2, 0, 0, 0, 0, 10]),
+            &[],
+        );
+        discover[wire::GIADDR].copy_from_slice(&relay.octets());
+        transport.push(&discover, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+
+        // Nor may a client pick its own link without a relay
+        let relay_info = types::RelayAgentInfo::try_from(&[5, 4, 10, 9, 0, 0][..]).unwrap();
+        let discover = request(
+            MessageType::Discover,
+            MacAddr::new([2, 0, 0, 0, 0, 10]),
+            &[DhcpOption::RelayAgentInformation(relay_info)],
+        );
+        exchange(&transport, &pool, &discover);
+    }
+
+    #[test]
+    fn force_renew_is_only_sent_to_bound_clients() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 12]);
+        let mut buffer = [0u8; DEFAULT_UDP_BUFFER_SIZE];
+
+        let discover = request(MessageType::Discover, mac_address, &[]);
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        let offered = yiaddr(&offer);
+        // Offered is not bound
+        assert_eq!(
+            Dhcp::force_renew(&pool.lock().unwrap(), &offered, &mut buffer),
+            None
+        );
+
+        let request = request(
+            MessageType::Request,
+            mac_address,
+            &[
+                DhcpOption::RequestedIpAddr(offered.octets()),
+                DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+            ],
+        );
+        exchange(&transport, &pool, &request);
+        let len = Dhcp::force_renew(&pool.lock().unwrap(), &offered, &mut buffer).unwrap();
+        let force_renew = &buffer[..len];
+        assert_eq!(force_renew[0], 2, "a reply");
+        assert_eq!(force_renew[28..34], mac_address.bytes());
+        let options = OptionsView::new(force_renew);
+        assert_eq!(options.message_type(), Some(MessageType::ForceRenew));
+        assert_eq!(options.server_identifier(), Some(SERVER_ID));
+    }
+
+    #[test]
+    fn options_are_sent_in_the_configured_then_requested_order() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        pool.lock()
+            .unwrap()
+            .options_mut()
+            .set(DhcpOption::DomainNameServer([192, 168, 1, 53]))
+            .set(DhcpOption::Router([192, 168, 1, 254]))
+            .set(DhcpOption::SubnetMask([255, 255, 255, 0]));
+        let params = vec![
+            ParameterRequest::DomainNameServer,
+            ParameterRequest::SubnetMask,
+            ParameterRequest::Router,
+        ];
+        let discover = request(
+            MessageType::Discover,
+            MacAddr::new([2, 0, 0, 0, 0, 11]),
+            &[DhcpOption::ParameterRequestList(params)],
+        );
+        let codes = |reply: &[u8]| -> Vec<u8> {
+            OptionsView::new(reply)
+                .iter()
+                .map(|(code, _)| code)
+                .collect()
+        };
+
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        assert_eq!(codes(&offer), [53, 54, 51, 6, 1, 3]);
+
+        pool.lock().unwrap().set_option_order(vec![1, 53]);
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        assert_eq!(codes(&offer), [1, 53, 6, 3, 51, 54]);
+    }
+
+    #[test]
+    fn replies_go_to_the_configured_ports() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        pool.lock().unwrap().set_ports(Ports {
+            server: 6767,
+            client: 6868,
+        });
+        let discover = request(MessageType::Discover, MacAddr::new([2, 0, 0, 0, 0, 9]), &[]);
+        let (_, destination) = exchange(&transport, &pool, &discover);
+        assert_eq!(destination, SocketAddr::from((BROADCAST_ADDRESS, 6868)));
+
+        let relay = Ipv4Addr::new(192, 168, 1, 254);
+        let mut relayed = discover.clone();
+        relayed[wire::GIADDR].copy_from_slice(&relay.octets());
+        let (_, destination) = exchange(&transport, &pool, &relayed);
+        assert_eq!(destination, SocketAddr::from((relay, 6767)));
+    }
+
+    #[test]
+    fn requests_larger_than_the_buffer_are_discarded() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 1]);
+        let mut discover = request(MessageType::Discover, mac_address, &[]);
+        discover.resize(DEFAULT_UDP_BUFFER_SIZE, 0);
+        transport.push(&discover, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert_eq!(transport.take_sent().len(), 1);
+
+        // One byte more and the socket would have truncated it
+        discover.push(0);
+        transport.push(&discover, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn our_address_and_configured_servers_are_never_offered() {
+        let config = "\
+            subnet = 192.168.1.0
+            mask = 255.255.255.0
+            range = 192.168.1.10 192.168.1.20
+            server-id = 192.168.1.10
+            [options]
+            router = 192.168.1.11
+            domain-name-server = 192.168.1.1";
+        let args = Args {
+            not_authoritative: true,
+            ..Args::default()
+        };
+        let pool = configure_pool(&args, Config::parse(config).unwrap()).unwrap();
+        assert_eq!(
+            pool.server_identifier(),
+            Some(Ipv4Addr::new(192, 168, 1, 10))
+        );
+        assert!(!pool.authoritative());
+        // The DNS server is outside our range, nothing to exclude
+        let excluded = [10, 11].map(|host| Ipv4Addr::new(192, 168, 1, host));
+        assert_eq!(pool.exclusions(), excluded.map(|ip| (ip, ip)));
+    }
+
+    #[test]
+    fn unparseable_requests_get_no_reply() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        transport.push(&[1, 2, 3], CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+
+        // Nothing queued, we time out like the socket would
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn clients_from_another_network_are_naked_by_broadcast() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 13]);
+        let init_reboot = request(
+            MessageType::Request,
+            mac_address,
+            &[DhcpOption::RequestedIpAddr([10, 9, 0, 5])],
+        );
+
+        let (nak, destination) = exchange(&transport, &pool, &init_reboot);
+        assert_eq!(
+            destination,
+            SocketAddr::from((BROADCAST_ADDRESS, CLIENT_PORT))
+        );
+        let options = OptionsView::new(&nak);
+        assert_eq!(options.message_type(), Some(MessageType::Nack));
+        assert_eq!(
+            options.get(DhcpOption::MESSAGE).as_deref(),
+            Some(&b"requested address is on another network"[..])
+        );
+
+        // Through a relay, which is told to broadcast it
+        let relay = Ipv4Addr::new(192, 168, 1, 1);
+        let mut relayed = init_reboot.clone();
+        relayed[wire::GIADDR].copy_from_slice(&relay.octets());
+        let (nak, destination) = exchange(&transport, &pool, &relayed);
+        assert_eq!(destination, SocketAddr::from((relay, SERVER_PORT)));
+        assert_eq!(nak[10..12], [0x80, 0x00]);
+
+        // Another server may know the network
+        pool.lock().unwrap().set_authoritative(false);
+        transport.push(&init_reboot, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn renewals_are_unicast_to_the_address_the_client_holds() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 14]);
+        let discover = request(MessageType::Discover, mac_address, &[]);
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        let offered = yiaddr(&offer);
+        let select = request(
+            MessageType::Request,
+            mac_address,
+            &[
+                DhcpOption::RequestedIpAddr(offered.octets()),
+                DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+            ],
+        );
+        exchange(&transport, &pool, &select);
+
+        let mut renew = request(MessageType::Request, mac_address, &[]);
+        renew[12..16].copy_from_slice(&offered.octets());
+        let (ack, destination) = exchange(&transport, &pool, &renew);
+        assert_eq!(
+            OptionsView::new(&ack).message_type(),
+            Some(MessageType::Ack)
+        );
+        assert_eq!(destination, SocketAddr::from((offered, CLIENT_PORT)));
+    }
+
+    #[test]
+    fn requests_are_answered_while_a_delayed_offer_waits() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let renewing = MacAddr::new([2, 0, 0, 0, 0, 15]);
+        let discover = request(MessageType::Discover, renewing, &[]);
+        let (offer, _) = exchange(&transport, &pool, &discover);
+        let bound = yiaddr(&offer);
+        let select = request(
+            MessageType::Request,
+            renewing,
+            &[
+                DhcpOption::RequestedIpAddr(bound.octets()),
+                DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+            ],
+        );
+        exchange(&transport, &pool, &select);
+
+        let delay = Duration::from_millis(200);
+        pool.lock().unwrap().set_load_sharing(state::LoadSharing {
+            delay,
+            parity: None,
+            percent: 100,
+        });
+        let discover = request(
+            MessageType::Discover,
+            MacAddr::new([2, 0, 0, 0, 0, 16]),
+            &[],
+        );
+        let mut renew = request(MessageType::Request, renewing, &[]);
+        renew[wire::CIADDR].copy_from_slice(&bound.octets());
+        transport.push(&discover, CLIENT);
+        transport.push(&renew, CLIENT);
+        let started = Instant::now();
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+
+        let sent = transport.take_sent();
+        assert!(started.elapsed() < delay, "we waited on the OFFER");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            OptionsView::new(&sent[0].0).message_type(),
+            Some(MessageType::Ack)
+        );
+        assert_eq!(next_delayed().map(|due| due > Instant::now()), Some(true));
+
+        thread::sleep(delay);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        let sent = transport.take_sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            OptionsView::new(&sent[0].0).message_type(),
+            Some(MessageType::Offer)
+        );
+        assert_eq!(next_delayed(), None);
+    }
+
+    #[test]
+    fn replies_are_unicast_to_clients_put_in_the_arp_cache() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 3]);
+        let discover = request(MessageType::Discover, mac_address, &[]);
+
+        pool.lock()
+            .unwrap()
+            .set_inject_arp(Some(ArpInjection::Keep));
+        let (offer, destination) = exchange(&transport, &pool, &discover);
+        let offered = yiaddr(&offer);
+        assert_eq!(destination, SocketAddr::from((offered, CLIENT_PORT)));
+        assert_eq!(transport.arp_entries().get(&offered), Some(&mac_address));
+
+        pool.lock()
+            .unwrap()
+            .set_inject_arp(Some(ArpInjection::Remove));
+        transport.remove_arp_entry(offered).unwrap();
+        let (_, destination) = exchange(&transport, &pool, &discover);
+        assert_eq!(destination, SocketAddr::from((offered, CLIENT_PORT)));
+        assert!(transport.arp_entries().is_empty());
+    }
+
+    #[test]
+    fn the_lease_journal_is_replayed_after_a_crash() {
+        use leases::Lease;
+        use std::time::{Duration, UNIX_EPOCH};
+        use store::{JournalStore, LeaseStore};
+
+        let dir = std::env::temp_dir().join(format!("dhc3po-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("leases");
+        let lease = |last_octet, ip_addr| {
+            let mac_address = MacAddr::new([2, 0, 0, 0, 0, last_octet]);
+            Lease {
+                ip_addr,
+                // Whole seconds in the future, as the file keeps them
+                expires: UNIX_EPOCH + Duration::from_secs(4_000_000_000),
+                mac_address,
+                key: types::ClientKey::Mac(mac_address),
+            }
+        };
+        let (first, second) = (
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 11),
+        );
+        leases::save(&path, &[lease(1, first)]).unwrap();
+        // The first client moved, the second bound, then we died mid-line
+        let journal = format!(
+            "{}\n{}\n192.168.1.1",
+            lease(1, second).encode(),
+            lease(2, first).encode()
+        );
+        std::fs::write(path.with_extension("journal"), journal).unwrap();
+
+        let mut store = JournalStore::new(path.clone());
+        assert_eq!(store.load().unwrap(), [lease(2, first), lease(1, second)]);
+        // Folded into the lease file, with an empty journal to append to
+        assert_eq!(leases::load(&path).unwrap().len(), 2);
+        assert!(std::fs::read(path.with_extension("journal"))
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn requests_without_an_offer_are_naked() {
+        let (transport, pool) = (MemoryTransport::default(), pool());
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, 0x13]);
+        let request = request(
+            MessageType::Request,
+            mac_address,
+            &[
+                DhcpOption::RequestedIpAddr([192, 168, 1, 10]),
+                DhcpOption::DhcpServerIpAddr(SERVER_ID.octets()),
+            ],
+        );
+        let (nak, _) = exchange(&transport, &pool, &request);
+        let options = OptionsView::new(&nak);
+        assert_eq!(options.message_type(), Some(MessageType::Nack));
+        assert_eq!(
+            options.message().as_deref(),
+            Some("no offer was made to this client")
+        );
+
+        // Nothing to NAK for a server that is not authoritative
+        pool.lock().unwrap().set_authoritative(false);
+        transport.push(&request, CLIENT);
+        serve(&transport, &pool, &mut [0u8; DEFAULT_UDP_BUFFER_SIZE + 1]);
+        assert!(transport.take_sent().is_empty());
+    }
+
+    #[test]
+    fn only_requests_from_traced_clients_are_traced() {
+        let (traced, other) = (
+            MacAddr::new([2, 0, 0, 0, 0, 0x71]),
+            MacAddr::new([2, 0, 0, 0, 0, 0x72]),
+        );
+        logging::set_traced([traced]);
+        assert!(logging::trace(&request(MessageType::Discover, traced, &[])).is_some());
+        assert!(logging::trace(&request(MessageType::Discover, other, &[])).is_none());
+
+        assert!(logging::add_traced(other));
+        assert!(!logging::add_traced(other));
+        assert_eq!(logging::traced(), [traced, other]);
+        assert!(logging::trace(&request(MessageType::Discover, other, &[])).is_some());
+        assert!(logging::remove_traced(&other));
+        assert!(!logging::remove_traced(&other));
+        // Too short to have a chaddr
+        assert!(logging::trace(&[1, 1, 6]).is_none());
+        logging::set_traced([]);
+    }
+}
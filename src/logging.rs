@@ -0,0 +1,381 @@
+//! Where our log lines go. Until the config is loaded they go to stderr as
+//! formatted by env_logger, then to the target set with `log`:
+//!
+//! * `log = stderr` the default
+//! * `log = syslog` RFC 5424 messages to the local syslog daemon on
+//!   `/dev/log`, `log = syslog <path>` for another unix socket or
+//!   `log = syslog <host:port>` for a collector over UDP
+//! * `log = journald` the systemd journal, with the module, file and line of
+//!   each message as fields `journalctl` can match on
+//!
+//! `RUST_LOG` picks what is logged whichever the target. We connect when the
+//! config is loaded, before any chroot, so the socket stays reachable. A
+//! line the target refuses goes to stderr rather than being lost.
+//!
+//! Every packet we receive and send can be logged decoded field by field
+//! under the [PACKETS] target at debug, `RUST_LOG=dhc3po::packets=debug`.
+//! That is a lot on a busy network, so `log-packets`, or `POST
+//! /api/trace/<mac>` on the admin API, instead names clients to trace: their
+//! packets and every line logged while answering them go out whatever
+//! `RUST_LOG` says, see [trace]
+
+use crate::types::MacAddr;
+use crate::{decode, wire, Error, Result};
+use log::{debug, log_enabled, Level, LevelFilter, Log, Metadata, Record};
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the local syslog daemon listens
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Where journald listens for its native protocol
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// RFC 5424 facility 3, system daemons
+const SYSLOG_FACILITY_DAEMON: u8 = 3;
+/// Our APP-NAME in syslog and SYSLOG_IDENTIFIER in the journal
+const IDENTIFIER: &str = "dhc3po";
+
+/// The target packets are logged under
+pub const PACKETS: &str = "dhc3po::packets";
+
+/// Where lines go once the config is loaded, stderr when [None]
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+/// What `RUST_LOG` lets through, for when nobody is traced
+static FILTER: OnceLock<LevelFilter> = OnceLock::new();
+/// Clients we log everything about whatever `RUST_LOG` says
+static TRACED: RwLock<BTreeSet<MacAddr>> = RwLock::new(BTreeSet::new());
+
+thread_local! {
+    /// Set while this thread logs something `RUST_LOG` must not filter out
+    static FORCED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Where log lines go, set with `log` in the config
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+
+    /// A syslog daemon listening on a unix datagram socket
+    SyslogSocket(PathBuf),
+
+    /// A syslog collector listening on UDP
+    SyslogUdp(SocketAddr),
+
+    /// The systemd journal
+    Journald,
+}
+
+impl LogTarget {
+    /// `stderr`, `journald`, `syslog` or `syslog` followed by a socket path
+    /// or address
+    pub fn parse(value: &str) -> Option<Self> {
+        let (kind, server) = match value.split_once(char::is_whitespace) {
+            Some((kind, server)) => (kind, Some(server.trim())),
+            None => (value, None),
+        };
+        match (kind, server) {
+            ("stderr", None) => Some(Self::Stderr),
+            ("journald", None) => Some(Self::Journald),
+            ("syslog", None) => Some(Self::SyslogSocket(PathBuf::from(SYSLOG_SOCKET))),
+            ("syslog", Some(server)) => match server.parse() {
+                Ok(addr) => Some(Self::SyslogUdp(addr)),
+                Err(_) if server.starts_with('/') => Some(Self::SyslogSocket(server.into())),
+                Err(_) => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// A connected datagram socket, one datagram per line
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Socket {
+    fn udp(addr: SocketAddr) -> Result<Self> {
+        let bind = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        UdpSocket::bind(bind)
+            .and_then(|socket| socket.connect(addr).map(|()| Self::Udp(socket)))
+            .map_err(Error::CannotOpenLog)
+    }
+
+    #[cfg(unix)]
+    fn unix(path: &Path) -> Result<Self> {
+        UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(path).map(|()| Self::Unix(socket)))
+            .map_err(Error::CannotOpenLog)
+    }
+
+    #[cfg(not(unix))]
+    fn unix(_path: &Path) -> Result<Self> {
+        Err(Error::CannotOpenLog(io::ErrorKind::Unsupported.into()))
+    }
+
+    fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(socket) => socket.send(datagram),
+            #[cfg(unix)]
+            Self::Unix(socket) => socket.send(datagram),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    /// With our host name, read before any chroot hides it
+    Syslog(Socket, String),
+    Journald(Socket),
+}
+
+impl Sink {
+    fn send(&self, record: &Record) -> io::Result<usize> {
+        match self {
+            Self::Syslog(socket, hostname) => {
+                socket.send(syslog_message(record, hostname).as_bytes())
+            }
+            Self::Journald(socket) => socket.send(&journald_message(record)),
+        }
+    }
+}
+
+/// Filters with env_logger then writes to the [SINK] or stderr
+struct Logger {
+    stderr: env_logger::Logger,
+    /// Formats like `stderr` but lets everything through, for [FORCED] lines
+    unfiltered: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FORCED.get() || self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let forced = FORCED.get();
+        if !forced && !self.stderr.matches(record) {
+            return;
+        }
+        let sent = match SINK.lock().unwrap().as_ref() {
+            Some(sink) => sink.send(record).is_ok(),
+            None => false,
+        };
+        match (sent, forced) {
+            (true, _) => {}
+            (false, false) => self.stderr.log(record),
+            (false, true) => self.unfiltered.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+    }
+}
+
+/// Log to stderr, filtered by `RUST_LOG`, until [set_target] is called
+pub fn init() {
+    let stderr = env_logger::Builder::from_default_env().build();
+    let unfiltered = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    _ = FILTER.set(stderr.filter());
+    log::set_max_level(stderr.filter());
+    log::set_boxed_logger(Box::new(Logger { stderr, unfiltered })).unwrap();
+}
+
+/// Trace `clients`, replacing those traced before
+pub fn set_traced(clients: impl IntoIterator<Item = MacAddr>) {
+    let mut traced = TRACED.write().unwrap();
+    *traced = clients.into_iter().collect();
+    update_max_level(&traced);
+}
+
+/// Trace `client` as well, false when it already was
+pub fn add_traced(client: MacAddr) -> bool {
+    let mut traced = TRACED.write().unwrap();
+    let added = traced.insert(client);
+    update_max_level(&traced);
+    added
+}
+
+/// Stop tracing `client`, false when it was not
+pub fn remove_traced(client: &MacAddr) -> bool {
+    let mut traced = TRACED.write().unwrap();
+    let removed = traced.remove(client);
+    update_max_level(&traced);
+    removed
+}
+
+/// The clients we are tracing
+pub fn traced() -> Vec<MacAddr> {
+    TRACED.read().unwrap().iter().copied().collect()
+}
+
+/// The log macros check the max level before they reach our filter, so
+/// while anyone is traced it has to let everything through
+fn update_max_level(traced: &BTreeSet<MacAddr>) {
+    let filter = FILTER.get().copied().unwrap_or_else(log::max_level);
+    log::set_max_level(match traced.is_empty() {
+        true => filter,
+        false => LevelFilter::Trace,
+    });
+}
+
+/// Everything this thread logs goes out whatever `RUST_LOG` says until
+/// this is dropped
+#[derive(Debug)]
+pub struct Tracing(());
+
+impl Drop for Tracing {
+    fn drop(&mut self) {
+        FORCED.set(false);
+    }
+}
+
+/// Log everything about answering `packet` while the result is held, when
+/// the client in its chaddr is traced
+pub fn trace(packet: &[u8]) -> Option<Tracing> {
+    let client = packet
+        .get(wire::CHADDR)
+        .and_then(|chaddr| <[u8; MacAddr::LEN]>::try_from(&chaddr[..MacAddr::LEN]).ok())
+        .map(MacAddr::new)?;
+    if !TRACED.read().unwrap().contains(&client) {
+        return None;
+    }
+    FORCED.set(true);
+    Some(Tracing(()))
+}
+
+/// Log `packet` decoded in full, after `heading`, when `RUST_LOG` lets
+/// [PACKETS] through at debug or we are [trace]ing its client
+pub fn packet(heading: fmt::Arguments, packet: &[u8]) {
+    if !log_enabled!(target: PACKETS, Level::Debug) {
+        return;
+    }
+    let text = decode::decode(packet).unwrap_or_else(|error| format!("{error}\n"));
+    debug!(target: PACKETS, "{heading}\n{}", text.trim_end());
+}
+
+/// Send log lines to `target` from now on
+pub fn set_target(target: &LogTarget) -> Result<()> {
+    let sink = match target {
+        LogTarget::Stderr => None,
+        LogTarget::SyslogSocket(path) => Some(Sink::Syslog(Socket::unix(path)?, hostname())),
+        LogTarget::SyslogUdp(addr) => Some(Sink::Syslog(Socket::udp(*addr)?, hostname())),
+        LogTarget::Journald => Some(Sink::Journald(Socket::unix(Path::new(JOURNALD_SOCKET))?)),
+    };
+    *SINK.lock().unwrap() = sink;
+    Ok(())
+}
+
+/// syslog severities, there is nothing below debug for trace
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG` (RFC 5424 6)
+fn syslog_message(record: &Record, hostname: &str) -> String {
+    format!(
+        "<{}>1 {} {} {IDENTIFIER} {} - - {}",
+        SYSLOG_FACILITY_DAEMON * 8 + severity(record.level()),
+        timestamp(SystemTime::now()),
+        hostname,
+        std::process::id(),
+        record.args()
+    )
+}
+
+/// `KEY=value` lines, or the key, a little endian length and the value for
+/// values with a newline, see `systemd-journald.socket(8)`
+fn journald_message(record: &Record) -> Vec<u8> {
+    let mut message = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        message.extend_from_slice(key.as_bytes());
+        match value.contains('\n') {
+            true => {
+                message.push(b'\n');
+                message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            }
+            false => message.push(b'='),
+        }
+        message.extend_from_slice(value.as_bytes());
+        message.push(b'\n');
+    };
+
+    field("MESSAGE", &record.args().to_string());
+    field("PRIORITY", &severity(record.level()).to_string());
+    field("SYSLOG_IDENTIFIER", IDENTIFIER);
+    field("SYSLOG_FACILITY", &SYSLOG_FACILITY_DAEMON.to_string());
+    field("RUST_TARGET", record.target());
+    if let Some(module) = record.module_path() {
+        field("RUST_MODULE", module);
+    }
+    if let Some(file) = record.file() {
+        field("CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        field("CODE_LINE", &line.to_string());
+    }
+    message
+}
+
+/// `2023-01-05T22:00:00Z`, RFC 3339 in UTC
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Howard Hinnant's civil_from_days
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Our host name, or the NILVALUE when we cannot tell
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_owned())
+        .ok()
+        .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+        .unwrap_or_else(|| "-".to_owned())
+}
@@ -0,0 +1,27 @@
+//! Manufacturer Usage Descriptions (RFC 8520). IoT devices send the URL of a
+//! profile describing the network access they need in option 161, we keep it
+//! with their lease and POST new ones to `mud-webhook` so a policy system can
+//! fetch the profile
+
+use log::{info, warn};
+
+use crate::alerts::Webhook;
+use crate::json;
+use crate::types::MacAddr;
+use std::net::Ipv4Addr;
+use std::thread;
+
+/// Tell `webhook` that the client at `ip_addr` has this MUD URL, in the
+/// background so a slow webhook does not hold up our replies
+pub fn announce(webhook: &Webhook, ip_addr: Ipv4Addr, mac_address: MacAddr, url: &str) {
+    info!("{mac_address} at {ip_addr} has MUD URL {url}");
+    let body = format!(
+        "{{\"ip\": \"{ip_addr}\", \"mac\": \"{mac_address}\", \"mud_url\": {}}}",
+        json::string(url)
+    );
+    let webhook = webhook.clone();
+    thread::spawn(move || match webhook.post(&body) {
+        Ok(status) => info!("MUD webhook answered {status}"),
+        Err(error) => warn!("Could not send {mac_address}'s MUD URL to the webhook: {error}"),
+    });
+}
@@ -0,0 +1,165 @@
+//! Look up the manufacturer of a MAC address from its OUI, the first three
+//! bytes, so devices in logs and lease listings are recognisable.
+//!
+//! The full IEEE registry is large, so we only bundle the vendors that turn
+//! up on home and lab networks most often. On first use we try to load a
+//! complete table from `oui-file` or a copy the OS ships, in either the IEEE
+//! `oui.txt` format or Wireshark's `manuf`.
+
+use log::{info, warn};
+
+use crate::types::MacAddr;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Where distributions install the IEEE registry or Wireshark's copy of it
+const SYSTEM_TABLES: [&str; 4] = [
+    "/usr/share/ieee-data/oui.txt",
+    "/usr/share/misc/oui.txt",
+    "/usr/share/wireshark/manuf",
+    "/usr/share/hwdata/oui.txt",
+];
+
+/// Bit 1 of the first byte is set on addresses the device made up itself,
+/// phones and laptops do this for privacy
+const LOCALLY_ADMINISTERED: u8 = 0x02;
+
+const BUNDLED: [([u8; 3], &str); 28] = [
+    ([0x00, 0x00, 0x0C], "Cisco Systems"),
+    ([0x00, 0x04, 0x4B], "NVIDIA"),
+    ([0x00, 0x08, 0x9B], "QNAP Systems"),
+    ([0x00, 0x0C, 0x29], "VMware"),
+    ([0x00, 0x11, 0x32], "Synology"),
+    ([0x00, 0x15, 0x5D], "Microsoft (Hyper-V)"),
+    ([0x00, 0x16, 0x3E], "Xensource"),
+    ([0x00, 0x17, 0x88], "Philips Lighting"),
+    ([0x00, 0x1A, 0x11], "Google"),
+    ([0x00, 0x1C, 0x42], "Parallels"),
+    ([0x00, 0x50, 0x56], "VMware"),
+    ([0x00, 0x90, 0xA9], "Western Digital"),
+    ([0x00, 0xE0, 0x4C], "Realtek"),
+    ([0x08, 0x00, 0x27], "PCS Systemtechnik (VirtualBox)"),
+    ([0x18, 0xFE, 0x34], "Espressif"),
+    ([0x24, 0x0A, 0xC4], "Espressif"),
+    ([0x24, 0xA4, 0x3C], "Ubiquiti"),
+    ([0x28, 0xCD, 0xC1], "Raspberry Pi Trading"),
+    ([0x30, 0xAE, 0xA4], "Espressif"),
+    ([0x44, 0x65, 0x0D], "Amazon Technologies"),
+    ([0x52, 0x54, 0x00], "QEMU/KVM"),
+    ([0xB4, 0xFB, 0xE4], "Ubiquiti"),
+    ([0xB8, 0x27, 0xEB], "Raspberry Pi Foundation"),
+    ([0xD8, 0x3A, 0xDD], "Raspberry Pi Trading"),
+    ([0xDC, 0xA6, 0x32], "Raspberry Pi Trading"),
+    ([0xE4, 0x5F, 0x01], "Raspberry Pi Trading"),
+    ([0xEC, 0xFA, 0xBC], "Espressif"),
+    ([0xF4, 0xF5, 0xD8], "Google"),
+];
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+static TABLE: OnceLock<HashMap<[u8; 3], String>> = OnceLock::new();
+
+/// Load the table from `path` rather than looking in [SYSTEM_TABLES], only
+/// has an effect before the first lookup
+pub fn set_path(path: PathBuf) {
+    _ = PATH.set(path);
+}
+
+/// The manufacturer of `mac_address`, if we know it
+pub fn vendor(mac_address: &MacAddr) -> Option<&'static str> {
+    let [a, b, c, ..] = mac_address.bytes();
+    if let Some(vendor) = TABLE.get_or_init(load).get(&[a, b, c]) {
+        return Some(vendor);
+    }
+    // QEMU's prefix is itself locally administered so check the table first
+    (a & LOCALLY_ADMINISTERED != 0).then_some("Randomised")
+}
+
+/// A MAC address followed by its vendor when we know it, for logs
+pub fn describe(mac_address: &MacAddr) -> String {
+    match vendor(mac_address) {
+        Some(vendor) => format!("{mac_address} ({vendor})"),
+        None => mac_address.to_string(),
+    }
+}
+
+fn load() -> HashMap<[u8; 3], String> {
+    let mut table: HashMap<[u8; 3], String> = BUNDLED
+        .iter()
+        .map(|(oui, vendor)| (*oui, vendor.to_string()))
+        .collect();
+
+    let configured = PATH.get().map(PathBuf::as_path);
+    let path = configured.or_else(|| {
+        SYSTEM_TABLES
+            .iter()
+            .map(Path::new)
+            .find(|path| path.exists())
+    });
+    let Some(path) = path else {
+        return table;
+    };
+
+    match std::fs::read_to_string(path) {
+        Ok(text) => {
+            let loaded: Vec<_> = text.lines().filter_map(parse_line).collect();
+            info!("Loaded {} vendors from {}", loaded.len(), path.display());
+            table.extend(loaded);
+        }
+        Err(error) => warn!("Failed to read OUI table {}: {error}", path.display()),
+    }
+    table
+}
+
+/// `00-00-0C   (hex)        Cisco Systems, Inc` from the IEEE or
+/// `00:00:0C    Cisco    Cisco Systems, Inc` from Wireshark
+fn parse_line(line: &str) -> Option<([u8; 3], String)> {
+    let (prefix, vendor) = match line.split_once("(hex)") {
+        Some((prefix, vendor)) => (prefix.trim(), vendor.trim()),
+        None => {
+            let mut fields = line.split('\t');
+            let prefix = fields.next()?;
+            let short = fields.next()?;
+            (prefix, fields.next().unwrap_or(short).trim())
+        }
+    };
+
+    // Wireshark also lists longer /28 and /36 blocks which we do not split
+    let octets: Vec<&str> = prefix.split([':', '-']).collect();
+    let [a, b, c] = octets[..] else {
+        return None;
+    };
+    let parse = |octet: &str| u8::from_str_radix(octet, 16).ok();
+    let oui = [parse(a)?, parse(b)?, parse(c)?];
+    (!vendor.is_empty()).then(|| (oui, vendor.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ieee_and_wireshark_lines_are_read() {
+        let cisco = Some(([0x00, 0x00, 0x0C], "Cisco Systems, Inc".to_owned()));
+        assert_eq!(parse_line("00-00-0C   (hex)\t\tCisco Systems, Inc"), cisco);
+        assert_eq!(parse_line("00:00:0C\tCisco\tCisco Systems, Inc"), cisco);
+        // Without a long name Wireshark's short one will do
+        assert_eq!(
+            parse_line("00:00:0C\tCisco"),
+            Some(([0x00, 0x00, 0x0C], "Cisco".to_owned()))
+        );
+        assert_eq!(parse_line("00:55:DA:50/28\tQuantumC"), None);
+        assert_eq!(parse_line("# a comment"), None);
+        assert_eq!(parse_line("00-00-0C   (hex)"), None);
+    }
+
+    #[test]
+    fn made_up_addresses_are_named_as_such() {
+        assert!(vendor(&MacAddr::new([0xB8, 0x27, 0xEB, 0, 0, 1])).is_some());
+        assert_eq!(
+            vendor(&MacAddr::new([0x06, 0x12, 0x34, 0, 0, 1])),
+            Some("Randomised")
+        );
+        assert!(describe(&MacAddr::new([0x06, 0x12, 0x34, 0, 0, 1])).ends_with("(Randomised)"));
+    }
+}
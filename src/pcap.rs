@@ -0,0 +1,146 @@
+//! Just enough of the libpcap file format to pull UDP datagrams out of a
+//! capture, as written by `tcpdump -w`. Save pcapng captures from Wireshark
+//! as pcap first, e.g. `editcap -F pcap in.pcapng out.pcap`
+
+use crate::{Error, Result};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const MAGIC_PCAPNG: u32 = 0x0a0d_0d0a;
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const IP_PROTOCOL_UDP: u8 = 17;
+const UDP_HEADER_LEN: usize = 8;
+
+/// A UDP datagram from the capture
+#[derive(Debug)]
+pub struct Datagram<'a> {
+    pub time: SystemTime,
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+    pub payload: &'a [u8],
+}
+
+/// Every IPv4 UDP datagram in `capture`, other packets and fragments after
+/// the first are skipped
+pub fn datagrams(capture: &[u8]) -> Result<Vec<Datagram<'_>>> {
+    let header = capture
+        .get(..FILE_HEADER_LEN)
+        .ok_or(Error::InvalidCapture("too short for a pcap file"))?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let (big_endian, nanos) = match (magic, magic.swap_bytes()) {
+        (MAGIC_MICROS, _) => (false, false),
+        (MAGIC_NANOS, _) => (false, true),
+        (_, MAGIC_MICROS) => (true, false),
+        (_, MAGIC_NANOS) => (true, true),
+        (MAGIC_PCAPNG, _) => return Err(Error::InvalidCapture("pcapng, save it as pcap")),
+        _ => return Err(Error::InvalidCapture("not a pcap file")),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = bytes[..4].try_into().unwrap();
+        match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    };
+
+    let link_type = read_u32(&header[20..]);
+    if ![
+        LINKTYPE_ETHERNET,
+        LINKTYPE_RAW,
+        LINKTYPE_LINUX_SLL,
+        LINKTYPE_IPV4,
+        LINKTYPE_LINUX_SLL2,
+    ]
+    .contains(&link_type)
+    {
+        return Err(Error::UnsupportedLinkType(link_type));
+    }
+
+    let mut datagrams = Vec::new();
+    let mut records = &capture[FILE_HEADER_LEN..];
+    while !records.is_empty() {
+        let record = records
+            .get(..RECORD_HEADER_LEN)
+            .ok_or(Error::InvalidCapture("truncated record header"))?;
+        let (secs, fraction) = (read_u32(record), read_u32(&record[4..]));
+        let len = read_u32(&record[8..]) as usize;
+        let frame = records
+            .get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)
+            .ok_or(Error::InvalidCapture("truncated record"))?;
+        records = &records[RECORD_HEADER_LEN + len..];
+
+        let fraction = match nanos {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64),
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(secs as u64) + fraction;
+        if let Some(datagram) = ipv4(link_type, frame).and_then(|packet| udp(time, packet)) {
+            datagrams.push(datagram);
+        }
+    }
+
+    Ok(datagrams)
+}
+
+/// Strip the link layer header, if this frame carries IPv4
+fn ipv4(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    let ethertype = |bytes: &[u8]| Some(u16::from_be_bytes(bytes.get(..2)?.try_into().unwrap()));
+    match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            // Skip any 802.1Q tags
+            while matches!(
+                ethertype(frame.get(offset..)?)?,
+                ETHERTYPE_VLAN | ETHERTYPE_QINQ
+            ) {
+                offset += 4;
+            }
+            (ethertype(frame.get(offset..)?)? == ETHERTYPE_IPV4).then(|| &frame[offset + 2..])
+        }
+        LINKTYPE_LINUX_SLL => {
+            (ethertype(frame.get(14..)?)? == ETHERTYPE_IPV4).then(|| frame.get(16..))?
+        }
+        LINKTYPE_LINUX_SLL2 => (ethertype(frame)? == ETHERTYPE_IPV4).then(|| frame.get(20..))?,
+        _ => Some(frame),
+    }
+}
+
+fn udp(time: SystemTime, packet: &[u8]) -> Option<Datagram<'_>> {
+    let (version, header_len) = (packet.first()? >> 4, (packet[0] & 0x0f) as usize * 4);
+    let fragment_offset = u16::from_be_bytes(packet.get(6..8)?.try_into().unwrap()) & 0x1fff;
+    if version != 4 || *packet.get(9)? != IP_PROTOCOL_UDP || fragment_offset != 0 {
+        return None;
+    }
+    let address = |at: usize| {
+        Some(Ipv4Addr::from(
+            <[u8; 4]>::try_from(packet.get(at..at + 4)?).ok()?,
+        ))
+    };
+    let (source_ip, destination_ip) = (address(12)?, address(16)?);
+
+    let udp = packet.get(header_len..)?;
+    let u16_at = |at: usize| Some(u16::from_be_bytes(udp.get(at..at + 2)?.try_into().unwrap()));
+    let len = u16_at(4)? as usize;
+    // Snapped captures hold less than the datagram, replay what we have
+    let payload = udp.get(UDP_HEADER_LEN..len.min(udp.len()))?;
+
+    Some(Datagram {
+        time,
+        source: SocketAddrV4::new(source_ip, u16_at(0)?),
+        destination: SocketAddrV4::new(destination_ip, u16_at(2)?),
+        payload,
+    })
+}
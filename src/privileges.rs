@@ -0,0 +1,111 @@
+//! Dropping root once we hold our socket, the packet parser has no business
+//! running with more privileges than it needs
+
+use crate::{Error, Result};
+use log::info;
+use std::os::raw::c_int;
+
+extern "C" {
+    fn setuid(uid: u32) -> c_int;
+    fn setgid(gid: u32) -> c_int;
+    #[cfg(target_os = "linux")]
+    fn setgroups(size: usize, list: *const u32) -> c_int;
+    #[cfg(not(target_os = "linux"))]
+    fn setgroups(size: c_int, list: *const u32) -> c_int;
+}
+
+/// Chroot into the working directory, then switch to `user` and `group`.
+/// Names are resolved before the chroot as `/etc` will not be reachable after
+pub fn drop(user: Option<&str>, group: Option<&str>, chroot: bool) -> Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    // Default to the primary group of the user
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+
+    if chroot {
+        let dir = std::env::current_dir().map_err(Error::DropPrivileges)?;
+        std::os::unix::fs::chroot(&dir).map_err(Error::DropPrivileges)?;
+        std::env::set_current_dir("/").map_err(Error::DropPrivileges)?;
+        info!("Chrooted into {}", dir.display());
+    }
+
+    // The group must go first, we can no longer change it once we are not root
+    if let Some(gid) = gid {
+        // SAFETY: we pass a pointer to a single valid gid
+        check(unsafe { setgroups(1, &gid) })?;
+        check(unsafe { setgid(gid) })?;
+        info!("Dropped to gid {gid}");
+    }
+
+    if let Some((uid, _)) = user {
+        check(unsafe { setuid(uid) })?;
+        // Make sure we cannot get root back
+        if uid != 0 && unsafe { setuid(0) } == 0 {
+            return Err(Error::PrivilegesRegained);
+        }
+        info!("Dropped to uid {uid}");
+    }
+
+    Ok(())
+}
+
+fn check(ret: c_int) -> Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(Error::DropPrivileges(std::io::Error::last_os_error())),
+    }
+}
+
+/// Resolve a user name or uid into its (uid, primary gid)
+fn lookup_user(user: &str) -> Result<(u32, u32)> {
+    lookup("/etc/passwd", user)
+        .and_then(|fields| Some((fields.get(2)?.parse().ok()?, fields.get(3)?.parse().ok()?)))
+        .or_else(|| {
+            // A numeric uid without a passwd entry keeps its own id as gid
+            let uid = user.parse().ok()?;
+            Some((uid, uid))
+        })
+        .ok_or_else(|| Error::UnknownUser(user.to_owned()))
+}
+
+/// Resolve a group name or gid
+fn lookup_group(group: &str) -> Result<u32> {
+    lookup("/etc/group", group)
+        .and_then(|fields| fields.get(2)?.parse().ok())
+        .or_else(|| group.parse().ok())
+        .ok_or_else(|| Error::UnknownGroup(group.to_owned()))
+}
+
+/// Find the `:` separated line in a passwd style database with `name` as the
+/// first field
+fn lookup(database: &str, name: &str) -> Option<Vec<String>> {
+    std::fs::read_to_string(database)
+        .ok()?
+        .lines()
+        .map(|line| line.split(':').map(str::to_owned).collect::<Vec<_>>())
+        .find(|fields| fields.first().map(String::as_str) == Some(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_and_groups_resolve_by_name_or_id() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        // Ids without an entry are taken as they are
+        assert_eq!(lookup_user("54321").unwrap(), (54321, 54321));
+        assert_eq!(lookup_group("54321").unwrap(), 54321);
+        assert!(matches!(
+            lookup_user("no-such-user"),
+            Err(Error::UnknownUser(user)) if user == "no-such-user"
+        ));
+        assert!(matches!(
+            lookup_group("no-such-group"),
+            Err(Error::UnknownGroup(group)) if group == "no-such-group"
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+//! `dhc3po replay <CAPTURE>`, answer the requests in a pcap file as the
+//! server built from our config would and print what we would have sent.
+//! Nothing goes on the network and the lease file is left alone, so this is
+//! safe to run next to a live server to work out why a client was NAKed.
+//! Leases and offers expire on the capture's clock, not the wall clock
+
+use crate::cli::Args;
+use crate::dhcp::Dhcp;
+use crate::pcap;
+use crate::state::ManualClock;
+use crate::transport::MemoryTransport;
+use crate::types::OptionsView;
+use crate::{load_config, serve, setup_pool, Error, Result, SERVER_PORT};
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+/// The op of a BOOTREPLY, another server's replies to relays also go to
+/// [SERVER_PORT]
+const REPLY_OP_CODE: u8 = 2;
+
+pub fn run(args: &Args, capture: &Path) -> Result<()> {
+    let mut config = load_config(args)?;
+    let server_port = args.port.or(config.server_port).unwrap_or(SERVER_PORT);
+    let capture = fs::read(capture).map_err(Error::CannotReadCapture)?;
+    let requests: Vec<_> = pcap::datagrams(&capture)?
+        .into_iter()
+        .filter(|datagram| datagram.destination.port() == server_port)
+        .filter(|datagram| datagram.payload.first() != Some(&REPLY_OP_CODE))
+        .collect();
+    let Some(first) = requests.first() else {
+        println!("No DHCP requests in the capture");
+        return Ok(());
+    };
+    let start = first.time;
+
+    let buffer = &mut vec![0u8; config.buffer_size + 1];
+    // Leave the leases and audit file of any running server alone
+    config.lease_file = None;
+    config.lease_store = None;
+    config.audit_file = None;
+    let pool = setup_pool(args, config);
+    let clock = ManualClock::new(start);
+    pool.lock().unwrap().set_clock(clock.clone());
+
+    let transport = MemoryTransport::default();
+    for (index, request) in requests.iter().enumerate() {
+        clock.set(request.time);
+        let offset = request.time.duration_since(start).unwrap_or_default();
+        print!(
+            "#{} +{:.3}s {} ",
+            index + 1,
+            offset.as_secs_f64(),
+            request.source
+        );
+        let parsing = pool.lock().unwrap().parsing();
+        match Dhcp::parse_with(request.payload, parsing) {
+            Ok(parsed) => println!("{:?} from {}", parsed.message_type(), parsed.client_mac()),
+            Err(error) => {
+                println!("could not be parsed, {error}");
+                continue;
+            }
+        }
+
+        transport.push(request.payload, request.source.into());
+        serve(&transport, &pool, buffer);
+        let replies = transport.take_sent();
+        if replies.is_empty() {
+            println!("    no reply");
+        }
+        for (reply, destination) in replies {
+            println!("    {}", describe(&reply, destination));
+        }
+    }
+
+    Ok(())
+}
+
+/// The type, address and any message of a reply we built
+fn describe(reply: &[u8], destination: SocketAddr) -> String {
+    let options = OptionsView::new(reply);
+    let message_type = options
+        .message_type()
+        .map_or("Unknown".to_owned(), |message_type| {
+            format!("{message_type:?}")
+        });
+    let yiaddr = Ipv4Addr::new(reply[16], reply[17], reply[18], reply[19]);
+    let mut description = format!("{message_type} {yiaddr} to {destination}");
+    if let Some(message) = options.message() {
+        description += &format!(" \"{message}\"");
+    }
+    description
+}
@@ -0,0 +1,381 @@
+//! `dhc3po simulate`, pretend to be many clients at once against a server,
+//! ours or anyone's. Each made up client, with a random locally administered
+//! MAC, gets an address with DISCOVER, OFFER, REQUEST and ACK, renews it and
+//! releases it, and we report how many made it through each step and how
+//! long the server took to answer.
+//!
+//! Without `--relay` we listen on the client port for broadcasts, which only
+//! reach us for clients without an address, so renewals are sent the way a
+//! rebooting client checks its address rather than from it. With `--relay`
+//! every reply comes to us and renewals are sent as a client renewing would,
+//! only through the relay
+
+use crate::types::{DhcpOption, MacAddr, MessageType, OptionsView, ParameterRequest};
+use crate::wire::Header;
+use crate::{Error, Result, CLIENT_PORT, SERVER_PORT};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How long we wait for a reply before checking for clients that gave up
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Asks the server to broadcast its reply (RFC 2131 4.1)
+const BROADCAST_FLAG: [u8; 2] = [0x80, 0];
+/// What our clients ask for
+const PARAMETER_REQUESTS: [ParameterRequest; 4] = [
+    ParameterRequest::SubnetMask,
+    ParameterRequest::Router,
+    ParameterRequest::DomainNameServer,
+    ParameterRequest::IpAddressLease,
+];
+
+/// What to simulate, from the arguments after `simulate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Simulation {
+    /// How many clients to pretend to be
+    pub clients: usize,
+    /// Clients waiting on a reply at once
+    pub concurrency: usize,
+    /// Where to send requests
+    pub server: SocketAddr,
+    /// Pretend to be a relay with this address, listening on the port of
+    /// `server` as relays do
+    pub relay: Option<Ipv4Addr>,
+    /// Where we listen for broadcasts when not a relay
+    pub client_port: u16,
+    /// How long a client waits for each reply before giving up
+    pub timeout: Duration,
+    /// How many times each client renews before releasing
+    pub renewals: u32,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            clients: 1000,
+            concurrency: 64,
+            server: SocketAddr::from((Ipv4Addr::BROADCAST, SERVER_PORT)),
+            relay: None,
+            client_port: CLIENT_PORT,
+            timeout: Duration::from_millis(1000),
+            renewals: 1,
+        }
+    }
+}
+
+impl Simulation {
+    /// Set what `arg` sets to `value`, [None] when it is not ours or the
+    /// value makes no sense
+    pub fn set(&mut self, arg: &str, value: &str) -> Option<()> {
+        match arg {
+            "--clients" => self.clients = value.parse().ok()?,
+            "--concurrency" => {
+                self.concurrency = value.parse().ok().filter(|concurrency| *concurrency > 0)?
+            }
+            "--server" => self.server = value.parse().ok()?,
+            "--relay" => self.relay = Some(value.parse().ok()?),
+            "--client-port" => self.client_port = value.parse().ok()?,
+            "--timeout" => self.timeout = Duration::from_millis(value.parse().ok()?),
+            "--renewals" => self.renewals = value.parse().ok()?,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// The steps a client goes through, each waits for one reply but release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Discover,
+    Request,
+    Renew,
+    Release,
+}
+
+impl Step {
+    const ALL: [Self; 4] = [Self::Discover, Self::Request, Self::Renew, Self::Release];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Discover => "discover",
+            Self::Request => "request",
+            Self::Renew => "renew",
+            Self::Release => "release",
+        }
+    }
+}
+
+/// How one step went across every client
+#[derive(Debug, Default)]
+struct Tally {
+    sent: usize,
+    /// How long each answer took
+    answered: Vec<Duration>,
+    naked: usize,
+    unanswered: usize,
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} answered, {} NAKed, {} unanswered",
+            self.sent,
+            self.answered.len(),
+            self.naked,
+            self.unanswered
+        )?;
+        let mut latencies = self.answered.clone();
+        latencies.sort();
+        if let Some(max) = latencies.last() {
+            let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+            write!(
+                f,
+                ", p50 {:?}, p90 {:?}, p99 {:?}, max {max:?}",
+                percentile(50),
+                percentile(90),
+                percentile(99)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A made up client part way through
+#[derive(Debug)]
+struct Client {
+    mac_address: MacAddr,
+    step: Step,
+    /// Of the request we are waiting on
+    xid: u32,
+    /// When we sent the request we are waiting on
+    sent: Instant,
+    /// Renewals left before we release
+    renewals: u32,
+    /// Offered then bound
+    ip_addr: Ipv4Addr,
+    server_id: Ipv4Addr,
+}
+
+/// Run `simulation` and print how it went
+pub fn run(simulation: &Simulation) -> Result<()> {
+    let listen = match simulation.relay {
+        Some(relay) => SocketAddr::from((relay, simulation.server.port())),
+        None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, simulation.client_port)),
+    };
+    let socket = UdpSocket::bind(listen).map_err(Error::CannotBindToAddress)?;
+    socket
+        .set_broadcast(true)
+        .and_then(|()| socket.set_read_timeout(Some(POLL_INTERVAL)))
+        .map_err(Error::CannotBindToAddress)?;
+
+    let mut simulator = Simulator {
+        simulation,
+        socket,
+        waiting: HashMap::new(),
+        tallies: Step::ALL.map(|_| Tally::default()),
+        completed: 0,
+    };
+    let mut mac_addresses = BTreeSet::new();
+    let started = Instant::now();
+    let mut buffer = [0u8; 1500];
+    while mac_addresses.len() < simulation.clients || !simulator.waiting.is_empty() {
+        while mac_addresses.len() < simulation.clients
+            && simulator.waiting.len() < simulation.concurrency
+        {
+            let mac_address = random_mac_address();
+            if mac_addresses.insert(mac_address) {
+                simulator.start(mac_address);
+            }
+        }
+        if let Ok(len) = simulator.socket.recv(&mut buffer) {
+            simulator.answered(&buffer[..len]);
+        }
+        simulator.give_up();
+    }
+    let elapsed = started.elapsed();
+
+    println!(
+        "{} clients in {:.2}s, {} ({:.1}%) got an address, renewed it and released it",
+        simulation.clients,
+        elapsed.as_secs_f64(),
+        simulator.completed,
+        simulator.completed as f64 * 100.0 / simulation.clients.max(1) as f64
+    );
+    for (step, tally) in Step::ALL.iter().zip(&simulator.tallies) {
+        match step {
+            Step::Release => println!("{:<9} {} sent", step.name(), tally.sent),
+            _ => println!("{:<9} {tally}", step.name()),
+        }
+    }
+    Ok(())
+}
+
+struct Simulator<'a> {
+    simulation: &'a Simulation,
+    socket: UdpSocket,
+    /// Clients waiting on a reply by the xid of their request
+    waiting: HashMap<u32, Client>,
+    tallies: [Tally; 4],
+    /// Clients that released the address they were given
+    completed: usize,
+}
+
+impl Simulator<'_> {
+    fn start(&mut self, mac_address: MacAddr) {
+        let client = Client {
+            mac_address,
+            step: Step::Discover,
+            xid: random() as u32,
+            sent: Instant::now(),
+            renewals: self.simulation.renewals,
+            ip_addr: Ipv4Addr::UNSPECIFIED,
+            server_id: Ipv4Addr::UNSPECIFIED,
+        };
+        self.send(client);
+    }
+
+    /// Send the request for the step `client` is at
+    fn send(&mut self, mut client: Client) {
+        let relay = self.simulation.relay;
+        let mut ciaddr = Ipv4Addr::UNSPECIFIED;
+        let mut options = Vec::new();
+        let message_type = match client.step {
+            Step::Discover => MessageType::Discover,
+            Step::Request => {
+                options.push(DhcpOption::RequestedIpAddr(client.ip_addr.octets()));
+                options.push(DhcpOption::DhcpServerIpAddr(client.server_id.octets()));
+                MessageType::Request
+            }
+            // Replies to ciaddr only reach us through a relay
+            Step::Renew if relay.is_some() => {
+                ciaddr = client.ip_addr;
+                MessageType::Request
+            }
+            Step::Renew => {
+                options.push(DhcpOption::RequestedIpAddr(client.ip_addr.octets()));
+                MessageType::Request
+            }
+            Step::Release => {
+                ciaddr = client.ip_addr;
+                options.push(DhcpOption::DhcpServerIpAddr(client.server_id.octets()));
+                MessageType::Release
+            }
+        };
+        if message_type != MessageType::Release {
+            options.push(DhcpOption::ParameterRequestList(
+                PARAMETER_REQUESTS.to_vec(),
+            ));
+        }
+
+        let mut chaddr = [0u8; 16];
+        chaddr[..MacAddr::LEN].copy_from_slice(&client.mac_address.bytes());
+        let header = Header {
+            op: 1,
+            htype: 1,
+            hlen: MacAddr::LEN as u8,
+            xid: client.xid.to_be_bytes(),
+            flags: match relay {
+                Some(_) => [0, 0],
+                None => BROADCAST_FLAG,
+            },
+            ciaddr: ciaddr.octets(),
+            giaddr: relay.unwrap_or(Ipv4Addr::UNSPECIFIED).octets(),
+            chaddr,
+            ..Header::default()
+        };
+        let mut packet = [0u8; 576];
+        let mut len = header.write(&mut packet, &[], &[]).unwrap();
+        len += DhcpOption::MessageType(message_type).serialise(&mut packet[len..]);
+        for option in options.iter().chain([&DhcpOption::End]) {
+            len += option.serialise(&mut packet[len..]);
+        }
+
+        self.tallies[client.step as usize].sent += 1;
+        if let Err(error) = self.socket.send_to(&packet[..len], self.simulation.server) {
+            eprintln!("Could not send to {}: {error}", self.simulation.server);
+        }
+        match client.step {
+            Step::Release => self.completed += 1,
+            _ => {
+                client.sent = Instant::now();
+                self.waiting.insert(client.xid, client);
+            }
+        }
+    }
+
+    /// Move the client `reply` is for on to its next step
+    fn answered(&mut self, reply: &[u8]) {
+        let Ok(header) = Header::read(reply) else {
+            return;
+        };
+        let xid = u32::from_be_bytes(header.xid);
+        let Some(client) = self.waiting.get(&xid) else {
+            return;
+        };
+        if header.op != 2 || header.chaddr[..MacAddr::LEN] != client.mac_address.bytes() {
+            return;
+        }
+        let options = OptionsView::new(reply);
+        let expected = match client.step {
+            Step::Discover => MessageType::Offer,
+            _ => MessageType::Ack,
+        };
+        let message_type = options.message_type();
+        if message_type != Some(expected) && message_type != Some(MessageType::Nack) {
+            return;
+        }
+
+        let mut client = self.waiting.remove(&xid).unwrap();
+        let tally = &mut self.tallies[client.step as usize];
+        if message_type == Some(MessageType::Nack) {
+            tally.naked += 1;
+            return;
+        }
+        tally.answered.push(client.sent.elapsed());
+        match client.step {
+            // The REQUEST keeps the xid of the DISCOVER it answers
+            Step::Discover => {
+                client.ip_addr = header.yiaddr.into();
+                client.server_id = options.server_identifier().unwrap_or(Ipv4Addr::UNSPECIFIED);
+                client.step = Step::Request;
+            }
+            _ if client.renewals > 0 => {
+                client.renewals -= 1;
+                client.step = Step::Renew;
+                client.xid = random() as u32;
+            }
+            _ => {
+                client.step = Step::Release;
+                client.xid = random() as u32;
+            }
+        }
+        self.send(client);
+    }
+
+    /// Drop the clients that have waited too long for a reply
+    fn give_up(&mut self) {
+        let timeout = self.simulation.timeout;
+        let tallies = &mut self.tallies;
+        self.waiting.retain(|_, client| {
+            let waiting = client.sent.elapsed() < timeout;
+            if !waiting {
+                tallies[client.step as usize].unanswered += 1;
+            }
+            waiting
+        });
+    }
+}
+
+/// A locally administered unicast MAC address
+fn random_mac_address() -> MacAddr {
+    let [a, b, c, d, e, ..] = random().to_be_bytes();
+    MacAddr::new([0x02, a, b, c, d, e])
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
@@ -1,188 +1,2788 @@
 //! This is where we delcare our structs and logic for storage of IP Addresses
-use log::error;
+use log::{error, info, warn};
 
-use crate::error::Error;
-use crate::types::{DhcpOption, DhcpOptionList, MacAddr};
-use crate::DEFAULT_LEASE_TIME;
-use std::collections::BTreeMap;
+use crate::alerts::Webhook;
+use crate::arp::ArpInjection;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::dhcp::Parsing;
+use crate::error::{Error, Result};
+use crate::failover::Link;
+use crate::hostname::{self, HostnameConflict};
+use crate::leases::{self, Lease};
+use crate::store::{Claim, LeaseStore};
+use crate::types::{
+    ClientFqdn, ClientKey, DhcpOption, DhcpOptionList, MacAddr, RelayAgentInfo, RelayId, UserClass,
+    Uuid,
+};
+use crate::{DEFAULT_LEASE_TIME, INFINITE_LEASE_TIME};
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::net::Ipv4Addr;
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Wrapper for readability
 type DhcpRange = BTreeMap<Ipv4Addr, Option<Client>>;
 
-/// Remove magic numbers for IP Addr length
-const IP_ADDR_LEN: usize = 4;
+/// How long we hold an offered address for a client before it can be offered
+/// to someone else
+const OFFER_TIMEOUT: u32 = 60;
+
+/// How long we keep a DECLINEd address back, whoever is squatting on it may
+/// well have gone by then
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// An address DECLINEd this many times, each within [DECLINE_WINDOW] of the
+/// last, has something squatting on it for good, a static address or a
+/// broken device
+const CONFLICT_DECLINES: u32 = 3;
+
+/// How close together DECLINEs of an address count towards a conflict
+const DECLINE_WINDOW: Duration = Duration::from_secs(24 * 3600);
+
+/// How long we keep an address in conflict back, long enough for someone to
+/// find what is squatting on it
+const CONFLICT_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// How long one DISCOVER may spend claiming addresses in a shared lease
+/// store, every other request waits on the pool meanwhile
+const CLAIM_BUDGET: Duration = Duration::from_secs(1);
+
+/// How many reclaimed leases we remember so returning clients can get their
+/// old address back
+const LEASE_HISTORY_LEN: usize = 256;
+
+/// The options our replies start with unless `option-order` says otherwise,
+/// message type, server identifier and lease time
+pub const DEFAULT_OPTION_ORDER: [u8; 3] = [53, 54, 51];
+
+/// Where the pool gets the time from, so tests can move it along instead of
+/// waiting
+pub trait Clock: std::fmt::Debug + Send {
+    fn now(&self) -> SystemTime;
+}
+
+/// The clock on the wall, what we use outside of tests
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when it is told to, clones share the time
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<SystemTime>>);
+
+impl ManualClock {
+    pub fn new(time: SystemTime) -> Self {
+        Self(Arc::new(Mutex::new(time)))
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.0.lock().unwrap() = time;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Where a client is in acquiring an address from us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseState {
+    /// We sent an OFFER and are waiting for the client to REQUEST it
+    Offered,
+
+    /// The client REQUESTed the address and we sent an ACK
+    Bound,
+}
+
+/// Where a client is in the RFC 2131 4.4 state machine, as far as we can
+/// tell from what we gave it and when. A client stuck past its timers is
+/// not getting our ACKs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    /// Holds nothing from us, its next message should be a DISCOVER
+    Init,
+
+    /// We sent an OFFER and are waiting for the client to REQUEST it
+    Offered,
+
+    /// Holds a lease it has not had half of yet
+    Bound,
+
+    /// Past T1, half way through its lease, it should be renewing with us
+    Renewing,
+
+    /// Past T2, seven eighths of the way, it should be asking any server
+    Rebinding,
+}
+
+impl ClientState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Init => "init",
+            Self::Offered => "offered",
+            Self::Bound => "bound",
+            Self::Renewing => "renewing",
+            Self::Rebinding => "rebinding",
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Client {
+    key: ClientKey,
+    /// The chaddr we last saw from the client
     mac_address: MacAddr,
     expires: SystemTime,
+    state: LeaseState,
+    /// When we made the offer or bound the lease, [None] for leases we
+    /// learnt of, whose timers we do not know
+    granted: Option<SystemTime>,
+    /// Option 12 from the client's last REQUEST
+    hostname: Option<String>,
+    /// Option 161 from the client's last REQUEST
+    mud_url: Option<String>,
+    /// The option 82 circuit-id of the DISCOVER we offered the address for
+    circuit_id: Option<Vec<u8>>,
+    /// Option 97 from the client's last REQUEST
+    uuid: Option<Uuid>,
+    /// Our answer to option 81 in the client's last REQUEST
+    fqdn: Option<ClientFqdn>,
 }
 
 impl Client {
-    fn new(mac_address: &MacAddr, lease_time: u32) -> Self {
-        Self {
-            mac_address: *mac_address,
-            expires: SystemTime::now()
+    fn new(
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        now: SystemTime,
+        lease_time: u32,
+        state: LeaseState,
+    ) -> Self {
+        let expires = match lease_time {
+            INFINITE_LEASE_TIME => leases::never(),
+            _ => now
                 .checked_add(Duration::from_secs(lease_time as u64))
                 .unwrap(),
+        };
+        Self {
+            key: key.clone(),
+            mac_address: *mac_address,
+            expires,
+            state,
+            granted: Some(now),
+            hostname: None,
+            mud_url: None,
+            circuit_id: None,
+            uuid: None,
+            fqdn: None,
+        }
+    }
+
+    /// A pending offer held for [OFFER_TIMEOUT] seconds
+    fn offered(key: &ClientKey, mac_address: &MacAddr, now: SystemTime) -> Self {
+        Self::new(key, mac_address, now, OFFER_TIMEOUT, LeaseState::Offered)
+    }
+
+    /// A committed lease held for `lease_time` seconds
+    fn bound(key: &ClientKey, mac_address: &MacAddr, now: SystemTime, lease_time: u32) -> Self {
+        Self::new(key, mac_address, now, lease_time, LeaseState::Bound)
+    }
+
+    /// A lease we learnt of rather than bound ourselves
+    fn from_lease(lease: Lease) -> Self {
+        Self {
+            key: lease.key,
+            mac_address: lease.mac_address,
+            expires: lease.expires,
+            state: LeaseState::Bound,
+            granted: None,
+            hostname: None,
+            mud_url: None,
+            circuit_id: None,
+            uuid: None,
+            fqdn: None,
+        }
+    }
+
+    fn lease(&self, ip_addr: Ipv4Addr) -> Lease {
+        Lease {
+            ip_addr,
+            expires: self.expires,
+            mac_address: self.mac_address,
+            key: self.key.clone(),
+        }
+    }
+
+    /// Infinite leases are never expired, so are never evicted either
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires != leases::never() && self.expires <= now
+    }
+
+    /// Where the client is by the timers of the lease (RFC 2131 4.4.5)
+    fn client_state(&self, now: SystemTime) -> ClientState {
+        if self.is_expired(now) {
+            return ClientState::Init;
+        }
+        let granted = match (self.state, self.granted) {
+            (LeaseState::Offered, _) => return ClientState::Offered,
+            (LeaseState::Bound, Some(granted)) if self.expires != leases::never() => granted,
+            (LeaseState::Bound, _) => return ClientState::Bound,
+        };
+        let lease_time = self.expires.duration_since(granted).unwrap_or_default();
+        let held = now.duration_since(granted).unwrap_or_default();
+        if held >= lease_time * 7 / 8 {
+            ClientState::Rebinding
+        } else if held >= lease_time / 2 {
+            ClientState::Renewing
+        } else {
+            ClientState::Bound
+        }
+    }
+
+    /// An offer that was never taken up can be handed to someone else
+    fn is_stale_offer(&self, now: SystemTime) -> bool {
+        self.state == LeaseState::Offered && self.is_expired(now)
+    }
+}
+
+/// The UDP ports DHCP runs on, 67 and 68 unless moved for a test harness or
+/// a run without root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    /// Where we listen, and relays listen for our replies
+    pub server: u16,
+    /// Where clients listen for our replies
+    pub client: u16,
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Self {
+            server: crate::SERVER_PORT,
+            client: crate::CLIENT_PORT,
+        }
+    }
+}
+
+/// How often clients found an address in use by someone else
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Declines {
+    /// DECLINEs each within [DECLINE_WINDOW] of the last
+    pub count: u32,
+    pub last: SystemTime,
+    /// Nobody gets the address until then
+    pub until: SystemTime,
+}
+
+impl Declines {
+    /// Declined often enough that something has the address for good
+    pub fn is_conflict(&self) -> bool {
+        self.count >= CONFLICT_DECLINES
+    }
+}
+
+/// A row of the lease table, for showing to people
+#[derive(Debug, Clone)]
+pub struct LeaseEntry {
+    pub ip_addr: Ipv4Addr,
+    pub mac_address: MacAddr,
+    pub hostname: Option<String>,
+    pub mud_url: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub fqdn: Option<ClientFqdn>,
+    pub state: LeaseState,
+    pub client_state: ClientState,
+    pub expires: SystemTime,
+}
+
+/// What a client says about itself that class options are matched against
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientClass<'a> {
+    /// Option 60, `[class]` sections match the start of it
+    pub vendor: Option<&'a [u8]>,
+    /// Option 77, `[user-class]` sections match any one of its classes
+    pub user: Option<&'a UserClass>,
+}
+
+/// Which clients a `next-server` is for, so each stage of a network boot can
+/// load the next from its own server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootStage {
+    /// Every client, unless a class below says otherwise
+    Any,
+    /// Clients whose vendor class starts with this, the PXE ROM says PXEClient
+    VendorClass(String),
+    /// Clients with this user class, iPXE says iPXE once it is loaded
+    UserClass(String),
+}
+
+impl BootStage {
+    /// How specific a match this is for `class`, [None] if it is not one
+    fn rank(&self, class: ClientClass) -> Option<u8> {
+        match self {
+            Self::Any => Some(0),
+            Self::VendorClass(prefix) => class
+                .vendor
+                .filter(|vendor| vendor.starts_with(prefix.as_bytes()))
+                .map(|_| 1),
+            Self::UserClass(name) => class
+                .user
+                .filter(|user| user.contains(name.as_bytes()))
+                .map(|_| 2),
+        }
+    }
+}
+
+/// What to do with a DISCOVER when every address is leased and none of the
+/// leases have expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExhaustedPolicy {
+    /// Stay silent, the client will retry and another server may answer
+    #[default]
+    Ignore,
+
+    /// Tell the client straight away that we have nothing for it. This is not
+    /// standard, RFC 2131 only sends a DHCPNAK in reply to a DHCPREQUEST and
+    /// most clients drop one that answers their DISCOVER, it is for the few
+    /// clients and monitoring tools that look out for it
+    Nak,
+}
+
+/// Where we look for a free address for a client that has no reservation and
+/// no old address to go back to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// The lowest free address
+    #[default]
+    Sequential,
+
+    /// From a random point in the pool, so a device squatting on one address
+    /// does not collide with every client we hand it to
+    Random,
+
+    /// From a point picked by hashing the client's key, so a client lands on
+    /// the same address whenever it is free, on any of our servers
+    Hash,
+}
+
+impl Allocation {
+    /// Where in a pool of `len` addresses to start looking for `key`
+    fn start(self, key: &ClientKey, len: usize) -> usize {
+        let seed = match self {
+            Self::Sequential => return 0,
+            Self::Random => random(),
+            Self::Hash => match key {
+                ClientKey::ClientId(client_id) => stable_hash(client_id.as_bytes()),
+                ClientKey::Mac(mac_address) => stable_hash(&mac_address.bytes()),
+            },
+        };
+        (seed % len.max(1) as u64) as usize
+    }
+}
+
+/// Which half of the clients, by a hash of their MAC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    Even,
+    Odd,
+}
+
+impl Parity {
+    pub fn of(mac_address: &MacAddr) -> Self {
+        match stable_hash(&mac_address.bytes()) % 2 {
+            0 => Self::Even,
+            _ => Self::Odd,
+        }
+    }
+}
+
+/// How we share a segment with servers we do not speak failover to, each
+/// answering some of the DISCOVERs first and the rest only if the others
+/// stay quiet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadSharing {
+    /// How long we wait before sending an OFFER
+    pub delay: Duration,
+    /// Only wait before offering to these clients, the other server's half
+    pub parity: Option<Parity>,
+    /// How many DISCOVERs in a hundred we answer, clients retry the rest
+    pub percent: u8,
+}
+
+impl Default for LoadSharing {
+    fn default() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            parity: None,
+            percent: 100,
+        }
+    }
+}
+
+impl LoadSharing {
+    /// Whether we answer this DISCOVER at all
+    pub fn answers(&self) -> bool {
+        self.percent >= 100 || random() % 100 < u64::from(self.percent)
+    }
+
+    /// How long to wait before offering to this client
+    pub fn delay_for(&self, mac_address: &MacAddr) -> Option<Duration> {
+        let ours = self
+            .parity
+            .is_some_and(|parity| parity != Parity::of(mac_address));
+        (!self.delay.is_zero() && !ours).then_some(self.delay)
+    }
+}
+
+/// Clients that say in secs they have been trying for a while, which we put
+/// ahead of those that have only just started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waiting {
+    /// How long a client has to have been trying for
+    pub secs: u16,
+    /// Free addresses only waiting clients get
+    pub reserve: usize,
+}
+
+/// What became of the leases we kept across a restart, see
+/// [AddrPool::restore]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    /// Of those restored, the leases outside our ranges now
+    pub retiring: usize,
+    pub expired: usize,
+    pub outside_subnet: usize,
+    /// On an address now reserved for another client
+    pub reserved: usize,
+    /// On an address or for a client with a longer lease
+    pub duplicates: usize,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} restored of which {} are outside the ranges, dropped {} expired, \
+             {} outside the subnet, {} reserved for another client and {} duplicates",
+            self.restored,
+            self.retiring,
+            self.expired,
+            self.outside_subnet,
+            self.reserved,
+            self.duplicates
+        )
+    }
+}
+
+/// Shorter leases as the pool fills, so addresses come back sooner when a
+/// crowd turns up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveLease {
+    /// Percent of the pool in use above which leases shrink, below 100
+    pub above: u8,
+    /// The lease time we give with the pool full
+    pub min: u32,
+}
+
+impl AdaptiveLease {
+    /// `lease_time` shrunk in step with how far `in_use` of `size` is past
+    /// `above`, reaching `min` with the pool full
+    fn scale(&self, lease_time: u32, in_use: usize, size: usize) -> u32 {
+        if lease_time == INFINITE_LEASE_TIME || lease_time <= self.min {
+            return lease_time;
+        }
+        let (in_use, size) = (in_use as u64, size.max(1) as u64);
+        let excess = (in_use * 100).saturating_sub(self.above as u64 * size);
+        let range = (100 - self.above as u64) * size;
+        let cut = (lease_time - self.min) as u64 * excess.min(range) / range;
+        lease_time - cut as u32
+    }
+}
+
+/// Different on every call
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// FNV-1a, unlike std's hashers it never changes between builds, so every
+/// server agrees on it
+fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Bounded LRU of leases that expired and were reclaimed, most recent last
+#[derive(Debug, Default)]
+struct LeaseHistory(VecDeque<(ClientKey, Ipv4Addr)>);
+
+impl LeaseHistory {
+    fn remember(&mut self, key: &ClientKey, ip_addr: Ipv4Addr) {
+        self.forget(key);
+        if self.0.len() == LEASE_HISTORY_LEN {
+            self.0.pop_front();
         }
+        self.0.push_back((key.clone(), ip_addr));
+    }
+
+    fn forget(&mut self, key: &ClientKey) {
+        self.0.retain(|(previous, _)| previous != key);
+    }
+
+    /// The address this client last held
+    fn recall(&self, key: &ClientKey) -> Option<Ipv4Addr> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(previous, _)| previous == key)
+            .map(|(_, ip)| *ip)
+    }
+
+    /// Whether some client might come back for this address
+    fn contains(&self, ip_addr: &Ipv4Addr) -> bool {
+        self.0.iter().any(|(_, ip)| ip == ip_addr)
+    }
+}
+
+/// Where clients we do not know go, see [AddrPool::set_quarantine]
+#[derive(Debug)]
+struct Quarantine {
+    /// Also in the ranges of the pool, only unknown clients get these
+    ranges: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// Clients we know without a reservation or host options
+    allowed: BTreeSet<MacAddr>,
+    /// Layered over the class options of unknown clients
+    options: DhcpOptionList,
+}
+
+impl Quarantine {
+    fn contains(&self, ip_addr: &Ipv4Addr) -> bool {
+        self.ranges
+            .iter()
+            .any(|(start, end)| (start..=end).contains(&ip_addr))
     }
 }
 
 #[derive(Debug)]
-pub struct AddrPool<'dhcp_options> {
+pub struct AddrPool {
     subnet: Ipv4Addr,
+    mask: Ipv4Addr,
     pool: DhcpRange,
-    options: DhcpOptionList<'dhcp_options>,
+    /// What `pool` is built from, leases on addresses since removed stay in
+    /// `pool` until they expire but are not renewed
+    ranges: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// Addresses inside our ranges that we never hand out
+    exclusions: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// The global options with this subnet's layered over them
+    options: DhcpOptionList,
+    /// Layered over `options` for clients whose vendor class starts with the
+    /// name, in the order they were added
+    class_options: Vec<(String, DhcpOptionList)>,
+    /// Layered over the class options for clients with the user class
+    user_class_options: Vec<(String, DhcpOptionList)>,
+    /// Layered over everything else for one client
+    host_options: BTreeMap<MacAddr, DhcpOptionList>,
+    /// The siaddr we send each boot stage, layered like the class options
+    next_servers: Vec<(BootStage, Ipv4Addr)>,
+    /// When set we NAK requests for addresses we know nothing about,
+    /// otherwise we stay silent in case another server owns them
+    authoritative: bool,
+    history: LeaseHistory,
+    /// Addresses clients found in use by someone else
+    declined: BTreeMap<Ipv4Addr, Declines>,
+    on_exhausted: ExhaustedPolicy,
+    allocation: Allocation,
+    parsing: Parsing,
+    /// The codes of the options our replies start with, in order, before
+    /// those the client asked for in the order it asked
+    option_order: Vec<u8>,
+    /// Reply to clients without an address at the address we give them
+    /// rather than broadcasting, for networks that drop broadcasts
+    unicast_replies: bool,
+    /// Put clients in the ARP cache so we can unicast to them instead
+    inject_arp: Option<ArpInjection>,
+    ports: Ports,
+    /// Written to sname of our replies
+    server_hostname: Option<String>,
+    /// Whether we copy option 67 to file of our replies
+    boot_file_in_header: bool,
+    mud_webhook: Option<Webhook>,
+    /// Set when we share this pool with a failover peer
+    failover: Option<Arc<Link>>,
+    /// Where we keep our leases across restarts, shared with other servers
+    /// for some stores
+    store: Option<Box<dyn LeaseStore>>,
+    /// Who held which address when
+    audit: AuditLog,
+    /// Addresses only ever handed to one client, including those we learnt
+    /// the MAC of from `uuid_reservations` and `relay_reservations`
+    reservations: BTreeMap<MacAddr, Ipv4Addr>,
+    /// Addresses only ever handed to the machine with this SMBIOS UUID,
+    /// through whichever NIC it last booted from
+    uuid_reservations: BTreeMap<Uuid, Ipv4Addr>,
+    /// Addresses only ever handed to the client behind this relay circuit or
+    /// subscriber line, whatever hardware it last was
+    relay_reservations: BTreeMap<RelayId, Ipv4Addr>,
+    /// When set clients we do not know only get addresses from here
+    quarantine: Option<Quarantine>,
+    /// The most offers and leases clients behind one relay circuit can hold
+    max_leases_per_circuit: Option<usize>,
+    load_sharing: LoadSharing,
+    waiting: Option<Waiting>,
+    hostname_conflict: HostnameConflict,
+    adaptive_lease: Option<AdaptiveLease>,
+    clock: Box<dyn Clock>,
 }
 
-impl<'dhcp_options> AddrPool<'dhcp_options> {
+impl AddrPool {
+    /// Serve every address in `ranges` apart from those in `exclusions`, the
+    /// ranges must sit inside the subnet and not overlap each other
     pub fn new(
         subnet: impl Into<Ipv4Addr>,
         mask: impl Into<Ipv4Addr>,
-        range: (impl Into<Ipv4Addr>, impl Into<Ipv4Addr>),
-    ) -> Self {
+        ranges: &[(Ipv4Addr, Ipv4Addr)],
+        exclusions: &[(Ipv4Addr, Ipv4Addr)],
+    ) -> Result<Self> {
+        let (subnet, mask) = (subnet.into(), mask.into());
+        let in_subnet = |ip: Ipv4Addr| ip & mask == subnet & mask;
+
+        for &(start, end) in ranges {
+            if start > end || !in_subnet(start) || !in_subnet(end) {
+                return Err(Error::InvalidRange(start, end));
+            }
+        }
+        let mut sorted = ranges.to_vec();
+        sorted.sort();
+        if let Some(pair) = sorted.windows(2).find(|pair| pair[0].1 >= pair[1].0) {
+            return Err(Error::OverlappingRanges(pair[0].0, pair[1].0));
+        }
+
+        for &(start, end) in exclusions {
+            if start > end {
+                return Err(Error::InvalidRange(start, end));
+            }
+            if !ranges
+                .iter()
+                .any(|range| start <= range.1 && end >= range.0)
+            {
+                warn!("Exclusion {start}-{end} is outside every range, it has no effect");
+            }
+        }
+
         let mut options = DhcpOptionList::builder();
 
-        options
-            .add(DhcpOption::SubnetMask(mask.into().octets()))
-            .add(DhcpOption::End);
+        options.add(DhcpOption::SubnetMask(mask.octets()));
+        options.add(DhcpOption::BroadcastAddress((subnet | !mask).octets()));
 
-        Self {
-            subnet: subnet.into(),
-            pool: Self::initialise_range(range.0.into(), range.1.into()),
+        Ok(Self {
+            subnet,
+            mask,
+            pool: Self::initialise_ranges(ranges, exclusions),
+            ranges: ranges.to_vec(),
+            exclusions: exclusions.to_vec(),
             options,
-        }
+            class_options: Vec::new(),
+            user_class_options: Vec::new(),
+            host_options: BTreeMap::new(),
+            next_servers: Vec::new(),
+            authoritative: true,
+            history: LeaseHistory::default(),
+            declined: BTreeMap::new(),
+            on_exhausted: ExhaustedPolicy::default(),
+            allocation: Allocation::default(),
+            parsing: Parsing::default(),
+            option_order: DEFAULT_OPTION_ORDER.to_vec(),
+            unicast_replies: false,
+            inject_arp: None,
+            ports: Ports::default(),
+            server_hostname: None,
+            boot_file_in_header: false,
+            mud_webhook: None,
+            failover: None,
+            store: None,
+            audit: AuditLog::default(),
+            reservations: BTreeMap::new(),
+            uuid_reservations: BTreeMap::new(),
+            relay_reservations: BTreeMap::new(),
+            quarantine: None,
+            max_leases_per_circuit: None,
+            load_sharing: LoadSharing::default(),
+            hostname_conflict: HostnameConflict::default(),
+            adaptive_lease: None,
+            waiting: None,
+            clock: Box::new(SystemClock),
+        })
     }
 
-    pub fn options_mut(&mut self) -> &mut DhcpOptionList<'dhcp_options> {
-        &mut self.options
+    /// The network address of the subnet we serve
+    pub fn subnet(&self) -> Ipv4Addr {
+        self.subnet
     }
 
-    pub fn options(&self) -> &DhcpOptionList<'dhcp_options> {
-        &self.options
+    /// Whether `ip_addr` is on the subnet we serve
+    pub fn in_subnet(&self, ip_addr: &Ipv4Addr) -> bool {
+        *ip_addr & self.mask == self.subnet & self.mask
     }
 
-    fn allocate_address(&mut self, mac_address: &MacAddr) -> Option<Ipv4Addr> {
-        let lease_time = match self.options.get(DhcpOption::LEASE_TIME) {
-            Some(DhcpOption::LeaseTime(time)) => time,
-            _ => DEFAULT_LEASE_TIME,
-        };
+    pub fn authoritative(&self) -> bool {
+        self.authoritative
+    }
 
-        for (ip, client) in &mut self.pool {
-            if client.is_none() {
-                *client = Some(Client::new(mac_address, lease_time));
-                return Some(*ip);
-            }
+    pub fn set_authoritative(&mut self, authoritative: bool) -> &mut Self {
+        self.authoritative = authoritative;
+        self
+    }
+
+    pub fn on_exhausted(&self) -> ExhaustedPolicy {
+        self.on_exhausted
+    }
+
+    pub fn set_on_exhausted(&mut self, policy: ExhaustedPolicy) -> &mut Self {
+        self.on_exhausted = policy;
+        self
+    }
+
+    pub fn set_allocation(&mut self, allocation: Allocation) -> &mut Self {
+        self.allocation = allocation;
+        self
+    }
+
+    /// How requests to this pool are parsed, see [crate::dhcp::Dhcp::parse_with]
+    pub fn parsing(&self) -> Parsing {
+        self.parsing
+    }
+
+    pub fn set_parsing(&mut self, parsing: Parsing) -> &mut Self {
+        self.parsing = parsing;
+        self
+    }
+
+    pub fn option_order(&self) -> &[u8] {
+        &self.option_order
+    }
+
+    /// For clients that break unless some options come first
+    pub fn set_option_order(&mut self, codes: Vec<u8>) -> &mut Self {
+        self.option_order = codes;
+        self
+    }
+
+    pub fn unicast_replies(&self) -> bool {
+        self.unicast_replies
+    }
+
+    pub fn set_unicast_replies(&mut self, unicast_replies: bool) -> &mut Self {
+        self.unicast_replies = unicast_replies;
+        self
+    }
+
+    pub fn inject_arp(&self) -> Option<ArpInjection> {
+        self.inject_arp
+    }
+
+    pub fn set_inject_arp(&mut self, inject_arp: Option<ArpInjection>) -> &mut Self {
+        self.inject_arp = inject_arp;
+        self
+    }
+
+    pub fn ports(&self) -> Ports {
+        self.ports
+    }
+
+    /// Moving the server port takes a restart, we only bind once
+    pub fn set_ports(&mut self, ports: Ports) -> &mut Self {
+        self.ports = ports;
+        self
+    }
+
+    pub fn server_hostname(&self) -> Option<&str> {
+        self.server_hostname.as_deref()
+    }
+
+    pub fn set_server_hostname(&mut self, hostname: String) -> &mut Self {
+        self.server_hostname = Some(hostname);
+        self
+    }
+
+    /// Some PXE and thin client firmware ignores option 67 and only looks at
+    /// the file header field
+    pub fn boot_file_in_header(&self) -> bool {
+        self.boot_file_in_header
+    }
+
+    pub fn set_boot_file_in_header(&mut self, enabled: bool) -> &mut Self {
+        self.boot_file_in_header = enabled;
+        self
+    }
+
+    /// The address we identify ourselves with in [DhcpOption::DhcpServerIpAddr]
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        match self.options.get(DhcpOption::DHCP_SERVER_IP_ADDR) {
+            Some(DhcpOption::DhcpServerIpAddr(addr)) => Some((*addr).into()),
+            _ => None,
         }
+    }
 
-        error!("{:?}", Error::AllIPAddressesExhausted);
-        None
+    pub fn set_server_identifier(&mut self, addr: impl Into<Ipv4Addr>) -> &mut Self {
+        self.options
+            .set(DhcpOption::DhcpServerIpAddr(addr.into().octets()));
+        self
     }
 
-    /// Request an IP Address from the pool
-    pub fn request(&mut self, mac_address: &MacAddr) -> Ipv4Addr {
-        self.lookup_mac(&mac_address).unwrap_or_else(|| {
-            self.allocate_address(&mac_address)
-                .unwrap_or_else(|| self.evict_oldest_lease(&mac_address))
-        })
+    /// Tell the time with `clock` rather than the [SystemClock]
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) -> &mut Self {
+        self.clock = Box::new(clock);
+        self
     }
 
-    fn evict_oldest_lease(&mut self, mac_address: &MacAddr) -> Ipv4Addr {
-        let lease_time = match self.options.get(DhcpOption::LEASE_TIME) {
-            Some(DhcpOption::LeaseTime(time)) => time,
-            _ => DEFAULT_LEASE_TIME,
-        };
+    fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
 
-        let victim = self
-            .pool
-            .iter()
-            .filter(|(_, client)| client.is_some())
-            .min_by_key(|(_, client)| client.as_ref().unwrap().expires)
-            .unwrap()
-            .0
-            .to_owned();
+    pub fn set_failover(&mut self, link: Arc<Link>) -> &mut Self {
+        self.failover = Some(link);
+        self
+    }
 
-        self.pool
-            .insert(victim, Some(Client::new(mac_address, lease_time)));
+    /// Always give `mac_address` the address `ip_addr`, which may be outside
+    /// our ranges but not the subnet, and never give it to anyone else
+    pub fn add_reservation(
+        &mut self,
+        mac_address: MacAddr,
+        ip_addr: Ipv4Addr,
+    ) -> Result<&mut Self> {
+        let conflicts = !self.in_subnet(&ip_addr)
+            || self.is_excluded(&ip_addr)
+            || self.reservations.contains_key(&mac_address)
+            || self.is_reserved(&ip_addr);
+        if conflicts {
+            return Err(Error::InvalidReservation(mac_address, ip_addr));
+        }
 
-        victim
+        self.pool.entry(ip_addr).or_insert(None);
+        self.reservations.insert(mac_address, ip_addr);
+        Ok(self)
     }
 
-    fn lookup_mac(&self, mac_addr: &MacAddr) -> Option<Ipv4Addr> {
-        self.pool
-            .iter()
-            .find(|client| {
-                if let Some(client) = client.1 {
-                    if client.mac_address == *mac_addr {
-                        return true;
-                    }
-                }
-                return false;
-            })
-            .and_then(|(ip, mac)| Some(ip).copied())
+    /// Always give the machine with this SMBIOS UUID the address `ip_addr`,
+    /// see [AddrPool::bind_uuid] for how we find out its MAC
+    pub fn add_uuid_reservation(&mut self, uuid: Uuid, ip_addr: Ipv4Addr) -> Result<&mut Self> {
+        let conflicts = !self.in_subnet(&ip_addr)
+            || self.is_excluded(&ip_addr)
+            || self.uuid_reservations.contains_key(&uuid)
+            || self.is_reserved(&ip_addr);
+        if conflicts {
+            return Err(Error::InvalidUuidReservation(uuid, ip_addr));
+        }
+
+        self.pool.entry(ip_addr).or_insert(None);
+        self.uuid_reservations.insert(uuid, ip_addr);
+        Ok(self)
     }
 
-    pub fn verify_request(&self, mac_address: &MacAddr, ip_addr: &Ipv4Addr) -> Option<()> {
-        if let Some(Some(client)) = self.pool.get(ip_addr) {
-            if client.mac_address == *mac_address {
-                return Some(());
-            } else {
-                return None;
+    /// Always give the client behind this relay circuit or subscriber line
+    /// the address `ip_addr`, see [AddrPool::bind_relay_agent_info]
+    pub fn add_relay_reservation(
+        &mut self,
+        relay_id: RelayId,
+        ip_addr: Ipv4Addr,
+    ) -> Result<&mut Self> {
+        let conflicts = !self.in_subnet(&ip_addr)
+            || self.is_excluded(&ip_addr)
+            || self.relay_reservations.contains_key(&relay_id)
+            || self.is_reserved(&ip_addr);
+        if conflicts {
+            return Err(Error::InvalidRelayReservation(relay_id, ip_addr));
+        }
+
+        self.pool.entry(ip_addr).or_insert(None);
+        self.relay_reservations.insert(relay_id, ip_addr);
+        Ok(self)
+    }
+
+    /// A client sent option 97, if its UUID has a reservation it is now for
+    /// this MAC rather than the NIC the machine booted from before, which
+    /// loses whatever it held there. A reservation of the MAC itself wins
+    pub fn bind_uuid(&mut self, uuid: &Uuid, mac_address: &MacAddr) {
+        let Some(&ip_addr) = self.uuid_reservations.get(uuid) else {
+            return;
+        };
+        if self.reservations.contains_key(mac_address) {
+            return;
+        }
+        info!("Reserving {ip_addr} for {mac_address}, the NIC of {uuid}");
+        self.move_reservation(ip_addr, mac_address);
+    }
+
+    /// A relay forwarded a request from this MAC, if its circuit-id or
+    /// remote-id has a reservation it is now for this MAC rather than the
+    /// hardware that was there before, as [AddrPool::bind_uuid] does
+    pub fn bind_relay_agent_info(&mut self, info: &RelayAgentInfo, mac_address: &MacAddr) {
+        let Some((relay_id, ip_addr)) = RelayId::all(info).find_map(|relay_id| {
+            let ip_addr = *self.relay_reservations.get(&relay_id)?;
+            Some((relay_id, ip_addr))
+        }) else {
+            return;
+        };
+        if self.reservations.contains_key(mac_address) {
+            return;
+        }
+        info!("Reserving {ip_addr} for {mac_address}, the client on {relay_id}");
+        self.move_reservation(ip_addr, mac_address);
+    }
+
+    /// Reserve `ip_addr` for `mac_address` alone, whoever it was for before
+    fn move_reservation(&mut self, ip_addr: Ipv4Addr, mac_address: &MacAddr) {
+        self.reservations.retain(|_, reserved| *reserved != ip_addr);
+        self.reservations.insert(*mac_address, ip_addr);
+        // The client is not using the address through the hardware it left
+        if let Some(slot) = self.pool.get_mut(&ip_addr) {
+            if slot
+                .as_ref()
+                .is_some_and(|client| client.mac_address != *mac_address)
+            {
+                *slot = None;
             }
         }
-        None
     }
 
-    fn initialise_range(start: Ipv4Addr, end: Ipv4Addr) -> DhcpRange {
-        let mut pool = BTreeMap::new();
-        let start = start.octets();
-        let end = end.octets();
+    /// Whether `ip_addr` is reserved for anyone
+    fn is_reserved(&self, ip_addr: &Ipv4Addr) -> bool {
+        self.reservations
+            .values()
+            .chain(self.uuid_reservations.values())
+            .chain(self.relay_reservations.values())
+            .any(|reserved| reserved == ip_addr)
+    }
+
+    /// Stop handing out an address of our ranges that something else uses,
+    /// false when it was never ours to hand out or is reserved for a client
+    pub fn exclude(&mut self, ip_addr: Ipv4Addr) -> bool {
+        if self.is_reserved(&ip_addr) || self.pool.remove(&ip_addr).is_none() {
+            return false;
+        }
+        self.exclusions.push((ip_addr, ip_addr));
+        true
+    }
 
-        let mut range = [0u8; IP_ADDR_LEN];
-        (0..IP_ADDR_LEN).for_each(|octet| range[octet] = end[octet] - start[octet]);
+    pub fn ranges(&self) -> &[(Ipv4Addr, Ipv4Addr)] {
+        &self.ranges
+    }
 
-        for i in 0..=range[0] {
-            for ii in 0..=range[1] {
-                for iii in 0..=range[2] {
-                    for iiii in 0..=range[3] {
-                        let ip = Ipv4Addr::from([
-                            start[0] + i,
-                            start[1] + ii,
-                            start[2] + iii,
-                            start[3] + iiii,
-                        ]);
-                        pool.insert(ip, None);
-                    }
-                }
+    pub fn exclusions(&self) -> &[(Ipv4Addr, Ipv4Addr)] {
+        &self.exclusions
+    }
+
+    /// Add a range, or grow or shrink the one range it overlaps to match
+    pub fn set_range(&mut self, start: Ipv4Addr, end: Ipv4Addr) -> Result<&mut Self> {
+        if start > end || !self.in_subnet(&start) || !self.in_subnet(&end) {
+            return Err(Error::InvalidRange(start, end));
+        }
+        let overlapping: Vec<usize> = (0..self.ranges.len())
+            .filter(|index| start <= self.ranges[*index].1 && end >= self.ranges[*index].0)
+            .collect();
+        match overlapping[..] {
+            [] => self.ranges.push((start, end)),
+            [index] => self.ranges[index] = (start, end),
+            [first, second, ..] => {
+                return Err(Error::OverlappingRanges(
+                    self.ranges[first].0,
+                    self.ranges[second].0,
+                ))
             }
         }
+        self.reconcile();
+        Ok(self)
+    }
 
-        pool
+    /// Stop handing out a range, it must match one we have exactly
+    pub fn remove_range(&mut self, start: Ipv4Addr, end: Ipv4Addr) -> Result<&mut Self> {
+        let index = self
+            .ranges
+            .iter()
+            .position(|range| *range == (start, end))
+            .ok_or(Error::UnknownRange(start, end))?;
+        self.ranges.remove(index);
+        self.reconcile();
+        Ok(self)
+    }
+
+    pub fn add_exclusion(&mut self, start: Ipv4Addr, end: Ipv4Addr) -> Result<&mut Self> {
+        if start > end {
+            return Err(Error::InvalidRange(start, end));
+        }
+        self.exclusions.push((start, end));
+        self.reconcile();
+        Ok(self)
     }
 
-    fn create_pool_from_subnet(subnet: [u8; 4], mask: [u8; 4]) -> DhcpRange {
-        let mut pool = BTreeMap::new();
+    /// Hand out an excluded range again, it must match an exclusion exactly
+    pub fn remove_exclusion(&mut self, start: Ipv4Addr, end: Ipv4Addr) -> Result<&mut Self> {
+        let index = self
+            .exclusions
+            .iter()
+            .position(|exclusion| *exclusion == (start, end))
+            .ok_or(Error::UnknownRange(start, end))?;
+        self.exclusions.remove(index);
+        self.reconcile();
+        Ok(self)
+    }
 
-        let octet_ranges = [255 - mask[0], 255 - mask[1], 255 - mask[2], 255 - mask[3]];
+    /// Serve the ranges, options and settings of `new` from now on, keeping
+    /// our leases, store and audit log. The subnet cannot change
+    pub fn reconfigure(&mut self, new: AddrPool) -> Result<&mut Self> {
+        if (new.subnet, new.mask) != (self.subnet, self.mask) {
+            return Err(Error::SubnetChanged(self.subnet, new.subnet));
+        }
+        self.ranges = new.ranges;
+        self.exclusions = new.exclusions;
+        self.options = new.options;
+        self.class_options = new.class_options;
+        self.user_class_options = new.user_class_options;
+        self.host_options = new.host_options;
+        self.next_servers = new.next_servers;
+        self.authoritative = new.authoritative;
+        self.on_exhausted = new.on_exhausted;
+        self.allocation = new.allocation;
+        self.parsing = new.parsing;
+        self.option_order = new.option_order;
+        self.unicast_replies = new.unicast_replies;
+        self.inject_arp = new.inject_arp;
+        self.ports = new.ports;
+        self.server_hostname = new.server_hostname;
+        self.boot_file_in_header = new.boot_file_in_header;
+        self.mud_webhook = new.mud_webhook;
+        self.reservations = new.reservations;
+        self.uuid_reservations = new.uuid_reservations;
+        self.relay_reservations = new.relay_reservations;
+        self.quarantine = new.quarantine;
+        self.max_leases_per_circuit = new.max_leases_per_circuit;
+        self.load_sharing = new.load_sharing;
+        self.waiting = new.waiting;
+        self.hostname_conflict = new.hostname_conflict;
+        self.adaptive_lease = new.adaptive_lease;
+        let reserved = self
+            .reservations
+            .values()
+            .chain(self.uuid_reservations.values())
+            .chain(self.relay_reservations.values());
+        for ip_addr in reserved {
+            self.pool.entry(*ip_addr).or_insert(None);
+        }
+        self.reconcile();
+        Ok(self)
+    }
 
-        for i in 0..=octet_ranges[0] {
-            for ii in 0..=octet_ranges[1] {
-                for iii in 0..=octet_ranges[2] {
-                    for iiii in 0..=octet_ranges[3] {
-                        let ip = std::net::Ipv4Addr::from([
-                            (subnet[0] & mask[0]) + i,
-                            (subnet[1] & mask[1]) + ii,
-                            (subnet[2] & mask[2]) + iii,
-                            (subnet[3] & mask[3]) + iiii,
-                        ]);
-                        pool.insert(ip, None);
-                    }
-                }
+    /// Give clients we do not know addresses from `ranges` only, with
+    /// `options` layered over theirs, and never give those to clients we know.
+    /// We know clients with a reservation or host options and those in
+    /// `allowed`. The ranges must not overlap any we already have
+    pub fn set_quarantine(
+        &mut self,
+        ranges: &[(Ipv4Addr, Ipv4Addr)],
+        allowed: &[MacAddr],
+        options: DhcpOptionList,
+    ) -> Result<&mut Self> {
+        for &(start, end) in ranges {
+            if start > end || !self.in_subnet(&start) || !self.in_subnet(&end) {
+                return Err(Error::InvalidRange(start, end));
             }
+            if let Some(range) = self
+                .ranges
+                .iter()
+                .find(|range| start <= range.1 && end >= range.0)
+            {
+                return Err(Error::OverlappingRanges(range.0, start));
+            }
+            self.ranges.push((start, end));
         }
-        pool
+        self.quarantine = Some(Quarantine {
+            ranges: ranges.to_vec(),
+            allowed: allowed.iter().copied().collect(),
+            options,
+        });
+        self.reconcile();
+        Ok(self)
+    }
+
+    /// Cap the offers and leases held by clients whose DISCOVER came through
+    /// the same relay circuit, so one port or VLAN cannot drain the pool
+    pub fn set_max_leases_per_circuit(&mut self, max: usize) -> &mut Self {
+        self.max_leases_per_circuit = Some(max);
+        self
+    }
+
+    pub fn load_sharing(&self) -> LoadSharing {
+        self.load_sharing
+    }
+
+    pub fn set_load_sharing(&mut self, load_sharing: LoadSharing) -> &mut Self {
+        self.load_sharing = load_sharing;
+        self
+    }
+
+    pub fn set_waiting(&mut self, waiting: Waiting) -> &mut Self {
+        self.waiting = Some(waiting);
+        self
+    }
+
+    pub fn set_adaptive_lease(&mut self, adaptive_lease: AdaptiveLease) -> &mut Self {
+        self.adaptive_lease = Some(adaptive_lease);
+        self
+    }
+
+    pub fn set_hostname_conflict(&mut self, conflict: HostnameConflict) -> &mut Self {
+        self.hostname_conflict = conflict;
+        self
+    }
+
+    /// Whether a client that has been trying for `secs` has waited long
+    /// enough to skip our offer delay and the reserve
+    pub fn is_waiting(&self, secs: u16) -> bool {
+        self.waiting.is_some_and(|waiting| secs >= waiting.secs)
+    }
+
+    /// Whether this client would need one of the addresses we keep for
+    /// clients that have waited, when it has none of its own
+    pub fn is_held_back(&self, key: &ClientKey, mac_address: &MacAddr) -> bool {
+        let Some(waiting) = self.waiting else {
+            return false;
+        };
+        let free = self.size().saturating_sub(self.in_use());
+        free <= waiting.reserve
+            && self.lookup_client(key).is_none()
+            && !self.reservations.contains_key(mac_address)
+    }
+
+    /// How many offers and leases clients on this circuit hold
+    fn circuit_leases(&self, circuit_id: &[u8]) -> usize {
+        let now = self.now();
+        self.pool
+            .values()
+            .flatten()
+            .filter(|client| {
+                client.circuit_id.as_deref() == Some(circuit_id) && !client.is_expired(now)
+            })
+            .count()
+    }
+
+    /// Whether the client is one we know, everyone is without a quarantine
+    pub fn is_known(&self, mac_address: &MacAddr) -> bool {
+        match &self.quarantine {
+            Some(quarantine) => {
+                quarantine.allowed.contains(mac_address)
+                    || self.reservations.contains_key(mac_address)
+                    || self.host_options.contains_key(mac_address)
+            }
+            None => true,
+        }
+    }
+
+    /// Clients we know stay out of the quarantine and those we do not stay in
+    fn is_for(&self, ip_addr: &Ipv4Addr, mac_address: &MacAddr) -> bool {
+        match &self.quarantine {
+            Some(quarantine) => quarantine.contains(ip_addr) != self.is_known(mac_address),
+            None => true,
+        }
+    }
+
+    /// Whether we hand out `ip_addr`, from a range or a reservation
+    fn is_served(&self, ip_addr: &Ipv4Addr) -> bool {
+        let in_range = self
+            .ranges
+            .iter()
+            .any(|(start, end)| (start..=end).contains(&ip_addr));
+        (in_range && !self.is_excluded(ip_addr)) || self.is_reserved(ip_addr)
+    }
+
+    /// Bring the pool in line with our ranges and exclusions. Clients keep
+    /// addresses we no longer serve until their lease ends, they are NAKed
+    /// when they try to renew and pick up a new address from us
+    fn reconcile(&mut self) {
+        let served: Vec<Ipv4Addr> = self
+            .ranges
+            .iter()
+            .flat_map(|(start, end)| (u32::from(*start)..=u32::from(*end)).map(Ipv4Addr::from))
+            .filter(|ip| !self.is_excluded(ip))
+            .collect();
+        for ip_addr in served {
+            self.pool.entry(ip_addr).or_insert(None);
+        }
+        self.retire_expired();
+
+        let retiring = self.pool.keys().filter(|ip| !self.is_served(ip)).count();
+        if retiring > 0 {
+            info!("{retiring} leases are outside the pool, they will not be renewed");
+        }
+    }
+
+    /// Forget addresses we no longer serve once nobody holds them, and
+    /// declines too old to count. This scans the whole pool, so the server
+    /// calls it now and then rather than for every request
+    pub fn retire_expired(&mut self) {
+        let now = self.now();
+        let retired: Vec<Ipv4Addr> = self
+            .pool
+            .iter()
+            .filter(|(ip, client)| {
+                !self.is_served(ip) && client.as_ref().is_none_or(|client| client.is_expired(now))
+            })
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip_addr in retired {
+            self.pool.remove(&ip_addr);
+        }
+        self.declined
+            .retain(|_, declines| declines.until > now || declines.last + DECLINE_WINDOW > now);
+    }
+
+    fn is_excluded(&self, ip_addr: &Ipv4Addr) -> bool {
+        self.exclusions
+            .iter()
+            .any(|(start, end)| (start..=end).contains(&ip_addr))
+    }
+
+    /// Keep leases in `store`, which should already have been loaded
+    pub fn set_lease_store(&mut self, store: Box<dyn LeaseStore>) -> &mut Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Let our store finish writing, we are stopping
+    pub fn close_lease_store(&mut self) {
+        if let Some(store) = &mut self.store {
+            store.close();
+        }
+    }
+
+    /// Record bindings in `log` rather than only in memory
+    pub fn set_audit_log(&mut self, log: AuditLog) -> &mut Self {
+        self.audit = log;
+        self
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Write a lease we bound or were told of to our store if we have one
+    fn persist(&mut self, lease: &Lease) {
+        if let Some(store) = &mut self.store {
+            if let Err(error) = store.save(lease) {
+                error!("{error}");
+            }
+        }
+    }
+
+    /// Claim `ip_addr` for an offer in our store by `deadline`, false when
+    /// another client holds it through another server, whose lease we then
+    /// take on
+    fn claim(
+        &mut self,
+        ip_addr: Ipv4Addr,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        deadline: Instant,
+    ) -> Result<bool> {
+        let offer = Client::offered(key, mac_address, self.now()).lease(ip_addr);
+        let Some(store) = &mut self.store else {
+            return Ok(true);
+        };
+        if Instant::now() >= deadline {
+            return Err(Error::LeaseStore(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "ran out of time claiming an address",
+            )));
+        }
+        match store.claim(&offer, deadline)? {
+            Claim::Granted => Ok(true),
+            Claim::Held(lease) => {
+                info!(
+                    "{ip_addr} is held by {} through another server",
+                    lease.mac_address
+                );
+                self.adopt(lease);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Whether we should answer clients, a secondary stays silent while its
+    /// primary is up
+    pub fn serving(&self) -> bool {
+        self.failover.as_ref().is_none_or(|link| link.serving())
+    }
+
+    pub fn options_mut(&mut self) -> &mut DhcpOptionList {
+        &mut self.options
+    }
+
+    pub fn options(&self) -> &DhcpOptionList {
+        &self.options
+    }
+
+    /// Options for clients whose vendor class identifier starts with `class`
+    pub fn add_class_options(&mut self, class: String, options: DhcpOptionList) -> &mut Self {
+        self.class_options.push((class, options));
+        self
+    }
+
+    /// Options for clients with `class` among their user classes
+    pub fn add_user_class_options(&mut self, class: String, options: DhcpOptionList) -> &mut Self {
+        self.user_class_options.push((class, options));
+        self
+    }
+
+    /// Options for this client alone
+    pub fn add_host_options(&mut self, mac_address: MacAddr, options: DhcpOptionList) -> &mut Self {
+        self.host_options.insert(mac_address, options);
+        self
+    }
+
+    /// Send `stage` to `ip_addr` for the next file it loads
+    pub fn add_next_server(&mut self, stage: BootStage, ip_addr: Ipv4Addr) -> &mut Self {
+        self.next_servers.push((stage, ip_addr));
+        self
+    }
+
+    /// The siaddr for a client, from its user class over its vendor class,
+    /// the last added winning between equals
+    pub fn next_server(&self, class: ClientClass) -> Option<Ipv4Addr> {
+        self.next_servers
+            .iter()
+            .filter_map(|(stage, ip_addr)| Some((stage.rank(class)?, *ip_addr)))
+            .max_by_key(|(rank, _)| *rank)
+            .map(|(_, ip_addr)| ip_addr)
+    }
+
+    /// The options one client gets, host options override quarantine options
+    /// which override user class options, then vendor class options and
+    /// finally the subnet's
+    pub fn options_for(&self, mac_address: &MacAddr, class: ClientClass) -> DhcpOptionList {
+        let mut options = self.options.clone();
+        if let Some(vendor_class) = class.vendor {
+            self.class_options
+                .iter()
+                .filter(|(class, _)| vendor_class.starts_with(class.as_bytes()))
+                .for_each(|(_, class_options)| {
+                    options.merge(class_options);
+                });
+        }
+        if let Some(user_class) = class.user {
+            self.user_class_options
+                .iter()
+                .filter(|(class, _)| user_class.contains(class.as_bytes()))
+                .for_each(|(_, class_options)| {
+                    options.merge(class_options);
+                });
+        }
+        if let Some(quarantine) = &self.quarantine {
+            if !self.is_known(mac_address) {
+                options.merge(&quarantine.options);
+            }
+        }
+        if let Some(host_options) = self.host_options.get(mac_address) {
+            options.merge(host_options);
+        }
+        options
+    }
+
+    /// The lease time we hand out when binding a client, from the most
+    /// specific of their host, class and our own options
+    pub fn lease_time(&self, mac_address: &MacAddr, class: ClientClass) -> u32 {
+        let options = self.options_for(mac_address, class);
+        let lease_time = match options.get(DhcpOption::LEASE_TIME) {
+            Some(DhcpOption::LeaseTime(time)) => *time,
+            _ => DEFAULT_LEASE_TIME,
+        };
+        // Shrinking a reserved client's lease frees nothing for anyone else
+        let lease_time = match self.adaptive_lease {
+            Some(adaptive) if !self.reservations.contains_key(mac_address) => {
+                adaptive.scale(lease_time, self.in_use(), self.size())
+            }
+            _ => lease_time,
+        };
+        match &self.failover {
+            Some(link) => link.lease_time(lease_time),
+            None => lease_time,
+        }
+    }
+
+    fn is_reserved_for_other(&self, ip_addr: &Ipv4Addr, mac_address: &MacAddr) -> bool {
+        self.is_reserved(ip_addr) && self.reservations.get(mac_address) != Some(ip_addr)
+    }
+
+    /// Nobody holds this address, or an offer for it timed out, and it is not
+    /// reserved for another client
+    fn is_available(&self, ip_addr: &Ipv4Addr, mac_address: &MacAddr) -> bool {
+        if self.is_reserved_for_other(ip_addr, mac_address)
+            || !self.is_served(ip_addr)
+            || !self.is_for(ip_addr, mac_address)
+            || self.is_declined(ip_addr)
+        {
+            return false;
+        }
+
+        match self.pool.get(ip_addr) {
+            Some(Some(client)) => client.is_stale_offer(self.now()),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Whether a client DECLINEd `ip_addr` recently
+    fn is_declined(&self, ip_addr: &Ipv4Addr) -> bool {
+        self.declined
+            .get(ip_addr)
+            .is_some_and(|declines| declines.until > self.now())
+    }
+
+    fn allocate_address(
+        &mut self,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        requested: Option<Ipv4Addr>,
+        deadline: Instant,
+    ) -> Option<Ipv4Addr> {
+        // Every address, from where our allocation says to start for this client
+        let mut order: Vec<Ipv4Addr> = self.pool.keys().copied().collect();
+        let start = self.allocation.start(key, order.len());
+        order.rotate_left(start);
+
+        // Addresses another server turned out to hold
+        let mut held = BTreeSet::new();
+        let ip = loop {
+            let is_available =
+                |ip: &Ipv4Addr| self.is_available(ip, mac_address) && !held.contains(ip);
+
+            // A reservation beats anything the client asks for
+            let reserved = self
+                .reservations
+                .get(mac_address)
+                .copied()
+                .filter(is_available);
+
+            // The client asked for a specific address, usually the one it had last
+            let requested = || requested.filter(is_available);
+
+            // A returning client gets its old address back if it is still free
+            let previous = || self.history.recall(key).filter(is_available);
+
+            // Otherwise avoid addresses that other clients may come back for
+            let ip = reserved
+                .or_else(requested)
+                .or_else(previous)
+                .or_else(|| {
+                    order
+                        .iter()
+                        .find(|ip| is_available(ip) && !self.history.contains(ip))
+                        .copied()
+                })
+                .or_else(|| order.iter().find(|ip| is_available(ip)).copied())?;
+
+            match self.claim(ip, key, mac_address, deadline) {
+                Ok(true) => break ip,
+                Ok(false) => held.insert(ip),
+                Err(error) => {
+                    error!("Not offering an address, {error}");
+                    return None;
+                }
+            };
+        };
+
+        let now = self.now();
+        self.history.forget(key);
+        self.pool
+            .insert(ip, Some(Client::offered(key, mac_address, now)));
+        Some(ip)
+    }
+
+    /// Offer an IP Address from the pool, the address is held for the client
+    /// until they [AddrPool::commit] to it or the offer times out. We honour
+    /// the `requested` address if it is in our range and free. `circuit_id`
+    /// is the option 82 circuit the DISCOVER was relayed from, if any
+    pub fn offer(
+        &mut self,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        requested: Option<Ipv4Addr>,
+        circuit_id: Option<&[u8]>,
+    ) -> Result<Ipv4Addr> {
+        // A client starting over on an address we no longer serve, or no
+        // longer serve to them, moves
+        if let Some(ip) = self.lookup_client(key).filter(|ip| {
+            !self.is_served(ip)
+                || !self.is_for(ip, mac_address)
+                || self.is_reserved_for_other(ip, mac_address)
+        }) {
+            self.pool.remove(&ip);
+        }
+        if let Some(ip) = self.lookup_client(key) {
+            let now = self.now();
+            // Hold a repeated offer for longer, but never downgrade a lease
+            // the client still holds. One that ran out is offered afresh
+            if let Some(Some(client)) = self.pool.get_mut(&ip) {
+                if client.state == LeaseState::Offered || client.is_expired(now) {
+                    *client = Client::offered(key, mac_address, now);
+                    client.circuit_id = circuit_id.map(<[u8]>::to_vec);
+                }
+            }
+            return Ok(ip);
+        }
+
+        if let (Some(circuit_id), Some(max)) = (circuit_id, self.max_leases_per_circuit) {
+            if self.circuit_leases(circuit_id) >= max {
+                return Err(Error::CircuitLeaseLimit(max));
+            }
+        }
+
+        let deadline = Instant::now() + CLAIM_BUDGET;
+        let ip = self
+            .allocate_address(key, mac_address, requested, deadline)
+            .or_else(|| self.evict_expired_lease(key, mac_address, deadline))
+            .ok_or_else(|| {
+                error!("{:?}", Error::AllIPAddressesExhausted);
+                Error::AllIPAddressesExhausted
+            })?;
+        if let Some(Some(client)) = self.pool.get_mut(&ip) {
+            client.circuit_id = circuit_id.map(<[u8]>::to_vec);
+        }
+        Ok(ip)
+    }
+
+    /// Take back the lease that expired longest ago, leases that have not
+    /// expired are never touched as the client may still be using them
+    fn evict_expired_lease(
+        &mut self,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        deadline: Instant,
+    ) -> Option<Ipv4Addr> {
+        let now = self.now();
+        let mut held = BTreeSet::new();
+        let victim = loop {
+            let victim = self
+                .pool
+                .iter()
+                .filter_map(|(ip, client)| Some((ip, client.as_ref()?)))
+                .filter(|(ip, client)| {
+                    client.is_expired(now)
+                        && !self.is_reserved_for_other(ip, mac_address)
+                        && self.is_served(ip)
+                        && !held.contains(*ip)
+                })
+                .min_by_key(|(_, client)| client.expires)?
+                .0
+                .to_owned();
+
+            match self.claim(victim, key, mac_address, deadline) {
+                Ok(true) => break victim,
+                Ok(false) => held.insert(victim),
+                Err(error) => {
+                    error!("Not reclaiming an address, {error}");
+                    return None;
+                }
+            };
+        };
+
+        // Remember who had it in case they come back once it is free again
+        if let Some(Some(previous)) = self
+            .pool
+            .insert(victim, Some(Client::offered(key, mac_address, now)))
+        {
+            self.history.remember(&previous.key, victim);
+            self.audit.record(
+                now,
+                AuditEvent::Reclaimed,
+                victim,
+                previous.mac_address,
+                previous.expires,
+            );
+        }
+
+        Some(victim)
+    }
+
+    /// Where the client is in acquiring and keeping an address from us,
+    /// [ClientState::Init] when it holds nothing
+    pub fn client_state(&self, key: &ClientKey) -> ClientState {
+        self.pool
+            .values()
+            .flatten()
+            .filter(|client| client.key == *key)
+            .map(|client| client.client_state(self.now()))
+            .find(|state| *state != ClientState::Init)
+            .unwrap_or(ClientState::Init)
+    }
+
+    /// Find the address we have offered or leased to this client, offers that
+    /// timed out no longer count
+    fn lookup_client(&self, key: &ClientKey) -> Option<Ipv4Addr> {
+        self.pool
+            .iter()
+            .find(|(_, client)| match client {
+                Some(client) => client.key == *key && !client.is_stale_offer(self.now()),
+                None => false,
+            })
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Why the client cannot have `ip_addr`, for the text of a NAK
+    pub fn refusal_reason(
+        &self,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        ip_addr: &Ipv4Addr,
+    ) -> &'static str {
+        if self.is_reserved_for_other(ip_addr, mac_address) {
+            return "address reserved for another client";
+        }
+        match self.pool.get(ip_addr) {
+            None => "requested address not in pool",
+            Some(_) if !self.is_served(ip_addr) => "address no longer in pool",
+            Some(_) if !self.is_for(ip_addr, mac_address) => match self.is_known(mac_address) {
+                true => "client is now registered",
+                false => "client is not registered",
+            },
+            Some(Some(client)) if client.key != *key && !client.is_stale_offer(self.now()) => {
+                "address bound to another client"
+            }
+            Some(Some(client)) if client.is_stale_offer(self.now()) => "offer expired",
+            _ => "address not offered to this client",
+        }
+    }
+
+    /// Whether `ip_addr` is currently leased to anyone
+    pub fn is_leased(&self, ip_addr: &Ipv4Addr) -> bool {
+        matches!(self.pool.get(ip_addr), Some(Some(_)))
+    }
+
+    /// A client REQUESTed `ip_addr`, bind the lease for `lease_time` seconds
+    /// if we offered it to them or extend it if they already hold it. Leases
+    /// on addresses we no longer serve, or no longer serve to them, are left
+    /// to run out
+    pub fn commit(
+        &mut self,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        ip_addr: &Ipv4Addr,
+        lease_time: u32,
+    ) -> Option<()> {
+        if !self.is_served(ip_addr) || !self.is_for(ip_addr, mac_address) {
+            return None;
+        }
+        let now = self.now();
+        match self.pool.get_mut(ip_addr) {
+            Some(Some(client)) if client.key == *key => {
+                if client.is_stale_offer(now) {
+                    return None;
+                }
+                let event = match client.state {
+                    LeaseState::Bound => AuditEvent::Renewed,
+                    LeaseState::Offered => AuditEvent::Bound,
+                };
+                let (hostname, mud_url) = (client.hostname.take(), client.mud_url.take());
+                let (circuit_id, uuid) = (client.circuit_id.take(), client.uuid);
+                let fqdn = client.fqdn.take();
+                *client = Client::bound(key, mac_address, now, lease_time);
+                client.hostname = hostname;
+                client.mud_url = mud_url;
+                client.circuit_id = circuit_id;
+                client.uuid = uuid;
+                client.fqdn = fqdn;
+                let lease = client.lease(*ip_addr);
+                self.audit
+                    .record(now, event, *ip_addr, *mac_address, lease.expires);
+                if let Some(link) = &self.failover {
+                    link.replicate(lease.clone());
+                }
+                self.persist(&lease);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    /// A client in RENEWING or REBINDING wants to extend `ip_addr`, only
+    /// leases we actually bound to them can be renewed
+    pub fn renew(
+        &mut self,
+        key: &ClientKey,
+        mac_address: &MacAddr,
+        ip_addr: &Ipv4Addr,
+        lease_time: u32,
+    ) -> Option<()> {
+        match self.pool.get(ip_addr) {
+            Some(Some(client))
+                if client.state == LeaseState::Bound
+                    && self.is_served(ip_addr)
+                    && self.is_for(ip_addr, mac_address) =>
+            {
+                self.commit(key, mac_address, ip_addr, lease_time)
+            }
+            _ => None,
+        }
+    }
+
+    /// A client RELEASEd `ip_addr`, it is free for anyone but the client gets
+    /// it back first if it returns. Only the client holding it can release it
+    pub fn release(&mut self, key: &ClientKey, ip_addr: &Ipv4Addr) -> Option<()> {
+        self.end_lease(key, ip_addr, AuditEvent::Released)?;
+        self.history.remember(key, *ip_addr);
+        Some(())
+    }
+
+    /// A client DECLINEd `ip_addr` as someone else is using it, nobody gets
+    /// it for [DECLINE_TIMEOUT], or [CONFLICT_TIMEOUT] once it is a conflict.
+    /// Only the client we gave it to can decline it
+    pub fn decline(&mut self, key: &ClientKey, ip_addr: &Ipv4Addr) -> Option<Declines> {
+        self.end_lease(key, ip_addr, AuditEvent::Declined)?;
+        let now = self.now();
+        let count = match self.declined.get(ip_addr) {
+            Some(declines) if declines.last + DECLINE_WINDOW > now => declines.count + 1,
+            _ => 1,
+        };
+        let mut declines = Declines {
+            count,
+            last: now,
+            until: now + DECLINE_TIMEOUT,
+        };
+        if declines.is_conflict() {
+            declines.until = now + CONFLICT_TIMEOUT;
+        }
+        self.declined.insert(*ip_addr, declines);
+        Some(declines)
+    }
+
+    /// Addresses declined so often we keep them back for [CONFLICT_TIMEOUT]
+    pub fn conflicts(&self) -> Vec<(Ipv4Addr, Declines)> {
+        self.declined
+            .iter()
+            .filter(|(_, declines)| declines.is_conflict() && declines.until > self.now())
+            .map(|(ip_addr, declines)| (*ip_addr, *declines))
+            .collect()
+    }
+
+    /// End the offer or lease `key` holds on `ip_addr` now, everywhere we
+    /// keep it
+    fn end_lease(&mut self, key: &ClientKey, ip_addr: &Ipv4Addr, event: AuditEvent) -> Option<()> {
+        let slot = self.pool.get_mut(ip_addr)?;
+        if slot.as_ref()?.key != *key {
+            return None;
+        }
+        let mut client = slot.take()?;
+        let now = self.now();
+        client.expires = now;
+        let lease = client.lease(*ip_addr);
+        self.audit
+            .record(now, event, *ip_addr, client.mac_address, now);
+        if let Some(link) = &self.failover {
+            link.replicate(lease.clone());
+        }
+        self.persist(&lease);
+        Some(())
+    }
+
+    /// How many addresses we hand out, including reservations
+    pub fn size(&self) -> usize {
+        self.pool.keys().filter(|ip| self.is_served(ip)).count()
+    }
+
+    /// How many addresses are offered or leased right now
+    pub fn in_use(&self) -> usize {
+        self.pool
+            .values()
+            .flatten()
+            .filter(|client| !client.is_expired(self.now()))
+            .count()
+    }
+
+    /// Every offer and lease that has not expired
+    pub fn entries(&self) -> Vec<LeaseEntry> {
+        self.pool
+            .iter()
+            .filter_map(|(ip, client)| Some((ip, client.as_ref()?)))
+            .filter(|(_, client)| !client.is_expired(self.now()))
+            .map(|(ip, client)| LeaseEntry {
+                ip_addr: *ip,
+                mac_address: client.mac_address,
+                hostname: client.hostname.clone(),
+                mud_url: client.mud_url.clone(),
+                uuid: client.uuid,
+                fqdn: client.fqdn.clone(),
+                state: client.state,
+                client_state: client.client_state(self.now()),
+                expires: client.expires,
+            })
+            .collect()
+    }
+
+    /// Remember the name the client holding `ip_addr` gave us, cleaned up and
+    /// made unique as `hostname_conflict` says
+    pub fn set_hostname(&mut self, ip_addr: &Ipv4Addr, hostname: &str) {
+        let now = self.now();
+        let Some(Some(client)) = self.pool.get(ip_addr) else {
+            return;
+        };
+        let (key, mac_address) = (&client.key, client.mac_address);
+        let taken = |name: &str| {
+            self.pool
+                .iter()
+                .filter_map(|(ip, other)| Some((ip, other.as_ref()?)))
+                .filter(|(ip, other)| *ip != ip_addr && other.key != *key)
+                .filter(|(_, other)| !other.is_expired(now))
+                .filter_map(|(_, other)| other.hostname.as_deref())
+                .any(|held| held.eq_ignore_ascii_case(name))
+        };
+        let name = hostname::sanitize(hostname)
+            .and_then(|name| self.hostname_conflict.resolve(name, &mac_address, taken));
+        if name.as_deref() != Some(hostname) {
+            info!(
+                "{mac_address} at {ip_addr} calls itself {hostname:?}, keeping {}",
+                name.as_deref().unwrap_or("no name")
+            );
+        }
+
+        if let Some(Some(client)) = self.pool.get_mut(ip_addr) {
+            client.hostname = name;
+        }
+    }
+
+    /// Remember how we answered option 81 from the client holding `ip_addr`
+    pub fn set_fqdn(&mut self, ip_addr: &Ipv4Addr, fqdn: ClientFqdn) {
+        if let Some(Some(client)) = self.pool.get_mut(ip_addr) {
+            client.fqdn = Some(fqdn);
+        }
+    }
+
+    /// Remember the SMBIOS UUID of the client holding `ip_addr`
+    pub fn set_uuid(&mut self, ip_addr: &Ipv4Addr, uuid: Uuid) {
+        if let Some(Some(client)) = self.pool.get_mut(ip_addr) {
+            client.uuid = Some(uuid);
+        }
+    }
+
+    /// Remember the MUD URL of the client holding `ip_addr`, true if it is
+    /// new to us
+    pub fn set_mud_url(&mut self, ip_addr: &Ipv4Addr, url: &str) -> bool {
+        match self.pool.get_mut(ip_addr) {
+            Some(Some(client)) if client.mud_url.as_deref() != Some(url) => {
+                client.mud_url = Some(url.to_owned());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Where we announce new MUD URLs, see [crate::mud]
+    pub fn mud_webhook(&self) -> Option<&Webhook> {
+        self.mud_webhook.as_ref()
+    }
+
+    pub fn set_mud_webhook(&mut self, webhook: Webhook) -> &mut Self {
+        self.mud_webhook = Some(webhook);
+        self
+    }
+
+    /// Every lease we have bound, for our failover peer and the lease file
+    pub fn leases(&self) -> Vec<Lease> {
+        self.pool
+            .iter()
+            .filter_map(|(ip, client)| Some((ip, client.as_ref()?)))
+            .filter(|(_, client)| {
+                client.state == LeaseState::Bound && !client.is_expired(self.now())
+            })
+            .map(|(ip, client)| client.lease(*ip))
+            .collect()
+    }
+
+    /// A lease from our failover peer or the lease store, take it unless we
+    /// know of a lease on the same address that lasts longer
+    pub fn apply_lease(&mut self, lease: Lease) {
+        if self.adopt(lease.clone()) {
+            self.persist(&lease);
+        }
+    }
+
+    /// Take on the leases we kept across a restart, after the config may have
+    /// changed under them. Whatever order they come in, an address goes to
+    /// the lease on it that lasts longest and a client keeps its longest lease
+    pub fn restore(&mut self, mut leases: Vec<Lease>) -> RestoreReport {
+        let now = self.now();
+        let mut report = RestoreReport::default();
+        leases.sort_by_key(|lease| (Reverse(lease.expires), lease.ip_addr, lease.encode()));
+
+        for lease in leases {
+            let reserved_for_other = self.is_reserved_for_other(&lease.ip_addr, &lease.mac_address);
+            if lease.expires <= now {
+                report.expired += 1;
+                continue;
+            }
+            let dropped = if !self.in_subnet(&lease.ip_addr) {
+                report.outside_subnet += 1;
+                Some("is outside the subnet")
+            } else if reserved_for_other {
+                report.reserved += 1;
+                Some("is reserved for another client")
+            } else if matches!(self.pool.get(&lease.ip_addr), Some(Some(_))) {
+                report.duplicates += 1;
+                Some("is also leased to a client for longer")
+            } else if self.lookup_client(&lease.key).is_some() {
+                report.duplicates += 1;
+                Some("has a longer lease on another address")
+            } else {
+                None
+            };
+            if let Some(reason) = dropped {
+                warn!(
+                    "Dropping the lease of {} to {}, the address {reason}",
+                    lease.ip_addr, lease.mac_address
+                );
+                continue;
+            }
+
+            // Like any lease that leaves the pool, kept until it is renewed
+            if !self.is_served(&lease.ip_addr) {
+                report.retiring += 1;
+            }
+            report.restored += 1;
+            self.pool
+                .insert(lease.ip_addr, Some(Client::from_lease(lease)));
+        }
+        report
+    }
+
+    /// Take on `lease` in memory, false when we already knew better
+    fn adopt(&mut self, lease: Lease) -> bool {
+        let Some(slot) = self.pool.get_mut(&lease.ip_addr) else {
+            warn!("{} was leased out of our range", lease.ip_addr);
+            return false;
+        };
+        if let Some(client) = slot {
+            if client.state == LeaseState::Bound && client.expires >= lease.expires {
+                return false;
+            }
+        }
+
+        // The client can only hold one address across the pair
+        if let Some(ip) = self.lookup_client(&lease.key) {
+            if ip != lease.ip_addr {
+                self.pool.insert(ip, None);
+            }
+        }
+        self.pool
+            .insert(lease.ip_addr, Some(Client::from_lease(lease)));
+        true
+    }
+
+    fn initialise_ranges(
+        ranges: &[(Ipv4Addr, Ipv4Addr)],
+        exclusions: &[(Ipv4Addr, Ipv4Addr)],
+    ) -> DhcpRange {
+        let is_excluded = |ip: &Ipv4Addr| {
+            exclusions
+                .iter()
+                .any(|(start, end)| (start..=end).contains(&ip))
+        };
+
+        ranges
+            .iter()
+            .flat_map(|(start, end)| (u32::from(*start)..=u32::from(*end)).map(Ipv4Addr::from))
+            .filter(|ip| !is_excluded(ip))
+            .map(|ip| (ip, None))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl ManualClock {
+        fn advance(&self, secs: u64) {
+            self.set(self.now() + Duration::from_secs(secs));
+        }
+    }
+
+    const LEASE_TIME: u32 = 600;
+
+    /// A pool of `size` addresses from 192.168.1.10 on a clock we control
+    fn pool(size: u8) -> (AddrPool, ManualClock) {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let range = (
+            Ipv4Addr::new(192, 168, 1, 10),
+            Ipv4Addr::new(192, 168, 1, 9 + size),
+        );
+        let mut pool = AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], &[range], &[]).unwrap();
+        pool.set_clock(clock.clone());
+        pool.options_mut().set(DhcpOption::LeaseTime(LEASE_TIME));
+        (pool, clock)
+    }
+
+    fn client(last_octet: u8) -> (ClientKey, MacAddr) {
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, last_octet]);
+        (ClientKey::Mac(mac_address), mac_address)
+    }
+
+    /// Offer and commit, as a DISCOVER then REQUEST would
+    fn bind(pool: &mut AddrPool, last_octet: u8) -> Ipv4Addr {
+        let (key, mac_address) = client(last_octet);
+        let ip_addr = pool.offer(&key, &mac_address, None, None).unwrap();
+        let lease_time = pool.lease_time(&mac_address, ClientClass::default());
+        pool.commit(&key, &mac_address, &ip_addr, lease_time)
+            .unwrap();
+        ip_addr
+    }
+
+    #[test]
+    fn leases_expire_after_the_lease_time() {
+        let (mut pool, clock) = pool(4);
+        bind(&mut pool, 1);
+
+        clock.advance(LEASE_TIME as u64 - 1);
+        assert_eq!(pool.in_use(), 1);
+        assert_eq!(pool.leases().len(), 1);
+
+        clock.advance(1);
+        assert_eq!(pool.in_use(), 0);
+        assert!(pool.leases().is_empty());
+        assert!(pool.entries().is_empty());
+    }
+
+    #[test]
+    fn refusals_say_why_the_address_cannot_be_had() {
+        let (mut pool, clock) = pool(4);
+        let (key, mac_address) = client(1);
+        let (other_key, other_mac) = client(2);
+
+        let outside = Ipv4Addr::new(192, 168, 1, 200);
+        assert_eq!(
+            pool.refusal_reason(&key, &mac_address, &outside),
+            "requested address not in pool"
+        );
+        let free = Ipv4Addr::new(192, 168, 1, 13);
+        assert_eq!(
+            pool.refusal_reason(&key, &mac_address, &free),
+            "address not offered to this client"
+        );
+
+        let bound = bind(&mut pool, 1);
+        assert_eq!(
+            pool.refusal_reason(&other_key, &other_mac, &bound),
+            "address bound to another client"
+        );
+
+        let offered = pool.offer(&other_key, &other_mac, None, None).unwrap();
+        clock.advance(OFFER_TIMEOUT as u64);
+        assert_eq!(
+            pool.refusal_reason(&other_key, &other_mac, &offered),
+            "offer expired"
+        );
+    }
+
+    /// A shared store where another server holds every address, and takes
+    /// its time saying so
+    #[derive(Debug, Default)]
+    struct SlowStore {
+        claims: Arc<Mutex<usize>>,
+    }
+
+    impl LeaseStore for SlowStore {
+        fn load(&mut self) -> Result<Vec<Lease>> {
+            Ok(Vec::new())
+        }
+
+        fn claim(&mut self, offer: &Lease, _deadline: Instant) -> Result<Claim> {
+            *self.claims.lock().unwrap() += 1;
+            std::thread::sleep(CLAIM_BUDGET / 4);
+            let (key, mac_address) = client(99);
+            Ok(Claim::Held(Lease {
+                key,
+                mac_address,
+                ..offer.clone()
+            }))
+        }
+
+        fn save(&mut self, _lease: &Lease) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn claims_in_a_slow_store_stop_at_the_budget() {
+        let (mut pool, _) = pool(20);
+        let store = SlowStore::default();
+        let claims = store.claims.clone();
+        pool.set_lease_store(Box::new(store));
+
+        let (key, mac_address) = client(1);
+        let started = Instant::now();
+        assert!(pool.offer(&key, &mac_address, None, None).is_err());
+        assert!(started.elapsed() < CLAIM_BUDGET * 2);
+        assert!(*claims.lock().unwrap() <= 5);
+    }
+
+    #[test]
+    fn offers_hold_the_address_until_they_time_out() {
+        let (mut pool, clock) = pool(1);
+        let (offered_key, offered_mac) = client(1);
+        let (other_key, other_mac) = client(2);
+        let ip_addr = pool.offer(&offered_key, &offered_mac, None, None).unwrap();
+
+        clock.advance(OFFER_TIMEOUT as u64 - 1);
+        assert!(pool.offer(&other_key, &other_mac, None, None).is_err());
+
+        clock.advance(1);
+        assert_eq!(
+            pool.offer(&other_key, &other_mac, None, None).unwrap(),
+            ip_addr
+        );
+        // The first client was too slow to REQUEST it
+        assert!(pool
+            .commit(&offered_key, &offered_mac, &ip_addr, LEASE_TIME)
+            .is_none());
+    }
+
+    #[test]
+    fn discovers_get_the_address_they_ask_for_when_it_is_free() {
+        let (mut pool, _) = pool(3);
+        let (key, mac_address) = client(1);
+        let wanted = Ipv4Addr::new(192, 168, 1, 12);
+        assert_eq!(
+            pool.offer(&key, &mac_address, Some(wanted), None).unwrap(),
+            wanted
+        );
+
+        // Taken, or not ours to give, so the next free one instead
+        let (key, mac_address) = client(2);
+        let offered = pool.offer(&key, &mac_address, Some(wanted), None).unwrap();
+        assert_eq!(offered, Ipv4Addr::new(192, 168, 1, 10));
+        let (key, mac_address) = client(3);
+        let outside = Some(Ipv4Addr::new(192, 168, 1, 99));
+        let offered = pool.offer(&key, &mac_address, outside, None).unwrap();
+        assert_eq!(offered, Ipv4Addr::new(192, 168, 1, 11));
+    }
+
+    #[test]
+    fn leases_follow_the_client_identifier_not_the_mac() {
+        let (mut pool, _) = pool(2);
+        let client_id =
+            crate::types::ClientIdentifier::try_from(&[0, b'l', b'a', b'p'][..]).unwrap();
+        let key = ClientKey::new(Some(client_id), MacAddr::new([2, 0, 0, 0, 0, 1]));
+        let ip_addr = pool
+            .offer(&key, &MacAddr::new([2, 0, 0, 0, 0, 1]), None, None)
+            .unwrap();
+        pool.commit(
+            &key,
+            &MacAddr::new([2, 0, 0, 0, 0, 1]),
+            &ip_addr,
+            LEASE_TIME,
+        )
+        .unwrap();
+
+        // A rotated MAC with the same identifier is the same client
+        let rotated = MacAddr::new([2, 0, 0, 0, 0, 2]);
+        assert_eq!(pool.offer(&key, &rotated, None, None).unwrap(), ip_addr);
+
+        // Without option 61 the old MAC is someone else
+        let (mac_key, mac_address) = client(1);
+        assert_ne!(
+            pool.offer(&mac_key, &mac_address, None, None).unwrap(),
+            ip_addr
+        );
+    }
+
+    #[test]
+    fn every_range_is_served_but_its_exclusions() {
+        let ip = |host| Ipv4Addr::new(192, 168, 1, host);
+        let ranges = [(ip(20), ip(21)), (ip(10), ip(12))];
+        let exclusions = [(ip(11), ip(11))];
+        let mut pool =
+            AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], &ranges, &exclusions).unwrap();
+        assert_eq!(pool.size(), 4);
+        let offered: Vec<_> = (1..=4)
+            .map(|last_octet| {
+                let (key, mac_address) = client(last_octet);
+                pool.offer(&key, &mac_address, None, None).unwrap()
+            })
+            .collect();
+        assert_eq!(offered, [ip(10), ip(12), ip(20), ip(21)]);
+
+        let new = |ranges: &[_]| AddrPool::new([192, 168, 1, 0], [255, 255, 255, 0], ranges, &[]);
+        assert!(matches!(
+            new(&[(ip(10), ip(20)), (ip(20), ip(30))]),
+            Err(Error::OverlappingRanges(..))
+        ));
+        assert!(matches!(
+            new(&[(ip(20), ip(10))]),
+            Err(Error::InvalidRange(..))
+        ));
+        assert!(matches!(
+            new(&[(ip(10), Ipv4Addr::new(192, 168, 2, 10))]),
+            Err(Error::InvalidRange(..))
+        ));
+    }
+
+    #[test]
+    fn excluded_leases_run_out_and_are_retired_later() {
+        let (mut pool, clock) = pool(2);
+        let ip_addr = bind(&mut pool, 1);
+        pool.add_exclusion(ip_addr, ip_addr).unwrap();
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.in_use(), 1, "the client keeps it until the lease ends");
+
+        clock.advance(LEASE_TIME as u64);
+        let (key, mac_address) = client(2);
+        assert_ne!(pool.offer(&key, &mac_address, None, None).unwrap(), ip_addr);
+        assert!(pool.pool.contains_key(&ip_addr));
+
+        pool.retire_expired();
+        assert!(!pool.pool.contains_key(&ip_addr));
+    }
+
+    #[test]
+    fn leases_in_removed_ranges_are_not_extended_on_reboot() {
+        let (mut pool, _) = pool(1);
+        pool.set_range(
+            Ipv4Addr::new(192, 168, 1, 20),
+            Ipv4Addr::new(192, 168, 1, 20),
+        )
+        .unwrap();
+        bind(&mut pool, 1);
+        let (key, mac_address) = client(2);
+        let ip_addr = bind(&mut pool, 2);
+        assert_eq!(ip_addr, Ipv4Addr::new(192, 168, 1, 20));
+
+        pool.remove_range(ip_addr, ip_addr).unwrap();
+        // INIT-REBOOT asks for it again by option 50 rather than renewing
+        assert!(pool
+            .commit(&key, &mac_address, &ip_addr, LEASE_TIME)
+            .is_none());
+        assert_eq!(
+            pool.refusal_reason(&key, &mac_address, &ip_addr),
+            "address no longer in pool"
+        );
+        assert_eq!(pool.in_use(), 2, "the client keeps it until the lease ends");
+    }
+
+    #[test]
+    fn host_options_beat_class_options_which_beat_ours() {
+        let (mut pool, _) = pool(2);
+        let layer = |lease_time, domain: &str| {
+            let mut options = DhcpOptionList::builder();
+            options
+                .add(DhcpOption::LeaseTime(lease_time))
+                .add(DhcpOption::DomainName(domain.to_owned()));
+            options
+        };
+        pool.options_mut()
+            .set(DhcpOption::DomainName("home".to_owned()));
+        pool.add_class_options("PXEClient".to_owned(), layer(60, "pxe"));
+        let (_, host) = client(1);
+        let mut host_options = DhcpOptionList::builder();
+        host_options.add(DhcpOption::LeaseTime(30));
+        pool.add_host_options(host, host_options);
+
+        let pxe = ClientClass {
+            vendor: Some(b"PXEClient:Arch:00007"),
+            user: None,
+        };
+        let (_, other) = client(2);
+        let domain = |mac_address, class| {
+            pool.options_for(mac_address, class)
+                .get(DhcpOption::DOMAIN_NAME)
+                .cloned()
+        };
+        assert_eq!(pool.lease_time(&other, ClientClass::default()), LEASE_TIME);
+        assert_eq!(pool.lease_time(&other, pxe), 60);
+        assert_eq!(pool.lease_time(&host, pxe), 30);
+        // Layers only replace the options they have
+        assert_eq!(
+            domain(&host, pxe),
+            Some(DhcpOption::DomainName("pxe".to_owned()))
+        );
+        assert_eq!(
+            domain(&host, ClientClass::default()),
+            Some(DhcpOption::DomainName("home".to_owned()))
+        );
+    }
+
+    #[test]
+    fn released_addresses_are_free_and_declined_ones_held_back() {
+        let (mut pool, clock) = pool(2);
+        let released = bind(&mut pool, 1);
+        let declined = bind(&mut pool, 2);
+        let (key, mac_address) = client(1);
+        let (other_key, _) = client(2);
+
+        // Only the holder can give an address up
+        assert!(pool.release(&other_key, &released).is_none());
+        assert!(pool.decline(&key, &declined).is_none());
+
+        pool.release(&key, &released).unwrap();
+        pool.decline(&other_key, &declined).unwrap();
+        assert_eq!(pool.in_use(), 0);
+        assert!(pool.leases().is_empty());
+        let last_event = |ip_addr| pool.audit_log().for_ip(ip_addr).pop().unwrap().event;
+        assert_eq!(last_event(released), AuditEvent::Released);
+        assert_eq!(last_event(declined), AuditEvent::Declined);
+
+        // Someone is squatting on the declined address, so it stays out of
+        // the pool for a while, the released one goes back to its client
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            released
+        );
+        let (third_key, third_mac) = client(3);
+        assert!(pool.offer(&third_key, &third_mac, None, None).is_err());
+        assert!(pool.is_declined(&declined));
+        clock.advance(DECLINE_TIMEOUT.as_secs());
+        assert!(!pool.is_declined(&declined));
+    }
+
+    #[test]
+    fn addresses_declined_again_and_again_are_held_back_for_good() {
+        let (mut pool, clock) = pool(1);
+        let (key, _) = client(1);
+        let mut decline = || {
+            let ip_addr = bind(&mut pool, 1);
+            let declines = pool.decline(&key, &ip_addr).unwrap();
+            clock.advance(DECLINE_TIMEOUT.as_secs());
+            declines.count
+        };
+        assert_eq!(decline(), 1);
+        assert_eq!(decline(), 2);
+        // Far enough apart they are unrelated
+        clock.advance(DECLINE_WINDOW.as_secs());
+        assert_eq!(decline(), 1);
+        assert_eq!(decline(), 2);
+        assert_eq!(decline(), CONFLICT_DECLINES);
+
+        let ip_addr = Ipv4Addr::new(192, 168, 1, 10);
+        assert_eq!(pool.conflicts()[0].0, ip_addr);
+        assert!(pool.is_declined(&ip_addr));
+        clock.advance((CONFLICT_TIMEOUT - DECLINE_TIMEOUT).as_secs());
+        assert!(!pool.is_declined(&ip_addr));
+        assert!(pool.conflicts().is_empty());
+    }
+
+    #[test]
+    fn client_state_follows_the_lease_timers() {
+        let (mut pool, clock) = pool(1);
+        let (key, mac_address) = client(1);
+        assert_eq!(pool.client_state(&key), ClientState::Init);
+        let ip_addr = pool.offer(&key, &mac_address, None, None).unwrap();
+        assert_eq!(pool.client_state(&key), ClientState::Offered);
+        pool.commit(&key, &mac_address, &ip_addr, LEASE_TIME)
+            .unwrap();
+        assert_eq!(pool.client_state(&key), ClientState::Bound);
+
+        // T1 at half the lease, T2 at seven eighths
+        clock.advance(LEASE_TIME as u64 / 2);
+        assert_eq!(pool.client_state(&key), ClientState::Renewing);
+        assert_eq!(pool.entries()[0].client_state, ClientState::Renewing);
+        clock.advance(LEASE_TIME as u64 * 3 / 8);
+        assert_eq!(pool.client_state(&key), ClientState::Rebinding);
+        clock.advance(LEASE_TIME as u64 / 8);
+        assert_eq!(pool.client_state(&key), ClientState::Init);
+
+        // A renewal starts the timers again
+        let ip_addr = bind(&mut pool, 1);
+        clock.advance(LEASE_TIME as u64 * 7 / 8);
+        pool.renew(&key, &mac_address, &ip_addr, LEASE_TIME)
+            .unwrap();
+        assert_eq!(pool.client_state(&key), ClientState::Bound);
+    }
+
+    #[test]
+    fn eviction_takes_the_lease_that_expired_first() {
+        let (mut pool, clock) = pool(2);
+        let first = bind(&mut pool, 1);
+        clock.advance(60);
+        let second = bind(&mut pool, 2);
+
+        // Both have expired, neither is free until evicted
+        clock.advance(LEASE_TIME as u64);
+        let (key, mac_address) = client(3);
+        assert_eq!(pool.offer(&key, &mac_address, None, None).unwrap(), first);
+        let (key, mac_address) = client(4);
+        assert_eq!(pool.offer(&key, &mac_address, None, None).unwrap(), second);
+    }
+
+    #[test]
+    fn unexpired_leases_are_never_evicted() {
+        let (mut pool, clock) = pool(1);
+        let ip_addr = bind(&mut pool, 1);
+        let (key, mac_address) = client(2);
+
+        clock.advance(LEASE_TIME as u64 - 1);
+        assert!(pool.offer(&key, &mac_address, None, None).is_err());
+
+        clock.advance(1);
+        assert_eq!(pool.offer(&key, &mac_address, None, None).unwrap(), ip_addr);
+    }
+
+    #[test]
+    fn infinite_leases_never_expire() {
+        let (mut pool, clock) = pool(1);
+        pool.options_mut()
+            .set(DhcpOption::LeaseTime(INFINITE_LEASE_TIME));
+        bind(&mut pool, 1);
+        let (key, mac_address) = client(2);
+
+        // Long past when the lease would expire if it were u32::MAX seconds
+        clock.advance(200 * 365 * 24 * 60 * 60);
+        assert!(pool.offer(&key, &mac_address, None, None).is_err());
+        assert_eq!(pool.leases()[0].expires, leases::never());
+    }
+
+    #[test]
+    fn evicted_clients_get_their_address_back_when_it_is_free() {
+        let (mut pool, clock) = pool(2);
+        bind(&mut pool, 1);
+        let second = bind(&mut pool, 2);
+        clock.advance(LEASE_TIME as u64);
+
+        // Newcomers evict both, once their offers lapse the second client
+        // is remembered and gets its own address rather than the first free
+        for newcomer in [3, 4] {
+            let (key, mac_address) = client(newcomer);
+            pool.offer(&key, &mac_address, None, None).unwrap();
+        }
+        clock.advance(OFFER_TIMEOUT as u64);
+        let (key, mac_address) = client(2);
+        assert_eq!(pool.offer(&key, &mac_address, None, None).unwrap(), second);
+    }
+
+    #[test]
+    fn unknown_clients_are_quarantined_until_allowed() {
+        let (mut pool, _) = pool(2);
+        let quarantine = (
+            Ipv4Addr::new(192, 168, 1, 100),
+            Ipv4Addr::new(192, 168, 1, 100),
+        );
+        let mut options = DhcpOptionList::builder();
+        options.set(DhcpOption::LeaseTime(60));
+        pool.set_quarantine(&[quarantine], &[client(1).1], options)
+            .unwrap();
+
+        assert_eq!(bind(&mut pool, 1), Ipv4Addr::new(192, 168, 1, 10));
+        let (key, mac_address) = client(2);
+        let ip_addr = bind(&mut pool, 2);
+        assert_eq!(ip_addr, quarantine.0);
+        assert_eq!(pool.lease_time(&mac_address, ClientClass::default()), 60);
+
+        // Once registered the client must move out of the quarantine
+        let (mut new, _) = self::pool(2);
+        new.set_quarantine(
+            &[quarantine],
+            &[client(1).1, mac_address],
+            DhcpOptionList::builder(),
+        )
+        .unwrap();
+        pool.reconfigure(new).unwrap();
+        assert!(pool
+            .renew(&key, &mac_address, &ip_addr, LEASE_TIME)
+            .is_none());
+        // Nor may it keep the address by asking for it in INIT-REBOOT
+        assert!(pool
+            .commit(&key, &mac_address, &ip_addr, LEASE_TIME)
+            .is_none());
+        assert_eq!(
+            pool.refusal_reason(&key, &mac_address, &ip_addr),
+            "client is now registered"
+        );
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            Ipv4Addr::new(192, 168, 1, 11)
+        );
+    }
+
+    #[test]
+    fn circuits_hold_at_most_their_limit() {
+        let (mut pool, clock) = pool(4);
+        pool.set_max_leases_per_circuit(1);
+        let (port1, port2) = (Some(&b"port1"[..]), Some(&b"port2"[..]));
+        let (key, mac_address) = client(1);
+        let ip_addr = pool.offer(&key, &mac_address, None, port1).unwrap();
+        pool.commit(&key, &mac_address, &ip_addr, LEASE_TIME)
+            .unwrap();
+
+        // The client holding the lease is not held back by it
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, port1).unwrap(),
+            ip_addr
+        );
+        let (key, mac_address) = client(2);
+        assert!(pool.offer(&key, &mac_address, None, port1).is_err());
+        assert!(pool.offer(&key, &mac_address, None, port2).is_ok());
+
+        clock.advance(LEASE_TIME as u64);
+        let (key, mac_address) = client(3);
+        assert!(pool.offer(&key, &mac_address, None, port1).is_ok());
+    }
+
+    #[test]
+    fn uuid_reservations_follow_the_nic_the_machine_boots_from() {
+        let (mut pool, _clock) = pool(4);
+        let uuid: Uuid = "4c4c4544-0042-3510-8052-b4c04f385331".parse().unwrap();
+        let reserved = Ipv4Addr::new(192, 168, 1, 12);
+        pool.add_uuid_reservation(uuid, reserved).unwrap();
+
+        // Nobody gets it before the machine says who it is
+        let (key, mac_address) = client(1);
+        assert_ne!(
+            pool.offer(&key, &mac_address, Some(reserved), None)
+                .unwrap(),
+            reserved
+        );
+
+        let (key, mac_address) = client(2);
+        pool.bind_uuid(&uuid, &mac_address);
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            reserved
+        );
+
+        // Booting from another NIC moves the reservation
+        let (key, mac_address) = client(3);
+        pool.bind_uuid(&uuid, &mac_address);
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            reserved
+        );
+        let (key, mac_address) = client(2);
+        assert_ne!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            reserved
+        );
+    }
+
+    #[test]
+    fn relay_reservations_stay_with_the_port_when_the_hardware_changes() {
+        let (mut pool, _clock) = pool(4);
+        let relay_id: RelayId = "circuit-id:eth0/1/3".parse().unwrap();
+        assert_eq!(relay_id.to_string(), "circuit-id:eth0/1/3");
+        let remote_id: RelayId = "remote-id:0x020000000001".parse().unwrap();
+        assert_eq!(remote_id, RelayId::RemoteId(vec![2, 0, 0, 0, 0, 1]));
+        assert_eq!(remote_id.to_string(), "remote-id:0x020000000001");
+        let reserved = Ipv4Addr::new(192, 168, 1, 12);
+        pool.add_relay_reservation(relay_id, reserved).unwrap();
+        assert!(pool.add_relay_reservation(remote_id, reserved).is_err());
+
+        let on_port = [&[1, 8][..], b"eth0/1/3"].concat();
+        let on_port = RelayAgentInfo::try_from(&on_port[..]).unwrap();
+        let elsewhere = [&[1, 8][..], b"eth0/1/4"].concat();
+        let elsewhere = RelayAgentInfo::try_from(&elsewhere[..]).unwrap();
+
+        let (key, mac_address) = client(1);
+        pool.bind_relay_agent_info(&elsewhere, &mac_address);
+        assert_ne!(
+            pool.offer(&key, &mac_address, Some(reserved), None)
+                .unwrap(),
+            reserved
+        );
+
+        let (key, mac_address) = client(2);
+        pool.bind_relay_agent_info(&on_port, &mac_address);
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            reserved
+        );
+
+        // New CPE on the same port takes the address over
+        let (key, mac_address) = client(3);
+        pool.bind_relay_agent_info(&on_port, &mac_address);
+        assert_eq!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            reserved
+        );
+        let (key, mac_address) = client(2);
+        assert_ne!(
+            pool.offer(&key, &mac_address, None, None).unwrap(),
+            reserved
+        );
+    }
+
+    #[test]
+    fn hash_allocation_gives_clients_the_same_address_on_any_server() {
+        let (mut first, _clock) = pool(16);
+        let (mut second, _clock) = pool(16);
+        first.set_allocation(Allocation::Hash);
+        second.set_allocation(Allocation::Hash);
+
+        // Whatever order they turn up in
+        let (a, b) = (bind(&mut first, 1), bind(&mut first, 2));
+        assert_eq!(bind(&mut second, 2), b);
+        assert_eq!(bind(&mut second, 1), a);
+        assert_ne!(a, Ipv4Addr::new(192, 168, 1, 10));
+    }
+
+    #[test]
+    fn load_sharing_only_delays_the_other_servers_half() {
+        let sharing = LoadSharing {
+            delay: Duration::from_millis(500),
+            parity: Some(Parity::Odd),
+            percent: 100,
+        };
+        let parities: Vec<Parity> = (0..8)
+            .map(|last_octet| {
+                let (_, mac_address) = client(last_octet);
+                let parity = Parity::of(&mac_address);
+                assert_eq!(
+                    sharing.delay_for(&mac_address).is_some(),
+                    parity == Parity::Odd
+                );
+                parity
+            })
+            .collect();
+        assert!(parities.contains(&Parity::Even) && parities.contains(&Parity::Odd));
+        assert!(sharing.answers());
+    }
+
+    #[test]
+    fn the_last_addresses_are_kept_for_clients_that_have_waited() {
+        let (mut pool, _clock) = pool(4);
+        pool.set_waiting(Waiting {
+            secs: 4,
+            reserve: 1,
+        });
+        for last_octet in 1..=3 {
+            let (key, mac_address) = client(last_octet);
+            assert!(!pool.is_held_back(&key, &mac_address));
+            bind(&mut pool, last_octet);
+        }
+
+        let (key, mac_address) = client(4);
+        assert!(pool.is_held_back(&key, &mac_address));
+        assert!(!pool.is_waiting(3) && pool.is_waiting(4));
+        // Clients that already hold an address are never held back
+        let (key, mac_address) = client(1);
+        assert!(!pool.is_held_back(&key, &mac_address));
+    }
+
+    #[test]
+    fn hostnames_are_cleaned_up_and_kept_unique() {
+        let (mut pool, _clock) = pool(4);
+        let ips: Vec<Ipv4Addr> = (1..=4)
+            .map(|last_octet| bind(&mut pool, last_octet))
+            .collect();
+        let hostname = |pool: &AddrPool, ip_addr| {
+            pool.entries()
+                .into_iter()
+                .find(|entry| entry.ip_addr == ip_addr)
+                .and_then(|entry| entry.hostname)
+        };
+
+        pool.set_hostname(&ips[0], "Jo's iPhone");
+        assert_eq!(hostname(&pool, ips[0]).as_deref(), Some("Jo-s-iPhone"));
+        pool.set_hostname(&ips[1], "jo-s-iphone");
+        assert_eq!(hostname(&pool, ips[1]).as_deref(), Some("jo-s-iphone-2"));
+        // Renewing keeps the name it was given
+        pool.set_hostname(&ips[1], "jo-s-iphone");
+        assert_eq!(hostname(&pool, ips[1]).as_deref(), Some("jo-s-iphone-2"));
+
+        pool.set_hostname_conflict(HostnameConflict::Mac);
+        pool.set_hostname(&ips[2], "JO-S-IPHONE");
+        assert_eq!(
+            hostname(&pool, ips[2]).as_deref(),
+            Some("JO-S-IPHONE-000003")
+        );
+
+        pool.set_hostname_conflict(HostnameConflict::Refuse);
+        pool.set_hostname(&ips[3], "jo-s-iphone");
+        assert_eq!(hostname(&pool, ips[3]), None);
+        pool.set_hostname(&ips[3], "___");
+        assert_eq!(hostname(&pool, ips[3]), None);
+    }
+
+    #[test]
+    fn leases_shorten_as_the_pool_fills() {
+        let (mut pool, _clock) = pool(10);
+        pool.set_adaptive_lease(AdaptiveLease { above: 50, min: 60 });
+        let (_, mac_address) = client(10);
+        let lease_time = |pool: &AddrPool| pool.lease_time(&mac_address, ClientClass::default());
+
+        for last_octet in 1..=5 {
+            bind(&mut pool, last_octet);
+        }
+        assert_eq!(lease_time(&pool), LEASE_TIME);
+        for last_octet in 6..=9 {
+            bind(&mut pool, last_octet);
+        }
+        // 90% in use is 80% of the way from LEASE_TIME to min
+        assert_eq!(lease_time(&pool), 168);
+        bind(&mut pool, 10);
+        assert_eq!(lease_time(&pool), 60);
+
+        pool.add_reservation(mac_address, Ipv4Addr::new(192, 168, 1, 19))
+            .unwrap();
+        assert_eq!(lease_time(&pool), LEASE_TIME);
+    }
+
+    #[test]
+    fn restoring_leases_drops_those_the_config_no_longer_allows() {
+        let (mut pool, clock) = pool(6);
+        let (_, reserved_for) = client(9);
+        pool.add_reservation(reserved_for, Ipv4Addr::new(192, 168, 1, 13))
+            .unwrap();
+        let uuid: Uuid = "4c4c4544-0042-3510-8052-b4c04f385331".parse().unwrap();
+        pool.add_uuid_reservation(uuid, Ipv4Addr::new(192, 168, 1, 14))
+            .unwrap();
+        let relay_id: RelayId = "circuit-id:eth0/1/3".parse().unwrap();
+        pool.add_relay_reservation(relay_id, Ipv4Addr::new(192, 168, 1, 15))
+            .unwrap();
+        let lease = |last_octet, ip_addr: [u8; 4], secs| {
+            let (key, mac_address) = client(last_octet);
+            Lease {
+                ip_addr: ip_addr.into(),
+                expires: clock.now() + Duration::from_secs(secs),
+                mac_address,
+                key,
+            }
+        };
+
+        let report = pool.restore(vec![
+            lease(1, [192, 168, 1, 10], 100),
+            // Same address for longer, it wins wherever it is in the file
+            lease(2, [192, 168, 1, 10], 200),
+            // Client 2 again, for less time
+            lease(2, [192, 168, 1, 11], 50),
+            lease(3, [192, 168, 1, 12], 0),
+            lease(4, [192, 168, 1, 13], 100),
+            // Reserved by UUID and by relay circuit, for whoever boots there
+            lease(7, [192, 168, 1, 14], 100),
+            lease(8, [192, 168, 1, 15], 100),
+            lease(5, [10, 0, 0, 1], 100),
+            // The range shrank, so it goes once it expires
+            lease(6, [192, 168, 1, 50], 100),
+        ]);
+        assert_eq!(
+            report,
+            RestoreReport {
+                restored: 2,
+                retiring: 1,
+                expired: 1,
+                outside_subnet: 1,
+                reserved: 3,
+                duplicates: 2,
+            }
+        );
+        let (key, _) = client(2);
+        assert_eq!(
+            pool.lookup_client(&key),
+            Some(Ipv4Addr::new(192, 168, 1, 10))
+        );
     }
 }
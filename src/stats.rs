@@ -0,0 +1,239 @@
+//! Counters for how busy we are and how quickly we answer. They are atomics
+//! so the request path never waits on the admin API reading them, apart from
+//! lease churn by the hour which is only counted under the pool lock anyway
+
+use crate::audit::AuditEvent;
+use crate::types::MessageType;
+use crate::Error;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bounds of the latency buckets in microseconds, anything slower goes
+/// in one more bucket on the end
+pub const LATENCY_BUCKETS: [u64; 10] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000,
+];
+
+/// Why a request could not be parsed, in the order we count them
+pub const PARSE_FAILURES: [&str; 8] = [
+    "too_short",
+    "not_a_request",
+    "no_magic",
+    "bad_hw_addr",
+    "no_client_id",
+    "no_message_type",
+    "too_many_hops",
+    "bad_option",
+];
+
+/// One counter per message type, indexed by its code
+static RECEIVED: [AtomicU64; 10] = [const { AtomicU64::new(0) }; 10];
+
+static PARSE_ERRORS: [AtomicU64; PARSE_FAILURES.len()] =
+    [const { AtomicU64::new(0) }; PARSE_FAILURES.len()];
+
+static LATENCY: [AtomicU64; LATENCY_BUCKETS.len() + 1] =
+    [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1];
+
+/// Total of every latency we recorded, in microseconds
+static LATENCY_SUM: AtomicU64 = AtomicU64::new(0);
+
+/// How many hours of lease churn we keep
+pub const CHURN_HOURS: usize = 24;
+
+const HOUR: u64 = 3600;
+
+/// Lease events by [AuditEvent::ALL] since we started
+static LEASES: [AtomicU64; AuditEvent::ALL.len()] =
+    [const { AtomicU64::new(0) }; AuditEvent::ALL.len()];
+
+/// Lease events by [AuditEvent::ALL] for each hour since the epoch that had
+/// any, the last [CHURN_HOURS] of them oldest first
+static CHURN: Mutex<VecDeque<(u64, [u64; AuditEvent::ALL.len()])>> = Mutex::new(VecDeque::new());
+
+/// Leases handed to the journal writer it has not synced yet, see
+/// [crate::store::JournalStore]
+static JOURNAL_PENDING: AtomicU64 = AtomicU64::new(0);
+
+static JOURNAL_SYNCS: AtomicU64 = AtomicU64::new(0);
+
+/// How long the oldest lease of the last sync waited to be on disk, or has
+/// waited so far while syncs fail, in microseconds
+static JOURNAL_LAG: AtomicU64 = AtomicU64::new(0);
+
+/// A copy of the counters at one moment
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Requests by message type, those we have seen at least once
+    pub received: Vec<(MessageType, u64)>,
+    /// Requests we dropped, by [PARSE_FAILURES]
+    pub parse_errors: Vec<(&'static str, u64)>,
+    /// Requests answered within each of [LATENCY_BUCKETS], and slower
+    pub latency: Vec<u64>,
+    pub latency_sum: Duration,
+    /// Lease events by [AuditEvent::ALL]
+    pub leases: [u64; AuditEvent::ALL.len()],
+    /// The same for each of the last [CHURN_HOURS] hours that had any, by
+    /// the start of the hour
+    pub churn: Vec<(SystemTime, [u64; AuditEvent::ALL.len()])>,
+    /// Leases waiting for the journal writer
+    pub journal_pending: u64,
+    pub journal_syncs: u64,
+    /// How far behind the journal writer was at its last sync, or is while
+    /// it cannot sync
+    pub journal_lag: Duration,
+}
+
+/// Count a request we parsed
+pub fn record_received(message_type: MessageType) {
+    if let Some(counter) = RECEIVED.get(message_type.code() as usize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a request we could not parse
+pub fn record_parse_error(error: &Error) {
+    let index = match error {
+        Error::PayloadTooShort(_) => 0,
+        Error::NotADhcpRequest(_) => 1,
+        Error::DhcpMagicMissing => 2,
+        Error::InvalidHwAddrLen(..) => 3,
+        Error::MissingClientIdentifier(_) => 4,
+        Error::NoMessageDhcpTypeProvided => 5,
+        Error::TooManyHops(_) => 6,
+        _ => 7,
+    };
+    PARSE_ERRORS[index].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record how long we took to handle a request
+pub fn record_latency(latency: Duration) {
+    let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+    let bucket = LATENCY_BUCKETS
+        .iter()
+        .position(|bound| micros <= *bound)
+        .unwrap_or(LATENCY_BUCKETS.len());
+    LATENCY[bucket].fetch_add(1, Ordering::Relaxed);
+    LATENCY_SUM.fetch_add(micros, Ordering::Relaxed);
+}
+
+/// Count a lease bound, renewed or reclaimed at `time`
+pub fn record_lease(event: AuditEvent, time: SystemTime) {
+    LEASES[event as usize].fetch_add(1, Ordering::Relaxed);
+
+    let hour = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / HOUR;
+    let mut churn = CHURN.lock().unwrap();
+    match churn.iter_mut().rev().find(|(start, _)| *start == hour) {
+        Some((_, counts)) => counts[event as usize] += 1,
+        None => {
+            let mut counts = [0; AuditEvent::ALL.len()];
+            counts[event as usize] = 1;
+            churn.push_back((hour, counts));
+            churn.make_contiguous().sort_by_key(|(start, _)| *start);
+            // Drop the hours that are more than a day before the latest
+            let latest = churn.back().map_or(hour, |(start, _)| *start);
+            churn.retain(|(start, _)| start + (CHURN_HOURS as u64) > latest);
+        }
+    }
+}
+
+/// Count a lease queued for the journal writer
+pub fn record_journal_queued() {
+    JOURNAL_PENDING.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a journal sync of `leases`, the oldest queued `lag` ago
+pub fn record_journal_flush(leases: usize, lag: Duration) {
+    JOURNAL_PENDING.fetch_sub(leases as u64, Ordering::Relaxed);
+    JOURNAL_SYNCS.fetch_add(1, Ordering::Relaxed);
+    let micros = u64::try_from(lag.as_micros()).unwrap_or(u64::MAX);
+    JOURNAL_LAG.store(micros, Ordering::Relaxed);
+}
+
+/// Record a journal sync that failed, the oldest lease still waiting queued
+/// `lag` ago
+pub fn record_journal_failure(lag: Duration) {
+    let micros = u64::try_from(lag.as_micros()).unwrap_or(u64::MAX);
+    JOURNAL_LAG.store(micros, Ordering::Relaxed);
+}
+
+/// Read every counter, they are not read at the same instant so may be a
+/// request or two apart
+pub fn snapshot() -> Snapshot {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let received = RECEIVED
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(code, counter)| (MessageType::from(code as u8), load(counter)))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+
+    Snapshot {
+        received,
+        parse_errors: PARSE_FAILURES
+            .iter()
+            .copied()
+            .zip(PARSE_ERRORS.iter().map(load))
+            .collect(),
+        latency: LATENCY.iter().map(load).collect(),
+        latency_sum: Duration::from_micros(load(&LATENCY_SUM)),
+        leases: LEASES.each_ref().map(load),
+        churn: CHURN
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hour, counts)| (UNIX_EPOCH + Duration::from_secs(hour * HOUR), *counts))
+            .collect(),
+        journal_pending: load(&JOURNAL_PENDING),
+        journal_syncs: load(&JOURNAL_SYNCS),
+        journal_lag: Duration::from_micros(load(&JOURNAL_LAG)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The counters are shared by every test in the binary, so only look at
+    // how much they grew
+
+    #[test]
+    fn requests_and_parse_failures_are_counted_by_kind() {
+        let count = |snapshot: &Snapshot, message_type| {
+            snapshot
+                .received
+                .iter()
+                .find(|(counted, _)| *counted == message_type)
+                .map_or(0, |(_, count)| *count)
+        };
+        let before = snapshot();
+        record_received(MessageType::Decline);
+        record_received(MessageType::Decline);
+        record_parse_error(&Error::DhcpMagicMissing);
+        let after = snapshot();
+
+        assert!(count(&after, MessageType::Decline) >= count(&before, MessageType::Decline) + 2);
+        assert_eq!(after.parse_errors[2].0, "no_magic");
+        assert!(after.parse_errors[2].1 > before.parse_errors[2].1);
+    }
+
+    #[test]
+    fn latencies_land_in_the_first_bucket_that_holds_them() {
+        let before = snapshot();
+        record_latency(Duration::from_micros(250));
+        record_latency(Duration::from_secs(1));
+        let after = snapshot();
+
+        assert_eq!(after.latency.len(), LATENCY_BUCKETS.len() + 1);
+        assert!(after.latency[1] > before.latency[1]);
+        assert!(after.latency[LATENCY_BUCKETS.len()] > before.latency[LATENCY_BUCKETS.len()]);
+        assert!(after.latency_sum - before.latency_sum >= Duration::from_micros(1_000_250));
+    }
+}
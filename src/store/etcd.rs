@@ -0,0 +1,215 @@
+//! Leases in etcd, for clusters where leases must survive losing a node. Each
+//! address is one key, `<prefix>/lease/<ip>`, holding the lease as a line of
+//! the lease file. A claim is a transaction that only writes the key when it
+//! is unchanged since we read it, so of two servers offering the same address
+//! at once only one gets it. Expired leases stay in etcd until their address
+//! is claimed again.
+//!
+//! `lease-store = etcd://host[:port][,host[:port]...][/prefix]`, the prefix
+//! is `dhc3po` when not given
+
+use super::{Claim, LeaseStore};
+use crate::etcd::{Etcd, KeyValue, Put};
+use crate::leases::Lease;
+use crate::{Error, Result};
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Instant, SystemTime};
+
+const DEFAULT_PREFIX: &str = "dhc3po";
+
+/// How many times we try to claim an address other servers keep changing
+const CLAIM_ATTEMPTS: usize = 3;
+
+#[derive(Debug)]
+pub struct EtcdStore {
+    etcd: Etcd,
+    /// Every key we own starts with this
+    prefix: String,
+}
+
+impl EtcdStore {
+    /// Parse an `etcd://` URL, we connect when first used
+    pub fn parse(url: &str) -> Option<Self> {
+        let (etcd, prefix) = Etcd::parse(url)?;
+        let prefix = match prefix {
+            "" => DEFAULT_PREFIX,
+            prefix => prefix,
+        };
+        Some(Self {
+            etcd,
+            prefix: format!("{prefix}/lease/"),
+        })
+    }
+
+    fn key(&self, ip_addr: Ipv4Addr) -> String {
+        format!("{}{ip_addr}", self.prefix)
+    }
+}
+
+impl LeaseStore for EtcdStore {
+    fn load(&mut self) -> Result<Vec<Lease>> {
+        let now = SystemTime::now();
+        let kvs = self
+            .etcd
+            .get_prefix(&self.prefix)
+            .map_err(Error::LeaseStore)?;
+        let leases = kvs.iter().map(decode).collect::<Result<Vec<_>>>()?;
+        Ok(leases
+            .into_iter()
+            .filter(|lease| lease.expires > now)
+            .collect())
+    }
+
+    fn claim(&mut self, offer: &Lease, deadline: Instant) -> Result<Claim> {
+        let key = self.key(offer.ip_addr);
+        let value = offer.encode();
+        // Create the key, or take it over when the lease in it ran out or is
+        // this client's, so long as nobody changes it in between
+        let mut mod_revision = 0;
+        for _ in 0..CLAIM_ATTEMPTS {
+            if Instant::now() >= deadline {
+                return Err(Error::LeaseStore(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("ran out of time claiming {key}"),
+                )));
+            }
+            let put = self
+                .etcd
+                .put_if(&key, value.as_bytes(), mod_revision)
+                .map_err(Error::LeaseStore)?;
+            match put {
+                Put::Done => return Ok(Claim::Granted),
+                Put::Conflict(None) => mod_revision = 0,
+                Put::Conflict(Some(held)) => {
+                    let lease = decode(&held)?;
+                    if lease.key != offer.key && lease.expires > SystemTime::now() {
+                        return Ok(Claim::Held(lease));
+                    }
+                    mod_revision = held.mod_revision;
+                }
+            }
+        }
+        Err(Error::LeaseStore(io::Error::other(format!(
+            "{key} kept changing while we claimed it"
+        ))))
+    }
+
+    fn save(&mut self, lease: &Lease) -> Result<()> {
+        let key = self.key(lease.ip_addr);
+        self.etcd
+            .put(&key, lease.encode().as_bytes())
+            .map_err(Error::LeaseStore)
+    }
+}
+
+fn decode(kv: &KeyValue) -> Result<Lease> {
+    let text = String::from_utf8_lossy(&kv.value);
+    Lease::decode(&text).ok_or_else(|| {
+        Error::LeaseStore(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} holds {text:?}, not a lease", kv.key),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::etcd::base64;
+    use crate::json;
+    use crate::types::{ClientKey, MacAddr};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Answer each request with the next of `replies`, returning the bodies
+    /// of the requests
+    fn fake_etcd(replies: Vec<String>) -> (String, thread::JoinHandle<Vec<json::Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("etcd://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in replies {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut length = 0;
+                let mut line = String::new();
+                while stream.read_line(&mut line).unwrap() > 2 {
+                    if let Some(value) = line.strip_prefix("Content-Length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0u8; length];
+                stream.read_exact(&mut body).unwrap();
+                requests.push(json::parse(std::str::from_utf8(&body).unwrap()).unwrap());
+                write!(
+                    stream.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{reply}",
+                    reply.len()
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    /// Whole seconds, as the lease file keeps them
+    fn later() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(4_000_000_000)
+    }
+
+    fn lease(last_octet: u8, expires: SystemTime) -> Lease {
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, last_octet]);
+        Lease {
+            ip_addr: Ipv4Addr::new(192, 168, 1, 10),
+            expires,
+            mac_address,
+            key: ClientKey::Mac(mac_address),
+        }
+    }
+
+    /// A failed transaction whose key holds `lease`
+    fn conflict(lease: &Lease, mod_revision: i64) -> String {
+        let kv = format!(
+            "{{\"key\": {}, \"value\": {}, \"mod_revision\": \"{mod_revision}\"}}",
+            json::string(&base64(b"dhc3po/lease/192.168.1.10")),
+            json::string(&base64(lease.encode().as_bytes()))
+        );
+        format!("{{\"responses\": [{{\"response_range\": {{\"kvs\": [{kv}]}}}}]}}")
+    }
+
+    fn mod_revision(request: &json::Value) -> Option<i64> {
+        request.get("compare")?.items()[0]
+            .get("mod_revision")
+            .and_then(json::Value::as_i64)
+    }
+
+    #[test]
+    fn claims_on_keys_of_other_clients_are_held() {
+        let held = lease(2, later());
+        let (url, server) = fake_etcd(vec![conflict(&held, 7)]);
+        let mut store = EtcdStore::parse(&url).unwrap();
+
+        let claim = store.claim(&lease(1, later()), Instant::now() + Duration::from_secs(5));
+        assert_eq!(claim.unwrap(), Claim::Held(held));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn expired_leases_are_taken_over_at_their_revision() {
+        let expired = lease(2, SystemTime::now() - Duration::from_secs(1));
+        let replies = vec![conflict(&expired, 7), "{\"succeeded\": true}".to_owned()];
+        let (url, server) = fake_etcd(replies);
+        let mut store = EtcdStore::parse(&url).unwrap();
+
+        let claim = store.claim(&lease(1, later()), Instant::now() + Duration::from_secs(5));
+        assert_eq!(claim.unwrap(), Claim::Granted);
+        let requests = server.join().unwrap();
+        assert_eq!(mod_revision(&requests[0]), None);
+        assert_eq!(mod_revision(&requests[1]), Some(7));
+    }
+}
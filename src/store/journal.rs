@@ -0,0 +1,284 @@
+//! The lease file behind a write-ahead journal, for busy servers where
+//! rewriting the whole file on every bind would hold up replies. Leases are
+//! handed to a writer thread which appends them to `<lease-file>.journal`,
+//! syncing everything that queued up while the last sync ran in one go, and
+//! folds the journal into the lease file every [COMPACT_EVERY] leases. On
+//! start we replay whatever journal a crash left behind.
+//!
+//! A lease is acknowledged before it is on disk, so a crash loses the last
+//! few milliseconds of binds rather than holding every reply up on a sync.
+//! A clean stop waits for the writer to finish. When an append fails we keep
+//! the leases and try again, and the journal lag in the stats keeps growing
+//! until it works
+
+use log::{error, info, warn};
+
+use super::{FileStore, LeaseStore};
+use crate::leases::{self, Lease};
+use crate::{stats, Error, Result};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Journal entries between rewrites of the lease file
+const COMPACT_EVERY: usize = 1000;
+
+/// How long we wait before trying a failed append again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct JournalStore {
+    path: PathBuf,
+    /// Starts once we have loaded
+    writer: Option<Writer>,
+}
+
+/// The thread appending to the journal
+#[derive(Debug)]
+struct Writer {
+    sender: Sender<(Instant, Lease)>,
+    handle: JoinHandle<()>,
+}
+
+impl JournalStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, writer: None }
+    }
+
+    fn journal_path(path: &Path) -> PathBuf {
+        path.with_extension("journal")
+    }
+}
+
+impl LeaseStore for JournalStore {
+    /// The lease file with the journal replayed over it, which we then fold
+    /// in so the journal starts empty
+    fn load(&mut self) -> Result<Vec<Lease>> {
+        let mut held: BTreeMap<Ipv4Addr, Lease> = leases::load(&self.path)?
+            .into_iter()
+            .map(|lease| (lease.ip_addr, lease))
+            .collect();
+
+        let journal_path = Self::journal_path(&self.path);
+        let journal = match std::fs::read_to_string(&journal_path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(Error::CannotReadLeases(error)),
+        };
+        let mut replayed = 0;
+        for line in journal.lines().filter(|line| !line.trim().is_empty()) {
+            match Lease::decode(line) {
+                Some(lease) => {
+                    FileStore::merge(&mut held, lease);
+                    replayed += 1;
+                }
+                // Only the line being written when we stopped can be torn
+                None => warn!("Skipping a torn line in the lease journal: {line:?}"),
+            }
+        }
+        if replayed > 0 {
+            info!("Replayed {replayed} leases from the lease journal");
+        }
+
+        compact(&self.path, &journal_path, &held)?;
+        let leases = held.values().cloned().collect();
+        let (sender, receiver) = mpsc::channel();
+        let path = self.path.clone();
+        let handle = thread::spawn(move || write(&path, &journal_path, held, receiver));
+        self.writer = Some(Writer { sender, handle });
+        Ok(leases)
+    }
+
+    fn save(&mut self, lease: &Lease) -> Result<()> {
+        if self.writer.is_none() {
+            self.load()?;
+        }
+        let Some(writer) = &self.writer else {
+            unreachable!("loading starts the writer")
+        };
+        stats::record_journal_queued();
+        writer
+            .sender
+            .send((Instant::now(), lease.clone()))
+            .map_err(|_| Error::CannotWriteLeases(std::io::ErrorKind::BrokenPipe.into()))
+    }
+
+    /// Hang up on the writer so it drains the queue and stops, then wait
+    fn close(&mut self) {
+        if let Some(Writer { sender, handle }) = self.writer.take() {
+            drop(sender);
+            if handle.join().is_err() {
+                error!("The lease journal writer panicked");
+            }
+        }
+    }
+}
+
+impl Drop for JournalStore {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Rewrite the lease file with `held` and empty the journal
+fn compact(path: &Path, journal_path: &Path, held: &BTreeMap<Ipv4Addr, Lease>) -> Result<()> {
+    let leases: Vec<Lease> = held.values().cloned().collect();
+    leases::save(path, &leases)?;
+    File::create(journal_path)
+        .and_then(|file| file.sync_all())
+        .map_err(Error::CannotWriteLeases)
+}
+
+/// The writer thread, until the store is closed
+fn write(
+    path: &Path,
+    journal_path: &Path,
+    mut held: BTreeMap<Ipv4Addr, Lease>,
+    receiver: Receiver<(Instant, Lease)>,
+) {
+    let mut journaled = 0;
+    // Leases to append, oldest first, kept until an append works
+    let mut batch: Vec<(Instant, Lease)> = Vec::new();
+    let mut open = true;
+    while open || !batch.is_empty() {
+        let next = match batch.is_empty() {
+            true => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            false => receiver.recv_timeout(RETRY_INTERVAL),
+        };
+        match next {
+            Ok(lease) => batch.push(lease),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => open = false,
+        }
+        // Whatever queued while we were syncing goes in the same sync
+        batch.extend(receiver.try_iter());
+        let Some((oldest, _)) = batch.first() else {
+            continue;
+        };
+        let lag = oldest.elapsed();
+
+        if let Err(error) = append(journal_path, &batch) {
+            error!("{}", Error::CannotWriteLeases(error));
+            stats::record_journal_failure(lag);
+            if !open {
+                error!("Stopping with {} leases not in the journal", batch.len());
+                return;
+            }
+            continue;
+        }
+        stats::record_journal_flush(batch.len(), lag);
+
+        journaled += batch.len();
+        for (_, lease) in batch.drain(..) {
+            FileStore::merge(&mut held, lease);
+        }
+        if journaled >= COMPACT_EVERY {
+            match compact(path, journal_path, &held) {
+                Ok(()) => journaled = 0,
+                Err(error) => error!("{error}"),
+            }
+        }
+    }
+}
+
+/// Append `batch` to the journal and sync it
+fn append(journal_path: &Path, batch: &[(Instant, Lease)]) -> std::io::Result<()> {
+    let text: String = batch
+        .iter()
+        .map(|(_, lease)| lease.encode() + "\n")
+        .collect();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal_path)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ClientKey, MacAddr};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn lease(last_octet: u8) -> Lease {
+        let mac_address = MacAddr::new([2, 0, 0, 0, 0, last_octet]);
+        Lease {
+            ip_addr: Ipv4Addr::new(192, 168, 1, last_octet),
+            expires: UNIX_EPOCH + Duration::from_secs(4_000_000_000),
+            mac_address,
+            key: ClientKey::Mac(mac_address),
+        }
+    }
+
+    fn lease_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dhc3po-{name}-{}", std::process::id()))
+    }
+
+    fn remove(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(JournalStore::journal_path(path));
+    }
+
+    #[test]
+    fn a_torn_journal_is_replayed_up_to_the_tear() {
+        let path = lease_path("torn-journal");
+        leases::save(&path, &[lease(10)]).unwrap();
+        let journal = format!("{}\n{}\n", lease(11).encode(), lease(12).encode());
+        // The crash came halfway through the last line
+        let torn = &journal[..journal.len() - 10];
+        std::fs::write(JournalStore::journal_path(&path), torn).unwrap();
+
+        let mut store = JournalStore::new(path.clone());
+        assert_eq!(store.load().unwrap(), [lease(10), lease(11)]);
+        store.close();
+        // Folded into the lease file, the journal starts again empty
+        assert_eq!(leases::load(&path).unwrap(), [lease(10), lease(11)]);
+        let journal = std::fs::read_to_string(JournalStore::journal_path(&path)).unwrap();
+        assert_eq!(journal, "");
+        remove(&path);
+    }
+
+    #[test]
+    fn closing_waits_for_the_queue_to_be_written() {
+        let path = lease_path("close-journal");
+        let _ = std::fs::remove_file(&path);
+        let mut store = JournalStore::new(path.clone());
+        store.load().unwrap();
+        let leases: Vec<Lease> = (10..60).map(lease).collect();
+        for lease in &leases {
+            store.save(lease).unwrap();
+        }
+        store.close();
+
+        assert_eq!(JournalStore::new(path.clone()).load().unwrap(), leases);
+        remove(&path);
+    }
+
+    #[test]
+    fn failed_appends_are_kept_and_tried_again() {
+        let path = lease_path("failing-journal");
+        let _ = std::fs::remove_file(&path);
+        let mut store = JournalStore::new(path.clone());
+        store.load().unwrap();
+        // Nothing can be appended to a directory
+        let journal_path = JournalStore::journal_path(&path);
+        std::fs::remove_file(&journal_path).unwrap();
+        std::fs::create_dir(&journal_path).unwrap();
+
+        store.save(&lease(10)).unwrap();
+        thread::sleep(RETRY_INTERVAL / 2);
+        std::fs::remove_dir(&journal_path).unwrap();
+        thread::sleep(RETRY_INTERVAL);
+
+        let journal = std::fs::read_to_string(&journal_path).unwrap();
+        assert_eq!(journal, lease(10).encode() + "\n");
+        store.close();
+        remove(&path);
+    }
+}
@@ -0,0 +1,112 @@
+//! Where leases live beyond our memory. The lease file is all a single server
+//! needs, a shared store such as Redis or etcd lets several servers behind
+//! anycast or the same relays hand out one pool without handing out the same
+//! address twice: before we offer an address we claim it in the store, and
+//! only an address nobody else holds can be claimed
+
+mod etcd;
+mod journal;
+mod redis;
+
+pub use etcd::EtcdStore;
+pub use journal::JournalStore;
+pub use redis::RedisStore;
+
+use crate::leases::{self, Lease};
+use crate::Result;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+
+/// The store a `lease-store` URL points at, we connect when first used
+pub fn open(url: &str) -> Option<Box<dyn LeaseStore>> {
+    match url.split_once("://")?.0 {
+        "redis" => Some(Box::new(RedisStore::parse(url)?)),
+        "etcd" => Some(Box::new(EtcdStore::parse(url)?)),
+        _ => None,
+    }
+}
+
+/// `url` without any password in it, for logs
+pub fn redact(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) if rest.contains('@') => {
+            let host = rest.rsplit_once('@').map_or(rest, |(_, host)| host);
+            format!("{scheme}://***@{host}")
+        }
+        _ => url.to_owned(),
+    }
+}
+
+/// What became of a claim on an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The address is ours to offer until the claim runs out
+    Granted,
+
+    /// Another client holds it, through us or another server
+    Held(Lease),
+}
+
+/// Keeps our leases, see the module docs
+pub trait LeaseStore: std::fmt::Debug + Send {
+    /// Every lease in the store, to start with
+    fn load(&mut self) -> Result<Vec<Lease>>;
+
+    /// Hold the address of `offer` for its client until it expires, so long
+    /// as no other client holds it. Give up at `deadline`, the pool waits on
+    /// us. A store only we use has nobody to race
+    fn claim(&mut self, _offer: &Lease, _deadline: Instant) -> Result<Claim> {
+        Ok(Claim::Granted)
+    }
+
+    /// `lease` was bound or renewed
+    fn save(&mut self, lease: &Lease) -> Result<()>;
+
+    /// Finish writing anything still queued, we are stopping
+    fn close(&mut self) {}
+}
+
+/// The lease file, see [crate::leases]
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    /// What is in the file, which we rewrite as a whole
+    leases: BTreeMap<Ipv4Addr, Lease>,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            leases: BTreeMap::new(),
+        }
+    }
+
+    /// Add `lease` to what a lease file holds, a client holds one address
+    /// and expired leases are no use to anyone
+    fn merge(leases: &mut BTreeMap<Ipv4Addr, Lease>, lease: Lease) {
+        let now = SystemTime::now();
+        leases.retain(|_, held| held.key != lease.key && held.expires > now);
+        leases.insert(lease.ip_addr, lease);
+    }
+}
+
+impl LeaseStore for FileStore {
+    fn load(&mut self) -> Result<Vec<Lease>> {
+        let leases = leases::load(&self.path)?;
+        self.leases = leases
+            .iter()
+            .map(|lease| (lease.ip_addr, lease.clone()))
+            .collect();
+        Ok(leases)
+    }
+
+    fn save(&mut self, lease: &Lease) -> Result<()> {
+        Self::merge(&mut self.leases, lease.clone());
+
+        let leases: Vec<Lease> = self.leases.values().cloned().collect();
+        leases::save(&self.path, &leases)
+    }
+}
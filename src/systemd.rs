@@ -0,0 +1,130 @@
+//! Integration with systemd, socket activation and the sd_notify protocol
+//!
+//! When started from a `.socket` unit systemd binds port 67 for us and hands
+//! over the file descriptor, so the binary never needs
+//! `CAP_NET_BIND_SERVICE`. See `sd_listen_fds(3)` and `sd_notify(3)`.
+
+use log::{info, warn};
+use std::net::UdpSocket;
+use std::os::fd::FromRawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+/// The first file descriptor passed to us by systemd
+const SD_LISTEN_FDS_START: i32 = 3;
+/// The shortest interval we ping the watchdog at, however short its timeout
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// If systemd passed us a socket, take ownership of it
+pub fn listen_socket() -> Option<UdpSocket> {
+    let pid = std::env::var("LISTEN_PID").ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?;
+
+    // Make sure our children do not try and use the same sockets
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    // The variables were meant for another process
+    if pid.parse::<u32>().ok()? != std::process::id() {
+        return None;
+    }
+
+    match fds.parse::<i32>().ok()? {
+        0 => None,
+        fds => {
+            if fds > 1 {
+                warn!("systemd passed {fds} sockets, only using the first");
+            }
+            info!("Using socket passed by systemd");
+            // SAFETY: systemd guarantees fd 3 is an open socket owned by us
+            // and we only ever take it once as we cleared the environment
+            Some(unsafe { UdpSocket::from_raw_fd(SD_LISTEN_FDS_START) })
+        }
+    }
+}
+
+/// Send a state string such as `READY=1` to the service manager, does
+/// nothing if we were not started by systemd
+pub fn notify(state: &str) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    // Paths starting with @ live in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+
+    let result =
+        addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(error) = result {
+        warn!("Failed to notify systemd of {state:?}: {error}");
+    }
+}
+
+/// Keeps systemd's watchdog happy as long as our main loop keeps turning
+#[derive(Debug)]
+pub struct Watchdog {
+    interval: Duration,
+    last_kick: Instant,
+}
+
+impl Watchdog {
+    /// Returns [None] when the unit does not have `WatchdogSec=` set
+    pub fn from_env() -> Option<Self> {
+        if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok()? != std::process::id() {
+                return None;
+            }
+        }
+        let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+        Self::with_timeout(usec)
+    }
+
+    /// A watchdog that fires after `usec` microseconds, 0 means it is disabled
+    fn with_timeout(usec: u64) -> Option<Self> {
+        if usec == 0 {
+            return None;
+        }
+        // systemd recommends pinging at half the timeout, a zero read timeout
+        // is an error so we wake up at most every millisecond
+        Some(Self {
+            interval: Duration::from_micros(usec / 2).max(MIN_INTERVAL),
+            last_kick: Instant::now(),
+        })
+    }
+
+    /// How often we must wake up to ping the watchdog
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Ping the watchdog if we are due
+    pub fn kick(&mut self) {
+        if self.last_kick.elapsed() >= self.interval {
+            notify("WATCHDOG=1");
+            self.last_kick = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_of_zero_is_disabled() {
+        assert!(Watchdog::with_timeout(0).is_none());
+    }
+
+    #[test]
+    fn watchdog_interval_is_never_zero() {
+        let watchdog = Watchdog::with_timeout(1).unwrap();
+        assert_eq!(watchdog.interval(), MIN_INTERVAL);
+        let watchdog = Watchdog::with_timeout(30_000_000).unwrap();
+        assert_eq!(watchdog.interval(), Duration::from_secs(15));
+    }
+}
@@ -0,0 +1,261 @@
+//! OpenTelemetry traces of how long each stage of answering a request takes.
+//! With `otlp-endpoint` set every request becomes a trace, its root span runs
+//! from receiving the datagram to sending our reply with a child span for
+//! each stage: parse, classify, allocate, serialize and send. We batch the
+//! spans and POST them as OTLP/HTTP JSON, to an OpenTelemetry collector on
+//! `http://127.0.0.1:4318/v1/traces` for example.
+//!
+//! Without an endpoint none of this does anything.
+
+use crate::alerts::Webhook;
+use crate::json;
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often we send the spans we have collected
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spans waiting to be sent beyond this are dropped, oldest first, so a
+/// collector that is down cannot eat our memory
+const MAX_QUEUED_SPANS: usize = 10_000;
+
+/// OTLP span kinds
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+/// OTLP status code for a span that failed
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Set once we have somewhere to send spans
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Finished spans waiting for the exporter
+static QUEUE: Mutex<VecDeque<SpanData>> = Mutex::new(VecDeque::new());
+
+thread_local! {
+    /// The trace of the request this thread is answering
+    static CURRENT: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+/// A span that has ended
+#[derive(Debug)]
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    /// Why the span failed
+    error: Option<String>,
+}
+
+/// A request's trace, the root span is still open
+#[derive(Debug)]
+struct Trace {
+    root: SpanData,
+    spans: Vec<SpanData>,
+}
+
+/// Ends the current trace when dropped, see [trace]
+#[derive(Debug)]
+pub struct TraceGuard(());
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let Some(mut trace) = CURRENT.with(|current| current.borrow_mut().take()) else {
+            return;
+        };
+        trace.root.end = SystemTime::now();
+
+        let mut queue = QUEUE.lock().unwrap();
+        queue.push_back(trace.root);
+        queue.extend(trace.spans);
+        let excess = queue.len().saturating_sub(MAX_QUEUED_SPANS);
+        queue.drain(..excess);
+    }
+}
+
+/// Ends a stage of the current trace when dropped, see [span]
+#[derive(Debug)]
+pub struct Span {
+    name: &'static str,
+    start: SystemTime,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        CURRENT.with(|current| {
+            if let Some(trace) = current.borrow_mut().as_mut() {
+                let span = SpanData {
+                    trace_id: trace.root.trace_id,
+                    span_id: random_id(),
+                    parent_id: Some(trace.root.span_id),
+                    name: self.name,
+                    start: self.start,
+                    end: SystemTime::now(),
+                    attributes: Vec::new(),
+                    error: None,
+                };
+                trace.spans.push(span);
+            }
+        });
+    }
+}
+
+/// Start tracing the request this thread has just received, until the
+/// guard is dropped
+pub fn trace(name: &'static str) -> TraceGuard {
+    if ENABLED.load(Ordering::Relaxed) {
+        let root = SpanData {
+            trace_id: u128::from(random_id()) << 64 | u128::from(random_id()),
+            span_id: random_id(),
+            parent_id: None,
+            name,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        };
+        let trace = Trace {
+            root,
+            spans: Vec::new(),
+        };
+        CURRENT.with(|current| *current.borrow_mut() = Some(trace));
+    }
+    TraceGuard(())
+}
+
+/// Time a stage of the current request, until the span is dropped
+pub fn span(name: &'static str) -> Span {
+    Span {
+        name,
+        start: SystemTime::now(),
+    }
+}
+
+/// Describe the current request, e.g. with its message type
+pub fn set_attribute(key: &'static str, value: impl ToString) {
+    CURRENT.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            trace.root.attributes.push((key, value.to_string()));
+        }
+    });
+}
+
+/// Mark the current request as failed
+pub fn set_error(message: impl ToString) {
+    CURRENT.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            trace.root.error = Some(message.to_string());
+        }
+    });
+}
+
+/// Trace requests from now on and send the spans to `endpoint` in the
+/// background
+pub fn start(endpoint: Webhook) {
+    info!("Exporting request traces over OTLP");
+    ENABLED.store(true, Ordering::Relaxed);
+    thread::spawn(move || {
+        // Only complain when the collector goes away, not every interval
+        let mut failing = false;
+        loop {
+            thread::sleep(EXPORT_INTERVAL);
+            let spans: Vec<SpanData> = QUEUE.lock().unwrap().drain(..).collect();
+            if spans.is_empty() {
+                continue;
+            }
+            let result = endpoint.post(&export_json(&spans));
+            match result {
+                Ok(status) if status.split(' ').nth(1) == Some("200") => failing = false,
+                Ok(status) if !failing => {
+                    warn!(
+                        "Trace collector answered {status}, dropped {} spans",
+                        spans.len()
+                    );
+                    failing = true;
+                }
+                Err(error) if !failing => {
+                    warn!("Failed to export traces: {error}");
+                    failing = true;
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Random enough for trace and span ids, which only need to be unique
+fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    // Zero means no id
+    hasher.finish().max(1)
+}
+
+/// An `ExportTraceServiceRequest` in the OTLP JSON encoding
+fn export_json(spans: &[SpanData]) -> String {
+    let nanos = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    };
+    let spans: Vec<String> = spans
+        .iter()
+        .map(|span| {
+            let parent = span.parent_id.map_or_else(String::new, |id| {
+                format!("\"parentSpanId\": \"{id:016x}\", ")
+            });
+            let kind = match span.parent_id {
+                Some(_) => SPAN_KIND_INTERNAL,
+                None => SPAN_KIND_SERVER,
+            };
+            let attributes: Vec<String> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{{\"key\": {}, \"value\": {{\"stringValue\": {}}}}}",
+                        json::string(key),
+                        json::string(value)
+                    )
+                })
+                .collect();
+            let status = span.error.as_ref().map_or_else(String::new, |error| {
+                format!(
+                    ", \"status\": {{\"code\": {STATUS_CODE_ERROR}, \"message\": {}}}",
+                    json::string(error)
+                )
+            });
+            format!(
+                "{{\"traceId\": \"{:032x}\", \"spanId\": \"{:016x}\", {parent}\"name\": {}, \
+                 \"kind\": {kind}, \"startTimeUnixNano\": \"{}\", \"endTimeUnixNano\": \"{}\", \
+                 \"attributes\": [{}]{status}}}",
+                span.trace_id,
+                span.span_id,
+                json::string(span.name),
+                nanos(span.start),
+                nanos(span.end),
+                attributes.join(", ")
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"resourceSpans\": [{{\"resource\": {{\"attributes\": [{{\"key\": \"service.name\", \
+         \"value\": {{\"stringValue\": \"dhc3po\"}}}}]}}, \"scopeSpans\": [{{\"scope\": \
+         {{\"name\": \"dhc3po\", \"version\": \"{}\"}}, \"spans\": [{}]}}]}}]}}",
+        env!("CARGO_PKG_VERSION"),
+        spans.join(", ")
+    )
+}
@@ -0,0 +1,108 @@
+//! How datagrams get in and out of the server. In production this is our UDP
+//! socket, tests and replays swap in a [MemoryTransport] so they need neither
+//! root nor a network
+
+use crate::types::MacAddr;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+
+/// The calls we make on our socket
+pub trait Transport {
+    /// Wait for the next datagram, errors as [UdpSocket::recv_from]
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<usize>;
+
+    /// Reach `ip_addr` at `mac_address` without ARP, see [crate::arp]
+    fn add_arp_entry(&self, _ip_addr: Ipv4Addr, _mac_address: MacAddr) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn remove_arp_entry(&self, _ip_addr: Ipv4Addr) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Transport for UdpSocket {
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+
+    fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, destination)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn add_arp_entry(&self, ip_addr: Ipv4Addr, mac_address: MacAddr) -> io::Result<()> {
+        crate::arp::add(self.as_raw_fd(), ip_addr, mac_address)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn remove_arp_entry(&self, ip_addr: Ipv4Addr) -> io::Result<()> {
+        crate::arp::remove(self.as_raw_fd(), ip_addr)
+    }
+}
+
+/// Datagrams queued by a test, and those we sent back
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    inbox: std::sync::Mutex<std::collections::VecDeque<(Vec<u8>, SocketAddr)>>,
+    sent: std::sync::Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+    arp: std::sync::Mutex<BTreeMap<Ipv4Addr, MacAddr>>,
+}
+
+impl MemoryTransport {
+    /// Queue a datagram from `source` for the server to receive
+    pub fn push(&self, data: &[u8], source: SocketAddr) {
+        self.inbox
+            .lock()
+            .unwrap()
+            .push_back((data.to_vec(), source));
+    }
+
+    /// Everything sent since the last call
+    pub fn take_sent(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        std::mem::take(&mut self.sent.lock().unwrap())
+    }
+
+    /// The ARP entries we were asked to add and have not removed
+    #[cfg(test)]
+    pub fn arp_entries(&self) -> BTreeMap<Ipv4Addr, MacAddr> {
+        self.arp.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    /// Times out straight away when nothing is queued, like a socket whose
+    /// read timeout expired
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, source) = self
+            .inbox
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        // A real socket truncates what does not fit
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        Ok((len, source))
+    }
+
+    fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        self.sent.lock().unwrap().push((data.to_vec(), destination));
+        Ok(data.len())
+    }
+
+    fn add_arp_entry(&self, ip_addr: Ipv4Addr, mac_address: MacAddr) -> io::Result<()> {
+        self.arp.lock().unwrap().insert(ip_addr, mac_address);
+        Ok(())
+    }
+
+    fn remove_arp_entry(&self, ip_addr: Ipv4Addr) -> io::Result<()> {
+        self.arp.lock().unwrap().remove(&ip_addr);
+        Ok(())
+    }
+}
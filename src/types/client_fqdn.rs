@@ -0,0 +1,117 @@
+use crate::Error;
+
+/// Option 81, the name a client goes by and who should put it in DNS
+/// (RFC 4702). Clients send flags saying what they would like, we answer with
+/// flags saying what will happen. In our config only the flags matter, they
+/// are the policy we answer with, see [ClientFqdn::answer]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFqdn {
+    flags: u8,
+    rcode1: u8,
+    rcode2: u8,
+    /// As sent, DNS wire format when [ClientFqdn::E] is set or else ASCII
+    name: Vec<u8>,
+}
+
+impl ClientFqdn {
+    /// The server updates the A record, in a reply the server will
+    pub const S: u8 = 0x01;
+    /// In a reply, the server overrode what the client asked for
+    pub const O: u8 = 0x02;
+    /// The name is in DNS wire format
+    pub const E: u8 = 0x04;
+    /// The server updates nothing, not even the PTR record
+    pub const N: u8 = 0x08;
+
+    /// What servers put in the deprecated rcode fields
+    const RCODE: u8 = 255;
+
+    /// A policy for [ClientFqdn::answer], from `S`, `N` or neither
+    pub fn policy(flags: u8) -> Self {
+        Self {
+            flags,
+            rcode1: 0,
+            rcode2: 0,
+            name: Vec::new(),
+        }
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The name the client sent, a trailing `.` when fully qualified
+    pub fn name(&self) -> Option<String> {
+        if !self.has(Self::E) {
+            return String::from_utf8(self.name.clone()).ok();
+        }
+        let mut labels = Vec::new();
+        let mut rest = &self.name[..];
+        while let [len, after @ ..] = rest {
+            if *len == 0 {
+                labels.push("");
+                break;
+            }
+            let label = after.get(..*len as usize)?;
+            labels.push(std::str::from_utf8(label).ok()?);
+            rest = &after[label.len()..];
+        }
+        Some(labels.join("."))
+    }
+
+    /// Our reply to this client under `policy`. With `S` we update A and PTR
+    /// whatever the client asked, with `N` nothing, and with neither we do
+    /// what the client asked for. `O` says when that was not what it asked
+    pub fn answer(&self, policy: &Self) -> Self {
+        let asked = match self.has(Self::N) {
+            true => Self::N,
+            false => self.flags & Self::S,
+        };
+        let decided = match (policy.has(Self::S), policy.has(Self::N)) {
+            (true, _) => Self::S,
+            (false, true) => Self::N,
+            (false, false) => asked,
+        };
+        let overridden = match decided == asked {
+            true => 0,
+            false => Self::O,
+        };
+        Self {
+            flags: decided | overridden | (self.flags & Self::E),
+            rcode1: Self::RCODE,
+            rcode2: Self::RCODE,
+            name: self.name.clone(),
+        }
+    }
+
+    /// The records the server updates, by the flags of an answer. Without `S`
+    /// the client does its own A record and leaves us the PTR
+    pub fn server_updates(&self) -> &'static str {
+        match (self.has(Self::S), self.has(Self::N)) {
+            (true, _) => "a-and-ptr",
+            (false, true) => "none",
+            (false, false) => "ptr",
+        }
+    }
+
+    /// The wire format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&[self.flags, self.rcode1, self.rcode2][..], &self.name].concat()
+    }
+}
+
+impl TryFrom<&[u8]> for ClientFqdn {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match value {
+            [flags, rcode1, rcode2, name @ ..] => Ok(Self {
+                flags: *flags,
+                rcode1: *rcode1,
+                rcode2: *rcode2,
+                name: name.to_vec(),
+            }),
+            _ => Err(Error::InvalidClientFqdnLen(value.len())),
+        }
+    }
+}
@@ -1,32 +1,36 @@
-use super::MacAddr;
 use crate::Error;
 
-#[derive(Debug, Copy, Clone)]
-pub struct ClientIdentifier {
-    hw_type: u8,
-    id: MacAddr,
-}
+/// Option 61, a type byte followed by an opaque identifier. Most clients send
+/// hardware type 1 and their MAC, RFC 4361 clients send 255 with an IAID and
+/// DUID, so we make no assumptions past the type byte
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientIdentifier(Vec<u8>);
 
 impl ClientIdentifier {
     pub const ETHERNET: u8 = 0x1;
-    pub const LEN: u8 = 7;
+    /// RFC 4361, an IAID and a DHCPv6 DUID follow
+    pub const DUID: u8 = 0xFF;
+    /// A type byte and at least one byte of identifier (RFC 2132)
+    pub const MIN_LEN: usize = 2;
+
+    /// The type byte, a hardware type or [ClientIdentifier::DUID]
+    pub fn id_type(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// The wire format, type byte first
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
 }
 
 impl TryFrom<&[u8]> for ClientIdentifier {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let hw_type = *value.first().unwrap();
-        if hw_type != Self::ETHERNET {
-            return Err(Error::UnsupportedClientIdHwType(hw_type));
+        if value.len() < Self::MIN_LEN {
+            return Err(Error::InvalidClientIdLen(value.len()));
         }
-        let mac_bytes = value[1..].get(..MacAddr::LEN).unwrap();
-        let mut mac_addr: [u8; 6] = [0u8; 6];
-        mac_addr.copy_from_slice(mac_bytes);
-
-        Ok(Self {
-            hw_type,
-            id: MacAddr::new(mac_addr),
-        })
+        Ok(Self(value.to_vec()))
     }
 }
@@ -0,0 +1,23 @@
+//! How we recognise the same client across requests
+
+use super::{ClientIdentifier, MacAddr};
+
+/// The key a lease is bound to. Modern devices rotate their MAC but keep a
+/// stable [ClientIdentifier], so we prefer that when the client sends one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientKey {
+    /// Option 61 sent by the client
+    ClientId(ClientIdentifier),
+
+    /// Fallback to chaddr for clients that do not send option 61
+    Mac(MacAddr),
+}
+
+impl ClientKey {
+    pub fn new(client_id: Option<ClientIdentifier>, mac_address: MacAddr) -> Self {
+        match client_id {
+            Some(client_id) => Self::ClientId(client_id),
+            None => Self::Mac(mac_address),
+        }
+    }
+}
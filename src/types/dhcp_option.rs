@@ -1,33 +1,70 @@
-use super::{ClientIdentifier, MessageType, ParameterRequest};
-
-#[derive(Debug, Clone, Copy)]
+use super::{
+    ClientFqdn, ClientIdentifier, MessageType, ParameterRequest, PxeOptions, RelayAgentInfo,
+    UserClass,
+};
+use crate::{wire, Error, Result};
+use log::info;
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, PartialEq)]
 #[repr(u8)]
 #[allow(dead_code)]
-pub enum DhcpOption<'option> {
+pub enum DhcpOption {
     /// 0
     Pad,
 
     /// 1
     SubnetMask([u8; 4]),
 
+    /// 2, seconds east of UTC, negative to the west
+    TimeOffset(i32),
+
     /// 3
     Router([u8; 4]),
 
+    /// 4, RFC 868 time servers in order of preference
+    TimeServer(Vec<[u8; 4]>),
+
     /// 6
     DomainNameServer([u8; 4]),
 
     /// 12
-    HostName(&'option str),
+    HostName(String),
 
-    /// 12
-    DomainName(&'option str),
+    /// 15
+    DomainName(String),
 
-    /// 12
+    /// 17, the root disk of a diskless client, e.g. `server:/export/root`
+    RootPath(String),
+
+    /// 18, a file of further options for the client to fetch with TFTP
+    ExtensionsPath(String),
+
+    /// 26
+    InterfaceMtu(u16),
+
+    /// 28, we work it out from the subnet and mask unless configured
     BroadcastAddress([u8; 4]),
 
     /// 13
     BootFileSize(u16),
 
+    /// 42, NTP servers in order of preference
+    NtpServers(Vec<[u8; 4]>),
+
+    /// 43, the boot menu and discovery settings of PXE clients, we only
+    /// send this and parse what clients send as [DhcpOption::Unknown]
+    PxeOptions(PxeOptions),
+
+    /// 44, WINS servers in order of preference
+    NetBiosNameServer(Vec<[u8; 4]>),
+
+    /// 46, how to resolve NetBIOS names, one of the `NETBIOS_*_NODE` values
+    NetBiosNodeType(u8),
+
+    /// 47
+    NetBiosScope(String),
+
     /// 50
     RequestedIpAddr([u8; 4]),
 
@@ -41,22 +78,34 @@ pub enum DhcpOption<'option> {
     DhcpServerIpAddr([u8; 4]),
 
     /// 55
-    ParameterRequestList([Option<ParameterRequest>; DhcpOptionList::MAX_LEN as usize]),
+    ParameterRequestList(Vec<ParameterRequest>),
+
+    /// 56, why we sent a NAK or why a client declined
+    Message(String),
 
     /// 57
     MaxMessageSize(u16),
 
     /// 60
-    VendorClassIndentifier([u8; DhcpOption::MAX_VENDOR_CLASS_ID_LEN as usize]),
+    VendorClassIndentifier(Vec<u8>),
 
     /// 61
     ClientIdentifier(ClientIdentifier),
 
     /// 66
-    TftpServerName(&'option str),
+    TftpServerName(String),
 
     /// 67
-    BootFileName(&'option str),
+    BootFileName(String),
+
+    /// 77, matched against `[user-class]` sections
+    UserClass(UserClass),
+
+    /// 81, the client's name and who should update DNS with it
+    ClientFqdn(ClientFqdn),
+
+    /// 82, added by the relay agent that forwarded the request
+    RelayAgentInformation(RelayAgentInfo),
 
     /// 93
     ClientSystemArch([u8; 2]),
@@ -65,63 +114,264 @@ pub enum DhcpOption<'option> {
     ClientNetworkDeviceInterface([u8; DhcpOption::CLIENT_NET_DEV_INTERFACE_LEN as usize]),
 
     /// 97
-    ClientUid([u8; DhcpOption::MAX_CLIENT_UID_LEN as usize]),
+    ClientUid(Vec<u8>),
+
+    /// 108, seconds a client that can live without IPv4 should go without
+    /// it (RFC 8925)
+    Ipv6OnlyPreferred(u32),
+
+    /// 114, the URI of the captive portal API (RFC 8910)
+    CaptivePortal(String),
+
+    /// 161, where the client's Manufacturer Usage Description lives (RFC 8520)
+    MudUrl(String),
+
+    /// 119, domains to try unqualified names in, in order (RFC 3397)
+    DomainSearch(Vec<String>),
+
+    /// 118, the subnet a relay or VPN concentrator wants the client placed on
+    SubnetSelection([u8; 4]),
+
+    /// 252, the URL of a proxy auto-config file (WPAD), empty to tell
+    /// clients not to go looking for one
+    ProxyAutodiscovery(String),
 
     /// 255
     End,
+
+    /// Any option we do not have a variant for, the code and raw value are
+    /// kept so the option can still be inspected, logged and echoed
+    Unknown(u8, Vec<u8>),
 }
 
-impl<'option> DhcpOption<'option> {
+impl DhcpOption {
     pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const TIME_OFFSET: u8 = 2;
+    pub const TIME_SERVER: u8 = 4;
+    pub const HOST_NAME: u8 = 12;
+    pub const DOMAIN_NAME: u8 = 15;
+    pub const BROADCAST_ADDRESS: u8 = 28;
+    pub const NTP_SERVERS: u8 = 42;
+    pub const VENDOR_SPECIFIC_INFO: u8 = 43;
+    pub const BOOT_FILE_NAME: u8 = 67;
     pub const REQUESTED_IP_ADDR: u8 = 50;
     pub const LEASE_TIME: u8 = 51;
+    pub const OPTION_OVERLOAD: u8 = 52;
     pub const MESSAGE_TYPE: u8 = 53;
     pub const DHCP_SERVER_IP_ADDR: u8 = 54;
     pub const PARAMETER_REQUEST_LIST: u8 = 55;
+    pub const MESSAGE: u8 = 56;
     pub const MAX_MESSAGE_SIZE: u8 = 57;
     pub const VENDOR_CLASS_ID: u8 = 60;
     pub const CLIENT_ID: u8 = 61;
+    pub const USER_CLASS: u8 = 77;
+    pub const CLIENT_FQDN: u8 = 81;
+    pub const RELAY_AGENT_INFO: u8 = 82;
     pub const CLIENT_SYSTEM_ARCH: u8 = 93;
     pub const CLIENT_NET_DEV_INTERFACE: u8 = 94;
     pub const CLIENT_UID: u8 = 97;
+    pub const IPV6_ONLY_PREFERRED: u8 = 108;
+    pub const SUBNET_SELECTION: u8 = 118;
+    pub const CLASSLESS_STATIC_ROUTE: u8 = 121;
+    pub const MUD_URL: u8 = 161;
     pub const END: u8 = 255;
 
     // Expected values
     pub const IP_ADDR_LEN: u8 = 4;
     pub const MAX_PARAMETER_REQUEST_LIST_LEN: u8 = 40;
-    pub const MAX_CLIENT_UID_LEN: u8 = 17;
     pub const MIN_CLIENT_UID_LEN: u8 = 2;
     pub const MAX_MESSAGE_SIZE_LEN: u8 = 2;
     pub const MESSAGE_TYPE_LEN: u8 = 1;
     pub const MIN_PARAMETER_REQUEST_LEN: u8 = 1;
     pub const CLIENT_NET_DEV_INTERFACE_LEN: u8 = 3;
     pub const CLIENT_SYSTEM_ARCH_LEN: u8 = 2;
-    pub const MAX_VENDOR_CLASS_ID_LEN: u8 = 32;
+    pub const MAX_DOMAIN_NAME_LEN: usize = 253;
+    pub const MAX_DOMAIN_LABEL_LEN: usize = 63;
+    /// The smallest MTU an IPv4 host must accept (RFC 2132 5.1)
+    pub const MIN_INTERFACE_MTU: u16 = 68;
+    /// Broadcast, point to point via WINS, broadcast then WINS, and WINS then
+    /// broadcast (RFC 1001)
+    pub const NETBIOS_B_NODE: u8 = 0x1;
+    pub const NETBIOS_P_NODE: u8 = 0x2;
+    pub const NETBIOS_M_NODE: u8 = 0x4;
+    pub const NETBIOS_H_NODE: u8 = 0x8;
+    /// Clients wait at least this long whatever we send (RFC 8925 3.4)
+    pub const MIN_V6ONLY_WAIT: u32 = 300;
+
+    /// Decode the value of an option received from a client. Options we do
+    /// not have a variant for are kept as [DhcpOption::Unknown]
+    pub fn parse(code: u8, value: &[u8]) -> Result<Self> {
+        // Joined RFC 3396 options can be longer than a length byte can say
+        let len = u8::try_from(value.len()).unwrap_or(u8::MAX);
+
+        let option = match code {
+            Self::MESSAGE_TYPE => {
+                if len != Self::MESSAGE_TYPE_LEN {
+                    return Err(Error::MessageTypeBadLen(len));
+                }
+                Self::MessageType(value[0].into())
+            }
+            Self::HOST_NAME => Self::HostName(String::from_utf8_lossy(value).into_owned()),
+            Self::MESSAGE => Self::Message(String::from_utf8_lossy(value).into_owned()),
+            Self::MUD_URL => Self::MudUrl(String::from_utf8_lossy(value).into_owned()),
+            Self::REQUESTED_IP_ADDR => Self::RequestedIpAddr(Self::parse_ip_addr(value)?),
+            Self::DHCP_SERVER_IP_ADDR => Self::DhcpServerIpAddr(Self::parse_ip_addr(value)?),
+            Self::SUBNET_SELECTION => Self::SubnetSelection(Self::parse_ip_addr(value)?),
+            Self::BROADCAST_ADDRESS => Self::BroadcastAddress(Self::parse_ip_addr(value)?),
+            Self::MAX_MESSAGE_SIZE => {
+                let size = value
+                    .try_into()
+                    .map_err(|_| Error::MaxMessageSizeBadLen(len))?;
+                Self::MaxMessageSize(u16::from_be_bytes(size))
+            }
+            Self::PARAMETER_REQUEST_LIST => {
+                if !(Self::MIN_PARAMETER_REQUEST_LEN..=Self::MAX_PARAMETER_REQUEST_LIST_LEN)
+                    .contains(&len)
+                {
+                    return Err(Error::InvalidParameterRequestLen(len));
+                }
+
+                Self::ParameterRequestList(value.iter().map(|param| (*param).into()).collect())
+            }
+            Self::VENDOR_CLASS_ID => Self::VendorClassIndentifier(value.to_vec()),
+            Self::CLIENT_SYSTEM_ARCH => Self::ClientSystemArch(
+                value
+                    .try_into()
+                    .map_err(|_| Error::InvalidClientSystemArchLen(len))?,
+            ),
+            Self::CLIENT_NET_DEV_INTERFACE => Self::ClientNetworkDeviceInterface(
+                value
+                    .try_into()
+                    .map_err(|_| Error::InvalidClientNetworkDeviceInterfaceLen(len))?,
+            ),
+            Self::CLIENT_ID => Self::ClientIdentifier(ClientIdentifier::try_from(value)?),
+            Self::USER_CLASS => Self::UserClass(value.into()),
+            Self::CLIENT_FQDN => Self::ClientFqdn(ClientFqdn::try_from(value)?),
+            Self::RELAY_AGENT_INFO => Self::RelayAgentInformation(RelayAgentInfo::try_from(value)?),
+            Self::CLIENT_UID => {
+                if len < Self::MIN_CLIENT_UID_LEN {
+                    return Err(Error::InvalidClientUidLen(len));
+                }
+                Self::ClientUid(value.to_vec())
+            }
+            // Catch options we have not defined, keeping their raw value
+            code => {
+                info!("Unknown DhcpOption Recieved: {code} {value:02X?}");
+                Self::Unknown(code, value.to_vec())
+            }
+        };
+
+        Ok(option)
+    }
+
+    fn parse_ip_addr(value: &[u8]) -> Result<[u8; 4]> {
+        value
+            .try_into()
+            .map_err(|_| Error::InvalidIpAddrLen(u8::try_from(value.len()).unwrap_or(u8::MAX)))
+    }
+
+    /// Build an option from its numeric code and a value in one of the
+    /// encodings below, for options we do not have a typed variant for. The
+    /// value is serialised verbatim
+    ///
+    /// * `hex:0A000001` raw bytes
+    /// * `ip:10.0.0.1,10.0.0.2` one or more IPv4 addresses
+    /// * `u8:1`, `u16:1500`, `u32:3600` big endian integers
+    /// * `str:hello` a string without a null terminator
+    pub fn from_code_value(code: u8, value: &str) -> Result<Self> {
+        let invalid = || Error::InvalidOptionValue(code, value.to_owned());
+
+        let (encoding, data) = value.split_once(':').ok_or_else(invalid)?;
+        let bytes = match encoding {
+            "hex" => {
+                if data.len() % 2 != 0 {
+                    return Err(invalid());
+                }
+                (0..data.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(invalid)?
+            }
+            "ip" => data
+                .split(',')
+                .map(|ip| ip.trim().parse::<Ipv4Addr>().map(|ip| ip.octets()))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?
+                .concat(),
+            "u8" => data
+                .parse::<u8>()
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            "u16" => data
+                .parse::<u16>()
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            "u32" => data
+                .parse::<u32>()
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+                .to_vec(),
+            "str" => data.as_bytes().to_vec(),
+            _ => return Err(invalid()),
+        };
+
+        // Pad and End have no length byte so cannot carry a value
+        if code == Self::PAD || code == Self::END {
+            return Err(invalid());
+        }
+
+        Ok(Self::Unknown(code, bytes))
+    }
 
     pub fn opcode(&self) -> u8 {
         match self {
             Self::Pad => 0,
             Self::SubnetMask(_) => 1,
+            Self::TimeOffset(_) => 2,
             Self::Router(_) => 3,
+            Self::TimeServer(_) => 4,
             Self::DomainNameServer(_) => 6,
             Self::HostName(_) => 12,
             Self::BootFileSize(_) => 13,
             Self::DomainName(_) => 15,
+            Self::RootPath(_) => 17,
+            Self::ExtensionsPath(_) => 18,
+            Self::InterfaceMtu(_) => 26,
             Self::BroadcastAddress(_) => 28,
+            Self::NtpServers(_) => 42,
+            Self::PxeOptions(_) => 43,
+            Self::NetBiosNameServer(_) => 44,
+            Self::NetBiosNodeType(_) => 46,
+            Self::NetBiosScope(_) => 47,
             Self::RequestedIpAddr(_) => 50,
             Self::LeaseTime(_) => 51,
             Self::MessageType(_) => 53,
             Self::DhcpServerIpAddr(_) => 54,
             Self::ParameterRequestList(_) => 55,
+            Self::Message(_) => 56,
             Self::MaxMessageSize(_) => 57,
             Self::VendorClassIndentifier(_) => 60,
             Self::TftpServerName(_) => 66,
             Self::BootFileName(_) => 67,
             Self::ClientIdentifier(_) => 61,
+            Self::UserClass(_) => 77,
+            Self::ClientFqdn(_) => 81,
+            Self::RelayAgentInformation(_) => 82,
             Self::ClientSystemArch(_) => 93,
             Self::ClientNetworkDeviceInterface(_) => 94,
             Self::ClientUid(_) => 97,
+            Self::Ipv6OnlyPreferred(_) => 108,
+            Self::CaptivePortal(_) => 114,
+            Self::SubnetSelection(_) => 118,
+            Self::DomainSearch(_) => 119,
+            Self::MudUrl(_) => 161,
+            Self::ProxyAutodiscovery(_) => 252,
             Self::End => 255,
+            Self::Unknown(code, _) => *code,
         }
     }
 
@@ -133,7 +383,8 @@ impl<'option> DhcpOption<'option> {
             | Self::Router(address)
             | Self::BroadcastAddress(address)
             | Self::DomainNameServer(address)
-            | Self::DhcpServerIpAddr(address) => {
+            | Self::DhcpServerIpAddr(address)
+            | Self::SubnetSelection(address) => {
                 let len: u8 = 6;
                 buffer[1] = len - 2;
                 buffer[2..6].copy_from_slice(address);
@@ -142,16 +393,19 @@ impl<'option> DhcpOption<'option> {
             Self::DomainName(name)
             | Self::TftpServerName(name)
             | Self::BootFileName(name)
-            | Self::HostName(name) => {
-                let len = name.len() + 2;
-                buffer[1] = (len - 2) as u8;
-                buffer[2..len].copy_from_slice(name.as_bytes());
-                len
-            }
+            | Self::HostName(name)
+            | Self::CaptivePortal(name)
+            | Self::MudUrl(name)
+            | Self::NetBiosScope(name)
+            | Self::ProxyAutodiscovery(name)
+            | Self::RootPath(name)
+            | Self::ExtensionsPath(name)
+            | Self::Message(name) => Self::serialise_value(buffer, name.as_bytes()),
+            Self::NetBiosNodeType(node_type) => Self::serialise_value(buffer, &[*node_type]),
             Self::MessageType(message) => {
                 let len: u8 = 3;
                 buffer[1] = len - 2;
-                buffer[2] = *message as u8;
+                buffer[2] = message.code();
                 len as usize
             }
             Self::BootFileSize(size) => {
@@ -170,35 +424,151 @@ impl<'option> DhcpOption<'option> {
                 buffer[5] = *time as u8;
                 len as usize
             }
+            Self::TimeOffset(offset) => Self::serialise_value(buffer, &offset.to_be_bytes()),
+            Self::TimeServer(addresses)
+            | Self::NtpServers(addresses)
+            | Self::NetBiosNameServer(addresses) => {
+                Self::serialise_value(buffer, &addresses.concat())
+            }
+            Self::RequestedIpAddr(address) => Self::serialise_value(buffer, address),
+            Self::MaxMessageSize(size) | Self::InterfaceMtu(size) => {
+                Self::serialise_value(buffer, &size.to_be_bytes())
+            }
+            Self::Ipv6OnlyPreferred(wait) => Self::serialise_value(buffer, &wait.to_be_bytes()),
+            Self::ParameterRequestList(params) => {
+                let params: Vec<u8> = params.iter().map(ParameterRequest::code).collect();
+                Self::serialise_value(buffer, &params)
+            }
+            Self::DomainSearch(domains) => {
+                Self::serialise_value(buffer, &Self::encode_domain_search(domains))
+            }
+            Self::VendorClassIndentifier(id) => Self::serialise_value(buffer, id),
+            Self::ClientIdentifier(id) => Self::serialise_value(buffer, id.as_bytes()),
+            Self::PxeOptions(pxe) => Self::serialise_value(buffer, &pxe.to_bytes()),
+            Self::UserClass(class) => Self::serialise_value(buffer, class.as_bytes()),
+            Self::ClientFqdn(fqdn) => Self::serialise_value(buffer, &fqdn.to_bytes()),
+            Self::RelayAgentInformation(info) => Self::serialise_value(buffer, &info.to_bytes()),
+            Self::ClientSystemArch(arch) => Self::serialise_value(buffer, arch),
+            Self::ClientNetworkDeviceInterface(interface) => {
+                Self::serialise_value(buffer, interface)
+            }
+            Self::ClientUid(uid) => Self::serialise_value(buffer, uid),
             Self::End => 1,
-            option => todo!("We dont yet serialise DHCP Option {option:?}"),
+            Self::Unknown(_, value) => Self::serialise_value(buffer, value),
+        }
+    }
+
+    /// Domains as DNS names, a suffix already written is replaced with a
+    /// pointer to it (RFC 1035 4.1.4)
+    pub fn encode_domain_search(domains: &[String]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        // Where each suffix we have written starts
+        let mut suffixes: Vec<(String, u16)> = Vec::new();
+        for domain in domains {
+            let labels: Vec<&str> = domain.trim_end_matches('.').split('.').collect();
+            let mut pointer = None;
+            for at in 0..labels.len() {
+                let suffix = labels[at..].join(".").to_ascii_lowercase();
+                if let Some((_, offset)) = suffixes.iter().find(|(known, _)| *known == suffix) {
+                    pointer = Some(0xc000 | offset);
+                    break;
+                }
+                // Pointers have 14 bits for the offset
+                if let Ok(offset @ ..0x4000) = u16::try_from(encoded.len()) {
+                    suffixes.push((suffix, offset));
+                }
+                encoded.push(labels[at].len() as u8);
+                encoded.extend_from_slice(labels[at].as_bytes());
+            }
+            match pointer {
+                Some(pointer) => encoded.extend_from_slice(&u16::to_be_bytes(pointer)),
+                None => encoded.push(0),
+            }
         }
+        encoded
+    }
+
+    /// Write the length and value after the opcode, returns the total length.
+    /// Values too long for one length byte are split across several
+    /// instances of the option, as described in RFC 3396
+    fn serialise_value(buffer: &mut [u8], value: &[u8]) -> usize {
+        wire::put_option(buffer, buffer[0], value).expect("the option fits in the buffer")
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct DhcpOptionList<'dhcp_option>(
-    [Option<DhcpOption<'dhcp_option>>; DhcpOptionList::MAX_LEN],
-);
+/// The options of a packet in the order they appear on the wire, an opcode
+/// can appear more than once
+#[derive(Debug, Clone, Default)]
+pub struct DhcpOptionList(Vec<DhcpOption>);
 
-impl<'dhcp_option> DhcpOptionList<'dhcp_option> {
+impl DhcpOptionList {
+    /// How many distinct option codes exist
     pub const MAX_LEN: usize = 256;
 
+    /// Most packets carry around this many options
+    const TYPICAL_LEN: usize = 16;
+
     pub fn builder() -> Self {
-        Self([None; DhcpOptionList::MAX_LEN])
+        Self(Vec::with_capacity(Self::TYPICAL_LEN))
+    }
+
+    /// Append an option, keeping any others with the same opcode
+    pub fn add(&mut self, option: DhcpOption) -> &mut Self {
+        self.0.push(option);
+        self
+    }
+
+    /// Replace every option with the same opcode, the new option takes the
+    /// place of the first one or is appended if there was none
+    pub fn set(&mut self, option: DhcpOption) -> &mut Self {
+        let opcode = option.opcode();
+        match self.0.iter().position(|opt| opt.opcode() == opcode) {
+            Some(index) => {
+                self.0[index] = option;
+                let mut seen = 0;
+                self.0.retain(|opt| {
+                    if opt.opcode() == opcode {
+                        seen += 1;
+                    }
+                    opt.opcode() != opcode || seen == 1
+                });
+            }
+            None => self.0.push(option),
+        }
+        self
+    }
+
+    /// Drop every option with this opcode
+    pub fn remove(&mut self, opcode: u8) -> &mut Self {
+        self.0.retain(|opt| opt.opcode() != opcode);
+        self
     }
 
-    pub fn add(&mut self, option: DhcpOption<'dhcp_option>) -> &mut Self {
-        self.0[option.opcode() as usize] = Some(option);
+    /// Set an option by numeric code, see [DhcpOption::from_code_value]
+    pub fn add_raw(&mut self, code: u8, value: &str) -> Result<&mut Self> {
+        Ok(self.set(DhcpOption::from_code_value(code, value)?))
+    }
+
+    /// Layer `other` over this list, its options replace every option here
+    /// with the same opcode
+    pub fn merge(&mut self, other: &DhcpOptionList) -> &mut Self {
+        self.0.retain(|opt| other.get(opt.opcode()).is_none());
+        self.0.extend(other.iter().cloned());
         self
     }
 
-    /// Returns the completed array of options
-    pub fn consume(&self) -> &[Option<DhcpOption<'dhcp_option>>; DhcpOptionList::MAX_LEN] {
-        &self.0
+    /// The first option with this opcode
+    pub fn get(&self, opcode: u8) -> Option<&DhcpOption> {
+        self.0.iter().find(|opt| opt.opcode() == opcode)
+    }
+
+    /// Every option with this opcode, in order
+    pub fn get_all(&self, opcode: u8) -> impl Iterator<Item = &DhcpOption> {
+        self.0.iter().filter(move |opt| opt.opcode() == opcode)
     }
 
-    pub fn get(&self, opcode: u8) -> Option<DhcpOption> {
-        self.0[opcode as usize]
+    /// Every option in order
+    pub fn iter(&self) -> impl Iterator<Item = &DhcpOption> {
+        self.0.iter()
     }
 }
@@ -1,6 +1,10 @@
 //! Deals with mac addresses
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MacAddr([u8; 6]);
 
 impl MacAddr {
@@ -9,6 +13,10 @@ impl MacAddr {
     pub fn new(bytes: [u8; 6]) -> Self {
         Self(bytes)
     }
+
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
 }
 
 impl From<[u8; 6]> for MacAddr {
@@ -16,3 +24,32 @@ impl From<[u8; 6]> for MacAddr {
         Self(value)
     }
 }
+
+impl FromStr for MacAddr {
+    type Err = Error;
+
+    /// Six hex octets separated by `:` or `-`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidMacAddr(text.to_owned());
+        let mut bytes = [0u8; Self::LEN];
+        let mut octets = text.split([':', '-']);
+        for byte in &mut bytes {
+            let octet = octets
+                .next()
+                .filter(|octet| octet.len() == 2)
+                .ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(octet, 16).map_err(|_| invalid())?;
+        }
+        match octets.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(Self(bytes)),
+        }
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
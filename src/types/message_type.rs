@@ -1,33 +1,54 @@
-use crate::Error;
-
 /// Value message types for [DhcpOption::MessageType] (53)
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageType {
-    Discover = 1,
-    Offer = 2,
-    Request = 3,
-    Decline = 4,
-    Ack = 5,
-    Nack = 6,
-    Release = 7,
-    Inform = 8,
-    Unset = 255,
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nack,
+    Release,
+    Inform,
+    /// RFC 3203, sent by us to make a client renew now
+    ForceRenew,
+    /// A code from a later RFC, or garbage, which we ignore rather than
+    /// dropping the whole packet
+    Unknown(u8),
+    Unset,
 }
 
-impl TryFrom<u8> for MessageType {
-    type Error = Error;
+impl MessageType {
+    /// The value of option 53
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Discover => 1,
+            Self::Offer => 2,
+            Self::Request => 3,
+            Self::Decline => 4,
+            Self::Ack => 5,
+            Self::Nack => 6,
+            Self::Release => 7,
+            Self::Inform => 8,
+            Self::ForceRenew => 9,
+            Self::Unknown(code) => *code,
+            Self::Unset => 255,
+        }
+    }
+}
 
-    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
         match value {
-            1 => Ok(Self::Discover),
-            2 => Ok(Self::Offer),
-            3 => Ok(Self::Request),
-            4 => Ok(Self::Decline),
-            5 => Ok(Self::Ack),
-            6 => Ok(Self::Nack),
-            7 => Ok(Self::Release),
-            8 => Ok(Self::Inform),
-            value => Err(Error::InvalidDhcpOptionMessageType(value)),
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nack,
+            7 => Self::Release,
+            8 => Self::Inform,
+            9 => Self::ForceRenew,
+            value => Self::Unknown(value),
         }
     }
 }
@@ -3,6 +3,9 @@
 mod dhcp_option;
 pub use dhcp_option::{DhcpOption, DhcpOptionList};
 
+mod options_view;
+pub use options_view::OptionsView;
+
 mod message_type;
 pub use message_type::MessageType;
 
@@ -12,5 +15,26 @@ pub use parameter_request::ParameterRequest;
 mod client_identifier;
 pub use client_identifier::ClientIdentifier;
 
+mod client_fqdn;
+pub use client_fqdn::ClientFqdn;
+
+mod relay_agent_info;
+pub use relay_agent_info::RelayAgentInfo;
+
+mod relay_id;
+pub use relay_id::RelayId;
+
+mod user_class;
+pub use user_class::UserClass;
+
+mod pxe_options;
+pub use pxe_options::PxeOptions;
+
+mod uuid;
+pub use uuid::Uuid;
+
 mod mac;
 pub use mac::MacAddr;
+
+mod client_key;
+pub use client_key::ClientKey;
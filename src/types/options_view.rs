@@ -0,0 +1,100 @@
+use crate::types::{DhcpOption, MessageType};
+use crate::wire::Options;
+use std::borrow::Cow;
+use std::net::Ipv4Addr;
+
+/// The options of a packet read where they lie in the receive buffer, so
+/// looking one up copies nothing unless the client split it across several
+/// instances (RFC 3396). Values are only decoded when asked for, anything we
+/// cannot decode reads as [None]. [Options] does the same without `std`
+#[derive(Debug, Clone, Copy)]
+pub struct OptionsView<'a> {
+    /// An options area, from just after the magic or a whole overloaded
+    /// file or sname field
+    area: &'a [u8],
+}
+
+impl<'a> OptionsView<'a> {
+    /// The options of a whole packet, none when it is too short to have any
+    pub fn new(packet: &'a [u8]) -> Self {
+        Self::over(packet.get(crate::wire::OPTIONS_START..).unwrap_or_default())
+    }
+
+    /// The options in an area on its own, such as an overloaded file field
+    pub fn over(area: &'a [u8]) -> Self {
+        Self { area }
+    }
+
+    /// Every instance of every option in order, without padding, up to END
+    /// or the first option that runs past the end of the area
+    pub fn iter(&self) -> Options<'a> {
+        Options::over(self.area)
+    }
+
+    /// The code of the option that runs past the end of the area, where
+    /// [OptionsView::iter] stops early
+    pub fn truncated(&self) -> Option<u8> {
+        let mut options = self.iter();
+        options.by_ref().for_each(drop);
+        options.truncated()
+    }
+
+    /// Each option with its instances joined, in the order their codes first
+    /// appear
+    pub fn joined(&self) -> Vec<(u8, Cow<'a, [u8]>)> {
+        let mut options: Vec<(u8, Cow<'a, [u8]>)> = Vec::new();
+        for (code, value) in self.iter() {
+            match options.iter_mut().find(|(existing, _)| *existing == code) {
+                Some((_, existing)) => existing.to_mut().extend_from_slice(value),
+                None => options.push((code, Cow::Borrowed(value))),
+            }
+        }
+        options
+    }
+
+    /// The value of option `code`, every instance of it joined
+    pub fn get(&self, code: u8) -> Option<Cow<'a, [u8]>> {
+        let mut instances = self.iter().filter(|(existing, _)| *existing == code);
+        let (_, first) = instances.next()?;
+        let mut value = Cow::Borrowed(first);
+        for (_, more) in instances {
+            value.to_mut().extend_from_slice(more);
+        }
+        Some(value)
+    }
+
+    /// 53
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self.get(DhcpOption::MESSAGE_TYPE)?[..] {
+            [message_type] => Some(message_type.into()),
+            _ => None,
+        }
+    }
+
+    /// 50
+    pub fn requested_ip_addr(&self) -> Option<Ipv4Addr> {
+        self.ip_addr(DhcpOption::REQUESTED_IP_ADDR)
+    }
+
+    /// 54
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        self.ip_addr(DhcpOption::DHCP_SERVER_IP_ADDR)
+    }
+
+    /// 57
+    pub fn max_message_size(&self) -> Option<u16> {
+        let value = self.get(DhcpOption::MAX_MESSAGE_SIZE)?;
+        Some(u16::from_be_bytes(value[..].try_into().ok()?))
+    }
+
+    /// 56, with anything that is not UTF-8 replaced
+    pub fn message(&self) -> Option<String> {
+        let value = self.get(DhcpOption::MESSAGE)?;
+        Some(String::from_utf8_lossy(&value).into_owned())
+    }
+
+    fn ip_addr(&self, code: u8) -> Option<Ipv4Addr> {
+        let value = self.get(code)?;
+        Some(<[u8; 4]>::try_from(&value[..]).ok()?.into())
+    }
+}
@@ -1,56 +1,114 @@
-use log::warn;
-
-#[allow(unused)]
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
+/// The options a client asks for in option 55, in the order it asks
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ParameterRequest {
-    SubnetMask = 1,
-    TimeOffset = 2,
-    Router = 3,
-    TimeServer = 4,
-    NameServer = 5,
-    DomainNameServer = 6,
-    LogServer = 7,
-    HostName = 12,
-    BootFileSize = 13,
-    DomainName = 15,
-    RootPath = 17,
-    ExtensionsPath = 18,
-    MaxDatagramReassmblySize = 22,
-    DefaultIpTtl = 23,
-    InterfaceMtu = 26,
-    BroadcastAddress = 28,
-    PerformRouterDiscover = 31,
-    StaticRoute = 33,
-    NetworkInformationServiceDomain = 40,
-    NetworkInformationServiceServers = 41,
-    NtpServers = 42,
-    VendorSpecificInfo = 43,
-    NetBiosNameServer = 44,
-    NetBiosNodeType = 46,
-    NetBiosScope = 47,
-    RequestedIpAddress = 50,
-    IpAddressLease = 51,
-    DhcpServerIndentifier = 54,
-    RenewalTimeValue = 58,
-    RebindingTimeValue = 59,
-    VendorClassIndentifier = 60,
-    TftpServerName = 66,
-    BootfileName = 67,
-    UUIDBasedClientIdentifier = 97,
-    DomainSearch = 119,
-    ClasslessStaticRoute = 121,
-    DocsisFullSecurityServerIp = 128,
-    PxeUndefined1 = 129,
-    PxeUndefined2 = 130,
-    PxeUndefined3 = 131,
-    PxeUndefined4 = 132,
-    PxeUndefined5 = 133,
-    PxeUndefined6 = 134,
-    PxeUndefined7 = 135,
-    ClasslessStaticRouteMicrosoft = 249,
-    ProxyAutodiscovery = 252,
-    Unimplemented,
+    SubnetMask,
+    TimeOffset,
+    Router,
+    TimeServer,
+    NameServer,
+    DomainNameServer,
+    LogServer,
+    HostName,
+    BootFileSize,
+    DomainName,
+    RootPath,
+    ExtensionsPath,
+    MaxDatagramReassmblySize,
+    DefaultIpTtl,
+    InterfaceMtu,
+    BroadcastAddress,
+    PerformRouterDiscover,
+    StaticRoute,
+    NetworkInformationServiceDomain,
+    NetworkInformationServiceServers,
+    NtpServers,
+    VendorSpecificInfo,
+    NetBiosNameServer,
+    NetBiosNodeType,
+    NetBiosScope,
+    RequestedIpAddress,
+    IpAddressLease,
+    DhcpServerIndentifier,
+    RenewalTimeValue,
+    RebindingTimeValue,
+    VendorClassIndentifier,
+    TftpServerName,
+    BootfileName,
+    UUIDBasedClientIdentifier,
+    Ipv6OnlyPreferred,
+    CaptivePortal,
+    DomainSearch,
+    ClasslessStaticRoute,
+    DocsisFullSecurityServerIp,
+    PxeUndefined1,
+    PxeUndefined2,
+    PxeUndefined3,
+    PxeUndefined4,
+    PxeUndefined5,
+    PxeUndefined6,
+    PxeUndefined7,
+    ClasslessStaticRouteMicrosoft,
+    ProxyAutodiscovery,
+    /// A code we have no name for, served from options set by code such as
+    /// `--option 150=ip:10.0.0.1`
+    Other(u8),
+}
+
+impl ParameterRequest {
+    /// The code of the option being asked for
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::SubnetMask => 1,
+            Self::TimeOffset => 2,
+            Self::Router => 3,
+            Self::TimeServer => 4,
+            Self::NameServer => 5,
+            Self::DomainNameServer => 6,
+            Self::LogServer => 7,
+            Self::HostName => 12,
+            Self::BootFileSize => 13,
+            Self::DomainName => 15,
+            Self::RootPath => 17,
+            Self::ExtensionsPath => 18,
+            Self::MaxDatagramReassmblySize => 22,
+            Self::DefaultIpTtl => 23,
+            Self::InterfaceMtu => 26,
+            Self::BroadcastAddress => 28,
+            Self::PerformRouterDiscover => 31,
+            Self::StaticRoute => 33,
+            Self::NetworkInformationServiceDomain => 40,
+            Self::NetworkInformationServiceServers => 41,
+            Self::NtpServers => 42,
+            Self::VendorSpecificInfo => 43,
+            Self::NetBiosNameServer => 44,
+            Self::NetBiosNodeType => 46,
+            Self::NetBiosScope => 47,
+            Self::RequestedIpAddress => 50,
+            Self::IpAddressLease => 51,
+            Self::DhcpServerIndentifier => 54,
+            Self::RenewalTimeValue => 58,
+            Self::RebindingTimeValue => 59,
+            Self::VendorClassIndentifier => 60,
+            Self::TftpServerName => 66,
+            Self::BootfileName => 67,
+            Self::UUIDBasedClientIdentifier => 97,
+            Self::Ipv6OnlyPreferred => 108,
+            Self::CaptivePortal => 114,
+            Self::DomainSearch => 119,
+            Self::ClasslessStaticRoute => 121,
+            Self::DocsisFullSecurityServerIp => 128,
+            Self::PxeUndefined1 => 129,
+            Self::PxeUndefined2 => 130,
+            Self::PxeUndefined3 => 131,
+            Self::PxeUndefined4 => 132,
+            Self::PxeUndefined5 => 133,
+            Self::PxeUndefined6 => 134,
+            Self::PxeUndefined7 => 135,
+            Self::ClasslessStaticRouteMicrosoft => 249,
+            Self::ProxyAutodiscovery => 252,
+            Self::Other(code) => *code,
+        }
+    }
 }
 
 impl From<u8> for ParameterRequest {
@@ -90,6 +148,8 @@ impl From<u8> for ParameterRequest {
             66 => Self::TftpServerName,
             67 => Self::BootfileName,
             97 => Self::UUIDBasedClientIdentifier,
+            108 => Self::Ipv6OnlyPreferred,
+            114 => Self::CaptivePortal,
             119 => Self::DomainSearch,
             121 => Self::ClasslessStaticRoute,
             128 => Self::DocsisFullSecurityServerIp,
@@ -102,10 +162,7 @@ impl From<u8> for ParameterRequest {
             135 => Self::PxeUndefined7,
             249 => Self::ClasslessStaticRouteMicrosoft,
             252 => Self::ProxyAutodiscovery,
-            unimplemented => {
-                warn!("RequestedParameter {unimplemented} is unimplemented");
-                Self::Unimplemented
-            }
+            code => Self::Other(code),
         }
     }
 }
@@ -0,0 +1,108 @@
+/// Option 43 for PXE clients, the sub-options that control boot server
+/// discovery and the menu the firmware shows before booting (PXE 2.1 2.4).
+/// Each sub-option must fit in 255 bytes, the setters refuse what does not
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PxeOptions {
+    /// 6, bits that turn off broadcast or multicast discovery, restrict the
+    /// client to `boot_servers`, or boot the file in the reply at once
+    discovery_control: Option<u8>,
+    /// 8, the servers to ask for each boot server type
+    boot_servers: Vec<(u16, Vec<[u8; 4]>)>,
+    /// 9, the menu entries in order, each a boot server type and a description
+    menu: Vec<(u16, String)>,
+    /// 10, seconds to show the prompt for and the prompt itself
+    prompt: Option<(u8, String)>,
+}
+
+impl PxeOptions {
+    pub const DISCOVERY_CONTROL: u8 = 6;
+    pub const BOOT_SERVERS: u8 = 8;
+    pub const BOOT_MENU: u8 = 9;
+    pub const MENU_PROMPT: u8 = 10;
+    pub const END: u8 = 255;
+
+    /// The most a sub-option's length byte can say
+    const MAX_LEN: usize = u8::MAX as usize;
+
+    pub fn set_discovery_control(&mut self, bits: u8) -> &mut Self {
+        self.discovery_control = Some(bits);
+        self
+    }
+
+    /// Where clients choosing a menu entry of `server_type` go
+    pub fn add_boot_server(
+        &mut self,
+        server_type: u16,
+        servers: Vec<[u8; 4]>,
+    ) -> Option<&mut Self> {
+        let len = self.boot_servers_bytes().len() + 3 + 4 * servers.len();
+        if servers.is_empty() || len > Self::MAX_LEN {
+            return None;
+        }
+        self.boot_servers.push((server_type, servers));
+        Some(self)
+    }
+
+    pub fn add_menu_entry(&mut self, server_type: u16, description: String) -> Option<&mut Self> {
+        if self.menu_bytes().len() + 3 + description.len() > Self::MAX_LEN {
+            return None;
+        }
+        self.menu.push((server_type, description));
+        Some(self)
+    }
+
+    /// `timeout` of 0 boots the first entry at once, 255 waits for a choice
+    pub fn set_prompt(&mut self, timeout: u8, prompt: String) -> Option<&mut Self> {
+        if prompt.len() >= Self::MAX_LEN {
+            return None;
+        }
+        self.prompt = Some((timeout, prompt));
+        Some(self)
+    }
+
+    fn boot_servers_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (server_type, servers) in &self.boot_servers {
+            bytes.extend_from_slice(&server_type.to_be_bytes());
+            bytes.push(servers.len() as u8);
+            bytes.extend_from_slice(&servers.concat());
+        }
+        bytes
+    }
+
+    fn menu_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (server_type, description) in &self.menu {
+            bytes.extend_from_slice(&server_type.to_be_bytes());
+            bytes.push(description.len() as u8);
+            bytes.extend_from_slice(description.as_bytes());
+        }
+        bytes
+    }
+
+    /// The wire format, the sub-options we have then an end sub-option
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sub_options = Vec::new();
+        if let Some(bits) = self.discovery_control {
+            sub_options.push((Self::DISCOVERY_CONTROL, vec![bits]));
+        }
+        if !self.boot_servers.is_empty() {
+            sub_options.push((Self::BOOT_SERVERS, self.boot_servers_bytes()));
+        }
+        if !self.menu.is_empty() {
+            sub_options.push((Self::BOOT_MENU, self.menu_bytes()));
+        }
+        if let Some((timeout, prompt)) = &self.prompt {
+            sub_options.push((Self::MENU_PROMPT, [&[*timeout], prompt.as_bytes()].concat()));
+        }
+
+        let mut bytes = Vec::new();
+        for (code, value) in sub_options {
+            bytes.push(code);
+            bytes.push(value.len() as u8);
+            bytes.extend_from_slice(&value);
+        }
+        bytes.push(Self::END);
+        bytes
+    }
+}
@@ -0,0 +1,74 @@
+use crate::Error;
+use std::net::Ipv4Addr;
+
+/// Option 82, what a relay agent adds to the requests it forwards to say where
+/// the client is (RFC 3046). The value is sub-options, each a code, length and
+/// value like an option, which we keep in the order the relay sent them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayAgentInfo(Vec<(u8, Vec<u8>)>);
+
+impl RelayAgentInfo {
+    /// 1, the port or VLAN the request came in on, as the relay names it
+    pub const CIRCUIT_ID: u8 = 1;
+    /// 2, the relay or subscriber line the request came from
+    pub const REMOTE_ID: u8 = 2;
+    /// 5, the subnet the client is on when giaddr is only where the relay
+    /// can be reached, such as a relay in another VRF (RFC 3527)
+    pub const LINK_SELECTION: u8 = 5;
+
+    /// The value of the first sub-option with this code
+    pub fn get(&self, code: u8) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(existing, _)| *existing == code)
+            .map(|(_, value)| &value[..])
+    }
+
+    pub fn circuit_id(&self) -> Option<&[u8]> {
+        self.get(Self::CIRCUIT_ID)
+    }
+
+    pub fn remote_id(&self) -> Option<&[u8]> {
+        self.get(Self::REMOTE_ID)
+    }
+
+    /// [None] unless the sub-option is an address
+    pub fn link_selection(&self) -> Option<Ipv4Addr> {
+        let address: [u8; 4] = self.get(Self::LINK_SELECTION)?.try_into().ok()?;
+        Some(address.into())
+    }
+
+    /// Every sub-option in order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &[u8])> {
+        self.0.iter().map(|(code, value)| (*code, &value[..]))
+    }
+
+    /// The wire format, sub-options back to back
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|(code, value)| [&[*code, value.len() as u8][..], value].concat())
+            .collect()
+    }
+}
+
+impl TryFrom<&[u8]> for RelayAgentInfo {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let mut sub_options = Vec::new();
+        let mut rest = value;
+        while let [code, len, after @ ..] = rest {
+            let sub_value = after
+                .get(..*len as usize)
+                .ok_or(Error::InvalidRelayAgentInfo)?;
+            sub_options.push((*code, sub_value.to_vec()));
+            rest = &after[sub_value.len()..];
+        }
+        // A lone byte is a sub-option without a length
+        if !rest.is_empty() || sub_options.is_empty() {
+            return Err(Error::InvalidRelayAgentInfo);
+        }
+        Ok(Self(sub_options))
+    }
+}
@@ -0,0 +1,77 @@
+//! A client known by what its relay agent says about where it is, a port or
+//! a subscriber line, rather than by its hardware
+
+use crate::types::RelayAgentInfo;
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A circuit-id or remote-id from option 82 (RFC 3046), written
+/// `circuit-id:<id>` or `remote-id:<id>` with the id as text, or as `0x` and
+/// hex digits when it is binary
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RelayId {
+    CircuitId(Vec<u8>),
+    RemoteId(Vec<u8>),
+}
+
+impl RelayId {
+    /// The ids in `info` we may have a reservation for, circuit-id first
+    pub fn all(info: &RelayAgentInfo) -> impl Iterator<Item = Self> + '_ {
+        let circuit_id = info.circuit_id().map(|id| Self::CircuitId(id.to_vec()));
+        let remote_id = info.remote_id().map(|id| Self::RemoteId(id.to_vec()));
+        circuit_id.into_iter().chain(remote_id)
+    }
+
+    fn id(&self) -> &[u8] {
+        match self {
+            Self::CircuitId(id) | Self::RemoteId(id) => id,
+        }
+    }
+}
+
+impl FromStr for RelayId {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRelayId(text.to_owned());
+        let (kind, id) = text.split_once(':').ok_or_else(invalid)?;
+        let id = match id.strip_prefix("0x") {
+            Some(digits) if digits.len() % 2 == 0 => (0..digits.len())
+                .step_by(2)
+                .map(|index| u8::from_str_radix(digits.get(index..index + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(invalid)?,
+            Some(_) => return Err(invalid()),
+            None => id.as_bytes().to_vec(),
+        };
+        // Sub-options hold at most 255 bytes and are never empty
+        if id.is_empty() || id.len() > u8::MAX as usize {
+            return Err(invalid());
+        }
+        match kind {
+            "circuit-id" => Ok(Self::CircuitId(id)),
+            "remote-id" => Ok(Self::RemoteId(id)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for RelayId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CircuitId(_) => f.write_str("circuit-id:")?,
+            Self::RemoteId(_) => f.write_str("remote-id:")?,
+        }
+        let id = self.id();
+        match std::str::from_utf8(id) {
+            Ok(text) if !text.starts_with("0x") && text.chars().all(|c| c.is_ascii_graphic()) => {
+                f.write_str(text)
+            }
+            _ => {
+                f.write_str("0x")?;
+                id.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+/// Option 77, the classes a user or boot stage puts the client in. RFC 3004
+/// sends each class with a length byte in front, but iPXE and Windows send a
+/// single class without one, so we keep the raw value and work out which we
+/// were sent when asked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserClass(Vec<u8>);
+
+impl UserClass {
+    /// Every class the client is in
+    pub fn classes(&self) -> Vec<&[u8]> {
+        let mut classes = Vec::new();
+        let mut rest = &self.0[..];
+        while let [len, after @ ..] = rest {
+            let Some(class) = after.get(..*len as usize).filter(|class| !class.is_empty()) else {
+                break;
+            };
+            classes.push(class);
+            rest = &after[class.len()..];
+        }
+        // Anything that is not a whole list of classes is one class
+        match rest.is_empty() {
+            true => classes,
+            false => vec![&self.0[..]],
+        }
+    }
+
+    /// Whether `class` is one of the client's classes
+    pub fn contains(&self, class: &[u8]) -> bool {
+        self.classes().contains(&class)
+    }
+
+    /// The wire format
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for UserClass {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
@@ -0,0 +1,55 @@
+//! The SMBIOS UUID PXE clients send in option 97, which names the machine
+//! rather than whichever of its NICs is booting
+
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    pub const LEN: usize = 16;
+
+    /// Option 97 is a type byte of 0 and the UUID (PXE 2.1 2.4.5)
+    const CLIENT_UID_TYPE: u8 = 0;
+
+    /// The UUID in the value of option 97, [None] for other kinds of UID
+    pub fn from_client_uid(uid: &[u8]) -> Option<Self> {
+        match uid {
+            [Self::CLIENT_UID_TYPE, uuid @ ..] => Some(Self(uuid.try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Uuid {
+    type Err = Error;
+
+    /// 32 hex digits, usually grouped 8-4-4-4-12 by `-`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidUuid(text.to_owned());
+        let digits: Vec<u8> = text.bytes().filter(|byte| *byte != b'-').collect();
+        if digits.len() != Self::LEN * 2 {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; Self::LEN];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if [4, 6, 8, 10].contains(&index) {
+                f.write_str("-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Running under the Windows service control manager (SCM)
+//!
+//! Install with `sc.exe create dhc3po binPath= "C:\path\to\dhc3po.exe --service"`
+//! and the SCM will start us with `--service`. We hand our main thread to the
+//! SCM dispatcher which calls [service_main] on a new thread to run the server.
+
+use crate::cli::Args;
+use crate::{Error, Result, SHUTDOWN};
+use log::{error, info};
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+/// Our name as registered with the SCM, UTF-16 and null terminated
+const SERVICE_NAME: &[u16] = &[
+    b'd' as u16,
+    b'h' as u16,
+    b'c' as u16,
+    b'3' as u16,
+    b'p' as u16,
+    b'o' as u16,
+    0,
+];
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_START_PENDING: u32 = 2;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+
+/// SERVICE_STATUS_HANDLE given to us by the SCM
+static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+
+type ServiceMain = unsafe extern "system" fn(argc: u32, argv: *mut *mut u16);
+type HandlerEx = unsafe extern "system" fn(
+    control: u32,
+    event_type: u32,
+    event_data: *mut c_void,
+    context: *mut c_void,
+) -> u32;
+
+/// SERVICE_TABLE_ENTRYW
+#[repr(C)]
+struct ServiceTableEntry {
+    service_name: *const u16,
+    service_proc: Option<ServiceMain>,
+}
+
+/// SERVICE_STATUS
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(service_table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        service_name: *const u16,
+        handler: HandlerEx,
+        context: *mut c_void,
+    ) -> isize;
+    fn SetServiceStatus(handle: isize, status: *const ServiceStatus) -> i32;
+}
+
+/// Connect to the SCM, this blocks until the service has stopped
+pub fn start() -> Result<()> {
+    let table = [
+        ServiceTableEntry {
+            service_name: SERVICE_NAME.as_ptr(),
+            service_proc: Some(service_main),
+        },
+        // The table is terminated by a null entry
+        ServiceTableEntry {
+            service_name: ptr::null(),
+            service_proc: None,
+        },
+    ];
+
+    // SAFETY: the table is valid and outlives the call, which only returns
+    // once every service in it has stopped
+    match unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } {
+        0 => Err(Error::WindowsService(std::io::Error::last_os_error())),
+        _ => Ok(()),
+    }
+}
+
+/// Called by the SCM on its own thread when the service starts
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let handle =
+        RegisterServiceCtrlHandlerExW(SERVICE_NAME.as_ptr(), control_handler, ptr::null_mut());
+    if handle == 0 {
+        error!(
+            "{:?}",
+            Error::WindowsService(std::io::Error::last_os_error())
+        );
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::Relaxed);
+
+    set_status(SERVICE_START_PENDING);
+    match Args::parse() {
+        Ok(args) => {
+            set_status(SERVICE_RUNNING);
+            info!("Running as a Windows service");
+            crate::run(&args);
+        }
+        Err(err) => error!("{err:?}"),
+    }
+    set_status(SERVICE_STOPPED);
+}
+
+/// Called by the SCM when it wants something from us
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING);
+            // The server loop notices within [crate::SHUTDOWN_POLL_INTERVAL]
+            SHUTDOWN.store(true, Ordering::Relaxed);
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Report our current state to the SCM
+fn set_status(state: u32) {
+    let controls_accepted = match state {
+        SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+        _ => 0,
+    };
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        win32_exit_code: NO_ERROR,
+        service_specific_exit_code: 0,
+        check_point: 0,
+        wait_hint: 0,
+    };
+
+    // SAFETY: the handle came from RegisterServiceCtrlHandlerExW and the
+    // status is a valid SERVICE_STATUS
+    if unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::Relaxed), &status) } == 0 {
+        error!(
+            "{:?}",
+            Error::WindowsService(std::io::Error::last_os_error())
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! The DHCP wire format with nothing but `core`, no allocations and no
+//! `std::net`, so a bootloader or firmware can read and write packets the
+//! way the server does. Build with `default-features = false` to get only
+//! this module.
+//!
+//! [Header] is the fixed part of a packet, [Options] walks the options that
+//! follow it where they lie and [put_option] writes one
+
+use core::fmt;
+use core::ops::Range;
+
+/// Where each fixed field of the header lies (RFC 2131 2)
+pub const OP: usize = 0;
+pub const HTYPE: usize = 1;
+pub const HLEN: usize = 2;
+pub const HOPS: usize = 3;
+pub const XID: Range<usize> = 4..8;
+pub const SECS: Range<usize> = 8..10;
+pub const FLAGS: Range<usize> = 10..12;
+pub const CIADDR: Range<usize> = 12..16;
+pub const YIADDR: Range<usize> = 16..20;
+pub const SIADDR: Range<usize> = 20..24;
+pub const GIADDR: Range<usize> = 24..28;
+pub const CHADDR: Range<usize> = 28..SNAME_START;
+/// Where sname starts in a packet
+pub const SNAME_START: usize = 44;
+/// Where file starts in a packet
+pub const FILE_START: usize = 108;
+/// Where the magic cookie starts, after the BOOTP header
+pub const MAGIC_START: usize = 236;
+/// Where options start, after the magic cookie
+pub const OPTIONS_START: usize = 240;
+/// The cookie saying the options that follow are DHCP's (RFC 2131 3)
+pub const MAGIC: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+/// Padding between options, carries nothing
+pub const PAD: u8 = 0;
+/// The last option in an area
+pub const END: u8 = 255;
+
+/// What can go wrong reading or writing the wire format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Shorter than the header and magic, by its length
+    TooShort(usize),
+
+    /// No DHCP magic cookie, plain BOOTP or not DHCP at all
+    MagicMissing,
+
+    /// The buffer has no room for what we were writing
+    NoRoom,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(f, "{len} bytes is too short for a DHCP packet"),
+            Self::MagicMissing => write!(f, "no DHCP magic cookie"),
+            Self::NoRoom => write!(f, "no room left in the buffer"),
+        }
+    }
+}
+
+/// The fixed fields before the options, less sname and file which are
+/// read and written as they are, see [sname] and [file]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Header {
+    pub op: u8,
+    pub htype: u8,
+    pub hlen: u8,
+    pub hops: u8,
+    pub xid: [u8; 4],
+    pub secs: [u8; 2],
+    pub flags: [u8; 2],
+    pub ciaddr: [u8; 4],
+    pub yiaddr: [u8; 4],
+    pub siaddr: [u8; 4],
+    pub giaddr: [u8; 4],
+    pub chaddr: [u8; 16],
+}
+
+impl Header {
+    /// The header of `packet`, which must carry the magic cookie
+    pub fn read(packet: &[u8]) -> Result<Self, Error> {
+        if packet.len() < OPTIONS_START {
+            return Err(Error::TooShort(packet.len()));
+        }
+        if packet[MAGIC_START..OPTIONS_START] != MAGIC {
+            return Err(Error::MagicMissing);
+        }
+        Ok(Self {
+            op: packet[OP],
+            htype: packet[HTYPE],
+            hlen: packet[HLEN],
+            hops: packet[HOPS],
+            xid: packet[XID].try_into().unwrap(),
+            secs: packet[SECS].try_into().unwrap(),
+            flags: packet[FLAGS].try_into().unwrap(),
+            ciaddr: packet[CIADDR].try_into().unwrap(),
+            yiaddr: packet[YIADDR].try_into().unwrap(),
+            siaddr: packet[SIADDR].try_into().unwrap(),
+            giaddr: packet[GIADDR].try_into().unwrap(),
+            chaddr: packet[CHADDR].try_into().unwrap(),
+        })
+    }
+
+    /// Write the header, `sname`, `file` and the magic cookie to the start
+    /// of `buffer`, clearing whatever was there. Returns where options go
+    pub fn write(&self, buffer: &mut [u8], sname: &[u8], file: &[u8]) -> Result<usize, Error> {
+        // Both are null terminated
+        if buffer.len() < OPTIONS_START
+            || sname.len() >= FILE_START - SNAME_START
+            || file.len() >= MAGIC_START - FILE_START
+        {
+            return Err(Error::NoRoom);
+        }
+        buffer[..MAGIC_START].fill(0);
+        buffer[OP] = self.op;
+        buffer[HTYPE] = self.htype;
+        buffer[HLEN] = self.hlen;
+        buffer[HOPS] = self.hops;
+        buffer[XID].copy_from_slice(&self.xid);
+        buffer[SECS].copy_from_slice(&self.secs);
+        buffer[FLAGS].copy_from_slice(&self.flags);
+        buffer[CIADDR].copy_from_slice(&self.ciaddr);
+        buffer[YIADDR].copy_from_slice(&self.yiaddr);
+        buffer[SIADDR].copy_from_slice(&self.siaddr);
+        buffer[GIADDR].copy_from_slice(&self.giaddr);
+        buffer[CHADDR].copy_from_slice(&self.chaddr);
+        buffer[SNAME_START..SNAME_START + sname.len()].copy_from_slice(sname);
+        buffer[FILE_START..FILE_START + file.len()].copy_from_slice(file);
+        buffer[MAGIC_START..OPTIONS_START].copy_from_slice(&MAGIC);
+        Ok(OPTIONS_START)
+    }
+}
+
+/// sname up to its null terminator
+pub fn sname(packet: &[u8]) -> Option<&[u8]> {
+    packet.get(SNAME_START..FILE_START).map(c_string)
+}
+
+/// file up to its null terminator
+pub fn file(packet: &[u8]) -> Option<&[u8]> {
+    packet.get(FILE_START..MAGIC_START).map(c_string)
+}
+
+fn c_string(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// The options in an area as (code, value), one item per instance, without
+/// padding. Stops at END, the end of the area or an option that runs past
+/// it, see [Options::truncated]
+#[derive(Debug, Clone)]
+pub struct Options<'a> {
+    rest: &'a [u8],
+    truncated: Option<u8>,
+}
+
+impl<'a> Options<'a> {
+    /// The options of a whole packet, none when it is too short to have any
+    pub fn of(packet: &'a [u8]) -> Self {
+        Self::over(packet.get(OPTIONS_START..).unwrap_or_default())
+    }
+
+    /// The options in an area on its own, such as an overloaded file field
+    pub fn over(area: &'a [u8]) -> Self {
+        Self {
+            rest: area,
+            truncated: None,
+        }
+    }
+
+    /// The code of the option we stopped at because it runs past the end
+    /// of the area, once we have stopped
+    pub fn truncated(&self) -> Option<u8> {
+        self.truncated
+    }
+
+    /// The first instance of option `code`
+    pub fn get(mut self, code: u8) -> Option<&'a [u8]> {
+        self.find(|(existing, _)| *existing == code)
+            .map(|(_, value)| value)
+    }
+
+    /// Every instance of option `code` joined into `out` (RFC 3396), [None]
+    /// when there are none or they do not fit
+    pub fn join(self, code: u8, out: &mut [u8]) -> Option<&[u8]> {
+        let mut len = 0;
+        let mut found = false;
+        for (_, value) in self.filter(|(existing, _)| *existing == code) {
+            out.get_mut(len..len + value.len())?.copy_from_slice(value);
+            len += value.len();
+            found = true;
+        }
+        found.then_some(&out[..len])
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (code, after_code) = self.rest.split_first()?;
+            match *code {
+                PAD => self.rest = after_code,
+                END => {
+                    self.rest = &[];
+                    return None;
+                }
+                code => {
+                    let value = after_code
+                        .split_first()
+                        .and_then(|(len, after_len)| after_len.get(..*len as usize));
+                    let Some(value) = value else {
+                        self.rest = &[];
+                        self.truncated = Some(code);
+                        return None;
+                    };
+                    self.rest = &after_code[1 + value.len()..];
+                    return Some((code, value));
+                }
+            }
+        }
+    }
+}
+
+/// Write option `code` with `value` to the start of `buffer`, returning how
+/// many bytes that took. Values too long for one length byte are split
+/// across several instances of the option, as described in RFC 3396
+pub fn put_option(buffer: &mut [u8], code: u8, value: &[u8]) -> Result<usize, Error> {
+    // An empty value is still one instance of the option
+    if value.is_empty() {
+        buffer
+            .get_mut(..2)
+            .ok_or(Error::NoRoom)?
+            .copy_from_slice(&[code, 0]);
+        return Ok(2);
+    }
+
+    let mut len = 0;
+    for chunk in value.chunks(u8::MAX as usize) {
+        let instance = buffer
+            .get_mut(len..len + 2 + chunk.len())
+            .ok_or(Error::NoRoom)?;
+        instance[0] = code;
+        instance[1] = chunk.len() as u8;
+        instance[2..].copy_from_slice(chunk);
+        len += instance.len();
+    }
+    Ok(len)
+}